@@ -7,7 +7,7 @@ use log::info;
 
 use wampire::{
     client::{Client, Connection},
-    ArgList, Value, URI,
+    wamp_list, ArgList, Value, URI,
 };
 
 enum Command {
@@ -84,15 +84,13 @@ async fn add(client: &mut Client, args: &[String]) {
     };
 
     match client
-        .call(
-            URI::new("ca.test.add"),
-            Some(vec![Value::Integer(a), Value::Integer(b)]),
-            None,
-        )
+        .call_split(URI::new("ca.test.add"), Some(wamp_list![a, b]), None)
         .await
     {
-        Ok((args, _)) => {
-            println!("Result: {}", args.get_int(0).unwrap().unwrap());
+        Ok(result) => {
+            if let Some(Value::Integer(sum)) = result.first_arg() {
+                println!("Result: {}", sum);
+            }
         }
         Err(err) => {
             println!("Error: {:?}", err);
@@ -102,9 +100,7 @@ async fn add(client: &mut Client, args: &[String]) {
 
 async fn echo(client: &mut Client, args: Vec<String>) {
     let args = args.into_iter().map(Value::String).collect();
-    let result = client
-        .call(URI::new("ca.test.echo"), Some(args), None)
-        .await;
+    let result = client.call_args(URI::new("ca.test.echo"), args).await;
     println!("Result: {:?}", result);
 }
 
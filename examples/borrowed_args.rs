@@ -0,0 +1,55 @@
+//! Times publishing a large payload over and over, once with `publish`, which takes the
+//! arguments and so needs a fresh copy of them each time, and once with `publish_borrowed`.
+//!
+//! Run with `cargo run --release --example borrowed_args`.
+use std::time::{Duration, Instant};
+
+use futures::executor::block_on;
+
+use wampire::{Client, Connection, Router, Value, URI};
+
+/// Number of times the payload is published with each method
+const ROUNDS: usize = 1000;
+
+/// Publications sent between waits for the router to catch up, so that the websocket's
+/// output buffer doesn't fill up
+const BURST: usize = 10;
+
+/// Publishes `ROUNDS` times with `publish`, returning how long that took
+fn time(client: &mut Client, mut publish: impl FnMut(&mut Client)) -> Duration {
+    let start = Instant::now();
+    for round in 1..=ROUNDS {
+        publish(client);
+        if round % BURST == 0 {
+            // Acknowledged once the router has read everything sent before
+            block_on(client.publish_and_acknowledge(URI::new("ca.test.flush"), None, None))
+                .unwrap();
+        }
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+    let mut client = Connection::new(&url, "ca.test.realm").connect().unwrap();
+
+    let payload = (0..10_000).map(Value::Integer).collect::<Vec<_>>();
+    let topic = URI::new("ca.test.payload");
+
+    let owned = time(&mut client, |client| {
+        client
+            .publish(topic.clone(), Some(payload.clone()), None)
+            .unwrap()
+    });
+    println!("publish:          {:?}", owned);
+
+    let borrowed = time(&mut client, |client| {
+        client
+            .publish_borrowed(topic.clone(), Some(&payload), None)
+            .unwrap()
+    });
+    println!("publish_borrowed: {:?}", borrowed);
+}
@@ -5,14 +5,14 @@ use std::io;
 
 use log::info;
 
-use wampire::{client::Connection, ArgList, CallResult, Dict, List, Value, URI};
+use wampire::{client::Connection, reply, wamp_list, ArgList, CallResult, Dict, List, URI};
 
 fn addition_callback(args: List, _kwargs: Dict) -> CallResult<(Option<List>, Option<Dict>)> {
     info!("Performing addition");
     args.verify_len(2)?;
     let a = args.get_int(0)?.unwrap();
     let b = args.get_int(1)?.unwrap();
-    Ok((Some(vec![Value::Integer(a + b)]), None))
+    reply(wamp_list![a + b])
 }
 
 fn multiplication_callback(args: List, _kwargs: Dict) -> CallResult<(Option<List>, Option<Dict>)> {
@@ -20,7 +20,7 @@ fn multiplication_callback(args: List, _kwargs: Dict) -> CallResult<(Option<List
     args.verify_len(2)?;
     let a = args.get_int(0)?.unwrap();
     let b = args.get_int(1)?.unwrap();
-    Ok((Some(vec![Value::Integer(a * b)]), None))
+    reply(wamp_list![a * b])
 }
 
 fn echo_callback(args: List, kwargs: Dict) -> CallResult<(Option<List>, Option<Dict>)> {
@@ -98,10 +98,9 @@ async fn subscribe(
             policy,
         )
         .await
-        .and_then(move |subscription| {
+        .map(move |subscription| {
             println!("Subscribed to topic {}", subscription.topic.uri);
             subscriptions.lock().unwrap().push(subscription);
-            Ok(())
         })
         .unwrap();
 }
@@ -119,19 +118,20 @@ async fn unsubscribe(
     }
     match args[0].parse::<usize>() {
         Ok(i) => {
-            let mut subscriptions = subscriptions.lock().unwrap();
-            if i >= subscriptions.len() {
-                println!("Invalid subscription index: {}", i);
-                return;
-            }
-            let subscription = subscriptions.remove(i);
+            let subscription = {
+                let mut subscriptions = subscriptions.lock().unwrap();
+                if i >= subscriptions.len() {
+                    println!("Invalid subscription index: {}", i);
+                    return;
+                }
+                subscriptions.remove(i)
+            };
             let topic = subscription.topic.uri.clone();
             client
                 .unsubscribe(subscription)
                 .await
-                .and_then(move |()| {
+                .map(move |()| {
                     println!("Successfully unsubscribed from {}", topic);
-                    Ok(())
                 })
                 .unwrap();
         }
@@ -1,4 +1,4 @@
-#![allow(dead_code, unused_imports, unused_variables)]
+#![allow(dead_code, unused_imports, unused_variables, clippy::unnecessary_literal_unwrap)]
 use futures::channel::oneshot::*;
 
 #[derive(Debug)]
@@ -0,0 +1,1724 @@
+//! Client built on tokio-tungstenite
+//!
+//! The thread based [`Client`](crate::Client) drives its socket from a thread of its own.  This
+//! client instead spawns a task on the current tokio runtime when it connects, so it has to be
+//! used from within a runtime.  Callbacks run on that task and should not block.  Otherwise
+//! the two clients offer the same methods.
+//!
+//! ```no_run
+//! use wampire::{client::async_client::Connection, Value, URI};
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let connection = Connection::new("ws://127.0.0.1:8090/ws", "wampire_realm");
+//!     let mut client = connection.connect().await.unwrap();
+//!     let (args, _kwargs) = client
+//!         .call(URI::new("ca.test.echo"), Some(vec![Value::String("ping".to_string())]), None)
+//!         .await
+//!         .unwrap();
+//!     println!("Echoed {:?}", args);
+//!     client.shutdown().await.unwrap();
+//! }
+//! ```
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{
+    channel::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    future, SinkExt, Stream, StreamExt,
+};
+use intmap::IntMap;
+use log::{debug, error, info, warn};
+use tokio::{net::TcpStream, time};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        client::IntoClientRequest,
+        http::{HeaderName, HeaderValue},
+        Message as WSMessage,
+    },
+    MaybeTlsStream, WebSocketStream,
+};
+
+use crate::{
+    messages::{
+        CallOptions, ClientRoles, Dict, ErrorDetails, ErrorType, HelloDetails, List,
+        MatchingPolicy, Message, MessageRef, PublishOptions, Reason, RegisterOptions,
+        ResultDetails, RouterFeatures, RunMode, SubscribeOptions, Value, YieldOptions, URI,
+    },
+    serializer::{BatchedJsonSerializer, Payload, Serializer, Serializers},
+    CallError, CallResult, Error, ErrorKind, WampResult, ID,
+};
+
+use super::{
+    event_stream, CallOutput, CallRequest, Complete, Registration, Subscription, DEFAULT_AGENT,
+};
+
+/// Number of consecutive pings the router may leave unanswered before the connection is closed
+const MAX_MISSED_PINGS: u32 = 3;
+
+/// Number of messages held back while waiting for WELCOME before the connection is given up
+const MAX_EARLY_MESSAGES: usize = 32;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A connection that has been welcomed into its realm
+struct Session {
+    socket: Socket,
+    serializer: Arc<dyn Serializer>,
+    session_id: ID,
+    router_features: RouterFeatures,
+    /// Messages the router sent ahead of its welcome
+    early_messages: Vec<Message>,
+    max_message_size: usize,
+}
+
+/// Alias for WAMP callback run by the async client
+pub type Callback = Box<dyn FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)> + Send>;
+
+type EventCallback = Box<dyn FnMut(List, Dict) + Send>;
+
+/// Alias for the callback of a procedure that streams its results.  The invocation is answered
+/// through the `Yielder`, which may be kept and used from another task or thread.
+pub type StreamCallback = Box<dyn FnMut(List, Dict, Yielder) + Send>;
+
+/// How a registered procedure answers its invocations
+enum RegistrationCallback {
+    /// The callback returns the one result
+    Single(Callback),
+    /// The callback answers through a `Yielder`, with any number of progressive results
+    Stream(StreamCallback),
+}
+
+/// Something on its way to the event loop
+#[allow(clippy::large_enum_variant)]
+enum Outbound {
+    /// A message, encoded by the event loop
+    Message(Message),
+    /// A message encoded by whoever sent it, such as one made of borrowed arguments
+    Encoded(Payload),
+}
+
+/// Answers one invocation of a procedure registered with `Client::register_stream`.
+///
+/// Send any number of progressive results, then end the invocation with `finish` or `fail`.
+/// Progressive results are only sent if the caller asked for them.  Dropping the yielder
+/// without ending the invocation fails it with `Reason::InternalError`, so the caller isn't
+/// left waiting.
+pub struct Yielder {
+    sender: UnboundedSender<Outbound>,
+    request_id: ID,
+    receive_progress: bool,
+    finished: bool,
+}
+
+impl Yielder {
+    /// Send a progressive result
+    pub fn progress(&self, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
+        if !self.receive_progress {
+            debug!(
+                "Not sending a progressive result for invocation {}, the caller didn't ask for them",
+                self.request_id
+            );
+            return Ok(());
+        }
+        let mut options = YieldOptions::new();
+        options.progress = true;
+        self.send(Message::Yield(self.request_id, options, args, kwargs))
+    }
+
+    /// Send the final result, ending the invocation
+    pub fn finish(mut self, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
+        self.finished = true;
+        self.send(Message::Yield(
+            self.request_id,
+            YieldOptions::new(),
+            args,
+            kwargs,
+        ))
+    }
+
+    /// Fail the invocation with `error`
+    pub fn fail(mut self, error: CallError) -> WampResult<()> {
+        self.finished = true;
+        let (reason, args, kwargs) = error.into_tuple();
+        self.send_error(reason, args, kwargs)
+    }
+
+    fn send_error(
+        &self,
+        reason: Reason,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) -> WampResult<()> {
+        self.send(Message::Error(
+            ErrorType::Invocation,
+            self.request_id,
+            HashMap::new(),
+            reason,
+            args,
+            kwargs,
+        ))
+    }
+
+    fn send(&self, message: Message) -> WampResult<()> {
+        self.sender
+            .unbounded_send(Outbound::Message(message))
+            .map_err(|_| Error::new(ErrorKind::ConnectionLost))
+    }
+}
+
+impl Drop for Yielder {
+    fn drop(&mut self) {
+        if !self.finished {
+            warn!(
+                "Invocation {} was dropped without a final result",
+                self.request_id
+            );
+            self.send_error(Reason::InternalError, None, None).ok();
+        }
+    }
+}
+
+/// Represents WAMP connection
+pub struct Connection {
+    realm: URI,
+    url: String,
+    agent: String,
+    authid: Option<String>,
+    authrole: Option<String>,
+    ping_interval: Duration,
+    timeout: Duration,
+    serializers: Serializers,
+    max_message_size: usize,
+    /// Extra HTTP headers sent with the websocket handshake
+    headers: Vec<(String, String)>,
+    local_delivery: bool,
+}
+
+/// Represents WAMP Client
+pub struct Client {
+    connection_info: Arc<Mutex<ConnectionInfo>>,
+    max_session_id: ID,
+}
+
+#[derive(PartialEq, Debug)]
+enum ConnectionState {
+    Connected,
+    ShuttingDown,
+    Disconnected,
+}
+
+struct ConnectionInfo {
+    connection_state: ConnectionState,
+    sender: UnboundedSender<Outbound>,
+    serializer: Arc<dyn Serializer>,
+    subscription_requests: IntMap<(Complete<Subscription>, EventCallback, URI)>,
+    unsubscription_requests: IntMap<(Complete<()>, ID)>,
+    subscriptions: IntMap<(EventCallback, URI)>,
+    /// Resolve with the next event of a subscription, as asked for by `Client::next_event`
+    event_waiters: IntMap<Vec<oneshot::Sender<(List, Dict)>>>,
+    registrations: IntMap<(RegistrationCallback, URI)>,
+    call_requests: IntMap<CallRequest>,
+    registration_requests: IntMap<(Complete<Registration>, RegistrationCallback, URI)>,
+    unregistration_requests: IntMap<(Complete<()>, ID)>,
+    /// Resolve with the publication id and the number of subscribers the event reached
+    publish_requests: IntMap<Complete<(ID, usize)>>,
+    shutdown_complete: Option<Complete<()>>,
+    session_id: ID,
+    router_features: RouterFeatures,
+    /// Set by `Connection::with_local_delivery`
+    local_delivery: bool,
+}
+
+/// Held by a call's future so that dropping the future before the result arrives forgets
+/// the pending request
+struct PendingCall {
+    connection_info: Arc<Mutex<ConnectionInfo>>,
+    request_id: ID,
+}
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        // A no-op once the result has been delivered
+        self.connection_info
+            .lock()
+            .unwrap()
+            .call_requests
+            .remove(self.request_id);
+    }
+}
+
+macro_rules! cancel_future_tuple {
+    ($dict:expr) => {{
+        for (_, future) in $dict.drain() {
+            let _ = future
+                .0
+                .send(Err(CallError::new(Reason::NetworkFailure, None, None)));
+        }
+    }};
+}
+
+macro_rules! cancel_future {
+    ($dict:expr) => {{
+        for (_, future) in $dict.drain() {
+            let _ = future.send(Err(CallError::new(Reason::NetworkFailure, None, None)));
+        }
+    }};
+}
+
+fn tungstenite_error<E: Into<tokio_tungstenite::tungstenite::Error>>(error: E) -> Error {
+    Error::new(ErrorKind::TungsteniteError(error.into()))
+}
+
+fn encode_message(serializer: &dyn Serializer, message: &Message) -> WampResult<WSMessage> {
+    serializer.encode(message).map(ws_message)
+}
+
+fn ws_message(payload: Payload) -> WSMessage {
+    match payload {
+        Payload::Text(text) => WSMessage::text(text),
+        Payload::Binary(bytes) => WSMessage::binary(bytes),
+    }
+}
+
+fn decode_message(serializer: &dyn Serializer, message: WSMessage) -> Vec<Message> {
+    let payload = match message {
+        WSMessage::Text(text) => Payload::Text(text.to_string()),
+        WSMessage::Binary(bytes) => Payload::Binary(bytes.to_vec()),
+        _ => return Vec::new(),
+    };
+    match serializer.decode(&payload) {
+        Ok(messages) => messages,
+        Err(_) => {
+            error!("Could not understand message from the router");
+            Vec::new()
+        }
+    }
+}
+
+impl Connection {
+    /// Create new connection with uri and realm
+    pub fn new(url: &str, realm: &str) -> Connection {
+        Connection {
+            realm: URI::new(realm),
+            url: url.to_string(),
+            agent: DEFAULT_AGENT.to_string(),
+            authid: None,
+            authrole: None,
+            ping_interval: Duration::from_secs(0),
+            timeout: Duration::from_secs(5),
+            serializers: Serializers::default(),
+            max_message_size: usize::MAX,
+            headers: Vec::new(),
+            local_delivery: false,
+        }
+    }
+
+    /// Announce the client as `agent` in the HELLO message.  The default is
+    /// `wampire-client-<version>`.
+    pub fn with_agent(mut self, agent: &str) -> Connection {
+        self.agent = agent.to_string();
+        self
+    }
+
+    /// Announce `authid` as the identity of the session in the HELLO message
+    pub fn with_authid(mut self, authid: &str) -> Connection {
+        self.authid = Some(authid.to_string());
+        self
+    }
+
+    /// Announce `authrole` as the role of the session in the HELLO message
+    pub fn with_authrole(mut self, authrole: &str) -> Connection {
+        self.authrole = Some(authrole.to_string());
+        self
+    }
+
+    /// Give up on connecting if the router hasn't welcomed the session within `timeout`.
+    /// The default is five seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Connection {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Ping the router each `interval` and close the connection if several pings in a row go
+    /// unanswered.  A zero interval, the default, disables pings.
+    pub fn with_ping_interval(mut self, interval: Duration) -> Connection {
+        self.ping_interval = interval;
+        self
+    }
+
+    /// Close the connection if the router sends a message larger than `limit` bytes.  By
+    /// default messages of any size are accepted.
+    pub fn with_max_message_size(mut self, limit: usize) -> Connection {
+        self.max_message_size = limit;
+        self
+    }
+
+    /// Offer batched JSON (`wamp.2.json.batched`) ahead of the other serializations, letting
+    /// the router pack several messages into a single websocket frame
+    pub fn with_batched_json(mut self) -> Connection {
+        self.serializers = self.serializers.prefer(BatchedJsonSerializer);
+        self
+    }
+
+    /// Offer the sub-protocols of `serializers`, in their order of preference, in place of
+    /// the built in ones.  The router picks one it has a serializer for as well.
+    pub fn with_serializers(mut self, serializers: Serializers) -> Connection {
+        self.serializers = serializers;
+        self
+    }
+
+    /// Send the HTTP header `name: value` with the websocket handshake, such as an
+    /// `Authorization` token or a cookie for a gateway in front of the router
+    pub fn with_header(mut self, name: &str, value: &str) -> Connection {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Hand the client's own publications straight to its subscriptions on the same topic.
+    ///
+    /// The router never sends a session the events it published itself, so without this a
+    /// client that subscribes and publishes to one topic doesn't see its own events.  With it,
+    /// every publish first runs the callbacks of the client's subscriptions whose topic equals
+    /// the published URI, on the publishing task, and only then sends the event to the
+    /// router.  Pattern subscriptions are only invoked for the topic they were made with, and
+    /// the count of subscribers reported to `publish_and_acknowledge` only covers those the
+    /// router reached.
+    pub fn with_local_delivery(mut self) -> Connection {
+        self.local_delivery = true;
+        self
+    }
+
+    /// Connect to router and spawn the task driving the connection
+    pub async fn connect(&self) -> WampResult<Client> {
+        let session = time::timeout(self.timeout, self.handshake())
+            .await
+            .map_err(|_| Error::new(ErrorKind::Timeout))??;
+
+        let (sender, receiver) = mpsc::unbounded();
+        let info = Arc::new(Mutex::new(ConnectionInfo {
+            connection_state: ConnectionState::Connected,
+            sender,
+            serializer: Arc::clone(&session.serializer),
+            subscription_requests: IntMap::new(),
+            unsubscription_requests: IntMap::new(),
+            subscriptions: IntMap::new(),
+            event_waiters: IntMap::new(),
+            registrations: IntMap::new(),
+            call_requests: IntMap::new(),
+            registration_requests: IntMap::new(),
+            unregistration_requests: IntMap::new(),
+            publish_requests: IntMap::new(),
+            shutdown_complete: None,
+            session_id: session.session_id,
+            router_features: session.router_features,
+            local_delivery: self.local_delivery,
+        }));
+        let ping = if self.ping_interval.is_zero() {
+            None
+        } else {
+            let start = time::Instant::now() + self.ping_interval;
+            Some(time::interval_at(start, self.ping_interval))
+        };
+        tokio::spawn(event_loop(session, receiver, ping, Arc::clone(&info)));
+
+        Ok(Client {
+            connection_info: info,
+            max_session_id: 0,
+        })
+    }
+
+    async fn handshake(&self) -> WampResult<Session> {
+        let mut request = self
+            .url
+            .as_str()
+            .into_client_request()
+            .map_err(tungstenite_error)?;
+        let protocols = self.serializers.subprotocols().join(", ");
+        request.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            HeaderValue::from_str(&protocols).unwrap(),
+        );
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(tungstenite_error)?;
+            let value = HeaderValue::from_str(value).map_err(tungstenite_error)?;
+            request.headers_mut().append(name, value);
+        }
+        let (mut socket, response) = connect_async(request).await.map_err(tungstenite_error)?;
+        debug!("Connection Opened");
+
+        let protocol = response
+            .headers()
+            .get("Sec-WebSocket-Protocol")
+            .and_then(|protocol| protocol.to_str().ok());
+        let serializer = match protocol.and_then(|protocol| self.serializers.get(protocol)) {
+            Some(serializer) => serializer,
+            None => {
+                error!("Router selected {:?}, which wasn't offered", protocol);
+                return Err(Error::new(ErrorKind::HandshakeError(
+                    Reason::ProtocolViolation,
+                )));
+            }
+        };
+
+        debug!("Sending Hello message");
+        let mut details = HelloDetails::new_with_agent(ClientRoles::new(), &self.agent);
+        details.authid = self.authid.clone();
+        details.authrole = self.authrole.clone();
+        let hello_message = Message::Hello(self.realm.clone(), details);
+        socket
+            .send(encode_message(&*serializer, &hello_message)?)
+            .await
+            .map_err(tungstenite_error)?;
+
+        let mut early_messages = Vec::new();
+        let mut welcome = None;
+        while let Some(message) = socket.next().await {
+            let message = message.map_err(tungstenite_error)?;
+            if message.len() > self.max_message_size {
+                error!(
+                    "Message of {} bytes exceeds the limit of {}",
+                    message.len(),
+                    self.max_message_size
+                );
+                return Err(Error::new(ErrorKind::MalformedData));
+            }
+            for message in decode_message(&*serializer, message) {
+                match message {
+                    // Anything batched after the welcome is replayed along with the early messages
+                    message if welcome.is_some() => early_messages.push(message),
+                    Message::Welcome(session_id, details) => {
+                        welcome = Some((session_id, details.roles().features()));
+                    }
+                    Message::Abort(_, reason) => {
+                        error!("Router aborted connection.  Reason: {:?}", reason);
+                        return Err(Error::new(ErrorKind::HandshakeError(reason)));
+                    }
+                    message => {
+                        if early_messages.len() >= MAX_EARLY_MESSAGES {
+                            return Err(Error::new(ErrorKind::UnexpectedMessage(
+                                "Received too many messages before welcome",
+                            )));
+                        }
+                        debug!("Holding back message until welcome: {:?}", message);
+                        early_messages.push(message);
+                    }
+                }
+            }
+            if let Some((session_id, router_features)) = welcome {
+                return Ok(Session {
+                    socket,
+                    serializer,
+                    session_id,
+                    router_features,
+                    early_messages,
+                    max_message_size: self.max_message_size,
+                });
+            }
+        }
+        Err(Error::new(ErrorKind::ConnectionLost))
+    }
+}
+
+/// Wait for the next ping to be due, or forever if pings are disabled
+async fn next_ping(ping: &mut Option<time::Interval>) {
+    match ping {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+/// Handle a message from the router and send any reply, returning whether the connection
+/// should stay open
+async fn dispatch_message(
+    socket: &mut Socket,
+    serializer: &dyn Serializer,
+    info: &Mutex<ConnectionInfo>,
+    message: Message,
+) -> bool {
+    let (reply, disconnected) = {
+        let mut info = info.lock().unwrap();
+        let reply = info.handle_message(message);
+        (reply, info.connection_state == ConnectionState::Disconnected)
+    };
+    if let Some(reply) = reply {
+        debug!(
+            "Sending message {:?} via {}",
+            reply,
+            serializer.subprotocol_name()
+        );
+        let reply = match encode_message(serializer, &reply) {
+            Ok(reply) => reply,
+            Err(e) => {
+                error!("Could not encode message: {}", e);
+                return !disconnected;
+            }
+        };
+        if let Err(e) = socket.send(reply).await {
+            error!("Could not send message: {}", e);
+            return false;
+        }
+    }
+    !disconnected
+}
+
+async fn event_loop(
+    session: Session,
+    mut receiver: UnboundedReceiver<Outbound>,
+    mut ping: Option<time::Interval>,
+    info: Arc<Mutex<ConnectionInfo>>,
+) {
+    let Session {
+        mut socket,
+        serializer,
+        early_messages,
+        max_message_size,
+        ..
+    } = session;
+
+    // Replay whatever the router pipelined ahead of its welcome
+    let mut open = true;
+    for message in early_messages {
+        if !dispatch_message(&mut socket, &*serializer, &info, message).await {
+            open = false;
+            break;
+        }
+    }
+
+    let mut missed_pings = 0;
+    while open {
+        tokio::select! {
+            _ = next_ping(&mut ping) => {
+                if missed_pings >= MAX_MISSED_PINGS {
+                    warn!("Router left {} pings unanswered.  Closing connection", missed_pings);
+                    break;
+                }
+                missed_pings += 1;
+                if let Err(e) = socket.send(WSMessage::Ping(Default::default())).await {
+                    error!("Could not send ping: {}", e);
+                    break;
+                }
+            },
+            message = receiver.next() => match message {
+                Some(Outbound::Message(message)) => {
+                    debug!("Sending message {:?} via {}", message, serializer.subprotocol_name());
+                    let message = match encode_message(&*serializer, &message) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            error!("Could not encode message: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = socket.send(message).await {
+                        error!("Could not send message: {}", e);
+                        break;
+                    }
+                }
+                Some(Outbound::Encoded(payload)) => {
+                    let message = ws_message(payload);
+                    if let Err(e) = socket.send(message).await {
+                        error!("Could not send message: {}", e);
+                        break;
+                    }
+                }
+                // The client was dropped
+                None => break,
+            },
+            message = socket.next() => match message {
+                Some(Ok(WSMessage::Pong(_))) => missed_pings = 0,
+                Some(Ok(message)) if message.len() > max_message_size => {
+                    error!(
+                        "Message of {} bytes exceeds the limit of {}",
+                        message.len(),
+                        max_message_size
+                    );
+                    break;
+                }
+                Some(Ok(message)) => {
+                    for message in decode_message(&*serializer, message) {
+                        open = dispatch_message(&mut socket, &*serializer, &info, message).await;
+                        if !open {
+                            break;
+                        }
+                    }
+                }
+                Some(Err(e)) => {
+                    error!("Connection failed: {}", e);
+                    break;
+                }
+                None => break,
+            },
+        }
+    }
+
+    debug!("Closing connection");
+    let _ = socket.close(None).await;
+    info.lock().unwrap().close();
+}
+
+impl ConnectionInfo {
+    fn send_message(&self, message: Message) -> WampResult<()> {
+        if self.connection_state == ConnectionState::Disconnected {
+            return Err(Error::new(ErrorKind::ConnectionLost));
+        }
+        self.sender
+            .unbounded_send(Outbound::Message(message))
+            .map_err(|_| Error::new(ErrorKind::ConnectionLost))
+    }
+
+    /// Like `send_message`, encoding the borrowed arguments where they are rather than leaving
+    /// that to the event loop
+    fn send_ref(&self, message: MessageRef<'_>) -> WampResult<()> {
+        if self.connection_state == ConnectionState::Disconnected {
+            return Err(Error::new(ErrorKind::ConnectionLost));
+        }
+        debug!(
+            "Sending message {:?} via {}",
+            message,
+            self.serializer.subprotocol_name()
+        );
+        let payload = self.serializer.encode_ref(&message)?;
+        self.sender
+            .unbounded_send(Outbound::Encoded(payload))
+            .map_err(|_| Error::new(ErrorKind::ConnectionLost))
+    }
+
+    /// Runs the callbacks of the subscriptions to `topic` with an event the client is about to
+    /// publish, if local delivery is on
+    fn deliver_locally(&mut self, topic: &URI, args: Option<&[Value]>, kwargs: Option<&Dict>) {
+        if !self.local_delivery {
+            return;
+        }
+        for (callback, subscribed_topic) in self.subscriptions.values_mut() {
+            if subscribed_topic == topic {
+                callback(
+                    args.map(<[Value]>::to_vec).unwrap_or_default(),
+                    kwargs.cloned().unwrap_or_default(),
+                );
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        self.connection_state = ConnectionState::Disconnected;
+        cancel_future_tuple!(self.subscription_requests);
+        cancel_future_tuple!(self.unsubscription_requests);
+        cancel_future_tuple!(self.registration_requests);
+        cancel_future_tuple!(self.unregistration_requests);
+        cancel_future!(self.publish_requests);
+        cancel_future!(self.call_requests);
+        // Their receivers fail, which tells the waiting tasks the subscription is gone
+        self.event_waiters.clear();
+
+        if let Some(promise) = self.shutdown_complete.take() {
+            let _ = promise.send(Ok(()));
+        }
+    }
+
+    /// Handle a message from the router, returning the reply to send, if any
+    fn handle_message(&mut self, message: Message) -> Option<Message> {
+        debug!(
+            "Processing message from server (state: {:?})",
+            self.connection_state
+        );
+        if self.connection_state == ConnectionState::ShuttingDown {
+            if let Message::Goodbye(_, _) = message {
+                // The router has seen our goodbye message and has responded in kind
+                info!("Router acknowledged disconnect");
+            } else {
+                warn!(
+                    "Received message after shutting down, ignoring: {:?}",
+                    message
+                );
+            }
+            self.connection_state = ConnectionState::Disconnected;
+            return None;
+        }
+
+        match message {
+            Message::Subscribed(request_id, subscription_id) => {
+                match self.subscription_requests.remove(request_id) {
+                    Some((promise, callback, topic)) => {
+                        self.subscriptions
+                            .insert(subscription_id, (callback, topic.clone()));
+                        let _ = promise.send(Ok(Subscription {
+                            topic,
+                            subscription_id,
+                        }));
+                    }
+                    None => warn!(
+                        "Received a subscribed notification for a subscription we don't have.  ID: {}",
+                        request_id
+                    ),
+                }
+            }
+            Message::Unsubscribed(request_id) => {
+                match self.unsubscription_requests.remove(request_id) {
+                    Some((promise, subscription_id)) => {
+                        self.subscriptions.remove(subscription_id);
+                        self.event_waiters.remove(subscription_id);
+                        let _ = promise.send(Ok(()));
+                    }
+                    None => warn!(
+                        "Received a unsubscribed notification for a subscription we don't have.  ID: {}",
+                        request_id
+                    ),
+                }
+            }
+            Message::Event(subscription_id, _, _, args, kwargs) => {
+                let args = args.unwrap_or_default();
+                let kwargs = kwargs.unwrap_or_default();
+                if let Some(waiters) = self.event_waiters.remove(subscription_id) {
+                    for waiter in waiters {
+                        let _ = waiter.send((args.clone(), kwargs.clone()));
+                    }
+                }
+                match self.subscriptions.get_mut(subscription_id) {
+                    Some((callback, _)) => callback(args, kwargs),
+                    None => warn!(
+                        "Received an event for a subscription we don't have.  ID: {}",
+                        subscription_id
+                    ),
+                }
+            }
+            Message::Published(request_id, publication_id, details) => {
+                match self.publish_requests.remove(request_id) {
+                    Some(promise) => {
+                        let recipients = details.recipients.unwrap_or(0) as usize;
+                        let _ = promise.send(Ok((publication_id, recipients)));
+                    }
+                    None => warn!(
+                        "Received published notification for a request we weren't tracking: {}",
+                        request_id
+                    ),
+                }
+            }
+            Message::Registered(request_id, registration_id) => {
+                match self.registration_requests.remove(request_id) {
+                    Some((promise, callback, procedure)) => {
+                        self.registrations
+                            .insert(registration_id, (callback, procedure.clone()));
+                        let _ = promise.send(Ok(Registration {
+                            procedure,
+                            registration_id,
+                        }));
+                    }
+                    None => warn!(
+                        "Received a registered notification for a registration we don't have.  ID: {}",
+                        request_id
+                    ),
+                }
+            }
+            Message::Unregistered(request_id) => {
+                match self.unregistration_requests.remove(request_id) {
+                    Some((promise, registration_id)) => {
+                        self.registrations.remove(registration_id);
+                        let _ = promise.send(Ok(()));
+                    }
+                    None => warn!(
+                        "Received a unregistered notification for a registration we don't have.  ID: {}",
+                        request_id
+                    ),
+                }
+            }
+            Message::Invocation(request_id, registration_id, details, args, kwargs) => {
+                let args = args.unwrap_or_default();
+                let kwargs = kwargs.unwrap_or_default();
+                let callback = match self.registrations.get_mut(registration_id) {
+                    Some((RegistrationCallback::Single(callback), _)) => callback,
+                    Some((RegistrationCallback::Stream(callback), _)) => {
+                        let yielder = Yielder {
+                            sender: self.sender.clone(),
+                            request_id,
+                            receive_progress: details.receive_progress,
+                            finished: false,
+                        };
+                        callback(args, kwargs, yielder);
+                        return None;
+                    }
+                    None => {
+                        warn!(
+                            "Received an invocation for a procedure we don't have.  ID: {}",
+                            registration_id
+                        );
+                        return None;
+                    }
+                };
+                return Some(match callback(args, kwargs) {
+                    Ok((rargs, rkwargs)) => {
+                        Message::Yield(request_id, YieldOptions::new(), rargs, rkwargs)
+                    }
+                    Err(error) => {
+                        let (reason, args, kwargs) = error.into_tuple();
+                        Message::Error(
+                            ErrorType::Invocation,
+                            request_id,
+                            HashMap::new(),
+                            reason,
+                            args,
+                            kwargs,
+                        )
+                    }
+                });
+            }
+            Message::Result(call_id, details, args, kwargs) => {
+                let args = args.unwrap_or_default();
+                let kwargs = kwargs.unwrap_or_default();
+                // A progressive result leaves the call pending until the final one arrives
+                let delivered = if details.progress {
+                    self.call_requests
+                        .get(call_id)
+                        .map(|request| request.progress((args, kwargs)))
+                        .is_some()
+                } else {
+                    self.call_requests
+                        .remove(call_id)
+                        .map(|request| request.finish(args, kwargs, details))
+                        .is_some()
+                };
+                if !delivered {
+                    warn!(
+                        "Received a result for a call we didn't make.  ID: {}",
+                        call_id
+                    );
+                }
+            }
+            Message::Error(e_type, request_id, _details, reason, args, kwargs) => {
+                self.handle_error(e_type, request_id, CallError::new(reason, args, kwargs))
+            }
+            Message::Goodbye(_, reason) => {
+                info!("Router said goodbye.  Reason: {:?}", reason);
+                self.connection_state = ConnectionState::Disconnected;
+                return Some(Message::Goodbye(
+                    ErrorDetails::new(),
+                    Reason::GoodbyeAndOut,
+                ));
+            }
+            _ => warn!("Received unknown message.  Ignoring. {:?}", message),
+        }
+        None
+    }
+
+    fn handle_error(&mut self, e_type: ErrorType, request_id: ID, error: CallError) {
+        let promise_found = match e_type {
+            ErrorType::Subscribe => self
+                .subscription_requests
+                .remove(request_id)
+                .map(|(promise, _, _)| promise.send(Err(error)))
+                .is_some(),
+            ErrorType::Unsubscribe => self
+                .unsubscription_requests
+                .remove(request_id)
+                .map(|(promise, _)| promise.send(Err(error)))
+                .is_some(),
+            ErrorType::Publish => self
+                .publish_requests
+                .remove(request_id)
+                .map(|promise| promise.send(Err(error)))
+                .is_some(),
+            ErrorType::Register => self
+                .registration_requests
+                .remove(request_id)
+                .map(|(promise, _, _)| promise.send(Err(error)))
+                .is_some(),
+            ErrorType::Unregister => self
+                .unregistration_requests
+                .remove(request_id)
+                .map(|(promise, _)| promise.send(Err(error)))
+                .is_some(),
+            ErrorType::Call => self
+                .call_requests
+                .remove(request_id)
+                .map(|promise| promise.send(Err(error)))
+                .is_some(),
+            ErrorType::Invocation => {
+                warn!("Received an error for an invocation message, which we did not (and could not) send");
+                true
+            }
+        };
+        if !promise_found {
+            warn!(
+                "Received an error for a request we didn't make.  ID: {}",
+                request_id
+            );
+        }
+    }
+}
+
+/// Resolves with the reply to a request, as received by `receiver`.  Without a receiver the
+/// request was never sent.
+async fn reply<T>(receiver: Option<oneshot::Receiver<CallResult<T>>>) -> CallResult<T> {
+    match receiver {
+        Some(receiver) => receiver
+            .await
+            .unwrap_or_else(|_| Err(CallError::new(Reason::InternalError, None, None))),
+        None => Err(CallError::new(Reason::NetworkFailure, None, None)),
+    }
+}
+
+impl Client {
+    fn get_next_session_id(&mut self) -> ID {
+        self.max_session_id += 1;
+        self.max_session_id
+    }
+
+    /// Session id assigned by the router
+    pub fn session_id(&self) -> ID {
+        self.connection_info.lock().unwrap().session_id
+    }
+
+    /// Whether the session is still established.  Once the connection is lost or shut down
+    /// the client can't be used again.
+    pub fn is_connected(&self) -> bool {
+        self.connection_info.lock().unwrap().connection_state == ConnectionState::Connected
+    }
+
+    /// Advanced features the router announced when the session was established
+    pub fn router_features(&self) -> RouterFeatures {
+        self.connection_info.lock().unwrap().router_features
+    }
+
+    /// The serialization sub-protocol negotiated with the router, such as `wamp.2.json` or
+    /// `wamp.2.msgpack`
+    pub fn protocol(&self) -> String {
+        let info = self.connection_info.lock().unwrap();
+        info.serializer.subprotocol_name().to_string()
+    }
+
+    /// Ids and topics of the subscriptions the router has confirmed
+    pub fn subscriptions(&self) -> Vec<(ID, URI)> {
+        let info = self.connection_info.lock().unwrap();
+        info.subscriptions
+            .iter()
+            .map(|(id, (_, topic))| (*id, topic.clone()))
+            .collect()
+    }
+
+    /// Replace the callback of a subscription, keeping the subscription itself.
+    ///
+    /// Callbacks run with the client locked, so an event that is being handled finishes with
+    /// the old callback, and every event handled after this returns goes to the new one,
+    /// including events the router sent before the swap.  Returns false, dropping `callback`,
+    /// if the client has no subscription with that id.
+    pub fn set_subscription_callback(
+        &mut self,
+        subscription_id: ID,
+        callback: Box<dyn FnMut(List, Dict) + Send>,
+    ) -> bool {
+        let mut info = self.connection_info.lock().unwrap();
+        match info.subscriptions.get_mut(subscription_id) {
+            Some((subscription, _)) => {
+                *subscription = callback;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Ids and procedures of the registrations the router has confirmed
+    pub fn registrations(&self) -> Vec<(ID, URI)> {
+        let info = self.connection_info.lock().unwrap();
+        info.registrations
+            .iter()
+            .map(|(id, (_, procedure))| (*id, procedure.clone()))
+            .collect()
+    }
+
+    /// Send `message` and track the request with `track` once it has been handed to the
+    /// event loop
+    fn send_request<T, F>(
+        &mut self,
+        message: Message,
+        track: F,
+    ) -> impl Future<Output = CallResult<T>> + Send
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut ConnectionInfo, Complete<T>),
+    {
+        reply(self.send_with(move |info| info.send_message(message), track))
+    }
+
+    /// Like `send_request`, sending the request through `send`.  Returns the receiver of the
+    /// reply, or `None` if the request couldn't be sent.
+    ///
+    /// `send` may borrow what it sends, which is why this doesn't return a future: one would
+    /// hold on to the borrow for as long as it's around.
+    fn send_with<T, S, F>(&mut self, send: S, track: F) -> Option<oneshot::Receiver<CallResult<T>>>
+    where
+        S: FnOnce(&mut ConnectionInfo) -> WampResult<()>,
+        F: FnOnce(&mut ConnectionInfo, Complete<T>),
+    {
+        let (complete, receiver) = oneshot::channel();
+
+        let mut info = self.connection_info.lock().unwrap();
+        send(&mut info).ok()?;
+        track(&mut info, complete);
+        Some(receiver)
+    }
+
+    /// Send a subscribe messages
+    pub fn subscribe_with_pattern(
+        &mut self,
+        topic_pattern: URI,
+        callback: Box<dyn FnMut(List, Dict) + Send>,
+        policy: MatchingPolicy,
+    ) -> impl Future<Output = CallResult<Subscription>> + Send {
+        let mut options = SubscribeOptions::new();
+        if policy != MatchingPolicy::Strict {
+            options.pattern_match = policy
+        }
+
+        self.send_subscribe(topic_pattern, callback, options)
+    }
+
+    /// Subscribe to topic, asking the router to keep its last `keep_last` events should this
+    /// session leave without unsubscribing.  The router sends them once a session with the same
+    /// authid subscribes to the topic again, so this only has an effect for a connection made
+    /// with an authid that the realm's authorizer vouches for.
+    pub fn subscribe_keeping_last(
+        &mut self,
+        topic: URI,
+        callback: Box<dyn FnMut(List, Dict) + Send>,
+        keep_last: usize,
+    ) -> impl Future<Output = CallResult<Subscription>> + Send {
+        let mut options = SubscribeOptions::new();
+        options.keep_last = Some(keep_last);
+        self.send_subscribe(topic, callback, options)
+    }
+
+    fn send_subscribe(
+        &mut self,
+        topic_pattern: URI,
+        callback: Box<dyn FnMut(List, Dict) + Send>,
+        options: SubscribeOptions,
+    ) -> impl Future<Output = CallResult<Subscription>> + Send {
+        let request_id = self.get_next_session_id();
+
+        let message = Message::Subscribe(request_id, options, topic_pattern.clone());
+        self.send_request(message, move |info, complete| {
+            info.subscription_requests
+                .insert(request_id, (complete, callback, topic_pattern));
+        })
+    }
+
+    /// Subscribe to topic
+    ///
+    /// Events published by a single publisher are delivered in the order they were published.
+    /// The callback runs on the connection's task, so it has to be `Send`.
+    pub fn subscribe(
+        &mut self,
+        topic: URI,
+        callback: Box<dyn FnMut(List, Dict) + Send>,
+    ) -> impl Future<Output = CallResult<Subscription>> + Send {
+        self.subscribe_with_pattern(topic, callback, MatchingPolicy::Strict)
+    }
+
+    /// Subscribe to topic, receiving its events through a stream instead of a callback.
+    ///
+    /// Each item holds the arguments and keyword arguments of one event.  The stream buffers
+    /// up to 256 events; events that arrive while it is full are dropped, so keep polling it.
+    /// Dropping the stream discards later events but doesn't unsubscribe.
+    pub fn subscribe_stream(
+        &mut self,
+        topic: URI,
+    ) -> impl Future<
+        Output = CallResult<(
+            Subscription,
+            impl Stream<Item = (List, Dict)> + Unpin + Send,
+        )>,
+    > + Send {
+        let (callback, events) = event_stream();
+        let subscription = self.subscribe(topic, callback);
+        async move {
+            subscription
+                .await
+                .map(|subscription| (subscription, events))
+        }
+    }
+
+    /// Wait for the next event of a subscription, without installing another callback.
+    ///
+    /// The subscription's callback still runs for the event.  Events that arrive before this
+    /// is called aren't seen, so call it before doing whatever leads to the publication.
+    /// Fails with `NoSuchSubscription` if the client doesn't hold the subscription or it is
+    /// unsubscribed before an event arrives.
+    pub fn next_event(
+        &mut self,
+        subscription: &Subscription,
+    ) -> impl Future<Output = CallResult<(List, Dict)>> + Send {
+        let subscription_id = subscription.subscription_id;
+        let (complete, receiver) = oneshot::channel();
+        let mut info = self.connection_info.lock().unwrap();
+        if info.subscriptions.contains_key(subscription_id) {
+            match info.event_waiters.get_mut(subscription_id) {
+                Some(waiters) => waiters.push(complete),
+                None => {
+                    info.event_waiters.insert(subscription_id, vec![complete]);
+                }
+            }
+        }
+        async move {
+            receiver
+                .await
+                .map_err(|_| CallError::new(Reason::NoSuchSubscription, None, None))
+        }
+    }
+
+    /// Send a register message
+    pub fn register_with_pattern(
+        &mut self,
+        procedure_pattern: URI,
+        callback: Callback,
+        policy: MatchingPolicy,
+    ) -> impl Future<Output = CallResult<Registration>> + Send {
+        let mut options = RegisterOptions::new();
+        if policy != MatchingPolicy::Strict {
+            options.pattern_match = policy
+        }
+
+        self.send_register(
+            procedure_pattern,
+            RegistrationCallback::Single(callback),
+            options,
+        )
+    }
+
+    /// Register procedure with callback, advertising a description and argument schema
+    /// that tooling can read back through the `wamp.registration.get` meta procedure
+    pub fn register_with_metadata(
+        &mut self,
+        procedure: URI,
+        callback: Callback,
+        description: Option<String>,
+        args_schema: Option<Dict>,
+    ) -> impl Future<Output = CallResult<Registration>> + Send {
+        let mut options = RegisterOptions::new();
+        options.description = description;
+        options.args_schema = args_schema;
+
+        self.send_register(procedure, RegistrationCallback::Single(callback), options)
+    }
+
+    fn send_register(
+        &mut self,
+        procedure_pattern: URI,
+        callback: RegistrationCallback,
+        options: RegisterOptions,
+    ) -> impl Future<Output = CallResult<Registration>> + Send {
+        let request_id = self.get_next_session_id();
+
+        let message = Message::Register(request_id, options, procedure_pattern.clone());
+        self.send_request(message, move |info, complete| {
+            info.registration_requests
+                .insert(request_id, (complete, callback, procedure_pattern));
+        })
+    }
+
+    /// Register procedure with callback
+    ///
+    /// The callback runs on the connection's task, so it has to be `Send`.
+    pub fn register(
+        &mut self,
+        procedure: URI,
+        callback: Callback,
+    ) -> impl Future<Output = CallResult<Registration>> + Send {
+        self.register_with_pattern(procedure, callback, MatchingPolicy::Strict)
+    }
+
+    /// Register a procedure whose callback streams its results through a `Yielder`.
+    ///
+    /// Callers that use `call_progressive` receive each progressive result as it's sent.
+    pub fn register_stream(
+        &mut self,
+        procedure: URI,
+        callback: StreamCallback,
+    ) -> impl Future<Output = CallResult<Registration>> + Send {
+        self.send_register(
+            procedure,
+            RegistrationCallback::Stream(callback),
+            RegisterOptions::new(),
+        )
+    }
+
+    /// Unsubscribe from topic
+    pub fn unsubscribe(
+        &mut self,
+        subscription: Subscription,
+    ) -> impl Future<Output = CallResult<()>> + Send {
+        let request_id = self.get_next_session_id();
+
+        let subscription_id = subscription.subscription_id;
+        let message = Message::Unsubscribe(request_id, subscription_id);
+        self.send_request(message, move |info, complete| {
+            info.unsubscription_requests
+                .insert(request_id, (complete, subscription_id));
+        })
+    }
+
+    /// Unsubscribe from every topic the client is subscribed to.  Resolves once the router has
+    /// confirmed each, or with the first error.
+    pub fn unsubscribe_all(&mut self) -> impl Future<Output = CallResult<()>> + Send {
+        let pending = self
+            .subscriptions()
+            .into_iter()
+            .map(|(subscription_id, topic)| {
+                self.unsubscribe(Subscription {
+                    topic,
+                    subscription_id,
+                })
+            })
+            .collect::<Vec<_>>();
+        async move {
+            for unsubscribed in pending {
+                unsubscribed.await?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Unregister procedure
+    pub fn unregister(
+        &mut self,
+        registration: Registration,
+    ) -> impl Future<Output = CallResult<()>> + Send {
+        let request_id = self.get_next_session_id();
+
+        let registration_id = registration.registration_id;
+        let message = Message::Unregister(request_id, registration_id);
+        self.send_request(message, move |info, complete| {
+            info.unregistration_requests
+                .insert(request_id, (complete, registration_id));
+        })
+    }
+
+    /// Unregister every procedure the client has registered.  Resolves once the router has
+    /// confirmed each, or with the first error.
+    pub fn unregister_all(&mut self) -> impl Future<Output = CallResult<()>> + Send {
+        let pending = self
+            .registrations()
+            .into_iter()
+            .map(|(registration_id, procedure)| {
+                self.unregister(Registration {
+                    procedure,
+                    registration_id,
+                })
+            })
+            .collect::<Vec<_>>();
+        async move {
+            for unregistered in pending {
+                unregistered.await?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Publish to topic
+    pub fn publish(
+        &mut self,
+        topic: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) -> WampResult<()> {
+        info!("Publishing to {:?} with {:?} | {:?}", topic, args, kwargs);
+
+        let request_id = self.get_next_session_id();
+
+        let mut info = self.connection_info.lock().unwrap();
+        info.deliver_locally(&topic, args.as_deref(), kwargs.as_ref());
+
+        info.send_message(Message::Publish(
+            request_id,
+            PublishOptions::new(false),
+            topic,
+            args,
+            kwargs,
+        ))
+    }
+
+    /// Publish to topic without taking the arguments.  They are encoded straight from the
+    /// borrowed values, which saves copying a large payload that is published again and again.
+    pub fn publish_borrowed(
+        &mut self,
+        topic: URI,
+        args: Option<&[Value]>,
+        kwargs: Option<&Dict>,
+    ) -> WampResult<()> {
+        info!("Publishing to {:?} with {:?} | {:?}", topic, args, kwargs);
+
+        let request_id = self.get_next_session_id();
+
+        let mut info = self.connection_info.lock().unwrap();
+        info.deliver_locally(&topic, args, kwargs);
+
+        info.send_ref(MessageRef::Publish(
+            request_id,
+            PublishOptions::new(false),
+            topic,
+            args,
+            kwargs,
+        ))
+    }
+
+    /// Publish positional arguments to topic.  Same as `publish` with `kwargs` set to `None`.
+    pub fn publish_args(&mut self, topic: URI, args: List) -> WampResult<()> {
+        self.publish(topic, Some(args), None)
+    }
+
+    /// Publish keyword arguments to topic.  Same as `publish` with `args` set to `None`.
+    pub fn publish_kwargs(&mut self, topic: URI, kwargs: Dict) -> WampResult<()> {
+        self.publish(topic, None, Some(kwargs))
+    }
+
+    /// Publish to several topics at once, each entry holding a topic and the arguments of its
+    /// event.  The connection is locked once for the whole batch rather than once per
+    /// publication.  The results line up with `publications`.
+    pub fn publish_many(
+        &mut self,
+        publications: &[(URI, Option<List>, Option<Dict>)],
+    ) -> Vec<WampResult<()>> {
+        info!("Publishing to {} topics", publications.len());
+        let request_ids = publications
+            .iter()
+            .map(|_| self.get_next_session_id())
+            .collect::<Vec<_>>();
+
+        let mut info = self.connection_info.lock().unwrap();
+        publications
+            .iter()
+            .zip(request_ids)
+            .map(|((topic, args, kwargs), request_id)| {
+                info.deliver_locally(topic, args.as_deref(), kwargs.as_ref());
+                info.send_message(Message::Publish(
+                    request_id,
+                    PublishOptions::new(false),
+                    topic.clone(),
+                    args.clone(),
+                    kwargs.clone(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Publish to topic and acknowledge.
+    ///
+    /// Resolves with the publication id and the number of subscribers the event was delivered
+    /// to.  The count is 0 with routers that don't report it.
+    pub fn publish_and_acknowledge(
+        &mut self,
+        topic: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) -> impl Future<Output = CallResult<(ID, usize)>> + Send {
+        self.publish_counting_recipients(topic, args, kwargs, PublishOptions::new(true))
+    }
+
+    /// Publish to topic with the given options, such as authid or authrole filters
+    ///
+    /// The publication is always acknowledged, whatever `options` asks for.
+    pub fn publish_with_options(
+        &mut self,
+        topic: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+        options: PublishOptions,
+    ) -> impl Future<Output = CallResult<ID>> + Send {
+        let published = self.publish_counting_recipients(topic, args, kwargs, options);
+        async move { published.await.map(|(publication_id, _)| publication_id) }
+    }
+
+    fn publish_counting_recipients(
+        &mut self,
+        topic: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+        mut options: PublishOptions,
+    ) -> impl Future<Output = CallResult<(ID, usize)>> + Send {
+        info!("Publishing to {:?} with {:?} | {:?}", topic, args, kwargs);
+
+        let request_id = self.get_next_session_id();
+
+        options.acknowledge = true;
+        options.report_recipients = true;
+        reply(self.send_with(
+            move |info| {
+                info.deliver_locally(&topic, args.as_deref(), kwargs.as_ref());
+                info.send_message(Message::Publish(request_id, options, topic, args, kwargs))
+            },
+            move |info, complete| {
+                info.publish_requests.insert(request_id, complete);
+            },
+        ))
+    }
+
+    /// Call the procedure
+    ///
+    /// Dropping the returned future before it resolves stops tracking the call, and a result
+    /// arriving afterwards is ignored. `call_split` resolves with the same results as a
+    /// `CallOutput`.  If the callee leaves before answering, the call fails with
+    /// `Reason::NoEligibleCallee`.
+    pub fn call(
+        &mut self,
+        procedure: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) -> impl Future<Output = CallResult<(List, Dict)>> + Send {
+        info!("Calling {:?} with {:?} | {:?}", procedure, args, kwargs);
+        self.track_call(CallRequest::Single, move |info, request_id| {
+            info.send_message(Message::Call(
+                request_id,
+                CallOptions::new(),
+                procedure,
+                args,
+                kwargs,
+            ))
+        })
+    }
+
+    /// Call the procedure, like `call`, but resolve with a `CallOutput` whose positional and
+    /// keyword results are named fields rather than the two halves of a tuple
+    pub fn call_split(
+        &mut self,
+        procedure: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) -> impl Future<Output = CallResult<CallOutput>> + Send {
+        let result = self.call(procedure, args, kwargs);
+        async move { result.await.map(CallOutput::from) }
+    }
+
+    /// Call the procedure, like `call`, also resolving with the details of the result.
+    ///
+    /// Their `callee` holds the session id of the callee that answered when the router
+    /// discloses it, which tells which registrant of a shared registration handled the call.
+    pub fn call_with_details(
+        &mut self,
+        procedure: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) -> impl Future<Output = CallResult<(List, Dict, ResultDetails)>> + Send {
+        info!("Calling {:?} with {:?} | {:?}", procedure, args, kwargs);
+        self.track_call(CallRequest::Detailed, move |info, request_id| {
+            info.send_message(Message::Call(
+                request_id,
+                CallOptions::new(),
+                procedure,
+                args,
+                kwargs,
+            ))
+        })
+    }
+
+    /// Call the procedure without taking the arguments, which are encoded the way
+    /// `publish_borrowed` encodes them.
+    pub fn call_borrowed(
+        &mut self,
+        procedure: URI,
+        args: Option<&[Value]>,
+        kwargs: Option<&Dict>,
+    ) -> impl Future<Output = CallResult<(List, Dict)>> + Send {
+        info!("Calling {:?} with {:?} | {:?}", procedure, args, kwargs);
+
+        let request_id = self.get_next_session_id();
+
+        let pending = PendingCall {
+            connection_info: Arc::clone(&self.connection_info),
+            request_id,
+        };
+        let receiver = self.send_with(
+            |info| {
+                info.send_ref(MessageRef::Call(
+                    request_id,
+                    CallOptions::new(),
+                    procedure,
+                    args,
+                    kwargs,
+                ))
+            },
+            move |info, complete| {
+                info.call_requests
+                    .insert(request_id, CallRequest::Single(complete));
+            },
+        );
+        async move {
+            let _pending = pending;
+            reply(receiver).await
+        }
+    }
+
+    /// Call the procedure with positional arguments.  Same as `call` with `kwargs` set to
+    /// `None`.
+    pub fn call_args(
+        &mut self,
+        procedure: URI,
+        args: List,
+    ) -> impl Future<Output = CallResult<(List, Dict)>> + Send {
+        self.call(procedure, Some(args), None)
+    }
+
+    /// Call the procedure with keyword arguments.  Same as `call` with `args` set to `None`.
+    pub fn call_kwargs(
+        &mut self,
+        procedure: URI,
+        kwargs: Dict,
+    ) -> impl Future<Output = CallResult<(List, Dict)>> + Send {
+        self.call(procedure, None, Some(kwargs))
+    }
+
+    /// Call every registrant of a shared registration at once, rather than the one its
+    /// invocation policy picks, by setting the call's `runmode` to `all`.
+    ///
+    /// The router answers once the last registrant has, with one positional result per
+    /// registrant: a dictionary holding its session id under `callee` and its `args` and
+    /// `kwargs`, plus the reason under `error` if it failed.  Their order is unspecified.
+    pub fn call_all(
+        &mut self,
+        procedure: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) -> impl Future<Output = CallResult<(List, Dict)>> + Send {
+        info!(
+            "Calling all registrants of {:?} with {:?} | {:?}",
+            procedure, args, kwargs
+        );
+        self.track_call(CallRequest::Single, move |info, request_id| {
+            let mut options = CallOptions::new();
+            options.runmode = Some(RunMode::All);
+            info.send_message(Message::Call(request_id, options, procedure, args, kwargs))
+        })
+    }
+
+    /// Call the procedure, giving up if no result arrives within `timeout`.
+    ///
+    /// When the timeout elapses first the future resolves with `Reason::Cancelled` and a
+    /// result arriving afterwards is ignored.
+    pub fn call_with_timeout(
+        &mut self,
+        procedure: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+        timeout: Duration,
+    ) -> impl Future<Output = CallResult<(List, Dict)>> + Send {
+        let request_id = self.max_session_id + 1;
+        let result = self.call(procedure, args, kwargs);
+
+        async move {
+            // The call stops being tracked when its future is dropped on timeout
+            match time::timeout(timeout, result).await {
+                Ok(result) => result,
+                Err(_) => {
+                    debug!("Call {} timed out", request_id);
+                    Err(CallError::new(Reason::Cancelled, None, None))
+                }
+            }
+        }
+    }
+
+    /// Call the procedure, asking the callee for progressive results.
+    ///
+    /// Each progressive result is an item of the returned stream, and the final result or the
+    /// error the call failed with is its last item.  Dropping the stream stops tracking the
+    /// call.
+    pub fn call_progressive(
+        &mut self,
+        procedure: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) -> impl Stream<Item = CallResult<(List, Dict)>> + Unpin + Send {
+        info!(
+            "Calling {:?} for progressive results with {:?} | {:?}",
+            procedure, args, kwargs
+        );
+
+        let request_id = self.get_next_session_id();
+
+        let (sender, results) = mpsc::unbounded();
+        let mut options = CallOptions::new();
+        options.receive_progress = true;
+        let message = Message::Call(request_id, options, procedure, args, kwargs);
+
+        let mut info = self.connection_info.lock().unwrap();
+        if info.send_message(message).is_ok() {
+            info.call_requests
+                .insert(request_id, CallRequest::Progressive(sender));
+        } else {
+            let _ = sender.unbounded_send(Err(CallError::new(Reason::NetworkFailure, None, None)));
+        }
+
+        let pending = PendingCall {
+            connection_info: Arc::clone(&self.connection_info),
+            request_id,
+        };
+        // The call is tracked for as long as the stream holds on to `pending`
+        results.map(move |result| {
+            let _pending = &pending;
+            result
+        })
+    }
+
+    /// Sends a CALL through `send` and resolves with the result `request` delivers.  The call
+    /// stops being tracked when the returned future is dropped.
+    fn track_call<T, F>(
+        &mut self,
+        request: fn(Complete<T>) -> CallRequest,
+        send: F,
+    ) -> impl Future<Output = CallResult<T>> + Send
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut ConnectionInfo, ID) -> WampResult<()>,
+    {
+        let request_id = self.get_next_session_id();
+
+        let pending = PendingCall {
+            connection_info: Arc::clone(&self.connection_info),
+            request_id,
+        };
+        let receiver = self.send_with(
+            move |info| send(info, request_id),
+            move |info, complete| {
+                info.call_requests.insert(request_id, request(complete));
+            },
+        );
+        async move {
+            let _pending = pending;
+            reply(receiver).await
+        }
+    }
+
+    /// Disconnect from router gracefully
+    pub fn shutdown(&mut self) -> impl Future<Output = CallResult<()>> + Send {
+        let (complete, receiver) = oneshot::channel();
+
+        let mut info = self.connection_info.lock().unwrap();
+        let sent = if info.connection_state == ConnectionState::Connected {
+            info.connection_state = ConnectionState::ShuttingDown;
+            info.shutdown_complete = Some(complete);
+            info.send_message(Message::Goodbye(
+                ErrorDetails::new(),
+                Reason::SystemShutdown,
+            ))
+            .is_ok()
+        } else {
+            false
+        };
+
+        async move {
+            if !sent {
+                return Err(CallError::new(Reason::InternalError, None, None));
+            }
+            receiver
+                .await
+                .unwrap_or_else(|_| Err(CallError::new(Reason::InternalError, None, None)))
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Contains the `ClientPool` struct, which shares a few sessions between many calls.
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use log::{debug, warn};
+
+use crate::{
+    messages::{Dict, List, Reason, URI},
+    CallError, WampResult,
+};
+
+use super::{Client, Connection};
+
+/// A fixed number of clients connected to the same router and realm.
+///
+/// Calls are spread over the clients in turn, so short-lived calls don't pay for a handshake
+/// each.  A client whose connection was lost is replaced the next time it's its turn.
+pub struct ClientPool {
+    connection: Connection,
+    clients: Vec<Mutex<Option<Client>>>,
+    next: AtomicUsize,
+}
+
+impl ClientPool {
+    /// Connect `size` clients with `connection`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn new(connection: Connection, size: usize) -> WampResult<ClientPool> {
+        assert!(size > 0, "A client pool needs at least one client");
+        let clients = (0..size)
+            .map(|_| connection.connect().map(|client| Mutex::new(Some(client))))
+            .collect::<WampResult<Vec<_>>>()?;
+        Ok(ClientPool {
+            connection,
+            clients,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Number of clients in the pool
+    pub fn size(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Call the procedure with the next client in the pool.
+    ///
+    /// If that client can't be reconnected the future resolves with
+    /// `Reason::NetworkFailure`.
+    #[allow(clippy::type_complexity)]
+    pub fn call(
+        &self,
+        procedure: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) -> Pin<Box<dyn Future<Output = Result<(List, Dict), CallError>>>> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.clients.len();
+        let mut slot = self.clients[index].lock().unwrap();
+        if !slot.as_ref().is_some_and(Client::is_connected) {
+            debug!("Reconnecting client {} of the pool", index);
+            *slot = match self.connection.connect() {
+                Ok(client) => Some(client),
+                Err(e) => {
+                    warn!("Could not reconnect client {} of the pool: {}", index, e);
+                    None
+                }
+            };
+        }
+        match slot.as_mut() {
+            Some(client) => client.call(procedure, args, kwargs),
+            None => Box::pin(async { Err(CallError::new(Reason::NetworkFailure, None, None)) }),
+        }
+    }
+}
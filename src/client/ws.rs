@@ -0,0 +1,2210 @@
+#![allow(dead_code)]
+#![allow(unused_imports)]
+use std::{
+    collections::HashMap,
+    fmt,
+    pin::Pin,
+    sync::{
+        mpsc::{channel, Receiver, Sender as CHSender},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::Duration,
+};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    Future, Stream, StreamExt,
+};
+use intmap::IntMap;
+use log::{debug, error, info, trace, warn};
+use url::Url;
+use parity_ws::{
+    connect, util::Token, CloseCode, Error as WSError, ErrorKind as WSErrorKind, Frame, Handler,
+    Handshake, Message as WSMessage, OpCode, Request, Result as WSResult, Sender,
+};
+use crate::{
+    messages::{
+        CallOptions, ClientRoles, Dict, ErrorDetails, ErrorType, HelloDetails, InvocationDetails,
+        List, MatchingPolicy, Message, MessageRef, PublishOptions, PublishedDetails, Reason,
+        RegisterOptions, ResultDetails, RouterFeatures, RunMode, SubscribeOptions, Value,
+        WelcomeDetails, YieldOptions, URI,
+    },
+    serializer::{BatchedJsonSerializer, Payload, Serializer, Serializers},
+    transport::loopback::{self, Endpoint, Event, LoopbackSender},
+    CallError, CallResult, Error, ErrorKind, WampResult, ID,
+};
+
+use super::{
+    event_stream, CallOutput, CallRequest, Callback, Complete, Registration, Subscription,
+    DEFAULT_AGENT,
+};
+
+// Call timeouts are scheduled with the request id as their token, so fixed tokens are
+// allocated from the top of the range.
+const CONNECTION_TIMEOUT: Token = Token(usize::MAX);
+const PING_TIMEOUT: Token = Token(usize::MAX - 1);
+
+/// Number of consecutive pings the router may leave unanswered before the connection is closed
+const MAX_MISSED_PINGS: u32 = 3;
+
+/// Number of messages held back while waiting for WELCOME before the connection is given up
+const MAX_EARLY_MESSAGES: usize = 32;
+
+/// Represents WAMP connection
+#[derive(Clone)]
+pub struct Connection {
+    realm: URI,
+    url: String,
+    agent: String,
+    authid: Option<String>,
+    authrole: Option<String>,
+    ping_interval: Duration,
+    timeout: Duration,
+    serializers: Serializers,
+    max_message_size: usize,
+    /// Extra HTTP headers sent with the websocket handshake
+    headers: Vec<(String, Vec<u8>)>,
+    local_delivery: bool,
+}
+
+/// The outbound side of a connection
+#[derive(Clone)]
+enum ConnectionSender {
+    /// A router reached over a websocket
+    WebSocket(Sender),
+    /// A router in the same process, connected through `Router::connect_loopback`
+    Loopback(LoopbackSender),
+}
+
+struct SubscriptionCallbackWrapper {
+    callback: Box<dyn FnMut(List, Dict) + Send>,
+}
+
+struct RegistrationCallbackWrapper {
+    callback: RegistrationCallback,
+}
+
+/// How a registered procedure answers its invocations
+enum RegistrationCallback {
+    /// The callback returns the one result
+    Single(Callback),
+    /// The callback answers through a `Yielder`, with any number of progressive results
+    Stream(StreamCallback),
+}
+
+/// Alias for the callback of a procedure that streams its results.  The invocation is answered
+/// through the `Yielder`, which may be kept and used from another thread.
+pub type StreamCallback = Box<dyn FnMut(List, Dict, Yielder) + Send>;
+
+/// Answers one invocation of a procedure registered with `Client::register_stream`.
+///
+/// Send any number of progressive results, then end the invocation with `finish` or `fail`.
+/// Progressive results are only sent if the caller asked for them.  Dropping the yielder
+/// without ending the invocation fails it with `Reason::InternalError`, so the caller isn't
+/// left waiting.
+pub struct Yielder {
+    sender: ConnectionSender,
+    serializer: Option<Arc<dyn Serializer>>,
+    request_id: ID,
+    receive_progress: bool,
+    finished: bool,
+}
+
+impl Yielder {
+    /// Send a progressive result
+    pub fn progress(&self, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
+        if !self.receive_progress {
+            debug!(
+                "Not sending a progressive result for invocation {}, the caller didn't ask for them",
+                self.request_id
+            );
+            return Ok(());
+        }
+        let mut options = YieldOptions::new();
+        options.progress = true;
+        self.sender.send(
+            Message::Yield(self.request_id, options, args, kwargs),
+            self.serializer.as_deref(),
+        )
+    }
+
+    /// Send the final result, ending the invocation
+    pub fn finish(mut self, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
+        self.finished = true;
+        self.sender.send(
+            Message::Yield(self.request_id, YieldOptions::new(), args, kwargs),
+            self.serializer.as_deref(),
+        )
+    }
+
+    /// Fail the invocation with `error`
+    pub fn fail(mut self, error: CallError) -> WampResult<()> {
+        self.finished = true;
+        let (reason, args, kwargs) = error.into_tuple();
+        self.send_error(reason, args, kwargs)
+    }
+
+    fn send_error(
+        &self,
+        reason: Reason,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) -> WampResult<()> {
+        self.sender.send(
+            Message::Error(
+                ErrorType::Invocation,
+                self.request_id,
+                HashMap::new(),
+                reason,
+                args,
+                kwargs,
+            ),
+            self.serializer.as_deref(),
+        )
+    }
+}
+
+impl Drop for Yielder {
+    fn drop(&mut self) {
+        if !self.finished {
+            warn!(
+                "Invocation {} was dropped without a final result",
+                self.request_id
+            );
+            self.send_error(Reason::InternalError, None, None).ok();
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+enum ConnectionState {
+    Connecting,
+    Connected,
+    ShuttingDown,
+    Disconnected,
+}
+
+type ConnectionResult = Result<Arc<Mutex<ConnectionInfo>>, Error>;
+
+/// Represents WAMP Client
+pub struct Client {
+    connection_info: Arc<Mutex<ConnectionInfo>>,
+    max_session_id: ID,
+}
+
+/// Represents connection handler
+pub struct ConnectionHandler {
+    connection_info: Arc<Mutex<ConnectionInfo>>,
+    realm: URI,
+    agent: String,
+    authid: Option<String>,
+    authrole: Option<String>,
+    ping_interval: Duration,
+    serializers: Serializers,
+    max_message_size: usize,
+    headers: Vec<(String, Vec<u8>)>,
+    missed_pings: u32,
+    early_messages: Vec<Message>,
+    state_transmission: CHSender<ConnectionResult>,
+}
+
+struct ConnectionInfo {
+    connection_state: ConnectionState,
+    sender: ConnectionSender,
+    subscription_requests: IntMap<(Complete<Subscription>, SubscriptionCallbackWrapper, URI)>,
+    unsubscription_requests: IntMap<(Complete<()>, ID)>,
+    subscriptions: IntMap<(SubscriptionCallbackWrapper, URI)>,
+    /// Resolve with the next event of a subscription, as asked for by `Client::next_event`
+    event_waiters: IntMap<Vec<oneshot::Sender<(List, Dict)>>>,
+    registrations: IntMap<(RegistrationCallbackWrapper, URI)>,
+    call_requests: IntMap<CallRequest>,
+    registration_requests: IntMap<(Complete<Registration>, RegistrationCallbackWrapper, URI)>,
+    unregistration_requests: IntMap<(Complete<()>, ID)>,
+    /// Set once the router has picked a sub-protocol.  A loopback connection has none.
+    serializer: Option<Arc<dyn Serializer>>,
+    /// Resolve with the publication id and the number of subscribers the event reached
+    publish_requests: IntMap<Complete<(ID, usize)>>,
+    shutdown_complete: Option<Complete<()>>,
+    session_id: ID,
+    router_features: RouterFeatures,
+    /// Set by `Connection::with_local_delivery`
+    local_delivery: bool,
+}
+
+/// Held by a call's future so that dropping the future before the result arrives forgets
+/// the pending request
+struct PendingCall {
+    connection_info: Arc<Mutex<ConnectionInfo>>,
+    request_id: ID,
+}
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        // A no-op once the result has been delivered
+        self.connection_info
+            .lock()
+            .unwrap()
+            .call_requests
+            .remove(self.request_id);
+    }
+}
+
+trait MessageSender {
+    fn send_message(&self, message: Message) -> WampResult<()>;
+}
+
+impl ConnectionInfo {
+    /// Runs the callbacks of the subscriptions to `topic` with an event the client is about to
+    /// publish, if local delivery is on
+    fn deliver_locally(&mut self, topic: &URI, args: Option<&[Value]>, kwargs: Option<&Dict>) {
+        if !self.local_delivery {
+            return;
+        }
+        for (subscription, subscribed_topic) in self.subscriptions.values_mut() {
+            if subscribed_topic == topic {
+                let callback = &mut subscription.callback;
+                callback(
+                    args.map(<[Value]>::to_vec).unwrap_or_default(),
+                    kwargs.cloned().unwrap_or_default(),
+                );
+            }
+        }
+    }
+}
+
+impl MessageSender for ConnectionInfo {
+    fn send_message(&self, message: Message) -> WampResult<()> {
+        self.sender.send(message, self.serializer.as_deref())
+    }
+}
+
+impl ConnectionSender {
+    fn send(&self, message: Message, serializer: Option<&dyn Serializer>) -> WampResult<()> {
+        debug!(
+            "Sending message {:?} via {}",
+            message,
+            self.protocol(serializer)
+        );
+        match *self {
+            ConnectionSender::WebSocket(ref sender) => {
+                let serializer = negotiated(serializer)?;
+                send_payload(sender, serializer.encode(&message)?)
+            }
+            ConnectionSender::Loopback(ref sender) => sender.send(message),
+        }
+    }
+
+    /// Like `send`, encoding the borrowed arguments where they are.  A loopback connection
+    /// hands over owned messages, so there the arguments are copied after all.
+    fn send_ref(
+        &self,
+        message: MessageRef<'_>,
+        serializer: Option<&dyn Serializer>,
+    ) -> WampResult<()> {
+        debug!(
+            "Sending message {:?} via {}",
+            message,
+            self.protocol(serializer)
+        );
+        match *self {
+            ConnectionSender::WebSocket(ref sender) => {
+                let serializer = negotiated(serializer)?;
+                send_payload(sender, serializer.encode_ref(&message)?)
+            }
+            ConnectionSender::Loopback(ref sender) => sender.send(message.to_message()),
+        }
+    }
+
+    /// The sub-protocol spoken over this connection
+    fn protocol<'a>(&self, serializer: Option<&'a dyn Serializer>) -> &'a str {
+        match *self {
+            ConnectionSender::WebSocket(_) => serializer.map_or("", |s| s.subprotocol_name()),
+            ConnectionSender::Loopback(_) => loopback::PROTOCOL,
+        }
+    }
+}
+
+/// The serializer a websocket connection agreed on, which is only missing before the handshake
+fn negotiated(serializer: Option<&dyn Serializer>) -> WampResult<&dyn Serializer> {
+    serializer.ok_or_else(|| Error::new(ErrorKind::InvalidState("No sub-protocol negotiated")))
+}
+
+/// Sends an encoded message over a websocket
+fn send_payload(sender: &Sender, payload: Payload) -> WampResult<()> {
+    sender
+        .send(WSMessage::from(payload))
+        .map_err(|e| Error::new(ErrorKind::WSError(e)))
+}
+
+impl ConnectionSender {
+    fn close(&self, code: CloseCode) -> WSResult<()> {
+        match *self {
+            ConnectionSender::WebSocket(ref sender) => sender.close(code),
+            ConnectionSender::Loopback(ref sender) => {
+                sender.close();
+                Ok(())
+            }
+        }
+    }
+
+    fn shutdown(&self) -> WSResult<()> {
+        match *self {
+            ConnectionSender::WebSocket(ref sender) => sender.shutdown(),
+            ConnectionSender::Loopback(ref sender) => {
+                sender.close();
+                Ok(())
+            }
+        }
+    }
+
+    fn ping(&self) -> WSResult<()> {
+        match *self {
+            ConnectionSender::WebSocket(ref sender) => sender.ping(Vec::new()),
+            ConnectionSender::Loopback(_) => Ok(()),
+        }
+    }
+
+    fn timeout(&self, ms: u64, token: Token) -> WSResult<()> {
+        match *self {
+            ConnectionSender::WebSocket(ref sender) => sender.timeout(ms, token),
+            ConnectionSender::Loopback(ref sender) => {
+                sender.timeout(ms, token);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Connection {
+    /// Create new connection with uri and realm
+    pub fn new(url: &str, realm: &str) -> Connection {
+        Connection {
+            realm: URI::new(realm),
+            url: url.to_string(),
+            agent: DEFAULT_AGENT.to_string(),
+            authid: None,
+            authrole: None,
+            ping_interval: Duration::from_secs(0),
+            timeout: Duration::from_secs(5),
+            serializers: Serializers::default(),
+            max_message_size: usize::MAX,
+            headers: Vec::new(),
+            local_delivery: false,
+        }
+    }
+
+    /// Announce the client as `agent` in the HELLO message.  The default is
+    /// `wampire-client-<version>`.
+    pub fn with_agent(mut self, agent: &str) -> Connection {
+        self.agent = agent.to_string();
+        self
+    }
+
+    /// Announce `authid` as the identity of the session in the HELLO message
+    pub fn with_authid(mut self, authid: &str) -> Connection {
+        self.authid = Some(authid.to_string());
+        self
+    }
+
+    /// Announce `authrole` as the role of the session in the HELLO message
+    pub fn with_authrole(mut self, authrole: &str) -> Connection {
+        self.authrole = Some(authrole.to_string());
+        self
+    }
+
+    /// Give up on connecting if the router hasn't welcomed the session within `timeout`.
+    /// The default is five seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Connection {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Ping the router each `interval` and close the connection if several pings in a row go
+    /// unanswered.  A zero interval, the default, disables pings.
+    pub fn with_ping_interval(mut self, interval: Duration) -> Connection {
+        self.ping_interval = interval;
+        self
+    }
+
+    /// Close the connection if the router sends a message larger than `limit` bytes.  By
+    /// default messages of any size are accepted.
+    pub fn with_max_message_size(mut self, limit: usize) -> Connection {
+        self.max_message_size = limit;
+        self
+    }
+
+    /// Offer batched JSON (`wamp.2.json.batched`) ahead of the other serializations, letting
+    /// the router pack several messages into a single websocket frame
+    pub fn with_batched_json(mut self) -> Connection {
+        self.serializers = self.serializers.prefer(BatchedJsonSerializer);
+        self
+    }
+
+    /// Offer the sub-protocols of `serializers`, in their order of preference, in place of
+    /// the built in ones.  The router picks one it has a serializer for as well.
+    pub fn with_serializers(mut self, serializers: Serializers) -> Connection {
+        self.serializers = serializers;
+        self
+    }
+
+    /// Send the HTTP header `name: value` with the websocket handshake, such as an
+    /// `Authorization` token or a cookie for a gateway in front of the router
+    pub fn with_header(mut self, name: &str, value: &str) -> Connection {
+        self.headers
+            .push((name.to_string(), value.as_bytes().to_vec()));
+        self
+    }
+
+    /// Hand the client's own publications straight to its subscriptions on the same topic.
+    ///
+    /// The router never sends a session the events it published itself, so without this a
+    /// client that subscribes and publishes to one topic doesn't see its own events.  With it,
+    /// every publish first runs the callbacks of the client's subscriptions whose topic equals
+    /// the published URI, on the publishing thread, and only then sends the event to the
+    /// router.  Those callbacks have therefore run before any other subscriber can receive the
+    /// event, and they run even if sending it fails.  Pattern subscriptions are only invoked
+    /// for the topic they were made with, and the count of subscribers reported to
+    /// `publish_and_acknowledge` only covers those the router reached.
+    pub fn with_local_delivery(mut self) -> Connection {
+        self.local_delivery = true;
+        self
+    }
+
+    /// Connect to router
+    pub fn connect(&self) -> WampResult<Client> {
+        let (tx, rx) = channel();
+        let url = self.url.clone();
+        let connection = self.clone();
+        let timeout = self.timeout.as_millis() as u64;
+        thread::spawn(move || {
+            trace!("Beginning Connection");
+            let connect_result = connect(url, |out| {
+                trace!("Got sender");
+                // Set up timeout
+                out.timeout(timeout, CONNECTION_TIMEOUT).unwrap();
+                connection.handler(ConnectionSender::WebSocket(out), tx.clone())
+            })
+            .map_err(|e| Error::new(ErrorKind::WSError(e)));
+            debug!("Result of connection: {:?}", connect_result);
+            match connect_result {
+                Ok(_) => (),
+                Err(e) => {
+                    tx.send(Err(e)).unwrap();
+                }
+            }
+        });
+        Connection::await_welcome(rx)
+    }
+
+    /// Connect through the client end of a loopback connection to the router
+    pub(crate) fn connect_loopback(&self, endpoint: Endpoint) -> WampResult<Client> {
+        let (tx, rx) = channel();
+        endpoint
+            .sender
+            .timeout(self.timeout.as_millis() as u64, CONNECTION_TIMEOUT);
+        let Endpoint { sender, events } = endpoint;
+        let mut handler = self.handler(ConnectionSender::Loopback(sender), tx);
+        thread::spawn(move || handler.run_loopback(events));
+        Connection::await_welcome(rx)
+    }
+
+    fn handler(
+        &self,
+        sender: ConnectionSender,
+        state_transmission: CHSender<ConnectionResult>,
+    ) -> ConnectionHandler {
+        let info = Arc::new(Mutex::new(ConnectionInfo {
+            serializer: None,
+            subscription_requests: IntMap::new(),
+            unsubscription_requests: IntMap::new(),
+            subscriptions: IntMap::new(),
+            event_waiters: IntMap::new(),
+            registrations: IntMap::new(),
+            call_requests: IntMap::new(),
+            registration_requests: IntMap::new(),
+            unregistration_requests: IntMap::new(),
+            sender,
+            connection_state: ConnectionState::Connecting,
+            publish_requests: IntMap::new(),
+            shutdown_complete: None,
+            session_id: 0,
+            router_features: RouterFeatures::default(),
+            local_delivery: self.local_delivery,
+        }));
+
+        ConnectionHandler {
+            state_transmission,
+            connection_info: info,
+            realm: self.realm.clone(),
+            agent: self.agent.clone(),
+            authid: self.authid.clone(),
+            authrole: self.authrole.clone(),
+            ping_interval: self.ping_interval,
+            serializers: self.serializers.clone(),
+            max_message_size: self.max_message_size,
+            headers: self.headers.clone(),
+            missed_pings: 0,
+            early_messages: Vec::new(),
+        }
+    }
+
+    fn await_welcome(rx: Receiver<ConnectionResult>) -> WampResult<Client> {
+        // The connection thread ends without a result if the router refuses the handshake
+        let info = rx
+            .recv()
+            .unwrap_or_else(|_| Err(Error::new(ErrorKind::ConnectionLost)))?;
+        Ok(Client {
+            connection_info: info,
+            max_session_id: 0,
+        })
+    }
+}
+
+macro_rules! cancel_future_tuple {
+    ($dict:expr) => {{
+        for (_, future) in $dict.drain() {
+            let _ = future
+                .0
+                .send(Err(CallError::new(Reason::NetworkFailure, None, None)));
+        }
+    }};
+}
+
+macro_rules! cancel_future {
+    ($dict:expr) => {{
+        for (_, future) in $dict.drain() {
+            let _ = future.send(Err(CallError::new(Reason::NetworkFailure, None, None)));
+        }
+    }};
+}
+
+impl Handler for ConnectionHandler {
+    fn on_open(&mut self, handshake: Handshake) -> WSResult<()> {
+        debug!("Connection Opened");
+        let mut info = self.connection_info.lock().unwrap();
+        let protocol = handshake.response.protocol()?;
+        info.serializer = match protocol.and_then(|protocol| self.serializers.get(protocol)) {
+            Some(serializer) => Some(serializer),
+            None => {
+                // Guessing would leave every message undecodable if the guess is wrong
+                error!(
+                    "Router selected {:?}, which wasn't offered.  Closing connection",
+                    protocol
+                );
+                info.connection_state = ConnectionState::Disconnected;
+                info.sender.close(CloseCode::Protocol)?;
+                drop(info);
+                let _ = self
+                    .state_transmission
+                    .send(Err(Error::new(ErrorKind::HandshakeError(
+                        Reason::ProtocolViolation,
+                    ))));
+                return Ok(());
+            }
+        };
+
+        if !self.ping_interval.is_zero() {
+            info.sender
+                .timeout(self.ping_interval.as_millis() as u64, PING_TIMEOUT)?;
+        }
+
+        match self.send_hello(&info) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                if let ErrorKind::WSError(e) = e.kind {
+                    Err(e)
+                } else {
+                    Err(WSError::new(WSErrorKind::Internal, "Unknown error"))
+                }
+            }
+        }
+    }
+
+    fn on_message(&mut self, message: WSMessage) -> WSResult<()> {
+        debug!("Server sent a message: {:?}", message);
+        if message.len() > self.max_message_size {
+            error!(
+                "Message of {} bytes exceeds the limit of {}",
+                message.len(),
+                self.max_message_size
+            );
+            return self
+                .connection_info
+                .lock()
+                .unwrap()
+                .sender
+                .close(CloseCode::Size);
+        }
+        let serializer = self.connection_info.lock().unwrap().serializer.clone();
+        let messages = match serializer.map(|serializer| serializer.decode(&message.into())) {
+            Some(Ok(messages)) => messages,
+            _ => {
+                error!("Could not understand message from the router");
+                return Ok(());
+            }
+        };
+        for message in messages {
+            if !self.handle_message(message) {
+                return self.connection_info.lock().unwrap().sender.shutdown();
+            }
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, _reason: &str) {
+        debug!("Closing connection");
+        let mut info = self.connection_info.lock().unwrap();
+        info.sender.close(code).ok();
+        info.connection_state = ConnectionState::Disconnected;
+        cancel_future_tuple!(info.subscription_requests);
+        cancel_future_tuple!(info.unsubscription_requests);
+        cancel_future_tuple!(info.registration_requests);
+        cancel_future_tuple!(info.unregistration_requests);
+        cancel_future!(info.publish_requests);
+        cancel_future!(info.call_requests);
+        info.sender.shutdown().ok();
+
+        if let Some(promise) = info.shutdown_complete.take() {
+            let _ = promise.send(Ok(()));
+        }
+    }
+
+    fn on_timeout(&mut self, token: Token) -> WSResult<()> {
+        if token == CONNECTION_TIMEOUT {
+            let info = self.connection_info.lock().unwrap();
+            if info.connection_state == ConnectionState::Connecting {
+                info.sender.shutdown().unwrap();
+                drop(info);
+                self.state_transmission
+                    .send(Err(Error::new(ErrorKind::Timeout)))
+                    .unwrap();
+            }
+        } else if token == PING_TIMEOUT {
+            if self.missed_pings >= MAX_MISSED_PINGS {
+                warn!(
+                    "Router left {} pings unanswered.  Closing connection",
+                    self.missed_pings
+                );
+                self.on_close(CloseCode::Away, "Ping timeout");
+                return Ok(());
+            }
+            self.missed_pings += 1;
+            let info = self.connection_info.lock().unwrap();
+            info.sender.ping()?;
+            info.sender
+                .timeout(self.ping_interval.as_millis() as u64, PING_TIMEOUT)?;
+        } else {
+            let mut info = self.connection_info.lock().unwrap();
+            if let Some(request) = info.call_requests.remove(token.0 as ID) {
+                debug!("Call {} timed out", token.0);
+                request.send(Err(CallError::new(Reason::Cancelled, None, None)));
+            }
+        }
+        Ok(())
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> WSResult<Option<Frame>> {
+        if frame.opcode() == OpCode::Pong {
+            self.missed_pings = 0;
+        }
+        Ok(Some(frame))
+    }
+
+    fn build_request(&mut self, url: &Url) -> WSResult<Request> {
+        trace!("Building request");
+        let mut request = Request::from_url(url)?;
+        for protocol in self.serializers.subprotocols() {
+            request.add_protocol(protocol);
+        }
+        request.headers_mut().extend(self.headers.iter().cloned());
+        Ok(request)
+    }
+}
+
+impl ConnectionHandler {
+    fn send_hello(&self, info: &ConnectionInfo) -> WampResult<()> {
+        let mut details = HelloDetails::new_with_agent(ClientRoles::new(), &self.agent);
+        details.authid = self.authid.clone();
+        details.authrole = self.authrole.clone();
+        let hello_message = Message::Hello(self.realm.clone(), details);
+
+        debug!("Sending Hello message");
+        info.send_message(hello_message)
+    }
+
+    /// Drives a loopback connection until either end closes it.  The router is in the same
+    /// process, so there are no pings.
+    fn run_loopback(&mut self, events: Receiver<Event>) {
+        {
+            let info = self.connection_info.lock().unwrap();
+            if let Err(e) = self.send_hello(&info) {
+                error!("Could not send Hello message: {:?}", e);
+                info.sender.shutdown().ok();
+            }
+        }
+        for event in events {
+            match event {
+                Event::Message(message) => {
+                    if !self.handle_message(message) {
+                        self.connection_info.lock().unwrap().sender.shutdown().ok();
+                    }
+                }
+                Event::Timeout(token) => {
+                    self.on_timeout(token).ok();
+                }
+                Event::Close => break,
+            }
+        }
+        self.on_close(CloseCode::Normal, "");
+    }
+
+    fn handle_message(&mut self, message: Message) -> bool {
+        let mut info = self.connection_info.lock().unwrap();
+        debug!(
+            "Processing message from server (state: {:?})",
+            info.connection_state
+        );
+        match info.connection_state {
+            ConnectionState::Connecting => match message {
+                Message::Welcome(session_id, details) => {
+                    self.handle_welcome(info, session_id, details);
+                    // Replay whatever the router pipelined ahead of its welcome
+                    for message in std::mem::take(&mut self.early_messages) {
+                        if !self.handle_message(message) {
+                            return false;
+                        }
+                    }
+                }
+                Message::Abort(_, reason) => {
+                    self.handle_abort(info, reason);
+                    return false;
+                }
+                _ => {
+                    if self.early_messages.len() >= MAX_EARLY_MESSAGES {
+                        error!(
+                            "Received more than {} messages before welcome.  Closing connection",
+                            MAX_EARLY_MESSAGES
+                        );
+                        let _ = self.state_transmission.send(Err(Error::new(
+                            ErrorKind::UnexpectedMessage("Received too many messages before welcome"),
+                        )));
+                        return false;
+                    }
+                    debug!("Holding back message until welcome: {:?}", message);
+                    self.early_messages.push(message);
+                }
+            },
+            ConnectionState::Connected => {
+                debug!("Received a message from the server: {:?}", message);
+                match message {
+                    Message::Subscribed(request_id, subscription_id) => {
+                        self.handle_subscribed(info, request_id, subscription_id)
+                    }
+                    Message::Unsubscribed(request_id) => self.handle_unsubscribed(info, request_id),
+                    Message::Event(subscription_id, _, _, args, kwargs) => {
+                        self.handle_event(info, subscription_id, args, kwargs)
+                    }
+                    Message::Published(request_id, publication_id, details) => {
+                        self.handle_published(info, request_id, publication_id, details)
+                    }
+                    Message::Registered(request_id, registration_id) => {
+                        self.handle_registered(info, request_id, registration_id)
+                    }
+                    Message::Unregistered(request_id) => self.handle_unregistered(info, request_id),
+                    Message::Invocation(request_id, registration_id, details, args, kwargs) => self
+                        .handle_invocation(
+                            info,
+                            request_id,
+                            registration_id,
+                            details,
+                            args,
+                            kwargs,
+                        ),
+                    Message::Result(call_id, details, args, kwargs) => {
+                        self.handle_result(info, call_id, details, args, kwargs)
+                    }
+                    Message::Error(e_type, request_id, details, reason, args, kwargs) => {
+                        self.handle_error(info, e_type, request_id, details, reason, args, kwargs)
+                    }
+                    Message::Goodbye(_, reason) => {
+                        self.handle_goodbye(info, reason);
+                        return false;
+                    }
+                    _ => warn!("Received unknown message.  Ignoring. {:?}", message),
+                }
+            }
+            ConnectionState::ShuttingDown => {
+                if let Message::Goodbye(_, _) = message {
+                    // The router has seen our goodbye message and has responded in kind
+                    info!("Router acknowledged disconnect");
+                    if let Some(promise) = info.shutdown_complete.take() {
+                        let _ = promise.send(Ok(()));
+                    }
+                    return false;
+                } else {
+                    warn!(
+                        "Received message after shutting down, ignoring: {:?}",
+                        message
+                    );
+                    return false;
+                }
+            }
+            ConnectionState::Disconnected => {
+                // Should never happen
+                return false;
+            }
+        }
+        true
+    }
+
+    fn handle_subscribed(
+        &self,
+        mut info: MutexGuard<'_, ConnectionInfo>,
+        request_id: ID,
+        subscription_id: ID,
+    ) {
+        // TODO handle errors here
+        info!("Received a subscribed notification");
+        match info.subscription_requests.remove(request_id) {
+            Some((promise, callback, topic)) => {
+                debug!("Completing promise");
+                info.subscriptions
+                    .insert(subscription_id, (callback, topic.clone()));
+                let subscription = Subscription {
+                    topic,
+                    subscription_id,
+                };
+                drop(info);
+                let _ = promise.send(Ok(subscription));
+            }
+            None => {
+                warn!(
+                    "Received a subscribed notification for a subscription we don't have.  ID: {}",
+                    request_id
+                );
+            }
+        }
+    }
+
+    fn handle_subscribe_error(
+        &self,
+        mut info: MutexGuard<'_, ConnectionInfo>,
+        request_id: ID,
+        reason: Reason,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) {
+        warn!("Received an error for a subscription");
+        match info.subscription_requests.remove(request_id) {
+            Some((promise, _, _)) => {
+                drop(info);
+                let _ = promise.send(Err(CallError::new(reason, args, kwargs)));
+            }
+            None => {
+                warn!(
+                    "Received a an error notification for a request we didn't make.  ID: {}",
+                    request_id
+                );
+            }
+        }
+    }
+
+    fn handle_unsubscribed(&self, mut info: MutexGuard<'_, ConnectionInfo>, request_id: ID) {
+        match info.unsubscription_requests.remove(request_id) {
+            Some((promise, subscription_id)) => {
+                info.subscriptions.remove(subscription_id);
+                info.event_waiters.remove(subscription_id);
+                drop(info);
+                let _ = promise.send(Ok(()));
+            }
+            None => {
+                warn!("Received a unsubscribed notification for a subscription we don't have.  ID: {}", request_id);
+            }
+        }
+    }
+
+    fn handle_unsubscribe_error(
+        &self,
+        mut info: MutexGuard<'_, ConnectionInfo>,
+        request_id: ID,
+        reason: Reason,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) {
+        match info.unsubscription_requests.remove(request_id) {
+            Some((promise, _)) => {
+                drop(info);
+                let _ = promise.send(Err(CallError::new(reason, args, kwargs)));
+            }
+            None => {
+                warn!(
+                    "Received a unsubscribed error for a subscription we don't have.  ID: {}",
+                    request_id
+                );
+            }
+        }
+    }
+
+    fn handle_registered(
+        &self,
+        mut info: MutexGuard<'_, ConnectionInfo>,
+        request_id: ID,
+        registration_id: ID,
+    ) {
+        // TODO handle errors here
+        info!("Received a registered notification");
+        match info.registration_requests.remove(request_id) {
+            Some((promise, callback, procedure)) => {
+                info.registrations
+                    .insert(registration_id, (callback, procedure.clone()));
+                drop(info);
+                let registration = Registration {
+                    procedure,
+                    registration_id,
+                };
+                let _ = promise.send(Ok(registration));
+            }
+            None => {
+                warn!(
+                    "Received a registered notification for a registration we don't have.  ID: {}",
+                    request_id
+                );
+            }
+        }
+    }
+
+    fn handle_register_error(
+        &self,
+        mut info: MutexGuard<'_, ConnectionInfo>,
+        request_id: ID,
+        reason: Reason,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) {
+        info!("Received a registration error");
+        match info.registration_requests.remove(request_id) {
+            Some((promise, _, _)) => {
+                drop(info);
+                let _ = promise.send(Err(CallError::new(reason, args, kwargs)));
+            }
+            None => {
+                warn!(
+                    "Received a registered error for a registration we don't have.  ID: {}",
+                    request_id
+                );
+            }
+        }
+    }
+
+    fn handle_unregistered(&self, mut info: MutexGuard<'_, ConnectionInfo>, request_id: ID) {
+        match info.unregistration_requests.remove(request_id) {
+            Some((promise, registration_id)) => {
+                info.registrations.remove(registration_id);
+                drop(info);
+                let _ = promise.send(Ok(()));
+            }
+            None => {
+                warn!("Received a unregistered notification for a registration we don't have.  ID: {}", request_id);
+            }
+        }
+    }
+
+    fn handle_unregister_error(
+        &self,
+        mut info: MutexGuard<'_, ConnectionInfo>,
+        request_id: ID,
+        reason: Reason,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) {
+        match info.unregistration_requests.remove(request_id) {
+            Some((promise, _)) => {
+                drop(info);
+                let _ = promise.send(Err(CallError::new(reason, args, kwargs)));
+            }
+            None => {
+                warn!(
+                    "Received a unregistered error for a registration we don't have.  ID: {}",
+                    request_id
+                );
+            }
+        }
+    }
+
+    fn handle_published(
+        &self,
+        mut info: MutexGuard<'_, ConnectionInfo>,
+        request_id: ID,
+        publication_id: ID,
+        details: PublishedDetails,
+    ) {
+        match info.publish_requests.remove(request_id) {
+            Some(promise) => {
+                let recipients = details.recipients.unwrap_or(0) as usize;
+                let _ = promise.send(Ok((publication_id, recipients)));
+            }
+            None => warn!(
+                "Received published notification for a request we weren't tracking: {}",
+                request_id
+            ),
+        }
+    }
+    fn handle_publish_error(
+        &self,
+        mut info: MutexGuard<'_, ConnectionInfo>,
+        request_id: ID,
+        reason: Reason,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) {
+        match info.publish_requests.remove(request_id) {
+            Some(promise) => {
+                let _ = promise.send(Err(CallError::new(reason, args, kwargs)));
+            }
+            None => warn!("Received published error for a publication: {}", request_id),
+        }
+    }
+
+    fn handle_welcome(
+        &self,
+        mut info: MutexGuard<'_, ConnectionInfo>,
+        session_id: ID,
+        details: WelcomeDetails,
+    ) {
+        info.session_id = session_id;
+        info.router_features = details.roles().features();
+        info.connection_state = ConnectionState::Connected;
+        drop(info);
+        self.state_transmission
+            .send(Ok(Arc::clone(&self.connection_info)))
+            .unwrap();
+    }
+
+    fn handle_abort(&self, mut info: MutexGuard<'_, ConnectionInfo>, reason: Reason) {
+        error!("Router aborted connection.  Reason: {:?}", reason);
+        info.connection_state = ConnectionState::ShuttingDown;
+    }
+
+    fn handle_event(
+        &self,
+        mut info: MutexGuard<'_, ConnectionInfo>,
+        subscription_id: ID,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) {
+        // An empty Vec or HashMap doesn't allocate, so events without arguments cost nothing
+        // here and the callback can keep taking owned values
+        let args = args.unwrap_or_default();
+        let kwargs = kwargs.unwrap_or_default();
+        if let Some(waiters) = info.event_waiters.remove(subscription_id) {
+            for waiter in waiters {
+                let _ = waiter.send((args.clone(), kwargs.clone()));
+            }
+        }
+        match info.subscriptions.get_mut(subscription_id) {
+            Some((subscription, _)) => {
+                let callback = &mut subscription.callback;
+                callback(args, kwargs);
+            }
+            None => {
+                warn!(
+                    "Received an event for a subscription we don't have.  ID: {}",
+                    subscription_id
+                );
+            }
+        }
+    }
+
+    fn handle_invocation(
+        &self,
+        mut info: MutexGuard<'_, ConnectionInfo>,
+        request_id: ID,
+        registration_id: ID,
+        details: InvocationDetails,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) {
+        let args = args.unwrap_or_default();
+        let kwargs = kwargs.unwrap_or_default();
+        // Borrowed apart, so a streaming callee's yielder can copy the sender
+        let info = &mut *info;
+        let message = match info.registrations.get_mut(registration_id) {
+            Some((registration, _)) => match registration.callback {
+                RegistrationCallback::Stream(ref mut callback) => {
+                    let yielder = Yielder {
+                        sender: info.sender.clone(),
+                        serializer: info.serializer.clone(),
+                        request_id,
+                        receive_progress: details.receive_progress,
+                        finished: false,
+                    };
+                    callback(args, kwargs, yielder);
+                    return;
+                }
+                RegistrationCallback::Single(ref mut callback) => match callback(args, kwargs) {
+                    Ok((rargs, rkwargs)) => {
+                        Message::Yield(request_id, YieldOptions::new(), rargs, rkwargs)
+                    }
+                    Err(error) => {
+                        let (reason, args, kwargs) = error.into_tuple();
+                        Message::Error(
+                            ErrorType::Invocation,
+                            request_id,
+                            HashMap::new(),
+                            reason,
+                            args,
+                            kwargs,
+                        )
+                    }
+                },
+            },
+            None => {
+                warn!(
+                    "Received an invocation for a procedure we don't have.  ID: {}",
+                    registration_id
+                );
+                return;
+            }
+        };
+        info.send_message(message).ok();
+    }
+
+    fn handle_result(
+        &self,
+        mut info: MutexGuard<'_, ConnectionInfo>,
+        call_id: ID,
+        details: ResultDetails,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) {
+        let args = args.unwrap_or_default();
+        let kwargs = kwargs.unwrap_or_default();
+        // A progressive result leaves the call pending until the final one arrives
+        let delivered = if details.progress {
+            info.call_requests
+                .get(call_id)
+                .map(|request| request.progress((args, kwargs)))
+                .is_some()
+        } else {
+            info.call_requests
+                .remove(call_id)
+                .map(|request| request.finish(args, kwargs, details))
+                .is_some()
+        };
+        if !delivered {
+            warn!(
+                "Received a result for a call we didn't make.  ID: {}",
+                call_id
+            );
+        }
+    }
+
+    fn handle_call_error(
+        &self,
+        mut info: MutexGuard<'_, ConnectionInfo>,
+        request_id: ID,
+        reason: Reason,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) {
+        match info.call_requests.remove(request_id) {
+            Some(request) => {
+                request.send(Err(CallError::new(reason, args, kwargs)));
+            }
+            None => {
+                warn!(
+                    "Received an error for a call we didn't make.  ID: {}",
+                    request_id
+                );
+            }
+        }
+    }
+
+    fn handle_goodbye(&self, mut info: MutexGuard<'_, ConnectionInfo>, reason: Reason) {
+        info!("Router said goodbye.  Reason: {:?}", reason);
+
+        info.send_message(Message::Goodbye(ErrorDetails::new(), Reason::GoodbyeAndOut))
+            .unwrap();
+        info.connection_state = ConnectionState::ShuttingDown;
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn handle_error(
+        &self,
+        info: MutexGuard<'_, ConnectionInfo>,
+        e_type: ErrorType,
+        request_id: ID,
+        _details: Dict,
+        reason: Reason,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) {
+        match e_type {
+            ErrorType::Subscribe => {
+                self.handle_subscribe_error(info, request_id, reason, args, kwargs)
+            }
+            ErrorType::Unsubscribe => {
+                self.handle_unsubscribe_error(info, request_id, reason, args, kwargs)
+            }
+            ErrorType::Publish => self.handle_publish_error(info, request_id, reason, args, kwargs),
+            ErrorType::Register => {
+                self.handle_register_error(info, request_id, reason, args, kwargs)
+            }
+            ErrorType::Unregister => {
+                self.handle_unregister_error(info, request_id, reason, args, kwargs)
+            }
+            ErrorType::Invocation => {
+                warn!("Received an error for an invocation message, which we did not (and could not) send")
+            }
+            ErrorType::Call => self.handle_call_error(info, request_id, reason, args, kwargs),
+        }
+    }
+}
+
+impl Client {
+    fn get_next_session_id(&mut self) -> ID {
+        self.max_session_id += 1;
+        self.max_session_id
+    }
+
+    /// Whether the session is still established.  Once the connection is lost or shut down
+    /// the client can't be used again.
+    pub fn is_connected(&self) -> bool {
+        self.connection_info.lock().unwrap().connection_state == ConnectionState::Connected
+    }
+
+    /// Advanced features the router announced when the session was established
+    pub fn router_features(&self) -> RouterFeatures {
+        self.connection_info.lock().unwrap().router_features
+    }
+
+    /// The serialization sub-protocol negotiated with the router, such as `wamp.2.json` or
+    /// `wamp.2.msgpack`
+    pub fn protocol(&self) -> String {
+        let info = self.connection_info.lock().unwrap();
+        info.sender.protocol(info.serializer.as_deref()).to_string()
+    }
+
+    /// Ids and topics of the subscriptions the router has confirmed
+    pub fn subscriptions(&self) -> Vec<(ID, URI)> {
+        let info = self.connection_info.lock().unwrap();
+        info.subscriptions
+            .iter()
+            .map(|(id, (_, topic))| (*id, topic.clone()))
+            .collect()
+    }
+
+    /// Replace the callback of a subscription, keeping the subscription itself.
+    ///
+    /// Callbacks run with the client locked, so an event that is being handled finishes with
+    /// the old callback, and every event handled after this returns goes to the new one,
+    /// including events the router sent before the swap.  Returns false, dropping `callback`,
+    /// if the client has no subscription with that id.
+    pub fn set_subscription_callback(
+        &mut self,
+        subscription_id: ID,
+        callback: Box<dyn FnMut(List, Dict) + Send>,
+    ) -> bool {
+        let mut info = self.connection_info.lock().unwrap();
+        match info.subscriptions.get_mut(subscription_id) {
+            Some((subscription, _)) => {
+                *subscription = SubscriptionCallbackWrapper { callback };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Ids and procedures of the registrations the router has confirmed
+    pub fn registrations(&self) -> Vec<(ID, URI)> {
+        let info = self.connection_info.lock().unwrap();
+        info.registrations
+            .iter()
+            .map(|(id, (_, procedure))| (*id, procedure.clone()))
+            .collect()
+    }
+
+    /// Send a subscribe messages
+    pub fn subscribe_with_pattern(
+        &mut self,
+        topic_pattern: URI,
+        callback: Box<dyn FnMut(List, Dict) + Send>,
+        policy: MatchingPolicy,
+    ) -> Pin<Box<dyn Future<Output = Result<Subscription, CallError>>>> {
+        let mut options = SubscribeOptions::new();
+
+        if policy != MatchingPolicy::Strict {
+            options.pattern_match = policy
+        }
+
+        self.send_subscribe(topic_pattern, callback, options)
+    }
+
+    /// Subscribe to topic, asking the router to keep its last `keep_last` events should this
+    /// session leave without unsubscribing.  The router sends them once a session with the same
+    /// authid subscribes to the topic again, so this only has an effect for a connection made
+    /// with an authid that the realm's authorizer vouches for.
+    pub fn subscribe_keeping_last(
+        &mut self,
+        topic: URI,
+        callback: Box<dyn FnMut(List, Dict) + Send>,
+        keep_last: usize,
+    ) -> Pin<Box<dyn Future<Output = Result<Subscription, CallError>>>> {
+        let mut options = SubscribeOptions::new();
+        options.keep_last = Some(keep_last);
+        self.send_subscribe(topic, callback, options)
+    }
+
+    fn send_subscribe(
+        &mut self,
+        topic_pattern: URI,
+        callback: Box<dyn FnMut(List, Dict) + Send>,
+        options: SubscribeOptions,
+    ) -> Pin<Box<dyn Future<Output = Result<Subscription, CallError>>>> {
+        let request_id = self.get_next_session_id();
+
+        let (complete, receiver) = oneshot::channel();
+
+        let callback = SubscriptionCallbackWrapper { callback };
+
+        let mut info = self.connection_info.lock().unwrap();
+        info.subscription_requests
+            .insert(request_id, (complete, callback, topic_pattern.clone()));
+
+        info.send_message(Message::Subscribe(request_id, options, topic_pattern))
+            .unwrap();
+
+        Box::pin(async {
+            receiver.await.unwrap_or(Err(CallError {
+                reason: Reason::InternalError,
+                args: None,
+                kwargs: None,
+            }))
+        })
+    }
+
+    /// Subscribe to topic
+    ///
+    /// Events published by a single publisher are delivered in the order they were published.
+    /// The callback runs on the client's websocket thread, so it has to be `Send`.
+    pub fn subscribe(
+        &mut self,
+        topic: URI,
+        callback: Box<dyn FnMut(List, Dict) + Send>,
+    ) -> Pin<Box<dyn Future<Output = Result<Subscription, CallError>>>> {
+        self.subscribe_with_pattern(topic, callback, MatchingPolicy::Strict)
+    }
+
+    /// Subscribe to topic, receiving its events through a stream instead of a callback.
+    ///
+    /// Each item holds the arguments and keyword arguments of one event.  The stream buffers
+    /// up to 256 events; events that arrive while it is full are dropped, so keep polling it.
+    /// Dropping the stream discards later events but doesn't unsubscribe.
+    #[allow(clippy::type_complexity)]
+    pub fn subscribe_stream(
+        &mut self,
+        topic: URI,
+    ) -> Pin<
+        Box<
+            dyn Future<
+                Output = Result<
+                    (Subscription, impl Stream<Item = (List, Dict)> + Unpin),
+                    CallError,
+                >,
+            >,
+        >,
+    > {
+        let (callback, events) = event_stream();
+        let subscription = self.subscribe(topic, callback);
+        Box::pin(async move {
+            subscription
+                .await
+                .map(|subscription| (subscription, events))
+        })
+    }
+
+    /// Wait for the next event of a subscription, without installing another callback.
+    ///
+    /// The subscription's callback still runs for the event.  Events that arrive before this
+    /// is called aren't seen, so call it before doing whatever leads to the publication.
+    /// Fails with `NoSuchSubscription` if the client doesn't hold the subscription or it is
+    /// unsubscribed before an event arrives.
+    #[allow(clippy::type_complexity)]
+    pub fn next_event(
+        &mut self,
+        subscription: &Subscription,
+    ) -> Pin<Box<dyn Future<Output = Result<(List, Dict), CallError>>>> {
+        let subscription_id = subscription.subscription_id;
+        let (complete, receiver) = oneshot::channel();
+        let mut info = self.connection_info.lock().unwrap();
+        if info.subscriptions.contains_key(subscription_id) {
+            match info.event_waiters.get_mut(subscription_id) {
+                Some(waiters) => waiters.push(complete),
+                None => {
+                    info.event_waiters.insert(subscription_id, vec![complete]);
+                }
+            }
+        }
+        Box::pin(async {
+            receiver.await.map_err(|_| CallError {
+                reason: Reason::NoSuchSubscription,
+                args: None,
+                kwargs: None,
+            })
+        })
+    }
+
+    /// Send a register message
+    pub fn register_with_pattern(
+        &mut self,
+        procedure_pattern: URI,
+        callback: Callback,
+        policy: MatchingPolicy,
+    ) -> Pin<Box<dyn Future<Output = Result<Registration, CallError>>>> {
+        let mut options = RegisterOptions::new();
+
+        if policy != MatchingPolicy::Strict {
+            options.pattern_match = policy
+        }
+
+        self.send_register(
+            procedure_pattern,
+            RegistrationCallback::Single(callback),
+            options,
+        )
+    }
+
+    /// Register procedure with callback, advertising a description and argument schema
+    /// that tooling can read back through the `wamp.registration.get` meta procedure
+    pub fn register_with_metadata(
+        &mut self,
+        procedure: URI,
+        callback: Callback,
+        description: Option<String>,
+        args_schema: Option<Dict>,
+    ) -> Pin<Box<dyn Future<Output = Result<Registration, CallError>>>> {
+        let mut options = RegisterOptions::new();
+        options.description = description;
+        options.args_schema = args_schema;
+
+        self.send_register(procedure, RegistrationCallback::Single(callback), options)
+    }
+
+    fn send_register(
+        &mut self,
+        procedure_pattern: URI,
+        callback: RegistrationCallback,
+        options: RegisterOptions,
+    ) -> Pin<Box<dyn Future<Output = Result<Registration, CallError>>>> {
+        let request_id = self.get_next_session_id();
+
+        let (complete, receiver) = oneshot::channel();
+
+        let callback = RegistrationCallbackWrapper { callback };
+
+        debug!("Acquiring lock on connection info");
+        let mut info = self.connection_info.lock().unwrap();
+
+        debug!("Lock on connection info acquired");
+        info.registration_requests
+            .insert(request_id, (complete, callback, procedure_pattern.clone()));
+
+        info.send_message(Message::Register(request_id, options, procedure_pattern))
+            .unwrap();
+
+        Box::pin(async {
+            receiver.await.unwrap_or(Err(CallError {
+                reason: Reason::InternalError,
+                args: None,
+                kwargs: None,
+            }))
+        })
+    }
+
+    /// Register procedure with callback
+    ///
+    /// The callback runs on the client's websocket thread, so it has to be `Send`.
+    pub fn register(
+        &mut self,
+        procedure: URI,
+        callback: Callback,
+    ) -> Pin<Box<dyn Future<Output = Result<Registration, CallError>>>> {
+        self.register_with_pattern(procedure, callback, MatchingPolicy::Strict)
+    }
+
+    /// Register a procedure whose callback streams its results through a `Yielder`.
+    ///
+    /// Callers that use `call_progressive` receive each progressive result as it's sent.
+    pub fn register_stream(
+        &mut self,
+        procedure: URI,
+        callback: StreamCallback,
+    ) -> Pin<Box<dyn Future<Output = Result<Registration, CallError>>>> {
+        self.send_register(
+            procedure,
+            RegistrationCallback::Stream(callback),
+            RegisterOptions::new(),
+        )
+    }
+
+    /// Unsubscribe from topic
+    ///
+    /// Subscription ids are assigned by the router and only hold for this session.  The client
+    /// doesn't reconnect on its own, so a subscription doesn't outlive its connection: after
+    /// connecting again, such as when a `ClientPool` replaces a client, subscribe anew.
+    pub fn unsubscribe(
+        &mut self,
+        subscription: Subscription,
+    ) -> Pin<Box<dyn Future<Output = Result<(), CallError>>>> {
+        let request_id = self.get_next_session_id();
+
+        let mut info = self.connection_info.lock().unwrap();
+
+        info.send_message(Message::Unsubscribe(
+            request_id,
+            subscription.subscription_id,
+        ))
+        .unwrap();
+
+        let (complete, receiver) = oneshot::channel();
+
+        info.unsubscription_requests
+            .insert(request_id, (complete, subscription.subscription_id));
+
+        Box::pin(async {
+            receiver.await.unwrap_or(Err(CallError {
+                reason: Reason::InternalError,
+                args: None,
+                kwargs: None,
+            }))
+        })
+    }
+
+    /// Unregister procedure 
+    pub fn unregister(
+        &mut self,
+        registration: Registration,
+    ) -> Pin<Box<dyn Future<Output = Result<(), CallError>>>> {
+        let request_id = self.get_next_session_id();
+
+        let mut info = self.connection_info.lock().unwrap();
+
+        info.send_message(Message::Unregister(
+            request_id,
+            registration.registration_id,
+        ))
+        .unwrap();
+
+        let (complete, receiver) = oneshot::channel();
+
+        info.unregistration_requests
+            .insert(request_id, (complete, registration.registration_id));
+
+        Box::pin(async {
+            receiver.await.unwrap_or(Err(CallError {
+                reason: Reason::InternalError,
+                args: None,
+                kwargs: None,
+            }))
+        })
+    }
+
+    /// Unsubscribe from every topic the client is subscribed to.  Resolves once the router has
+    /// confirmed each, or with the first error.
+    pub fn unsubscribe_all(&mut self) -> Pin<Box<dyn Future<Output = Result<(), CallError>>>> {
+        let pending = self
+            .subscriptions()
+            .into_iter()
+            .map(|(subscription_id, topic)| {
+                self.unsubscribe(Subscription {
+                    topic,
+                    subscription_id,
+                })
+            })
+            .collect::<Vec<_>>();
+        Box::pin(async {
+            for unsubscribed in pending {
+                unsubscribed.await?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Unregister every procedure the client has registered.  Resolves once the router has
+    /// confirmed each, or with the first error.
+    pub fn unregister_all(&mut self) -> Pin<Box<dyn Future<Output = Result<(), CallError>>>> {
+        let pending = self
+            .registrations()
+            .into_iter()
+            .map(|(registration_id, procedure)| {
+                self.unregister(Registration {
+                    procedure,
+                    registration_id,
+                })
+            })
+            .collect::<Vec<_>>();
+        Box::pin(async {
+            for unregistered in pending {
+                unregistered.await?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Publish to topic
+    pub fn publish(
+        &mut self,
+        topic: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) -> WampResult<()> {
+        info!("Publishing to {:?} with {:?} | {:?}", topic, args, kwargs);
+
+        let request_id = self.get_next_session_id();
+
+        let mut info = self.connection_info.lock().unwrap();
+        info.deliver_locally(&topic, args.as_deref(), kwargs.as_ref());
+
+        info.send_message(Message::Publish(
+            request_id,
+            PublishOptions::new(false),
+            topic,
+            args,
+            kwargs,
+        ))
+    }
+
+    /// Publish to topic without taking the arguments.  Over a websocket they are encoded
+    /// straight from the borrowed values, which saves copying a large payload that is published
+    /// again and again.
+    pub fn publish_borrowed(
+        &mut self,
+        topic: URI,
+        args: Option<&[Value]>,
+        kwargs: Option<&Dict>,
+    ) -> WampResult<()> {
+        info!("Publishing to {:?} with {:?} | {:?}", topic, args, kwargs);
+
+        let request_id = self.get_next_session_id();
+
+        let mut info = self.connection_info.lock().unwrap();
+        info.deliver_locally(&topic, args, kwargs);
+
+        info.sender.send_ref(
+            MessageRef::Publish(request_id, PublishOptions::new(false), topic, args, kwargs),
+            info.serializer.as_deref(),
+        )
+    }
+
+    /// Publish positional arguments to topic.  Same as `publish` with `kwargs` set to `None`.
+    pub fn publish_args(&mut self, topic: URI, args: List) -> WampResult<()> {
+        self.publish(topic, Some(args), None)
+    }
+
+    /// Publish keyword arguments to topic.  Same as `publish` with `args` set to `None`.
+    pub fn publish_kwargs(&mut self, topic: URI, kwargs: Dict) -> WampResult<()> {
+        self.publish(topic, None, Some(kwargs))
+    }
+
+    /// Publish to several topics at once, each entry holding a topic and the arguments of its
+    /// event.  The connection is locked once for the whole batch rather than once per
+    /// publication.  The results line up with `publications`.
+    pub fn publish_many(
+        &mut self,
+        publications: &[(URI, Option<List>, Option<Dict>)],
+    ) -> Vec<WampResult<()>> {
+        info!("Publishing to {} topics", publications.len());
+        let request_ids = publications
+            .iter()
+            .map(|_| self.get_next_session_id())
+            .collect::<Vec<_>>();
+
+        let mut info = self.connection_info.lock().unwrap();
+        publications
+            .iter()
+            .zip(request_ids)
+            .map(|((topic, args, kwargs), request_id)| {
+                info.deliver_locally(topic, args.as_deref(), kwargs.as_ref());
+                info.send_message(Message::Publish(
+                    request_id,
+                    PublishOptions::new(false),
+                    topic.clone(),
+                    args.clone(),
+                    kwargs.clone(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Call the procedure
+    ///
+    /// Dropping the returned future before it resolves stops tracking the call, and a result
+    /// arriving afterwards is ignored. `call_split` resolves with the same results as a
+    /// `CallOutput`.  If the callee leaves before answering, the call fails with
+    /// `Reason::NoEligibleCallee`.
+    #[allow(clippy::type_complexity)]
+    pub fn call(
+        &mut self,
+        procedure: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) -> Pin<Box<dyn Future<Output = Result<(List, Dict), CallError>>>> {
+        self.send_call(procedure, args, kwargs, None)
+    }
+
+    /// Call the procedure, like `call`, but resolve with a `CallOutput` whose positional and
+    /// keyword results are named fields rather than the two halves of a tuple
+    #[allow(clippy::type_complexity)]
+    pub fn call_split(
+        &mut self,
+        procedure: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) -> Pin<Box<dyn Future<Output = Result<CallOutput, CallError>>>> {
+        let result = self.call(procedure, args, kwargs);
+        Box::pin(async { result.await.map(CallOutput::from) })
+    }
+
+    /// Call the procedure, like `call`, also resolving with the details of the result.
+    ///
+    /// Their `callee` holds the session id of the callee that answered when the router
+    /// discloses it, which tells which registrant of a shared registration handled the call.
+    #[allow(clippy::type_complexity)]
+    pub fn call_with_details(
+        &mut self,
+        procedure: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) -> Pin<Box<dyn Future<Output = Result<(List, Dict, ResultDetails), CallError>>>> {
+        info!("Calling {:?} with {:?} | {:?}", procedure, args, kwargs);
+
+        let request_id = self.get_next_session_id();
+
+        let (complete, receiver) = oneshot::channel();
+
+        let mut info = self.connection_info.lock().unwrap();
+
+        info.call_requests
+            .insert(request_id, CallRequest::Detailed(complete));
+
+        info.send_message(Message::Call(
+            request_id,
+            CallOptions::new(),
+            procedure,
+            args,
+            kwargs,
+        ))
+        .unwrap();
+
+        let pending = PendingCall {
+            connection_info: Arc::clone(&self.connection_info),
+            request_id,
+        };
+        Box::pin(async move {
+            let _pending = pending;
+            receiver.await.unwrap_or(Err(CallError {
+                reason: Reason::InternalError,
+                args: None,
+                kwargs: None,
+            }))
+        })
+    }
+
+    /// Call the procedure without taking the arguments, which are encoded the way
+    /// `publish_borrowed` encodes them.
+    #[allow(clippy::type_complexity)]
+    pub fn call_borrowed(
+        &mut self,
+        procedure: URI,
+        args: Option<&[Value]>,
+        kwargs: Option<&Dict>,
+    ) -> Pin<Box<dyn Future<Output = Result<(List, Dict), CallError>>>> {
+        info!("Calling {:?} with {:?} | {:?}", procedure, args, kwargs);
+        self.track_call(None, |info, request_id| {
+            info.sender.send_ref(
+                MessageRef::Call(request_id, CallOptions::new(), procedure, args, kwargs),
+                info.serializer.as_deref(),
+            )
+        })
+    }
+
+    /// Call the procedure with positional arguments.  Same as `call` with `kwargs` set to
+    /// `None`.
+    #[allow(clippy::type_complexity)]
+    pub fn call_args(
+        &mut self,
+        procedure: URI,
+        args: List,
+    ) -> Pin<Box<dyn Future<Output = Result<(List, Dict), CallError>>>> {
+        self.call(procedure, Some(args), None)
+    }
+
+    /// Call the procedure with keyword arguments.  Same as `call` with `args` set to `None`.
+    #[allow(clippy::type_complexity)]
+    pub fn call_kwargs(
+        &mut self,
+        procedure: URI,
+        kwargs: Dict,
+    ) -> Pin<Box<dyn Future<Output = Result<(List, Dict), CallError>>>> {
+        self.call(procedure, None, Some(kwargs))
+    }
+
+    /// Call the procedure, giving up if no result arrives within `timeout`.
+    ///
+    /// When the timeout elapses first the future resolves with `Reason::Cancelled` and a
+    /// result arriving afterwards is ignored.
+    #[allow(clippy::type_complexity)]
+    pub fn call_with_timeout(
+        &mut self,
+        procedure: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+        timeout: Duration,
+    ) -> Pin<Box<dyn Future<Output = Result<(List, Dict), CallError>>>> {
+        self.send_call(procedure, args, kwargs, Some(timeout))
+    }
+
+    /// Call every registrant of a shared registration at once, rather than the one its
+    /// invocation policy picks, by setting the call's `runmode` to `all`.
+    ///
+    /// The router answers once the last registrant has, with one positional result per
+    /// registrant: a dictionary holding its session id under `callee` and its `args` and
+    /// `kwargs`, plus the reason under `error` if it failed.  Their order is unspecified.
+    #[allow(clippy::type_complexity)]
+    pub fn call_all(
+        &mut self,
+        procedure: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) -> Pin<Box<dyn Future<Output = Result<(List, Dict), CallError>>>> {
+        info!(
+            "Calling all registrants of {:?} with {:?} | {:?}",
+            procedure, args, kwargs
+        );
+        self.track_call(None, move |info, request_id| {
+            let mut options = CallOptions::new();
+            options.runmode = Some(RunMode::All);
+            info.send_message(Message::Call(request_id, options, procedure, args, kwargs))
+        })
+    }
+
+    /// Call the procedure, asking the callee for progressive results.
+    ///
+    /// Each progressive result is an item of the returned stream, and the final result or the
+    /// error the call failed with is its last item.  Dropping the stream stops tracking the
+    /// call.
+    pub fn call_progressive(
+        &mut self,
+        procedure: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) -> impl Stream<Item = Result<(List, Dict), CallError>> + Unpin {
+        info!(
+            "Calling {:?} for progressive results with {:?} | {:?}",
+            procedure, args, kwargs
+        );
+
+        let request_id = self.get_next_session_id();
+
+        let (sender, results) = mpsc::unbounded();
+
+        let mut info = self.connection_info.lock().unwrap();
+
+        info.call_requests
+            .insert(request_id, CallRequest::Progressive(sender));
+
+        let mut options = CallOptions::new();
+        options.receive_progress = true;
+        info.send_message(Message::Call(request_id, options, procedure, args, kwargs))
+            .unwrap();
+
+        let pending = PendingCall {
+            connection_info: Arc::clone(&self.connection_info),
+            request_id,
+        };
+        // The call is tracked for as long as the stream holds on to `pending`
+        results.map(move |result| {
+            let _pending = &pending;
+            result
+        })
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn send_call(
+        &mut self,
+        procedure: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+        timeout: Option<Duration>,
+    ) -> Pin<Box<dyn Future<Output = Result<(List, Dict), CallError>>>> {
+        info!("Calling {:?} with {:?} | {:?}", procedure, args, kwargs);
+        self.track_call(timeout, move |info, request_id| {
+            info.send_message(Message::Call(
+                request_id,
+                CallOptions::new(),
+                procedure,
+                args,
+                kwargs,
+            ))
+        })
+    }
+
+    /// Sends a CALL through `send` and resolves with its result
+    #[allow(clippy::type_complexity)]
+    fn track_call<F>(
+        &mut self,
+        timeout: Option<Duration>,
+        send: F,
+    ) -> Pin<Box<dyn Future<Output = Result<(List, Dict), CallError>>>>
+    where
+        F: FnOnce(&ConnectionInfo, ID) -> WampResult<()>,
+    {
+        let request_id = self.get_next_session_id();
+
+        let (complete, receiver) = oneshot::channel();
+
+        let mut info = self.connection_info.lock().unwrap();
+
+        info.call_requests
+            .insert(request_id, CallRequest::Single(complete));
+
+        send(&info, request_id).unwrap();
+
+        if let Some(timeout) = timeout {
+            if let Err(e) = info
+                .sender
+                .timeout(timeout.as_millis() as u64, Token(request_id as usize))
+            {
+                warn!("Could not schedule timeout for call {}: {}", request_id, e);
+            }
+        }
+
+        let pending = PendingCall {
+            connection_info: Arc::clone(&self.connection_info),
+            request_id,
+        };
+        Box::pin(async move {
+            let _pending = pending;
+            receiver.await.unwrap_or(Err(CallError {
+                reason: Reason::InternalError,
+                args: None,
+                kwargs: None,
+            }))
+        })
+    }
+
+    /// Publish to topic and acknowledge.
+    ///
+    /// Resolves with the publication id and the number of subscribers the event was delivered
+    /// to.  The count is 0 with routers that don't report it.
+    #[allow(clippy::type_complexity)]
+    pub fn publish_and_acknowledge(
+        &mut self,
+        topic: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+    ) -> Pin<Box<dyn Future<Output = Result<(ID, usize), CallError>>>> {
+        self.publish_counting_recipients(topic, args, kwargs, PublishOptions::new(true))
+    }
+
+    /// Publish to topic with the given options, such as authid or authrole filters
+    ///
+    /// The publication is always acknowledged, whatever `options` asks for.
+    pub fn publish_with_options(
+        &mut self,
+        topic: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+        options: PublishOptions,
+    ) -> Pin<Box<dyn Future<Output = Result<ID, CallError>>>> {
+        let published = self.publish_counting_recipients(topic, args, kwargs, options);
+        Box::pin(async { published.await.map(|(publication_id, _)| publication_id) })
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn publish_counting_recipients(
+        &mut self,
+        topic: URI,
+        args: Option<List>,
+        kwargs: Option<Dict>,
+        mut options: PublishOptions,
+    ) -> Pin<Box<dyn Future<Output = Result<(ID, usize), CallError>>>> {
+        info!("Publishing to {:?} with {:?} | {:?}", topic, args, kwargs);
+
+        let request_id = self.get_next_session_id();
+
+        let (complete, receiver) = oneshot::channel();
+
+        let mut info = self.connection_info.lock().unwrap();
+
+        info.publish_requests.insert(request_id, complete);
+        info.deliver_locally(&topic, args.as_deref(), kwargs.as_ref());
+
+        options.acknowledge = true;
+        options.report_recipients = true;
+        info.send_message(Message::Publish(
+            request_id,
+            options,
+            topic,
+            args,
+            kwargs,
+        ))
+        .unwrap();
+
+        Box::pin(async {
+            receiver.await.unwrap_or(Err(CallError {
+                reason: Reason::InternalError,
+                args: None,
+                kwargs: None,
+            }))
+        })
+    }
+
+    /// Disconnect from router gracefully 
+    pub fn shutdown(&mut self) -> Pin<Box<dyn Future<Output = Result<(), CallError>>>> {
+        let mut info = self.connection_info.lock().unwrap();
+
+        if info.connection_state == ConnectionState::Connected {
+            info.connection_state = ConnectionState::ShuttingDown;
+
+            let (complete, receiver) = oneshot::channel();
+
+            info.shutdown_complete = Some(complete);
+
+            // TODO add timeout in case server doesn't respond.
+            info.send_message(Message::Goodbye(
+                ErrorDetails::new(),
+                Reason::SystemShutdown,
+            ))
+            .unwrap();
+
+            Box::pin(async {
+                receiver.await.unwrap_or(Err(CallError {
+                    reason: Reason::InternalError,
+                    args: None,
+                    kwargs: None,
+                }))
+            })
+        } else {
+            Box::pin(async {
+                // Err(Error::new(ErrorKind::InvalidState(
+                //     "Tried to shut down a client that was already shutting down",
+                // )))
+                Err(CallError {
+                    reason: Reason::InternalError,
+                    args: None,
+                    kwargs: None,
+                })
+            })
+        }
+    }
+}
+
+impl fmt::Debug for ConnectionHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{Connection id: {}}}",
+            self.connection_info.lock().unwrap().session_id
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::TcpListener, sync::mpsc::channel, thread, time::Duration};
+
+    use futures::executor::block_on;
+
+    use parity_ws::Handler;
+    use url::Url;
+
+    use super::{Client, Connection, ConnectionSender};
+    use crate::{
+        messages::Message,
+        transport::loopback::{self, Event},
+        ErrorKind, Router, URI,
+    };
+
+    #[test]
+    fn client_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Client>();
+    }
+
+    #[test]
+    fn connect_times_out() {
+        // Accepts the TCP connection but never completes the websocket handshake
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        let error = Connection::new(&url, "ca.test.realm")
+            .with_timeout(Duration::from_millis(100))
+            .connect()
+            .err()
+            .unwrap();
+        assert!(matches!(error.get_kind(), ErrorKind::Timeout));
+    }
+
+    #[test]
+    fn dropped_call_is_forgotten() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+        let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/ws", addr);
+
+        let mut callee = Connection::new(&url, "ca.test.realm").connect().unwrap();
+        block_on(callee.register(
+            URI::new("ca.test.slow"),
+            Box::new(|args, kwargs| {
+                thread::sleep(Duration::from_millis(500));
+                Ok((Some(args), Some(kwargs)))
+            }),
+        ))
+        .unwrap();
+
+        let mut caller = Connection::new(&url, "ca.test.realm").connect().unwrap();
+        let result = caller.call(URI::new("ca.test.slow"), None, None);
+        assert_eq!(caller.connection_info.lock().unwrap().call_requests.len(), 1);
+        drop(result);
+        assert!(caller.connection_info.lock().unwrap().call_requests.is_empty());
+    }
+
+    #[test]
+    fn hello_announces_agent() {
+        let (client, router) = loopback::pair();
+        let (tx, _rx) = channel();
+        let handler = Connection::new("", "ca.test.realm")
+            .with_agent("ca.test.client")
+            .handler(ConnectionSender::Loopback(client.sender), tx);
+        handler
+            .send_hello(&handler.connection_info.lock().unwrap())
+            .unwrap();
+
+        let hello = match router.events.recv().unwrap() {
+            Event::Message(message @ Message::Hello(..)) => message,
+            event => panic!("Expected HELLO, got {:?}", event),
+        };
+        let json = serde_json::to_string(&hello).unwrap();
+        assert!(json.contains(r#""agent":"ca.test.client""#));
+    }
+
+    #[test]
+    fn handshake_carries_custom_headers() {
+        let (client, _router) = loopback::pair();
+        let (tx, _rx) = channel();
+        let mut handler = Connection::new("", "ca.test.realm")
+            .with_header("Authorization", "Bearer ca.test.token")
+            .with_header("Cookie", "session=1")
+            .handler(ConnectionSender::Loopback(client.sender), tx);
+
+        let url = Url::parse("ws://127.0.0.1/ws").unwrap();
+        let request = handler.build_request(&url).unwrap();
+        let headers = request.headers();
+        assert!(headers.contains(&(
+            "Authorization".to_string(),
+            b"Bearer ca.test.token".to_vec()
+        )));
+        assert!(headers.contains(&("Cookie".to_string(), b"session=1".to_vec())));
+    }
+}
@@ -1,38 +1,63 @@
 use std::{fmt, sync::mpsc::SendError};
 
-use rmp_serde::decode::Error as MsgPackError;
+use rmp_serde::{decode::Error as MsgPackError, encode::Error as MsgPackEncodeError};
 use serde_json::Error as JSONError;
 use url::ParseError;
 use parity_ws::Error as WSError;
+#[cfg(feature = "async-client")]
+use tokio_tungstenite::tungstenite::Error as TungsteniteError;
 
 use crate::messages::{self, Reason};
 
 use super::{ErrorType, Message, ID};
 
+/// An error raised by the router, a client or a serializer
 #[derive(Debug)]
 pub struct Error {
+    /// What went wrong
     pub kind: ErrorKind,
 }
 
+/// The kinds of error the crate raises
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum ErrorKind {
+    /// The websocket transport failed
     WSError(WSError),
+    /// The websocket transport of the async client failed
+    #[cfg(feature = "async-client")]
+    TungsteniteError(TungsteniteError),
+    /// A URL could not be parsed
     URLError(ParseError),
+    /// The peer refused the session
     HandshakeError(Reason),
-    UnexpectedMessage(&'static str), // Used when a peer receives another message before Welcome or Hello
+    /// A peer sent a message it wasn't allowed to send yet, such as one before HELLO or WELCOME
+    UnexpectedMessage(&'static str),
+    /// A message could not be handed to another thread
     ThreadError(SendError<messages::Message>),
+    /// The connection is gone
     ConnectionLost,
+    /// The connection is closing for the given reason
     Closing(String),
+    /// A message could not be encoded or decoded as JSON
     JSONError(JSONError),
+    /// A message could not be decoded as MessagePack
     MsgPackError(MsgPackError),
+    /// A message could not be encoded as MessagePack
+    MsgPackEncodeError(MsgPackEncodeError),
+    /// A frame didn't hold a well formed message
     MalformedData,
+    /// A message of this type wasn't expected here
     InvalidMessageType(Message),
+    /// A message doesn't fit the state of the session
     InvalidState(&'static str),
+    /// The peer didn't answer in time
     Timeout,
+    /// The peer answered a request with an error
     ErrorReason(ErrorType, ID, Reason),
 }
 impl Error {
+    /// Creates an error of the given kind
     pub fn new(kind: ErrorKind) -> Error {
         Error { kind }
     }
@@ -41,6 +66,7 @@ impl Error {
         format!("WAMP Error: {}", self.kind.description())
     }
 
+    /// What went wrong
     #[inline]
     pub fn get_kind(self) -> ErrorKind {
         self.kind
@@ -54,14 +80,18 @@ impl fmt::Display for Error {
 }
 
 impl ErrorKind {
+    /// Describes the error for a log or a human
     pub fn description(&self) -> String {
         match *self {
             ErrorKind::WSError(ref e) => e.to_string(),
+            #[cfg(feature = "async-client")]
+            ErrorKind::TungsteniteError(ref e) => e.to_string(),
             ErrorKind::URLError(ref e) => e.to_string(),
             ErrorKind::HandshakeError(ref r) => r.to_string(),
             ErrorKind::ThreadError(ref e) => e.to_string(),
             ErrorKind::JSONError(ref e) => e.to_string(),
             ErrorKind::MsgPackError(ref e) => e.to_string(),
+            ErrorKind::MsgPackEncodeError(ref e) => e.to_string(),
             ErrorKind::ErrorReason(_, _, ref s) => s.to_string(),
             ErrorKind::Closing(ref s) => s.clone(),
             ErrorKind::UnexpectedMessage(s) | ErrorKind::InvalidState(s) => s.to_string(),
@@ -1,6 +1,7 @@
 #![doc(html_logo_url = "https://raw.githubusercontent.com/wiki/ohyo-io/wampire/images/wampire.svg")]
 
 #![warn(missing_docs)]
+#![allow(clippy::result_large_err)]
 
 //! # Asynchronous implementation of Web Application Messaging Protocol (v2)
 //!
@@ -33,19 +34,30 @@ pub mod client;
 mod error;
 mod messages;
 pub mod router;
+pub mod serializer;
+#[cfg_attr(not(feature = "ws-client"), allow(dead_code))]
+mod transport;
 
-use self::error::{Error, ErrorKind};
+pub use self::error::{Error, ErrorKind};
 
-use crate::messages::{ErrorType, Message};
+use crate::messages::ErrorType;
+#[cfg(feature = "ws-client")]
+pub use crate::client::{Client, ClientPool, Connection};
 pub use crate::{
-    client::{Client, Connection},
     messages::{
-        ArgDict, ArgList, CallError, Dict, InvocationPolicy, List, MatchingPolicy, Reason, Value,
-        URI,
+        reply, reply_error, reply_kw, ArgDict, ArgList, CallError, Dict, InvocationPolicy, List,
+        MatchingPolicy, Message, MessageRef, PublishOptions, Reason, ResultDetails, RouterFeatures,
+        Value, URI,
     },
-    router::Router,
+    router::{ListenerSettings, RealmOptions, RealmStats, Router},
+    serializer::{Serializer, Serializers},
 };
 
+/// Forwards this crate's `log` records to the installed `tracing` subscriber, so they show up
+/// inside the router's per-message spans.  Call `LogTracer::init()` once at startup.
+#[cfg(feature = "tracing")]
+pub use tracing_log::LogTracer;
+
 /// Alias for call Result with [CallError]
 pub type CallResult<T> = Result<T, CallError>;
 
@@ -1,6 +1,9 @@
 use std::fmt;
 
-use crate::ID;
+use rmp_serde::{Deserializer as RMPDeserializer, Serializer};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, ErrorKind, WampResult, ID};
 
 mod types;
 pub use self::types::*;
@@ -14,31 +17,128 @@ macro_rules! try_or {
     };
 }
 
+/// A WAMP message, as exchanged between a router and its sessions
 #[allow(clippy::large_enum_variant)]
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Message {
+    /// `[HELLO, Realm, Details]`
     Hello(URI, HelloDetails),
+    /// `[WELCOME, Session, Details]`
     Welcome(ID, WelcomeDetails),
+    /// `[ABORT, Details, Reason]`
     Abort(ErrorDetails, Reason),
+    /// `[GOODBYE, Details, Reason]`
     Goodbye(ErrorDetails, Reason),
+    /// `[ERROR, REQUEST.Type, REQUEST.Request, Details, Error, Arguments, ArgumentsKw]`
     Error(ErrorType, ID, Dict, Reason, Option<List>, Option<Dict>),
+    /// `[SUBSCRIBE, Request, Options, Topic]`
     Subscribe(ID, SubscribeOptions, URI),
+    /// `[SUBSCRIBED, SUBSCRIBE.Request, Subscription]`
     Subscribed(ID, ID),
+    /// `[UNSUBSCRIBE, Request, SUBSCRIBED.Subscription]`
     Unsubscribe(ID, ID),
+    /// `[UNSUBSCRIBED, UNSUBSCRIBE.Request]`
     Unsubscribed(ID),
+    /// `[PUBLISH, Request, Options, Topic, Arguments, ArgumentsKw]`
     Publish(ID, PublishOptions, URI, Option<List>, Option<Dict>),
-    Published(ID, ID),
+    /// `[PUBLISHED, PUBLISH.Request, Publication, Details]`, where the details are left out
+    /// when empty as the WAMP spec has none
+    Published(ID, ID, PublishedDetails),
+    /// `[EVENT, SUBSCRIBED.Subscription, PUBLISHED.Publication, Details, Arguments, ArgumentsKw]`
     Event(ID, ID, EventDetails, Option<List>, Option<Dict>),
+    /// `[REGISTER, Request, Options, Procedure]`
     Register(ID, RegisterOptions, URI),
+    /// `[REGISTERED, REGISTER.Request, Registration]`
     Registered(ID, ID),
+    /// `[UNREGISTER, Request, REGISTERED.Registration]`
     Unregister(ID, ID),
+    /// `[UNREGISTERED, UNREGISTER.Request]`
     Unregistered(ID),
+    /// `[CALL, Request, Options, Procedure, Arguments, ArgumentsKw]`
     Call(ID, CallOptions, URI, Option<List>, Option<Dict>),
+    /// `[INVOCATION, Request, REGISTERED.Registration, Details, Arguments, ArgumentsKw]`
     Invocation(ID, ID, InvocationDetails, Option<List>, Option<Dict>),
+    /// `[YIELD, INVOCATION.Request, Options, Arguments, ArgumentsKw]`
     Yield(ID, YieldOptions, Option<List>, Option<Dict>),
+    /// `[RESULT, CALL.Request, Details, Arguments, ArgumentsKw]`
     Result(ID, ResultDetails, Option<List>, Option<Dict>),
 }
 
+/// A PUBLISH or CALL whose arguments are borrowed, so that a payload that is sent again and
+/// again can be encoded without first being copied into a `Message`
+#[derive(Debug)]
+pub enum MessageRef<'a> {
+    /// `[PUBLISH, Request, Options, Topic, Arguments, ArgumentsKw]`
+    Publish(
+        ID,
+        PublishOptions,
+        URI,
+        Option<&'a [Value]>,
+        Option<&'a Dict>,
+    ),
+    /// `[CALL, Request, Options, Procedure, Arguments, ArgumentsKw]`
+    Call(ID, CallOptions, URI, Option<&'a [Value]>, Option<&'a Dict>),
+}
+
+/// Encodes a message as it is sent over `wamp.2.json`
+pub(crate) fn encode_json<M: Serialize>(message: &M) -> WampResult<String> {
+    serde_json::to_string(message).map_err(|e| Error::new(ErrorKind::JSONError(e)))
+}
+
+/// Encodes a message as it is sent over `wamp.2.msgpack`
+pub(crate) fn encode_msgpack<M: Serialize>(message: &M) -> WampResult<Vec<u8>> {
+    let mut buf: Vec<u8> = Vec::new();
+    message
+        .serialize(&mut Serializer::new(&mut buf).with_struct_map())
+        .map_err(|e| Error::new(ErrorKind::MsgPackEncodeError(e)))?;
+    Ok(buf)
+}
+
+impl<'a> MessageRef<'a> {
+    /// Copies the arguments into an owned message
+    pub fn to_message(&self) -> Message {
+        match *self {
+            MessageRef::Publish(id, ref options, ref topic, args, kwargs) => Message::Publish(
+                id,
+                options.clone(),
+                topic.clone(),
+                args.map(<[Value]>::to_vec),
+                kwargs.cloned(),
+            ),
+            MessageRef::Call(id, ref options, ref procedure, args, kwargs) => Message::Call(
+                id,
+                options.clone(),
+                procedure.clone(),
+                args.map(<[Value]>::to_vec),
+                kwargs.cloned(),
+            ),
+        }
+    }
+}
+
+impl Message {
+    /// Encodes the message as it is sent over `wamp.2.json`
+    pub fn to_json_string(&self) -> WampResult<String> {
+        encode_json(self)
+    }
+
+    /// Encodes the message as it is sent over `wamp.2.msgpack`
+    pub fn to_msgpack(&self) -> WampResult<Vec<u8>> {
+        encode_msgpack(self)
+    }
+
+    /// Decodes a single message received over `wamp.2.json`
+    pub fn from_json_str(payload: &str) -> WampResult<Message> {
+        serde_json::from_str(payload).map_err(|e| Error::new(ErrorKind::JSONError(e)))
+    }
+
+    /// Decodes a message received over `wamp.2.msgpack`
+    pub fn from_msgpack(payload: &[u8]) -> WampResult<Message> {
+        let mut de = RMPDeserializer::new(payload);
+        Deserialize::deserialize(&mut de).map_err(|e| Error::new(ErrorKind::MsgPackError(e)))
+    }
+}
+
 macro_rules! serialize_with_args {
     ($args:expr, $kwargs:expr, $serializer:expr, $($item: expr),*) => (
         if let Some(ref kwargs) = *$kwargs {
@@ -86,8 +186,12 @@ impl serde::Serialize for Message {
             Message::Publish(id, ref details, ref topic, ref args, ref kwargs) => {
                 serialize_with_args!(args, kwargs, serializer, 16, id, details, topic)
             }
-            Message::Published(request_id, publication_id) => {
-                (17, request_id, publication_id).serialize(serializer)
+            Message::Published(request_id, publication_id, ref details) => {
+                if details.is_empty() {
+                    (17, request_id, publication_id).serialize(serializer)
+                } else {
+                    (17, request_id, publication_id, details).serialize(serializer)
+                }
             }
             Message::Event(subscription_id, publication_id, ref details, ref args, ref kwargs) => {
                 serialize_with_args!(
@@ -126,6 +230,22 @@ impl serde::Serialize for Message {
     }
 }
 
+impl<'a> serde::Serialize for MessageRef<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match *self {
+            MessageRef::Publish(id, ref options, ref topic, ref args, ref kwargs) => {
+                serialize_with_args!(args, kwargs, serializer, 16, id, options, topic)
+            }
+            MessageRef::Call(id, ref options, ref procedure, ref args, ref kwargs) => {
+                serialize_with_args!(args, kwargs, serializer, 48, id, options, procedure)
+            }
+        }
+    }
+}
+
 impl<'de> serde::Deserialize<'de> for Message {
     fn deserialize<D>(deserializer: D) -> Result<Message, D::Error>
     where
@@ -323,7 +443,8 @@ impl MessageVisitor {
             visitor.next_element(),
             "Published message ended before publication id"
         );
-        Ok(Message::Published(request, publication))
+        let details = visitor.next_element()?.unwrap_or_default();
+        Ok(Message::Published(request, publication, details))
     }
 
     fn visit_event<'de, V>(&self, mut visitor: V) -> Result<Message, V::Error>
@@ -536,31 +657,28 @@ impl<'de> serde::de::Visitor<'de> for MessageVisitor {
 
 #[cfg(test)]
 mod test {
-    use rmp_serde::{Deserializer as RMPDeserializer, Serializer};
-    use serde::{Deserialize, Serialize};
-    use std::collections::HashMap;
+    use std::{collections::HashMap, convert::TryFrom};
 
     use super::{
         types::{
-            CallOptions, ClientRoles, ErrorDetails, ErrorType, EventDetails, HelloDetails,
-            InvocationDetails, PublishOptions, Reason, RegisterOptions, ResultDetails, RouterRoles,
-            SubscribeOptions, Value, WelcomeDetails, YieldOptions, URI,
+            reply, reply_error, reply_kw, CallOptions, ClientRoles, ErrorDetails, ErrorType, EventDetails, HelloDetails,
+            InvocationDetails, PublishOptions, PublishedDetails, Reason, RegisterOptions, ResultDetails,
+            RouterFeatures, RouterRoles, RunMode, SubscribeOptions, URIIssue, Value, WelcomeDetails,
+            YieldOptions, URI,
         },
         Message,
     };
+    use crate::{CallError, ErrorKind};
 
     macro_rules! two_way_test {
         ($message:expr, $s:expr) => {{
             let message = $message;
-            assert_eq!(serde_json::to_string(&message).unwrap(), $s);
-            assert_eq!(serde_json::from_str::<Message>($s).unwrap(), message);
-            let mut buf: Vec<u8> = Vec::new();
-            message
-                .serialize(&mut Serializer::new(&mut buf).with_struct_map())
-                .unwrap();
-            let mut de = RMPDeserializer::new(&buf[..]);
-            let new_message: Message = Deserialize::deserialize(&mut de).unwrap();
-            assert_eq!(new_message, message);
+            assert_eq!(message.to_json_string().unwrap(), $s);
+            assert_eq!(Message::from_json_str($s).unwrap(), message);
+            assert_eq!(
+                Message::from_msgpack(&message.to_msgpack().unwrap()).unwrap(),
+                message
+            );
         }};
     }
 
@@ -588,6 +706,24 @@ mod test {
         );
     }
 
+    #[test]
+    fn welcome_features() {
+        let message: Message = serde_json::from_str(
+            "[2,493782,{\"roles\":{\"dealer\":{},\"broker\":{\"features\":{\"pattern_based_subscription\":true}}}}]",
+        )
+        .unwrap();
+        match message {
+            Message::Welcome(_, details) => assert_eq!(
+                details.roles().features(),
+                RouterFeatures {
+                    pattern_based_subscription: true,
+                    pattern_based_registration: false,
+                }
+            ),
+            _ => panic!("Expected a welcome message"),
+        }
+    }
+
     #[test]
     fn serialize_abort() {
         two_way_test!(
@@ -601,6 +737,10 @@ mod test {
             ),
             "[3,{\"message\":\"The realm does not exist\"},\"wamp.error.no_such_realm\"]"
         );
+        two_way_test!(
+            Message::Abort(ErrorDetails::new(), Reason::ProtocolViolation),
+            "[3,{},\"wamp.error.protocol_violation\"]"
+        );
     }
 
     #[test]
@@ -657,6 +797,25 @@ mod test {
         );
     }
 
+    #[test]
+    fn serialize_internal_error() {
+        two_way_test!(
+            Message::Error(
+                ErrorType::Call,
+                7_814_135,
+                HashMap::new(),
+                Reason::InternalError,
+                None,
+                None
+            ),
+            "[8,48,7814135,{},\"wamp.error.internal_error\"]"
+        );
+        two_way_test!(
+            Message::Goodbye(ErrorDetails::new(), Reason::InternalError),
+            "[6,{},\"wamp.error.internal_error\"]"
+        );
+    }
+
     #[test]
     fn serialize_subscribe() {
         two_way_test!(
@@ -669,6 +828,23 @@ mod test {
         )
     }
 
+    #[test]
+    fn explicit_strict_match() {
+        for options in &[r#"{"match":""}"#, r#"{"match":"exact"}"#] {
+            let message: Message =
+                serde_json::from_str(&format!(r#"[32,58944,{},"ca.dal.test.the_sub"]"#, options))
+                    .unwrap();
+            assert_eq!(
+                message,
+                Message::Subscribe(
+                    58944,
+                    SubscribeOptions::new(),
+                    URI::new("ca.dal.test.the_sub")
+                )
+            );
+        }
+    }
+
     #[test]
     fn serialize_subscribed() {
         two_way_test!(
@@ -721,12 +897,36 @@ mod test {
                 Some(kwargs)
             ),
             "[16,3243542,{\"acknowledge\":true},\"ca.dal.test.topic3\",[],{\"key1\":[-5]}]"
-        )
+        );
+
+        let mut options = PublishOptions::new(false);
+        options.eligible_authid = Some(vec!["alice".to_string()]);
+        options.exclude_authrole = Some(vec!["guest".to_string()]);
+        two_way_test!(
+            Message::Publish(
+                453_453,
+                options,
+                URI::new("ca.dal.test.topic1"),
+                None,
+                None
+            ),
+            "[16,453453,{\"eligible_authid\":[\"alice\"],\"exclude_authrole\":[\"guest\"]},\"ca.dal.test.topic1\"]"
+        );
     }
 
     #[test]
     fn serialize_published() {
-        two_way_test!(Message::Published(23443, 564_564), "[17,23443,564564]")
+        two_way_test!(
+            Message::Published(23443, 564_564, PublishedDetails::new()),
+            "[17,23443,564564]"
+        );
+
+        let mut details = PublishedDetails::new();
+        details.recipients = Some(2);
+        two_way_test!(
+            Message::Published(23443, 564_564, details),
+            "[17,23443,564564,{\"_recipients\":2}]"
+        )
     }
 
     #[test]
@@ -766,6 +966,23 @@ mod test {
             Message::Register(25_349_185, RegisterOptions::new(), URI::new("ca.test.proc")),
             "[64,25349185,{},\"ca.test.proc\"]"
         );
+
+        let mut options = RegisterOptions::new();
+        options.description = Some("Adds two integers".to_string());
+        let mut schema = HashMap::new();
+        schema.insert("a".to_string(), Value::String("integer".to_string()));
+        options.args_schema = Some(schema);
+        two_way_test!(
+            Message::Register(25_349_185, options, URI::new("ca.test.add")),
+            "[64,25349185,{\"description\":\"Adds two integers\",\"args_schema\":{\"a\":\"integer\"}},\"ca.test.add\"]"
+        );
+
+        let mut options = RegisterOptions::new();
+        options.concurrency = Some(1);
+        two_way_test!(
+            Message::Register(25_349_185, options, URI::new("ca.test.proc")),
+            "[64,25349185,{\"concurrency\":1},\"ca.test.proc\"]"
+        );
     }
 
     #[test]
@@ -826,6 +1043,28 @@ mod test {
                 Some(kwargs)
             ),
             "[48,764346,{},\"com.myapp.compute\",[],{\"key1\":[5]}]"
+        );
+
+        let mut options = CallOptions::new();
+        options.receive_progress = true;
+        two_way_test!(
+            Message::Call(7_814_135, options, URI::new("com.myapp.count"), None, None),
+            "[48,7814135,{\"receive_progress\":true},\"com.myapp.count\"]"
+        );
+
+        let mut options = CallOptions::new();
+        options.runmode = Some(RunMode::Partition);
+        options.rkey = Some(12);
+        two_way_test!(
+            Message::Call(7_814_136, options, URI::new("com.myapp.count"), None, None),
+            "[48,7814136,{\"runmode\":\"partition\",\"rkey\":12},\"com.myapp.count\"]"
+        );
+
+        let mut options = CallOptions::new();
+        options.runmode = Some(RunMode::All);
+        two_way_test!(
+            Message::Call(7_814_137, options, URI::new("com.myapp.count"), None, None),
+            "[48,7814137,{\"runmode\":\"all\"},\"com.myapp.count\"]"
         )
     }
 
@@ -892,6 +1131,16 @@ mod test {
                 Some(kwargs)
             ),
             "[70,6131533,{},[],{\"key1\":[5]}]"
+        );
+
+        let mut options = YieldOptions::new();
+        options.progress = true;
+        options
+            .custom
+            .insert("_trace".to_string(), Value::String("abc".to_string()));
+        two_way_test!(
+            Message::Yield(6_131_533, options, None, None),
+            "[70,6131533,{\"progress\":true,\"_trace\":\"abc\"}]"
         )
     }
 
@@ -921,6 +1170,248 @@ mod test {
                 Some(kwargs)
             ),
             "[50,764346,{},[],{\"key1\":[8.6]}]"
+        );
+
+        let mut details = ResultDetails::new();
+        details.progress = true;
+        two_way_test!(
+            Message::Result(7_814_135, details, None, None),
+            "[50,7814135,{\"progress\":true}]"
+        );
+
+        let mut details = ResultDetails::new();
+        details.callee = Some(42);
+        two_way_test!(
+            Message::Result(7_814_135, details, None, None),
+            "[50,7814135,{\"callee\":42}]"
         )
     }
+
+    #[test]
+    fn reply_helpers() {
+        let (args, kwargs) = reply(vec![Value::Integer(3)]).unwrap();
+        assert_eq!(args, Some(vec![Value::Integer(3)]));
+        assert_eq!(kwargs, None);
+
+        let mut dict = HashMap::new();
+        dict.insert("sum".to_string(), Value::Integer(3));
+        let (args, kwargs) = reply_kw(dict.clone()).unwrap();
+        assert_eq!(args, None);
+        assert_eq!(kwargs, Some(dict));
+
+        let error = reply_error(Reason::InvalidArgument, None, None).unwrap_err();
+        assert_eq!(error.get_reason(), &Reason::InvalidArgument);
+    }
+
+    #[test]
+    fn integer_values_compare_numerically() {
+        assert_eq!(Value::Integer(5), Value::UnsignedInteger(5));
+        assert_eq!(Value::UnsignedInteger(5), Value::Integer(5));
+        assert_ne!(Value::Integer(-1), Value::UnsignedInteger(u64::MAX));
+        assert_ne!(Value::UnsignedInteger(u64::MAX), Value::Integer(-1));
+        assert_ne!(Value::Integer(5), Value::Float(5.0));
+        assert_ne!(Value::Integer(5), Value::String("5".to_string()));
+
+        assert_eq!(
+            vec![Value::Integer(1), Value::List(vec![Value::UnsignedInteger(2)])],
+            vec![Value::UnsignedInteger(1), Value::List(vec![Value::Integer(2)])]
+        );
+        assert_ne!(
+            vec![Value::Integer(1), Value::Integer(2)],
+            vec![Value::UnsignedInteger(1)]
+        );
+
+        let mut signed = HashMap::new();
+        signed.insert("count".to_string(), Value::Integer(3));
+        let mut unsigned = HashMap::new();
+        unsigned.insert("count".to_string(), Value::UnsignedInteger(3));
+        assert_eq!(Value::Dict(signed), Value::Dict(unsigned));
+    }
+
+    #[test]
+    fn decode_malformed_payloads() {
+        assert!(matches!(
+            Message::from_json_str("[1,\"ca.dal.wamp.test\"")
+                .unwrap_err()
+                .get_kind(),
+            ErrorKind::JSONError(_)
+        ));
+        assert!(matches!(
+            Message::from_msgpack(&[0xc1]).unwrap_err().get_kind(),
+            ErrorKind::MsgPackError(_)
+        ));
+    }
+
+    #[test]
+    fn value_accessors() {
+        let string = Value::String("a".to_string());
+        assert_eq!(string.as_str(), Some("a"));
+        assert_eq!(Value::Integer(1).as_str(), None);
+
+        assert_eq!(Value::Integer(-1).as_i64(), Some(-1));
+        assert_eq!(Value::UnsignedInteger(1).as_i64(), Some(1));
+        assert_eq!(Value::UnsignedInteger(u64::MAX).as_i64(), None);
+        assert_eq!(Value::Float(1.0).as_i64(), None);
+
+        assert_eq!(Value::UnsignedInteger(u64::MAX).as_u64(), Some(u64::MAX));
+        assert_eq!(Value::Integer(1).as_u64(), Some(1));
+        assert_eq!(Value::Integer(-1).as_u64(), None);
+        assert_eq!(string.as_u64(), None);
+
+        assert_eq!(Value::Float(1.5).as_f64(), Some(1.5));
+        assert_eq!(Value::Integer(-2).as_f64(), Some(-2.0));
+        assert_eq!(Value::UnsignedInteger(2).as_f64(), Some(2.0));
+        assert_eq!(Value::Boolean(true).as_f64(), None);
+
+        assert_eq!(Value::Boolean(true).as_bool(), Some(true));
+        assert_eq!(Value::Integer(1).as_bool(), None);
+
+        assert_eq!(Value::from(vec![1u8, 2]).as_bytes(), Some(&[1u8, 2][..]));
+        assert_eq!(string.as_bytes(), None);
+
+        let list = vec![Value::Integer(1)];
+        assert_eq!(Value::List(list.clone()).as_array(), Some(&list));
+        assert_eq!(string.as_array(), None);
+
+        let mut dict = HashMap::new();
+        dict.insert("key".to_string(), Value::Integer(1));
+        assert_eq!(Value::Dict(dict.clone()).as_object(), Some(&dict));
+        assert_eq!(Value::List(list).as_object(), None);
+    }
+
+    #[test]
+    fn json_value_round_trip() {
+        let json = serde_json::json!({
+            "name": "sensor",
+            "readings": [1, -2, 3.5, {"nested": [true, "x"]}],
+            "limits": {"max": u64::MAX, "min": i64::MIN},
+        });
+        let value = Value::try_from(json.clone()).unwrap();
+        let dict = value.as_object().unwrap();
+        assert_eq!(dict["name"], Value::String("sensor".to_string()));
+        let readings = dict["readings"].as_array().unwrap();
+        assert!(matches!(readings[0], Value::UnsignedInteger(1)));
+        assert!(matches!(readings[1], Value::Integer(-2)));
+        assert!(matches!(readings[2], Value::Float(f) if f == 3.5));
+        let limits = dict["limits"].as_object().unwrap();
+        assert!(matches!(limits["max"], Value::UnsignedInteger(u64::MAX)));
+        assert!(matches!(limits["min"], Value::Integer(i64::MIN)));
+        assert_eq!(serde_json::Value::from(value), json);
+
+        let list = Value::List(vec![Value::Bytes(vec![1, 2]), Value::Float(f64::NAN)]);
+        assert_eq!(
+            serde_json::Value::from(list),
+            serde_json::json!(["\u{0}AQI=", "NaN"])
+        );
+        assert!(Value::try_from(serde_json::json!([1, null])).is_err());
+    }
+
+    #[test]
+    fn serialize_bytes() {
+        two_way_test!(
+            Message::Publish(
+                453_453,
+                PublishOptions::new(false),
+                URI::new("ca.dal.test.sensor"),
+                Some(vec![Value::Bytes(vec![0, 1, 2, 254, 255])]),
+                None
+            ),
+            "[16,453453,{},\"ca.dal.test.sensor\",[\"\\u0000AAEC/v8=\"]]"
+        );
+        // Sent as msgpack bin 8, not as a string
+        let message = Message::Yield(1, YieldOptions::new(), Some(vec![Value::Bytes(vec![7; 3])]), None);
+        let encoded = message.to_msgpack().unwrap();
+        assert!(encoded.ends_with(&[0xc4, 3, 7, 7, 7]));
+        // A string that only looks like it might be encoded stays a string
+        assert_eq!(
+            Message::from_json_str("[70,1,{},[\"\\u0000not base64!\"]]").unwrap(),
+            Message::Yield(1, YieldOptions::new(), Some(vec![Value::String("\0not base64!".to_string())]), None)
+        );
+    }
+
+    #[test]
+    fn serialize_non_finite_floats() {
+        for (float, name) in [(f64::NAN, "NaN"), (f64::INFINITY, "Infinity"), (f64::NEG_INFINITY, "-Infinity")] {
+            let message = Message::Yield(1, YieldOptions::new(), Some(vec![Value::Float(float)]), None);
+            let json = message.to_json_string().unwrap();
+            assert_eq!(json, format!("[70,1,{{}},[\"{}\"]]", name));
+            assert_eq!(
+                Message::from_json_str(&json).unwrap(),
+                Message::Yield(1, YieldOptions::new(), Some(vec![Value::String(name.to_string())]), None)
+            );
+
+            match Message::from_msgpack(&message.to_msgpack().unwrap()).unwrap() {
+                Message::Yield(1, _, Some(args), None) => match args[0] {
+                    Value::Float(f) => assert!(f.is_nan() && float.is_nan() || f == float),
+                    ref value => panic!("Expected a float, got {:?}", value),
+                },
+                message => panic!("Expected a yield, got {:?}", message),
+            }
+        }
+    }
+
+    #[test]
+    fn uri_validity() {
+        assert!(URI::new("ca.test.realm").is_valid());
+        assert!(URI::new("realm").is_valid());
+        assert!(!URI::new("").is_valid());
+        assert!(!URI::new("ca..realm").is_valid());
+        assert!(!URI::new(".ca.realm").is_valid());
+        assert!(!URI::new("ca.test realm").is_valid());
+        assert!(!URI::new("ca.#.realm").is_valid());
+    }
+
+    #[test]
+    fn call_error_is_timeout() {
+        assert!(CallError::new(Reason::Cancelled, None, None).is_timeout());
+        let reason = Reason::CustomReason(URI::new("wamp.error.timeout"));
+        assert!(CallError::new(reason, None, None).is_timeout());
+        assert!(!CallError::new(Reason::NoSuchProcedure, None, None).is_timeout());
+    }
+
+    #[test]
+    fn call_error_is_no_such_procedure() {
+        assert!(CallError::new(Reason::NoSuchProcedure, None, None).is_no_such_procedure());
+        assert!(!CallError::new(Reason::NoEligibleCallee, None, None).is_no_such_procedure());
+    }
+
+    #[test]
+    fn call_error_is_not_authorized() {
+        assert!(CallError::new(Reason::NotAuthorized, None, None).is_not_authorized());
+        assert!(CallError::new(Reason::AuthorizationFailed, None, None).is_not_authorized());
+        assert!(!CallError::new(Reason::InvalidArgument, None, None).is_not_authorized());
+    }
+
+    #[test]
+    fn call_error_is_application_error() {
+        let reason = Reason::CustomReason(URI::new("com.example.out_of_stock"));
+        assert!(CallError::new(reason, None, None).is_application_error());
+        let reason = Reason::CustomReason(URI::new("wamp.error.timeout"));
+        assert!(!CallError::new(reason, None, None).is_application_error());
+        assert!(!CallError::new(Reason::InvalidArgument, None, None).is_application_error());
+    }
+
+    #[test]
+    fn uri_issues() {
+        assert_eq!(URI::new("ca.test.realm").issues(false), vec![]);
+        assert_eq!(URI::new("").issues(true), vec![URIIssue::Empty]);
+        assert_eq!(URI::new("com.foo.").issues(false), vec![URIIssue::TrailingDot]);
+        assert_eq!(URI::new(".com.foo").issues(false), vec![URIIssue::LeadingDot]);
+        assert_eq!(URI::new("com..foo").issues(false), vec![URIIssue::EmptyComponent(1)]);
+        assert_eq!(URI::new(".com..foo").issues(true), vec![]);
+        assert_eq!(
+            URI::new("com.f oo.").issues(false),
+            vec![URIIssue::InvalidCharacter(1, ' '), URIIssue::TrailingDot]
+        );
+        assert_eq!(URIIssue::TrailingDot.to_string(), "the URI ends with a dot");
+    }
+
+    #[test]
+    fn uri_normalized() {
+        assert_eq!(URI::new(".com..foo.").normalized(), URI::new("com.foo"));
+        assert_eq!(URI::new("com.foo").normalized(), URI::new("com.foo"));
+        assert_eq!(URI::new("Com.Foo").normalized(), URI::new("Com.Foo"));
+        assert_eq!(URI::new("...").normalized(), URI::new(""));
+        assert_eq!(URI::new("com.foo").to_string(), "com.foo");
+    }
 }
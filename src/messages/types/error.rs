@@ -6,7 +6,7 @@ use super::{Dict, List};
 
 
 /// Represents error reasons
-#[derive(Hash, Eq, PartialEq, Debug)]
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
 pub enum Reason {
     /// Invalid URI
     InvalidURI,
@@ -18,6 +18,8 @@ pub enum Reason {
     NoSuchRegistration,
     /// No such subscription
     NoSuchSubscription,
+    /// No such session
+    NoSuchSession,
     /// Invalid argument
     InvalidArgument,
     /// System shutdown
@@ -50,6 +52,8 @@ pub enum Reason {
     CustomReason(URI),
     /// General case internal error
     InternalError,
+    /// Peer broke the protocol, e.g. by sending a message out of sequence
+    ProtocolViolation,
 }
 
 /// Represens WAMP calling error
@@ -60,7 +64,7 @@ pub struct CallError {
     pub(crate) kwargs: Option<Dict>,
 }
 
-#[derive(Hash, Eq, PartialEq, Debug)]
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
 pub enum ErrorType {
     Subscribe,
     Unsubscribe,
@@ -104,6 +108,39 @@ impl CallError {
     pub fn get_kwargs(&self) -> &Option<Dict> {
         &self.kwargs
     }
+
+    /// Whether the call was given up on before a result arrived.  Clients report their own
+    /// timeouts as `Reason::Cancelled`; routers that time out calls use `wamp.error.timeout`.
+    pub fn is_timeout(&self) -> bool {
+        match self.reason {
+            Reason::Cancelled => true,
+            Reason::CustomReason(ref reason) => reason.uri == "wamp.error.timeout",
+            _ => false,
+        }
+    }
+
+    /// Whether no procedure is registered under the called URI
+    pub fn is_no_such_procedure(&self) -> bool {
+        self.reason == Reason::NoSuchProcedure
+    }
+
+    /// Whether the router refused the call, either for lack of permission or because
+    /// authorizing it failed
+    pub fn is_not_authorized(&self) -> bool {
+        matches!(
+            self.reason,
+            Reason::NotAuthorized | Reason::AuthorizationFailed
+        )
+    }
+
+    /// Whether the callee failed the call with an error of its own, rather than one of the
+    /// `wamp.` errors defined by the protocol
+    pub fn is_application_error(&self) -> bool {
+        match self.reason {
+            Reason::CustomReason(ref reason) => !reason.uri.starts_with("wamp."),
+            _ => false,
+        }
+    }
 }
 
 struct ErrorTypeVisitor;
@@ -118,6 +155,7 @@ impl Reason {
             Reason::ProcedureAlreadyExists => "wamp.error.procedure_already_exists",
             Reason::NoSuchRegistration => "wamp.error.no_such_registration",
             Reason::NoSuchSubscription => "wamp.error.no_such_subscription",
+            Reason::NoSuchSession => "wamp.error.no_such_session",
             Reason::InvalidArgument => "wamp.error.invalid_argument",
             Reason::SystemShutdown => "wamp.error.system_shutdown",
             Reason::CloseRealm => "wamp.error.close_realm",
@@ -133,7 +171,8 @@ impl Reason {
             Reason::NetworkFailure => "wamp.error.network_failure",
             Reason::NormalClose => "wamp.close.normal",
             Reason::CustomReason(ref reason) => &reason.uri,
-            Reason::InternalError => "Client internal error",
+            Reason::InternalError => "wamp.error.internal_error",
+            Reason::ProtocolViolation => "wamp.error.protocol_violation",
         }
     }
 }
@@ -182,6 +221,7 @@ impl<'de> serde::de::Visitor<'de> for ReasonVisitor {
             "wamp.error.procedure_already_exists" => Ok(Reason::ProcedureAlreadyExists),
             "wamp.error.no_such_registration" => Ok(Reason::NoSuchRegistration),
             "wamp.error.no_such_subscription" => Ok(Reason::NoSuchSubscription),
+            "wamp.error.no_such_session" => Ok(Reason::NoSuchSession),
             "wamp.error.invalid_argument" => Ok(Reason::InvalidArgument),
             "wamp.error.system_shutdown" => Ok(Reason::SystemShutdown),
             "wamp.error.close_realm" => Ok(Reason::CloseRealm),
@@ -196,6 +236,8 @@ impl<'de> serde::de::Visitor<'de> for ReasonVisitor {
             "wamp.error.option-disallowed.disclose_me" => Ok(Reason::OptionDisallowedDiscloseMe),
             "wamp.error.network_failure" => Ok(Reason::NetworkFailure),
             "wamp.close.normal" => Ok(Reason::NormalClose),
+            "wamp.error.internal_error" => Ok(Reason::InternalError),
+            "wamp.error.protocol_violation" => Ok(Reason::ProtocolViolation),
             x => Ok(Reason::CustomReason(URI::new(x))),
         }
     }
@@ -43,12 +43,28 @@ pub enum InvocationPolicy {
     First,
     /// Last callee (in order of registration( is called
     Last,
+    /// Each caller keeps being routed to the callee it was first given, which is picked
+    /// sequentially like `RoundRobin`
+    Sticky,
+    /// Calls go to the callee with the fewest unanswered invocations, with ties broken like
+    /// `RoundRobin`
+    LeastBusy,
+}
+
+/// Which registrants of a shared registration a call goes to, in place of the invocation policy
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum RunMode {
+    /// Every registrant is invoked and the caller gets their results together
+    All,
+    /// Only the registrant whose session id is the call's `rkey` is invoked
+    Partition,
 }
 
 // Visitors
 
 struct MatchingPolicyVisitor;
 struct InvocationPolicyVisitor;
+struct RunModeVisitor;
 
 impl MatchingPolicy {
     #[allow(clippy::trivially_copy_pass_by_ref)]
@@ -120,6 +136,8 @@ impl<'de> serde::de::Visitor<'de> for MatchingPolicyVisitor {
         match value {
             "prefix" => Ok(MatchingPolicy::Prefix),
             "wildcard" => Ok(MatchingPolicy::Wildcard),
+            // Strict matching is the default, but peers may still name it explicitly
+            "" | "exact" => Ok(MatchingPolicy::Strict),
             x => Err(serde::de::Error::custom(format!(
                 "Invalid matching policy: {}",
                 x
@@ -139,6 +157,8 @@ impl serde::Serialize for InvocationPolicy {
             InvocationPolicy::Random => "random",
             InvocationPolicy::First => "first",
             InvocationPolicy::Last => "last",
+            InvocationPolicy::Sticky => "sticky",
+            InvocationPolicy::LeastBusy => "leastbusy",
         };
         serializer.serialize_str(ser_str)
     }
@@ -171,6 +191,8 @@ impl<'de> serde::de::Visitor<'de> for InvocationPolicyVisitor {
             "random" => Ok(InvocationPolicy::Random),
             "first" => Ok(InvocationPolicy::First),
             "last" => Ok(InvocationPolicy::Last),
+            "sticky" => Ok(InvocationPolicy::Sticky),
+            "leastbusy" => Ok(InvocationPolicy::LeastBusy),
             x => Err(serde::de::Error::custom(format!(
                 "Invalid invocation policy: {}",
                 x
@@ -178,3 +200,47 @@ impl<'de> serde::de::Visitor<'de> for InvocationPolicyVisitor {
         }
     }
 }
+
+// RunMode
+
+impl serde::Serialize for RunMode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let ser_str = match *self {
+            RunMode::All => "all",
+            RunMode::Partition => "partition",
+        };
+        serializer.serialize_str(ser_str)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for RunMode {
+    fn deserialize<D>(deserializer: D) -> Result<RunMode, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(RunModeVisitor)
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for RunModeVisitor {
+    type Value = RunMode;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("run mode for a call")
+    }
+
+    #[inline]
+    fn visit_str<E>(self, value: &str) -> Result<RunMode, E>
+    where
+        E: serde::de::Error,
+    {
+        match value {
+            "all" => Ok(RunMode::All),
+            "partition" => Ok(RunMode::Partition),
+            x => Err(serde::de::Error::custom(format!("Invalid run mode: {}", x))),
+        }
+    }
+}
@@ -1,28 +1,37 @@
 use serde::{Deserialize, Serialize};
 
-use super::{is_not, ClientRoles, InvocationPolicy, MatchingPolicy, RouterRoles, URI};
+use super::{
+    is_not, ClientRoles, Dict, InvocationPolicy, MatchingPolicy, RouterRoles, RunMode, URI,
+};
+use crate::ID;
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default, Clone)]
 pub struct HelloDetails {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     agent: Option<String>,
     roles: ClientRoles,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authid: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authrole: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default, Clone)]
 pub struct WelcomeDetails {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     agent: Option<String>,
     roles: RouterRoles,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default, Clone)]
 pub struct ErrorDetails {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     message: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default, Clone)]
 pub struct SubscribeOptions {
     #[serde(
         default,
@@ -30,15 +39,62 @@ pub struct SubscribeOptions {
         skip_serializing_if = "MatchingPolicy::is_strict"
     )]
     pub pattern_match: MatchingPolicy,
+
+    /// Have the router keep the last this many events of the topic if the session leaves
+    /// without unsubscribing, and send them once a session with the same authid subscribes
+    /// to the topic again.  Only honoured for strict subscriptions of sessions whose authid the
+    /// realm's authorizer vouches for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_last: Option<usize>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default, Clone)]
+/// Options sent along with a publication
 pub struct PublishOptions {
     #[serde(default, skip_serializing_if = "is_not")]
-    acknowledge: bool,
+    pub(crate) acknowledge: bool,
+
+    /// Only deliver the event to subscribers authenticated with one of these authids.  The
+    /// router matches these filters against identities the realm's authorizer vouched for, so
+    /// sessions with no such identity never pass an eligible list and are never excluded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eligible_authid: Option<Vec<String>>,
+
+    /// Never deliver the event to subscribers authenticated with one of these authids
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude_authid: Option<Vec<String>>,
+
+    /// Only deliver the event to subscribers holding one of these authroles
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eligible_authrole: Option<Vec<String>>,
+
+    /// Never deliver the event to subscribers holding one of these authroles
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude_authrole: Option<Vec<String>>,
+
+    /// Keep the event as the topic's last value, which the router sends to each new subscriber
+    #[serde(default, skip_serializing_if = "is_not")]
+    pub retain: bool,
+
+    /// Ask the router to tell in the PUBLISHED message how many subscribers got the event
+    #[serde(default, rename = "_report_recipients", skip_serializing_if = "is_not")]
+    pub(crate) report_recipients: bool,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+/// Details of a PUBLISHED message.  The WAMP spec defines none, so these are all
+/// implementation specific and only sent when the publisher asks for them.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default, Clone)]
+pub struct PublishedDetails {
+    /// Number of subscribers the event was delivered to
+    #[serde(
+        default,
+        rename = "_recipients",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub recipients: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default, Clone)]
 pub struct RegisterOptions {
     #[serde(
         default,
@@ -53,15 +109,56 @@ pub struct RegisterOptions {
         skip_serializing_if = "InvocationPolicy::is_single"
     )]
     pub invocation_policy: InvocationPolicy,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub args_schema: Option<Dict>,
+
+    /// Most invocations the router sends the callee at a time.  Calls beyond the limit go to
+    /// another registrant of a shared registration or wait for an invocation to finish.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<u32>,
+
+    /// Have the router tell the callee which session made each call, in the `caller` detail
+    /// of the invocation, whether or not the caller asked to be disclosed
+    #[serde(default, skip_serializing_if = "is_not")]
+    pub disclose_caller: bool,
 }
 
-#[derive(PartialEq, Debug, Default, Serialize, Deserialize)]
-pub struct CallOptions {}
+#[derive(PartialEq, Debug, Default, Serialize, Deserialize, Clone)]
+pub struct CallOptions {
+    /// Ask the callee for progressive results ahead of the final one
+    #[serde(default, skip_serializing_if = "is_not")]
+    pub receive_progress: bool,
+
+    /// Pick the registrants of a shared registration directly rather than through its
+    /// invocation policy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runmode: Option<RunMode>,
 
-#[derive(PartialEq, Debug, Default, Serialize, Deserialize)]
-pub struct YieldOptions {}
+    /// Session id of the registrant to invoke when `runmode` is `Partition`.  Unlike the
+    /// partition key of the WAMP advanced profile, which is a string the router maps onto a
+    /// registrant, this names the registrant's session directly, and a string key is refused
+    /// as a malformed CALL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rkey: Option<ID>,
+}
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+#[derive(PartialEq, Debug, Default, Serialize, Deserialize, Clone)]
+pub struct YieldOptions {
+    /// More results follow this one
+    #[serde(default, skip_serializing_if = "is_not")]
+    pub progress: bool,
+
+    /// Implementation specific options.  Those whose key starts with `_` are passed on to the
+    /// caller in the RESULT details.
+    #[serde(flatten)]
+    pub custom: Dict,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default, Clone)]
 pub struct EventDetails {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     publisher: Option<String>,
@@ -71,26 +168,59 @@ pub struct EventDetails {
 
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub topic: Option<URI>,
+
+    /// The event was published before the subscription and kept by the router
+    #[serde(default, skip_serializing_if = "is_not")]
+    pub retained: bool,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default, Clone)]
 pub struct InvocationDetails {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub procedure: Option<URI>,
+
+    /// The caller accepts progressive results
+    #[serde(default, skip_serializing_if = "is_not")]
+    pub receive_progress: bool,
+
+    /// Session id of the caller, sent when the registration asked for `disclose_caller`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caller: Option<ID>,
 }
 
-#[derive(PartialEq, Debug, Default, Serialize, Deserialize)]
-pub struct ResultDetails {}
+/// Details sent along with the result of a call
+#[derive(PartialEq, Debug, Default, Serialize, Deserialize, Clone)]
+pub struct ResultDetails {
+    /// More results follow this one
+    #[serde(default, skip_serializing_if = "is_not")]
+    pub progress: bool,
+
+    /// Session id of the callee that produced the result, sent when the router discloses
+    /// callees
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callee: Option<ID>,
+
+    /// Implementation specific details, such as those the callee passed on with its YIELD
+    #[serde(flatten)]
+    pub custom: Dict,
+}
 
 impl HelloDetails {
     pub fn new(roles: ClientRoles) -> HelloDetails {
-        HelloDetails { roles, agent: None }
+        HelloDetails {
+            roles,
+            agent: None,
+            authid: None,
+            authrole: None,
+        }
     }
 
     pub fn new_with_agent(roles: ClientRoles, agent: &str) -> HelloDetails {
         HelloDetails {
             roles,
             agent: Some(agent.to_string()),
+            authid: None,
+            authrole: None,
         }
     }
 }
@@ -106,6 +236,10 @@ impl WelcomeDetails {
             agent: Some(agent.to_string()),
         }
     }
+
+    pub fn roles(&self) -> &RouterRoles {
+        &self.roles
+    }
 }
 
 impl ErrorDetails {
@@ -124,18 +258,40 @@ impl SubscribeOptions {
     pub fn new() -> SubscribeOptions {
         SubscribeOptions {
             pattern_match: MatchingPolicy::Strict,
+            keep_last: None,
         }
     }
 }
 
 impl PublishOptions {
+    /// Create options that request a PUBLISHED acknowledgement if `acknowledge` is set
     pub fn new(acknowledge: bool) -> PublishOptions {
-        PublishOptions { acknowledge }
+        PublishOptions {
+            acknowledge,
+            ..Default::default()
+        }
     }
 
+    /// Whether the publisher asked for a PUBLISHED acknowledgement
     pub fn should_acknowledge(&self) -> bool {
         self.acknowledge
     }
+
+    /// Whether a subscriber with the given identity passes the authid and authrole filters.
+    /// The identity should be one the router trusts, not merely the one a client announced.
+    pub fn is_eligible(&self, authid: Option<&str>, authrole: Option<&str>) -> bool {
+        fn contains(list: &Option<Vec<String>>, value: Option<&str>) -> bool {
+            match (list, value) {
+                (Some(list), Some(value)) => list.iter().any(|item| item == value),
+                _ => false,
+            }
+        }
+
+        (self.eligible_authid.is_none() || contains(&self.eligible_authid, authid))
+            && !contains(&self.exclude_authid, authid)
+            && (self.eligible_authrole.is_none() || contains(&self.eligible_authrole, authrole))
+            && !contains(&self.exclude_authrole, authrole)
+    }
 }
 
 impl RegisterOptions {
@@ -143,19 +299,41 @@ impl RegisterOptions {
         RegisterOptions {
             pattern_match: MatchingPolicy::Strict,
             invocation_policy: InvocationPolicy::Single,
+            description: None,
+            args_schema: None,
+            concurrency: None,
+            disclose_caller: false,
         }
     }
 }
 
 impl CallOptions {
     pub fn new() -> CallOptions {
-        CallOptions {}
+        CallOptions {
+            receive_progress: false,
+            runmode: None,
+            rkey: None,
+        }
     }
 }
 
 impl YieldOptions {
     pub fn new() -> YieldOptions {
-        YieldOptions {}
+        YieldOptions {
+            progress: false,
+            custom: Dict::new(),
+        }
+    }
+}
+
+impl PublishedDetails {
+    pub fn new() -> PublishedDetails {
+        PublishedDetails { recipients: None }
+    }
+
+    /// Whether there is nothing to send, so the PUBLISHED message can leave the details out
+    pub fn is_empty(&self) -> bool {
+        self.recipients.is_none()
     }
 }
 
@@ -165,6 +343,7 @@ impl EventDetails {
             publisher: None,
             trustlevel: None,
             topic: None,
+            retained: false,
         }
     }
 
@@ -173,18 +352,28 @@ impl EventDetails {
             publisher: None,
             trustlevel: None,
             topic: Some(topic),
+            retained: false,
         }
     }
 }
 
 impl InvocationDetails {
     pub fn new() -> InvocationDetails {
-        InvocationDetails { procedure: None }
+        InvocationDetails {
+            procedure: None,
+            receive_progress: false,
+            caller: None,
+        }
     }
 }
 
 impl ResultDetails {
+    /// Create the details of a final result, without any disclosed callee
     pub fn new() -> ResultDetails {
-        ResultDetails {}
+        ResultDetails {
+            progress: false,
+            callee: None,
+            custom: Dict::new(),
+        }
     }
 }
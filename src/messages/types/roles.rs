@@ -4,7 +4,7 @@ use serde::{Deserialize, Serialize};
 
 use super::is_not;
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct ClientRoles {
     pub publisher: PublisherRole,
     pub subscriber: SubscriberRole,
@@ -12,7 +12,7 @@ pub struct ClientRoles {
     pub callee: CalleeRole,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct RouterRoles {
     pub dealer: DealerRole,
     pub broker: BrokerRole,
@@ -20,59 +20,68 @@ pub struct RouterRoles {
 
 // Roles
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct PublisherRole {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     features: Option<HashMap<String, bool>>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct CallerRole {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     features: Option<HashMap<String, bool>>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct CalleeRole {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     features: Option<HashMap<String, bool>>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct SubscriberRole {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     features: Option<SubscriberFeatures>,
 }
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct SubscriberFeatures {
     #[serde(skip_serializing_if = "is_not", default)]
     pattern_based_subscription: bool,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct DealerRole {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     features: Option<DealerFeatures>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct BrokerRole {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     features: Option<BrokerFeatures>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct DealerFeatures {
     #[serde(skip_serializing_if = "is_not", default)]
     pattern_based_registration: bool,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct BrokerFeatures {
     #[serde(skip_serializing_if = "is_not", default)]
     pattern_based_subscription: bool,
 }
 
+/// Advanced features a router announced in its WELCOME message
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct RouterFeatures {
+    /// The broker accepts prefix and wildcard subscriptions
+    pub pattern_based_subscription: bool,
+    /// The dealer accepts prefix and wildcard registrations
+    pub pattern_based_registration: bool,
+}
+
 //   Implementations
 
 impl RouterRoles {
@@ -99,6 +108,22 @@ impl RouterRoles {
             dealer: DealerRole { features: None },
         }
     }
+
+    #[inline]
+    pub fn features(&self) -> RouterFeatures {
+        RouterFeatures {
+            pattern_based_subscription: self
+                .broker
+                .features
+                .as_ref()
+                .is_some_and(|features| features.pattern_based_subscription),
+            pattern_based_registration: self
+                .dealer
+                .features
+                .as_ref()
+                .is_some_and(|features| features.pattern_based_registration),
+        }
+    }
 }
 
 impl ClientRoles {
@@ -1,5 +1,6 @@
-use std::{collections::HashMap, fmt};
+use std::{collections::HashMap, convert::TryFrom, fmt};
 
+use data_encoding::BASE64;
 use itertools::Itertools;
 
 use crate::CallResult;
@@ -28,10 +29,109 @@ impl URI {
             uri: uri.to_string(),
         }
     }
+
+    /// Whether the URI is well formed: one or more non-empty components separated by `.`,
+    /// none of which contain whitespace or `#`
+    pub fn is_valid(&self) -> bool {
+        self.uri.split('.').all(|component| {
+            !component.is_empty() && !component.chars().any(|c| c.is_whitespace() || c == '#')
+        })
+    }
+
+    /// The problems that keep the URI from being well formed, in the order they appear.
+    ///
+    /// Wildcard patterns use empty components to match any component, so when `wildcard` is set
+    /// empty components, including those left by a leading or trailing dot, are not reported.
+    pub fn issues(&self, wildcard: bool) -> Vec<URIIssue> {
+        if self.uri.is_empty() {
+            return vec![URIIssue::Empty];
+        }
+        let components: Vec<&str> = self.uri.split('.').collect();
+        let last = components.len() - 1;
+        let mut issues = Vec::new();
+        for (index, component) in components.iter().enumerate() {
+            if component.is_empty() {
+                if wildcard {
+                    continue;
+                } else if index == 0 {
+                    issues.push(URIIssue::LeadingDot);
+                } else if index == last {
+                    issues.push(URIIssue::TrailingDot);
+                } else {
+                    issues.push(URIIssue::EmptyComponent(index));
+                }
+            } else if let Some(c) = component.chars().find(|&c| c.is_whitespace() || c == '#') {
+                issues.push(URIIssue::InvalidCharacter(index, c));
+            }
+        }
+        issues
+    }
+
+    /// A copy of the URI with its empty components removed, which drops leading and trailing
+    /// dots and collapses runs of dots, so `".com..foo."` becomes `"com.foo"`.
+    ///
+    /// This is for concrete URIs only: the empty components of a wildcard pattern are part of
+    /// the pattern.  Case is left alone, since WAMP URIs are case sensitive.
+    pub fn normalized(&self) -> URI {
+        URI {
+            uri: self
+                .uri
+                .split('.')
+                .filter(|component| !component.is_empty())
+                .join("."),
+        }
+    }
+}
+
+impl fmt::Display for URI {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.uri)
+    }
+}
+
+/// A problem found by `URI::issues`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum URIIssue {
+    /// The URI is the empty string
+    Empty,
+    /// The URI starts with a dot
+    LeadingDot,
+    /// The URI ends with a dot
+    TrailingDot,
+    /// The component at this index, counted from zero, is empty
+    EmptyComponent(usize),
+    /// The component at this index contains whitespace or `#`
+    InvalidCharacter(usize, char),
+}
+
+impl fmt::Display for URIIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            URIIssue::Empty => write!(f, "the URI is empty"),
+            URIIssue::LeadingDot => write!(f, "the URI starts with a dot"),
+            URIIssue::TrailingDot => write!(f, "the URI ends with a dot"),
+            URIIssue::EmptyComponent(index) => write!(f, "component {} is empty", index),
+            URIIssue::InvalidCharacter(index, c) => {
+                write!(f, "component {} contains {:?}", index, c)
+            }
+        }
+    }
 }
 
 /// Represents WAMP Value
-#[derive(Debug, PartialEq, Clone)]
+///
+/// Equality compares integers by their numeric value, so `Integer(5) == UnsignedInteger(5)`.
+/// Decoders pick either variant for non-negative numbers depending on the serialization, so
+/// the distinction carries no meaning.  Floats are never equal to integers.
+///
+/// Byte strings are sent as binary over msgpack.  JSON has no binary type, so there they
+/// follow the WAMP convention: a string holding a `\0` followed by the base64 encoded bytes.
+/// Any such string received over JSON is decoded as bytes.
+///
+/// JSON can't represent the non-finite floats either, so over JSON `NaN`, infinity and negative
+/// infinity are sent as the strings `"NaN"`, `"Infinity"` and `"-Infinity"`, the way
+/// JavaScript spells them.  They arrive as string values.  Msgpack carries them as floats.
+#[derive(Debug, Clone)]
 pub enum Value {
     // The ID and URI types cannot be distinguished from string and integer types respectively.
     // So, we just ignore them here
@@ -49,6 +149,8 @@ pub enum Value {
     List(List),
     /// Boolean value
     Boolean(bool),
+    /// Byte string value
+    Bytes(Vec<u8>),
 }
 
 struct URIVisitor;
@@ -175,7 +277,203 @@ impl ArgDict for Dict {
     }
 }
 
+/// Reply to a call with positional results
+pub fn reply(args: List) -> CallResult<(Option<List>, Option<Dict>)> {
+    Ok((Some(args), None))
+}
+
+/// Reply to a call with keyword results
+pub fn reply_kw(kwargs: Dict) -> CallResult<(Option<List>, Option<Dict>)> {
+    Ok((None, Some(kwargs)))
+}
+
+/// Fail a call with the given reason and optional error payload
+pub fn reply_error(
+    reason: Reason,
+    args: Option<List>,
+    kwargs: Option<Dict>,
+) -> CallResult<(Option<List>, Option<Dict>)> {
+    Err(CallError::new(reason, args, kwargs))
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Dict(a), Value::Dict(b)) => a == b,
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::UnsignedInteger(a), Value::UnsignedInteger(b)) => a == b,
+            (Value::Integer(i), Value::UnsignedInteger(u))
+            | (Value::UnsignedInteger(u), Value::Integer(i)) => {
+                *i >= 0 && *i as u64 == *u
+            }
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::List(a), Value::List(b)) => a == b,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Bytes(a), Value::Bytes(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+macro_rules! value_from {
+    ($($source:ty => $variant:ident),* $(,)?) => {
+        $(
+            impl From<$source> for Value {
+                fn from(value: $source) -> Value {
+                    Value::$variant(value.into())
+                }
+            }
+        )*
+    };
+}
+
+value_from! {
+    i64 => Integer,
+    i32 => Integer,
+    u64 => UnsignedInteger,
+    u32 => UnsignedInteger,
+    f64 => Float,
+    bool => Boolean,
+    String => String,
+    &str => String,
+    List => List,
+    Dict => Dict,
+    Vec<u8> => Bytes,
+    &[u8] => Bytes,
+}
+
+/// Converts JSON the way it would be decoded off the wire: non-negative integers become
+/// `UnsignedInteger`, negative ones `Integer`, and numbers with a fraction or exponent `Float`.
+/// A string holding `\0` and base64 becomes `Bytes`.  `Value` has no null, so JSON holding a
+/// `null` anywhere is refused, as it is when received.
+impl TryFrom<serde_json::Value> for Value {
+    type Error = serde_json::Error;
+
+    fn try_from(json: serde_json::Value) -> Result<Value, serde_json::Error> {
+        serde::Deserialize::deserialize(json)
+    }
+}
+
+/// Converts the value into the JSON it is sent as, so bytes become a `\0` and base64 string
+/// and non-finite floats the strings `"NaN"`, `"Infinity"` and `"-Infinity"`.
+impl From<Value> for serde_json::Value {
+    fn from(value: Value) -> serde_json::Value {
+        serde_json::to_value(value).expect("values always have a JSON form")
+    }
+}
+
+/// Builds a [List], converting each element into a [Value]
+///
+/// ```
+/// use wampire::{wamp_list, Value};
+///
+/// assert_eq!(
+///     wamp_list![1, "a", true],
+///     vec![
+///         Value::Integer(1),
+///         Value::String("a".to_string()),
+///         Value::Boolean(true),
+///     ]
+/// );
+/// ```
+#[macro_export]
+macro_rules! wamp_list {
+    ($($value:expr),* $(,)?) => {{
+        let list: $crate::List = vec![$($crate::Value::from($value)),*];
+        list
+    }};
+}
+
+/// Builds a [Dict], converting each value into a [Value]
+///
+/// ```
+/// use wampire::{wamp_dict, wamp_list, Value};
+///
+/// let dict = wamp_dict! { "k" => 1, "name" => "x", "tags" => wamp_list!["a"] };
+/// assert_eq!(dict["k"], Value::Integer(1));
+/// assert_eq!(dict["name"], Value::String("x".to_string()));
+/// assert_eq!(dict["tags"], Value::List(vec![Value::String("a".to_string())]));
+/// ```
+#[macro_export]
+macro_rules! wamp_dict {
+    ($($key:expr => $value:expr),* $(,)?) => {{
+        #[allow(unused_mut)]
+        let mut dict = $crate::Dict::new();
+        $(dict.insert(::std::string::String::from($key), $crate::Value::from($value));)*
+        dict
+    }};
+}
+
 impl Value {
+    /// The string, if this is a string value
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The integer, if this is an integer value that fits in an `i64`
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Value::Integer(i) => Some(i),
+            Value::UnsignedInteger(u) if u <= i64::MAX as u64 => Some(u as i64),
+            _ => None,
+        }
+    }
+
+    /// The integer, if this is a non-negative integer value
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::UnsignedInteger(u) => Some(u),
+            Value::Integer(i) if i >= 0 => Some(i as u64),
+            _ => None,
+        }
+    }
+
+    /// The number as a float, if this is a numeric value
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Float(f) => Some(f),
+            Value::Integer(i) => Some(i as f64),
+            Value::UnsignedInteger(u) => Some(u as f64),
+            _ => None,
+        }
+    }
+
+    /// The boolean, if this is a boolean value
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Boolean(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// The bytes, if this is a byte string value
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match *self {
+            Value::Bytes(ref b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// The elements, if this is a list value
+    pub fn as_array(&self) -> Option<&List> {
+        match *self {
+            Value::List(ref l) => Some(l),
+            _ => None,
+        }
+    }
+
+    /// The entries, if this is a dictionary value
+    pub fn as_object(&self) -> Option<&Dict> {
+        match *self {
+            Value::Dict(ref d) => Some(d),
+            _ => None,
+        }
+    }
+
     /// Represent Value as string
     pub fn summarize(&self) -> String {
         match *self {
@@ -214,6 +512,7 @@ impl Value {
                 result
             }
             Value::Boolean(b) => b.to_string(),
+            Value::Bytes(ref b) => format!("<{} bytes>", b.len()),
         }
     }
 }
@@ -231,9 +530,30 @@ impl<'de> serde::de::Visitor<'de> for ValueVisitor {
     where
         E: serde::de::Error,
     {
+        if let Some(encoded) = value.strip_prefix('\0') {
+            if let Ok(bytes) = BASE64.decode(encoded.as_bytes()) {
+                return Ok(Value::Bytes(bytes));
+            }
+        }
         Ok(Value::String(value.to_string()))
     }
 
+    #[inline]
+    fn visit_bytes<E>(self, value: &[u8]) -> Result<Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(Value::Bytes(value.to_vec()))
+    }
+
+    #[inline]
+    fn visit_byte_buf<E>(self, value: Vec<u8>) -> Result<Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(Value::Bytes(value))
+    }
+
     #[inline]
     fn visit_i64<E>(self, value: i64) -> Result<Value, E>
     where
@@ -312,9 +632,26 @@ impl serde::Serialize for Value {
             Value::String(ref s) => serializer.serialize_str(s),
             Value::Integer(i) => serializer.serialize_i64(i),
             Value::UnsignedInteger(u) => serializer.serialize_u64(u),
+            Value::Float(f) if !f.is_finite() && serializer.is_human_readable() => {
+                let name = if f.is_nan() {
+                    "NaN"
+                } else if f > 0.0 {
+                    "Infinity"
+                } else {
+                    "-Infinity"
+                };
+                serializer.serialize_str(name)
+            }
             Value::Float(f) => serializer.serialize_f64(f),
             Value::List(ref list) => list.serialize(serializer),
             Value::Boolean(b) => serializer.serialize_bool(b),
+            Value::Bytes(ref b) => {
+                if serializer.is_human_readable() {
+                    serializer.serialize_str(&format!("\0{}", BASE64.encode(b)))
+                } else {
+                    serializer.serialize_bytes(b)
+                }
+            }
         }
     }
 }
@@ -0,0 +1,56 @@
+//! Contains the `Authorizer` trait, which lets a realm decide which sessions may use which URIs
+use crate::{ID, URI};
+
+/// Decides whether a session may perform an operation on a URI within a realm.
+///
+/// Each realm holds one authorizer, which is consulted before a subscribe, publish, register
+/// or call is processed.  When a method returns `false` the request is rejected with
+/// `wamp.error.not_authorized`.
+pub trait Authorizer: Send {
+    /// Whether the session may subscribe to the given topic
+    fn can_subscribe(&self, session_id: ID, topic: &URI) -> bool;
+    /// Whether the session may publish to the given topic
+    fn can_publish(&self, session_id: ID, topic: &URI) -> bool;
+    /// Whether the session may register the given procedure
+    fn can_register(&self, session_id: ID, procedure: &URI) -> bool;
+    /// Whether the session may call the given procedure
+    fn can_call(&self, session_id: ID, procedure: &URI) -> bool;
+    /// Whether the session may end another session through `wamp.session.kill`.  This is
+    /// checked on top of `can_call`, and refused unless an authorizer allows it.
+    fn can_kill(&self, _session_id: ID, _target_id: ID) -> bool {
+        false
+    }
+    /// Whether the session is who it says it is in HELLO.  There is no authentication, so the
+    /// authid and authrole a client announces are only its word, which the router doesn't act
+    /// on unless an authorizer vouches for them.
+    fn vouches_for(&self, _session_id: ID, _authid: Option<&str>, _authrole: Option<&str>) -> bool {
+        false
+    }
+}
+
+/// An authorizer that allows every operation but killing sessions, and vouches for no identity
+/// (the default)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AllowAll;
+
+impl Authorizer for AllowAll {
+    #[inline]
+    fn can_subscribe(&self, _session_id: ID, _topic: &URI) -> bool {
+        true
+    }
+
+    #[inline]
+    fn can_publish(&self, _session_id: ID, _topic: &URI) -> bool {
+        true
+    }
+
+    #[inline]
+    fn can_register(&self, _session_id: ID, _procedure: &URI) -> bool {
+        true
+    }
+
+    #[inline]
+    fn can_call(&self, _session_id: ID, _procedure: &URI) -> bool {
+        true
+    }
+}
@@ -0,0 +1,56 @@
+//! Contains `RouterError`, which describes the errors the router runs into while serving its
+//! sessions, and the sink that hands them to the callback given to `Router::on_error`
+use std::sync::RwLock;
+
+use crate::ID;
+
+/// Alias for the callback that receives the router's errors
+pub type ErrorHandler = Box<dyn Fn(RouterError) + Send + Sync>;
+
+/// The kinds of errors reported to `Router::on_error`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouterErrorKind {
+    /// The session broke the protocol, e.g. by sending a message out of sequence.  The
+    /// router aborts the session.
+    ProtocolViolation,
+    /// A message from the session couldn't be decoded, or one for it couldn't be encoded
+    Serialization,
+    /// A message couldn't be delivered to the session, such as an event or an invocation
+    SendFailed,
+    /// The session's connection failed
+    Connection,
+}
+
+/// An error the router ran into while serving a session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouterError {
+    /// The session the error concerns
+    pub session_id: ID,
+    /// What went wrong
+    pub kind: RouterErrorKind,
+    /// A description of the error and what the router was doing, as it is logged
+    pub context: String,
+}
+
+/// Holds the callback given to `Router::on_error`, if any
+#[derive(Default)]
+pub(super) struct ErrorSink {
+    handler: RwLock<Option<ErrorHandler>>,
+}
+
+impl ErrorSink {
+    pub fn set(&self, handler: ErrorHandler) {
+        *self.handler.write().unwrap() = Some(handler);
+    }
+
+    /// Hands the error to the callback
+    pub fn report(&self, session_id: ID, kind: RouterErrorKind, context: String) {
+        if let Some(ref handler) = *self.handler.read().unwrap() {
+            handler(RouterError {
+                session_id,
+                kind,
+                context,
+            });
+        }
+    }
+}
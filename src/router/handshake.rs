@@ -1,9 +1,14 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
 
 use log::{debug, info, warn};
 use parity_ws::{
     CloseCode, Error as WSError, ErrorKind as WSErrorKind, Request, Response, Result as WSResult,
 };
+use rand::{thread_rng, Rng};
 
 use crate::{
     messages::{ErrorDetails, HelloDetails, Message, Reason, RouterRoles, WelcomeDetails, URI},
@@ -11,21 +16,135 @@ use crate::{
     Error, ErrorKind, WampResult,
 };
 
-use super::{ConnectionHandler, ConnectionState, WAMP_JSON, WAMP_MSGPACK};
+use super::{ConnectionHandler, ConnectionState};
+
+/// Name of the cookie that ties a browser to the identity of its earlier sessions
+const SESSION_COOKIE: &str = "wampire_session";
+
+/// How long a session cookie resumes its identity after it was last used
+const SESSION_COOKIE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Number of session cookies the router remembers.  Beyond it, the least recently used is
+/// forgotten.
+const MAX_SESSION_COOKIES: usize = 10_000;
+
+/// The session cookies whose sessions joined a realm with an authid, which a later session
+/// presenting the cookie resumes
+#[derive(Default)]
+pub(super) struct SessionCookies {
+    /// The authid and the time of last use of each cookie
+    pub(super) entries: HashMap<String, (String, Instant)>,
+}
+
+impl SessionCookies {
+    /// The authid the cookie resumes, unless it is unknown or has expired
+    fn authid(&mut self, cookie: &str) -> Option<String> {
+        match self.entries.get(cookie) {
+            Some((authid, last_used)) if last_used.elapsed() < SESSION_COOKIE_TTL => {
+                Some(authid.clone())
+            }
+            Some(_) => {
+                self.entries.remove(cookie);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Ties the cookie to the authid of the session that joined with it.  A session without
+    /// an authid leaves nothing to resume, so the cookie is forgotten.
+    fn remember(&mut self, cookie: &str, authid: Option<String>) {
+        let authid = match authid {
+            Some(authid) => authid,
+            None => {
+                self.entries.remove(cookie);
+                return;
+            }
+        };
+        self.entries
+            .retain(|_, (_, last_used)| last_used.elapsed() < SESSION_COOKIE_TTL);
+        if self.entries.len() >= MAX_SESSION_COOKIES && !self.entries.contains_key(cookie) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(oldest, _)| oldest.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries
+            .insert(cookie.to_string(), (authid, Instant::now()));
+    }
+}
 
 impl ConnectionHandler {
-    pub fn handle_hello(&mut self, realm: URI, _details: HelloDetails) -> WampResult<()> {
+    pub fn handle_hello(&mut self, realm: URI, details: HelloDetails) -> WampResult<()> {
         debug!("Responding to hello message (realm: {:?})", realm);
+        if self.realm.is_some() {
+            return Err(Error::new(ErrorKind::UnexpectedMessage(
+                "Received a HELLO after WELCOME",
+            )));
+        }
+        if let Some(ref path_realm) = self.path_realm {
+            if *path_realm != realm.uri {
+                warn!(
+                    "Hello for realm {} on a connection bound to {}",
+                    realm.uri, path_realm
+                );
+                return Err(Error::new(ErrorKind::HandshakeError(Reason::NoSuchRealm)));
+            }
+        }
+        if realm.uri.is_empty() {
+            warn!("Hello without a realm");
+            return Err(Error::new(ErrorKind::HandshakeError(Reason::NoSuchRealm)));
+        }
+        if !realm.is_valid() {
+            warn!("Hello for malformed realm {:?}", realm.uri);
+            return Err(Error::new(ErrorKind::HandshakeError(
+                Reason::ProtocolViolation,
+            )));
+        }
+        // There is no authentication yet, so the identity the client announces is taken as is.
+        // A client that announces none keeps the one its session cookie was last used with.
+        let authid = match (details.authid, &self.cookie) {
+            (Some(authid), _) => Some(authid),
+            (None, Some(cookie)) => self.router.session_cookies.lock().unwrap().authid(cookie),
+            (None, None) => None,
+        };
         let id = {
             let mut info = self.info.lock().unwrap();
             info.state = ConnectionState::Connected;
+            info.authid = authid.clone();
+            info.authrole = details.authrole.clone();
             info.id
         };
 
         self.set_realm(realm.uri)?;
+        let vouched = match self.realm {
+            Some(ref realm) => realm.lock().unwrap().authorizer.vouches_for(
+                id,
+                authid.as_deref(),
+                details.authrole.as_deref(),
+            ),
+            None => false,
+        };
+        self.info.lock().unwrap().vouched = vouched;
+        // Only a session that has joined a realm is worth remembering
+        if let Some(ref cookie) = self.cookie {
+            self.router
+                .session_cookies
+                .lock()
+                .unwrap()
+                .remember(cookie, authid);
+        }
+        let agent = self.router.agent.lock().unwrap().clone();
         send_message(
             &self.info,
-            &Message::Welcome(id, WelcomeDetails::new(RouterRoles::new())),
+            &Message::Welcome(
+                id,
+                WelcomeDetails::new_with_agent(RouterRoles::new(), &agent),
+            ),
         )
     }
 
@@ -33,10 +152,19 @@ impl ConnectionHandler {
         let state = self.info.lock().unwrap().state.clone();
         match state {
             ConnectionState::Initializing => {
-                // TODO check specification for how this ought to work.
-                Err(Error::new(ErrorKind::InvalidState(
-                    "Received a goodbye message before handshake complete",
-                )))
+                // A session has to be established before it can be closed
+                warn!("Received goodbye message before handshake complete");
+                send_message(
+                    &self.info,
+                    &Message::Abort(ErrorDetails::new(), Reason::ProtocolViolation),
+                )
+                .ok();
+                let mut info = self.info.lock().unwrap();
+                info.state = ConnectionState::Disconnected;
+                match info.sender.close(CloseCode::Protocol) {
+                    Err(e) => Err(Error::new(ErrorKind::WSError(e))),
+                    _ => Ok(()),
+                }
             }
             ConnectionState::Connected => {
                 info!("Received goodbye message with reason: {:?}", reason);
@@ -89,23 +217,82 @@ impl ConnectionHandler {
         Ok(())
     }
 
+    /// Binds the connection to the realm named by the request path, if realms are selected
+    /// by path.  Returns false if the path names no realm.
+    pub fn process_path(&mut self, request: &Request) -> bool {
+        if !self.router.realm_by_path.load(Ordering::Relaxed) {
+            return true;
+        }
+        let resource = request.resource();
+        let path = resource.split('?').next().unwrap_or_default();
+        let realm = path.trim_start_matches('/');
+        debug!("Binding connection to realm {}", realm);
+        if !self.router.realms.lock().unwrap().contains_key(realm) {
+            return false;
+        }
+        self.path_realm = Some(realm.to_string());
+        true
+    }
+
+    /// Picks up the session cookie of the request if it still resumes an identity, or hands
+    /// out a new one.  Either way the response sets the cookie, so the browser keeps it.  A
+    /// new cookie is only remembered once its session joins a realm.
+    pub fn process_cookie(&mut self, request: &Request, response: &mut Response) {
+        let sent = request
+            .header("Cookie")
+            .and_then(|header| session_cookie(header))
+            .filter(|cookie| {
+                self.router
+                    .session_cookies
+                    .lock()
+                    .unwrap()
+                    .authid(cookie)
+                    .is_some()
+            });
+        let cookie = match sent {
+            Some(cookie) => {
+                debug!("Resuming session cookie");
+                cookie
+            }
+            None => format!("{:032x}", thread_rng().gen::<u128>()),
+        };
+        response.headers_mut().push((
+            "Set-Cookie".to_string(),
+            format!("{}={}; Path=/; HttpOnly", SESSION_COOKIE, cookie).into_bytes(),
+        ));
+        self.cookie = Some(cookie);
+    }
+
     pub fn process_protocol(&mut self, request: &Request, response: &mut Response) -> WSResult<()> {
         debug!("Checking protocol");
         let protocols = request.protocols()?;
-        for protocol in protocols {
-            if protocol == WAMP_JSON || protocol == WAMP_MSGPACK {
-                response.set_protocol(protocol);
-                let mut info = self.info.lock().unwrap();
-                info.protocol = protocol.to_string();
-                return Ok(());
+        let serializers = self.router.serializers.lock().unwrap().clone();
+        match serializers.negotiate(protocols) {
+            Some(serializer) => {
+                response.set_protocol(serializer.subprotocol_name());
+                self.info.lock().unwrap().serializer = Some(serializer);
+                Ok(())
             }
+            None => Err(WSError::new(
+                WSErrorKind::Protocol,
+                format!(
+                    "None of {} were selected as Websocket sub-protocols",
+                    serializers.subprotocols().join(", ")
+                ),
+            )),
         }
-        Err(WSError::new(
-            WSErrorKind::Protocol,
-            format!(
-                "Neither {} nor {} were selected as Websocket sub-protocols",
-                WAMP_JSON, WAMP_MSGPACK
-            ),
-        ))
     }
 }
+
+/// Finds the session cookie in the value of a `Cookie` header
+fn session_cookie(header: &[u8]) -> Option<String> {
+    let header = std::str::from_utf8(header).ok()?;
+    header.split(';').find_map(|pair| {
+        let (name, value) = pair.trim().split_once('=')?;
+        if name == SESSION_COOKIE {
+            Some(value.to_string())
+        } else {
+            None
+        }
+    })
+}
@@ -0,0 +1,56 @@
+//! Contains the `IdGenerator` trait, which supplies the ids the router assigns to sessions,
+//! subscriptions, registrations, publications and invocations
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rand::{thread_rng, Rng};
+
+use crate::ID;
+
+/// Random ids are drawn from below this limit
+const RANDOM_ID_LIMIT: ID = (1 << 56) - 1;
+
+/// Supplies ids for a router.
+///
+/// The ids handed out should be unique for as long as they are in use.
+pub trait IdGenerator: Send + Sync {
+    /// The next id to assign
+    fn next_id(&self) -> ID;
+}
+
+/// Draws ids at random (the default)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RandomIdGenerator;
+
+impl IdGenerator for RandomIdGenerator {
+    fn next_id(&self) -> ID {
+        thread_rng().gen_range(0..RANDOM_ID_LIMIT)
+    }
+}
+
+/// Counts up from a starting id, so that a router behaves the same way on each run.  Meant for
+/// tests.
+#[derive(Debug)]
+pub struct SequentialIdGenerator {
+    next: AtomicU64,
+}
+
+impl SequentialIdGenerator {
+    /// Creates a generator whose first id is `first`
+    pub fn new(first: ID) -> SequentialIdGenerator {
+        SequentialIdGenerator {
+            next: AtomicU64::new(first),
+        }
+    }
+}
+
+impl Default for SequentialIdGenerator {
+    fn default() -> SequentialIdGenerator {
+        SequentialIdGenerator::new(1)
+    }
+}
+
+impl IdGenerator for SequentialIdGenerator {
+    fn next_id(&self) -> ID {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,39 @@
+//! Contains the `MessageInterceptor` trait, which lets a realm inspect every message its
+//! sessions send before the router acts on it
+use crate::{
+    messages::{Message, Reason},
+    ID,
+};
+
+/// What the router does with a message once the interceptor has seen it
+#[derive(Debug, Clone, PartialEq)]
+pub enum InterceptAction {
+    /// Process the message as usual
+    Allow,
+    /// Ignore the message without telling the session
+    Drop,
+    /// Answer the request with an ERROR carrying the given reason.  Messages that aren't
+    /// requests, such as YIELD, are dropped instead.
+    Reject(Reason),
+}
+
+/// Inspects the messages sent by the sessions of a realm.
+///
+/// Each realm holds one interceptor, which sees every message a session sends once it has
+/// joined the realm, ahead of authorization and routing.  This is the place for audit logging,
+/// rate limiting and other policies that apply across message types.
+pub trait MessageInterceptor: Send {
+    /// Decides what happens to `message`, sent by the session `session_id`
+    fn on_inbound(&self, session_id: ID, message: &Message) -> InterceptAction;
+}
+
+/// An interceptor that allows every message (the default)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PassThrough;
+
+impl MessageInterceptor for PassThrough {
+    #[inline]
+    fn on_inbound(&self, _session_id: ID, _message: &Message) -> InterceptAction {
+        InterceptAction::Allow
+    }
+}
@@ -1,57 +1,115 @@
 use std::{
     collections::HashMap,
-    io::Cursor,
-    sync::{Arc, Mutex},
+    sync::{atomic::Ordering, mpsc::Receiver, Arc, Mutex},
 };
 
-use log::{debug, error, info, trace};
-use rmp_serde::{Deserializer as RMPDeserializer, Serializer};
-use serde::{Deserialize, Serialize};
+use log::{debug, error, info, trace, warn};
 
 use parity_ws::{
-    CloseCode, Error as WSError, ErrorKind as WSErrorKind, Handler, Message as WSMessage, Request,
-    Response, Result as WSResult, Sender,
+    util::Token, CloseCode, Error as WSError, ErrorKind as WSErrorKind, Frame, Handler, Handshake,
+    Message as WSMessage, OpCode, Request, Response, Result as WSResult,
 };
 
 use crate::{
     messages::{ErrorDetails, ErrorType, Message, Reason},
-    Dict, Error, ErrorKind, List, WampResult, ID,
+    serializer::Payload,
+    transport::loopback::Event,
+    CallError, Dict, Error, ErrorKind, List, WampResult, ID,
 };
 
-use super::{ConnectionHandler, ConnectionInfo, ConnectionState, WAMP_JSON};
+use super::{
+    connection_id, ConnectionHandler, ConnectionInfo, ConnectionSender, ConnectionState,
+    InterceptAction, RouterErrorKind, MAX_MISSED_PINGS, PING_TIMEOUT,
+};
 
 pub fn send_message(info: &Arc<Mutex<ConnectionInfo>>, message: &Message) -> WampResult<()> {
-    let info = info.lock().unwrap();
+    // A poisoned connection is treated as gone; its handler is past trusting
+    let info = info
+        .lock()
+        .map_err(|_| Error::new(ErrorKind::ConnectionLost))?;
 
-    debug!("Sending message {:?} via {}", message, info.protocol);
-    let send_result = if info.protocol == WAMP_JSON {
-        send_message_json(&info.sender, message)
-    } else {
-        send_message_msgpack(&info.sender, message)
+    debug!("Sending message {:?} via {}", message, info.protocol());
+    let outbox = match info.sender {
+        ConnectionSender::WebSocket(ref outbox) => outbox,
+        // Handed over as is, so nothing is queued for the event loop
+        ConnectionSender::Loopback(ref sender) => return sender.send(message.clone()),
+        ConnectionSender::Local => {
+            return Err(Error::new(ErrorKind::InvalidState(
+                "Tried to send a message to an in-process connection",
+            )))
+        }
     };
-    match send_result {
-        Ok(()) => Ok(()),
-        Err(e) => Err(Error::new(ErrorKind::WSError(e))),
-    }
+    let serializer = info.serializer.as_ref().ok_or_else(|| {
+        Error::new(ErrorKind::InvalidState(
+            "Tried to send a message before a sub-protocol was negotiated",
+        ))
+    })?;
+    // Encoded here rather than by the writer, so that a failure reaches the sender
+    outbox.send(serializer.encode(message)?)
 }
 
-fn send_message_json(sender: &Sender, message: &Message) -> WSResult<()> {
-    // Send the message
-    sender.send(WSMessage::Text(serde_json::to_string(message).unwrap()))
+/// The websocket close code that goes with ending a session for `reason`, so that the peer
+/// and any intermediaries can tell an orderly close from a failure
+fn close_code(reason: &Reason) -> CloseCode {
+    match *reason {
+        Reason::GoodbyeAndOut | Reason::NormalClose => CloseCode::Normal,
+        Reason::SystemShutdown | Reason::CloseRealm => CloseCode::Away,
+        Reason::NotAuthorized
+        | Reason::AuthorizationFailed
+        | Reason::NoSuchRealm
+        | Reason::NoSuchRole => CloseCode::Policy,
+        _ => CloseCode::Protocol,
+    }
 }
 
-fn send_message_msgpack(sender: &Sender, message: &Message) -> WSResult<()> {
-    // Send the message
-    let mut buf: Vec<u8> = Vec::new();
-    message
-        .serialize(&mut Serializer::new(&mut buf).with_struct_map())
-        .unwrap();
-    sender.send(WSMessage::Binary(buf))
+/// The type and id an ERROR answering `message` would carry, if it's a request
+fn request_of(message: &Message) -> Option<(ErrorType, ID)> {
+    match *message {
+        Message::Subscribe(request_id, ..) => Some((ErrorType::Subscribe, request_id)),
+        Message::Unsubscribe(request_id, _) => Some((ErrorType::Unsubscribe, request_id)),
+        Message::Publish(request_id, ..) => Some((ErrorType::Publish, request_id)),
+        Message::Register(request_id, ..) => Some((ErrorType::Register, request_id)),
+        Message::Unregister(request_id, _) => Some((ErrorType::Unregister, request_id)),
+        Message::Call(request_id, ..) => Some((ErrorType::Call, request_id)),
+        _ => None,
+    }
 }
 
 impl ConnectionHandler {
     fn handle_message(&mut self, message: Message) -> WampResult<()> {
         debug!("Received message {:?}", message);
+        if self.realm.is_none() && !matches!(message, Message::Hello(..) | Message::Goodbye(..)) {
+            // Only the handshake makes sense before the session is attached to a realm
+            return Err(Error::new(ErrorKind::UnexpectedMessage(
+                "Received a message before HELLO",
+            )));
+        }
+        if let Some(ref realm) = self.realm {
+            let session_id = connection_id(&self.info);
+            let action = realm
+                .lock()
+                .unwrap()
+                .interceptor
+                .on_inbound(session_id, &message);
+            match action {
+                InterceptAction::Allow => {}
+                InterceptAction::Drop => {
+                    debug!("Interceptor dropped message {:?}", message);
+                    return Ok(());
+                }
+                InterceptAction::Reject(reason) => {
+                    return match request_of(&message) {
+                        Some((error_type, request_id)) => Err(Error::new(ErrorKind::ErrorReason(
+                            error_type, request_id, reason,
+                        ))),
+                        None => {
+                            debug!("Interceptor rejected message {:?}", message);
+                            Ok(())
+                        }
+                    };
+                }
+            }
+        }
         match message {
             Message::Hello(realm, details) => self.handle_hello(realm, details),
             Message::Subscribe(request_id, options, topic) => {
@@ -97,19 +155,34 @@ impl ConnectionHandler {
                 "Responding to error message for invocation (id: {})",
                 request_id
             );
+            let session_id = connection_id(&self.info);
             match self.realm {
                 Some(ref realm) => {
                     let mut realm = realm.lock().unwrap();
                     let manager = &mut realm.registration_manager;
-                    if let Some((call_id, callee)) = manager.active_calls.remove(&request_id) {
+                    if !manager.answerable_by(request_id, session_id) {
+                        warn!(
+                            "Ignoring error from session {} for invocation {} sent to another",
+                            session_id, request_id
+                        );
+                        return Ok(());
+                    }
+                    if manager.is_fan_out_invocation(request_id) {
+                        let error = CallError::new(reason, args, kwargs);
+                        return manager.finish_fan_out_invocation(request_id, Err(error));
+                    }
+                    if let Some((call_id, caller, _)) = manager.active_calls.remove(&request_id) {
                         let error_message =
                             Message::Error(ErrorType::Call, call_id, details, reason, args, kwargs);
-                        send_message(&callee, &error_message)
+                        if let Err(e) = send_message(&caller, &error_message) {
+                            manager.report_unanswered(&caller, e);
+                        }
+                        manager.dispatch_queued_calls();
                     } else {
-                        Err(Error::new(ErrorKind::InvalidState(
-                            "Received an error message for a call that wasn't sent",
-                        )))
+                        // A late or repeated error, such as one for a call that timed out
+                        warn!("Ignoring error for unknown invocation {}", request_id);
                     }
+                    Ok(())
                 }
                 None => Err(Error::new(ErrorKind::InvalidState(
                     "Received a message while not attached to a realm",
@@ -122,19 +195,22 @@ impl ConnectionHandler {
         }
     }
 
-    fn parse_message(&self, msg: WSMessage) -> WampResult<Message> {
-        match msg {
-            WSMessage::Text(payload) => match serde_json::from_str(&payload) {
-                Ok(message) => Ok(message),
-                Err(e) => Err(Error::new(ErrorKind::JSONError(e))),
-            },
-            WSMessage::Binary(payload) => {
-                let mut de = RMPDeserializer::new(Cursor::new(payload));
-                match Deserialize::deserialize(&mut de) {
-                    Ok(message) => Ok(message),
-                    Err(e) => Err(Error::new(ErrorKind::MsgPackError(e))),
-                }
-            }
+    fn parse_message(&self, msg: WSMessage) -> WampResult<Vec<Message>> {
+        let max_message_size = self.router.max_message_size.load(Ordering::Relaxed);
+        if msg.len() > max_message_size {
+            warn!(
+                "Message of {} bytes exceeds the limit of {}",
+                msg.len(),
+                max_message_size
+            );
+            return Err(Error::new(ErrorKind::MalformedData));
+        }
+        let serializer = self.info.lock().unwrap().serializer.clone();
+        match serializer {
+            Some(serializer) => serializer.decode(&Payload::from(msg)),
+            None => Err(Error::new(ErrorKind::InvalidState(
+                "Received a message before a sub-protocol was negotiated",
+            ))),
         }
     }
 
@@ -153,8 +229,8 @@ impl ConnectionHandler {
         })
     }
 
-    fn send_abort(&self, reason: Reason) -> WSResult<()> {
-        send_message(&self.info, &Message::Abort(ErrorDetails::new(), reason)).map_err(|e| {
+    fn send_abort(&self, details: ErrorDetails, reason: Reason) -> WSResult<()> {
+        send_message(&self.info, &Message::Abort(details, reason)).map_err(|e| {
             let kind = e.get_kind();
             if let ErrorKind::WSError(e) = kind {
                 e
@@ -164,48 +240,141 @@ impl ConnectionHandler {
         })
     }
 
+    /// Tells the peer why its session is being ended and closes the connection with the
+    /// close code matching `reason`
+    fn abort_connection(&mut self, details: ErrorDetails, reason: Reason) -> WSResult<()> {
+        let code = close_code(&reason);
+        self.send_abort(details, reason)?;
+        self.close_connection(code)
+    }
+
+    /// Removes the connection from its realm and closes the websocket with `code`
+    fn close_connection(&mut self, code: CloseCode) -> WSResult<()> {
+        self.terminate_connection()?;
+        let mut info = self.info.lock().unwrap();
+        info.state = ConnectionState::Disconnected;
+        info.sender.close(code)
+    }
+
+    /// Hands an error met while handling the session's messages to `Router::on_error`.  Errors
+    /// that are answered within the protocol, like a refused call, aren't reported.
+    fn report_error(&self, error: &Error) {
+        let kind = match error.kind {
+            ErrorKind::UnexpectedMessage(_)
+            | ErrorKind::InvalidMessageType(_)
+            | ErrorKind::InvalidState(_) => RouterErrorKind::ProtocolViolation,
+            ErrorKind::JSONError(_)
+            | ErrorKind::MsgPackError(_)
+            | ErrorKind::MsgPackEncodeError(_)
+            | ErrorKind::MalformedData => RouterErrorKind::Serialization,
+            ErrorKind::WSError(_)
+            | ErrorKind::URLError(_)
+            | ErrorKind::ThreadError(_)
+            | ErrorKind::ConnectionLost
+            | ErrorKind::Timeout => RouterErrorKind::Connection,
+            #[cfg(feature = "async-client")]
+            ErrorKind::TungsteniteError(_) => RouterErrorKind::Connection,
+            ErrorKind::HandshakeError(_) | ErrorKind::Closing(_) | ErrorKind::ErrorReason(..) => {
+                return
+            }
+        };
+        self.router
+            .errors
+            .report(connection_id(&self.info), kind, error.to_string());
+    }
+
     fn on_message_error(&mut self, error: Error) -> WSResult<()> {
+        self.report_error(&error);
         match error.get_kind() {
             ErrorKind::WSError(e) => Err(e),
-            ErrorKind::URLError(_) => unimplemented!(),
+            #[cfg(feature = "async-client")]
+            ErrorKind::TungsteniteError(e) => {
+                error!("Websocket error: {}", e);
+                self.close_connection(CloseCode::Error)
+            }
+            ErrorKind::URLError(e) => {
+                error!("Invalid URL: {}", e);
+                self.close_connection(CloseCode::Error)
+            }
             ErrorKind::HandshakeError(r) => {
                 error!("Handshake error: {}", r);
-                self.send_abort(r)?;
-                self.terminate_connection()
+                self.abort_connection(ErrorDetails::new(), r)
             }
             ErrorKind::UnexpectedMessage(msg) => {
                 error!("Unexpected Message: {}", msg);
-                self.terminate_connection()
+                self.abort_connection(
+                    ErrorDetails::new_with_message(msg),
+                    Reason::ProtocolViolation,
+                )
+            }
+            ErrorKind::ThreadError(e) => {
+                error!("Could not hand message to another thread: {}", e);
+                self.close_connection(CloseCode::Error)
+            }
+            ErrorKind::ConnectionLost => {
+                error!("Connection lost");
+                self.close_connection(CloseCode::Away)
             }
-            ErrorKind::ThreadError(_) => unimplemented!(),
-            ErrorKind::ConnectionLost => unimplemented!(),
-            ErrorKind::Closing(_) => {
-                unimplemented! {}
+            ErrorKind::Closing(reason) => {
+                info!("Connection closing: {}", reason);
+                self.close_connection(CloseCode::Normal)
             }
             ErrorKind::JSONError(e) => {
                 error!("Could not parse JSON: {}", e);
-                self.terminate_connection()
+                self.abort_connection(
+                    ErrorDetails::new_with_message("Received a message that could not be parsed"),
+                    Reason::ProtocolViolation,
+                )
             }
             ErrorKind::MsgPackError(e) => {
                 error!("Could not parse MsgPack: {}", e);
-                self.terminate_connection()
+                self.abort_connection(
+                    ErrorDetails::new_with_message("Received a message that could not be parsed"),
+                    Reason::ProtocolViolation,
+                )
+            }
+            ErrorKind::MsgPackEncodeError(e) => {
+                error!("Could not encode MsgPack: {}", e);
+                self.close_connection(CloseCode::Error)
+            }
+            ErrorKind::MalformedData => {
+                error!("Received malformed data");
+                self.close_connection(CloseCode::Size)
             }
-            ErrorKind::MalformedData => unimplemented!(),
             ErrorKind::InvalidMessageType(msg) => {
                 error!("Router unable to handle message {:?}", msg);
-                self.terminate_connection()
+                self.abort_connection(ErrorDetails::new(), Reason::ProtocolViolation)
             }
             ErrorKind::InvalidState(s) => {
                 error!("Invalid State: {}", s);
-                self.terminate_connection()
+                self.abort_connection(ErrorDetails::new(), Reason::ProtocolViolation)
             }
             ErrorKind::Timeout => {
                 error!("Connection timeout");
-                self.terminate_connection()
+                self.close_connection(CloseCode::Away)
             }
             ErrorKind::ErrorReason(err_type, id, reason) => self.send_error(err_type, id, reason),
         }
     }
+
+    /// Serves a loopback connection until either end closes it
+    #[cfg_attr(not(feature = "ws-client"), allow(dead_code))]
+    pub fn run_loopback(&mut self, events: Receiver<Event>) {
+        for event in events {
+            match event {
+                Event::Message(message) => {
+                    if let Err(e) = self.handle_message(message) {
+                        if self.on_message_error(e).is_err() {
+                            break;
+                        }
+                    }
+                }
+                Event::Timeout(_) => {}
+                Event::Close => break,
+            }
+        }
+        self.on_close(CloseCode::Normal, "");
+    }
 }
 
 impl Handler for ConnectionHandler {
@@ -218,21 +387,58 @@ impl Handler for ConnectionHandler {
                 return Err(e);
             }
         };
+        if !self.process_path(request) {
+            warn!("No realm at {}", request.resource());
+            return Ok(Response::new(404, "Not Found", Vec::new()));
+        }
         self.process_protocol(request, &mut response)?;
+        self.process_cookie(request, &mut response);
         debug!("Sending response");
         Ok(response)
     }
 
+    fn on_open(&mut self, _handshake: Handshake) -> WSResult<()> {
+        self.schedule_ping()
+    }
+
     fn on_message(&mut self, msg: WSMessage) -> WSResult<()> {
         debug!("Receveied message: {:?}", msg);
-        let message = match self.parse_message(msg) {
+        let messages = match self.parse_message(msg) {
             Err(e) => return self.on_message_error(e),
             Ok(m) => m,
         };
-        match self.handle_message(message) {
-            Err(e) => self.on_message_error(e),
-            _ => Ok(()),
+        for message in messages {
+            if let Err(e) = self.handle_message(message) {
+                return self.on_message_error(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn on_timeout(&mut self, token: Token) -> WSResult<()> {
+        if token != PING_TIMEOUT {
+            return Ok(());
+        }
+        if self.missed_pings >= MAX_MISSED_PINGS {
+            warn!(
+                "Connection left {} pings unanswered.  Closing connection",
+                self.missed_pings
+            );
+            return self.close_connection(CloseCode::Away);
+        }
+        self.missed_pings += 1;
+        self.info.lock().unwrap().sender.ping()?;
+        self.schedule_ping()
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> WSResult<Option<Frame>> {
+        if frame.opcode() == OpCode::Pong {
+            self.missed_pings = 0;
+            if let ConnectionSender::WebSocket(ref outbox) = self.info.lock().unwrap().sender {
+                outbox.acknowledge(frame.payload());
+            }
         }
+        Ok(Some(frame))
     }
 
     fn on_close(&mut self, _code: CloseCode, _reason: &str) {
@@ -0,0 +1,248 @@
+//! Contains the meta procedures that the router provides in every realm
+use itertools::Itertools;
+use parity_ws::CloseCode;
+
+use crate::{
+    messages::{ErrorDetails, Message, Reason, URI},
+    CallError, CallResult, Dict, List, MatchingPolicy, Value, ID,
+};
+
+use super::{connection_id, messaging::send_message, Realm};
+
+impl Realm {
+    /// Handles a call to one of the `wamp.` meta procedures, made by the session `caller_id`.
+    pub fn call_meta_procedure(
+        &self,
+        caller_id: ID,
+        procedure: &URI,
+        args: List,
+        kwargs: Dict,
+    ) -> CallResult<(Option<List>, Option<Dict>)> {
+        match procedure.uri.as_str() {
+            "wamp.registration.get" => self.get_registration(&args),
+            "wamp.registration.list" => Ok((Some(vec![self.list_registrations()]), None)),
+            "wamp.registration.lookup" => self.lookup_registration(&args),
+            "wamp.subscription.list" => Ok((Some(vec![self.list_subscriptions()]), None)),
+            "wamp.subscription.lookup" => self.lookup_subscriptions(&args),
+            "wamp.subscription.count_subscribers" => self.count_subscribers(&args),
+            "wamp.session.kill" => self.kill_session(caller_id, &args, &kwargs),
+            _ => Err(CallError::new(Reason::NoSuchProcedure, None, None)),
+        }
+    }
+
+    fn get_registration(&self, args: &List) -> CallResult<(Option<List>, Option<Dict>)> {
+        let registration_id = get_id(args, 0)?;
+        match self
+            .registration_manager
+            .registration_ids_to_uris
+            .get(&registration_id)
+        {
+            Some((uri, _, metadata)) => {
+                let mut details = metadata.clone();
+                details.insert("id".to_string(), Value::UnsignedInteger(registration_id));
+                details.insert("uri".to_string(), Value::String(uri.clone()));
+                Ok((Some(vec![Value::Dict(details)]), None))
+            }
+            None => Err(CallError::new(Reason::NoSuchRegistration, None, None)),
+        }
+    }
+
+    /// Groups the ids of the registrations that have a registrant by how they match
+    fn list_registrations(&self) -> Value {
+        group_by_policy(self.registration_manager.registrations.registration_ids())
+    }
+
+    /// Finds the registration a call to the procedure would go to.  An optional second
+    /// argument holds options, whose `match` only accepts a registration matching that way.
+    /// Answers without arguments if there is no such registration.
+    fn lookup_registration(&self, args: &List) -> CallResult<(Option<List>, Option<Dict>)> {
+        let procedure = get_uri(args, 0)?;
+        let wanted = get_match_option(args, 1)?;
+        match self
+            .registration_manager
+            .registrations
+            .get_registrants_for(procedure)
+        {
+            Ok((_, id, policy)) if wanted.is_none() || wanted == Some(policy) => {
+                Ok((Some(vec![Value::UnsignedInteger(id)]), None))
+            }
+            _ => Ok((None, None)),
+        }
+    }
+
+    /// Groups the ids of the subscriptions that have a subscriber by how they match
+    fn list_subscriptions(&self) -> Value {
+        group_by_policy(self.subscription_manager.subscriptions.subscription_ids())
+    }
+
+    /// Finds the subscriptions an event published to the topic would be delivered through.
+    /// An optional second argument holds options, whose `match` only accepts subscriptions
+    /// matching that way.
+    fn lookup_subscriptions(&self, args: &List) -> CallResult<(Option<List>, Option<Dict>)> {
+        let topic = get_uri(args, 0)?;
+        let wanted = get_match_option(args, 1)?;
+        let mut ids = Vec::new();
+        for (_, id, policy) in self.subscription_manager.subscriptions.filter(topic) {
+            if (wanted.is_none() || wanted == Some(policy)) && !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        Ok((
+            Some(ids.into_iter().map(Value::UnsignedInteger).collect()),
+            None,
+        ))
+    }
+
+    /// Counts the sessions an event would reach.  The argument is either a subscription id,
+    /// counting that subscription's subscribers, or a topic uri, counting every session
+    /// subscribed to something matching it.
+    fn count_subscribers(&self, args: &List) -> CallResult<(Option<List>, Option<Dict>)> {
+        let manager = &self.subscription_manager;
+        let count = match args.first() {
+            Some(Value::String(topic)) => manager
+                .subscriptions
+                .filter(URI::new(topic))
+                .map(|(subscriber, _, _)| connection_id(subscriber))
+                .unique()
+                .count(),
+            _ => {
+                let subscription_id = get_id(args, 0)?;
+                let uri = match manager.subscription_ids_to_uris.get(&subscription_id) {
+                    Some((uri, _)) => URI::new(uri),
+                    None => return Err(CallError::new(Reason::NoSuchSubscription, None, None)),
+                };
+                manager
+                    .subscriptions
+                    .filter(uri)
+                    .filter(|(_, id, _)| *id == subscription_id)
+                    .count()
+            }
+        };
+        Ok((Some(vec![Value::UnsignedInteger(count as u64)]), None))
+    }
+
+    /// Ends another session of the realm with a GOODBYE and closes its connection.  The
+    /// optional `reason` and `message` keyword arguments go into the GOODBYE, the reason
+    /// defaulting to `wamp.close.killed`.  A session can't kill itself.
+    ///
+    /// Only sessions the realm's authorizer lets kill, through `Authorizer::can_kill`, may
+    /// call this; the default authorizer lets none.
+    fn kill_session(
+        &self,
+        caller_id: ID,
+        args: &List,
+        kwargs: &Dict,
+    ) -> CallResult<(Option<List>, Option<Dict>)> {
+        let session_id = get_id(args, 0)?;
+        if !self.authorizer.can_kill(caller_id, session_id) {
+            return Err(CallError::new(Reason::NotAuthorized, None, None));
+        }
+        let reason = match kwargs.get("reason") {
+            Some(Value::String(reason)) => Reason::CustomReason(URI::new(reason)),
+            Some(_) => return Err(invalid_argument("Expected a reason uri")),
+            None => Reason::CustomReason(URI::new("wamp.close.killed")),
+        };
+        let details = match kwargs.get("message") {
+            Some(Value::String(message)) => ErrorDetails::new_with_message(message),
+            Some(_) => return Err(invalid_argument("Expected a message")),
+            None => ErrorDetails::new(),
+        };
+        let connection = self
+            .connections
+            .iter()
+            .find(|connection| session_id != caller_id && connection_id(connection) == session_id)
+            .ok_or_else(|| CallError::new(Reason::NoSuchSession, None, None))?;
+        send_message(connection, &Message::Goodbye(details, reason)).ok();
+        // The session's own handler removes it from the realm once the connection has closed
+        if let Ok(connection) = connection.lock() {
+            connection.sender.close(CloseCode::Policy).ok();
+        }
+        Ok((None, None))
+    }
+}
+
+/// Builds a dictionary with the list of ids for each matching policy
+fn group_by_policy(ids: Vec<(ID, MatchingPolicy)>) -> Value {
+    let mut groups = Dict::new();
+    for name in &["exact", "prefix", "wildcard"] {
+        groups.insert(name.to_string(), Value::List(Vec::new()));
+    }
+    for (id, policy) in ids {
+        if let Some(Value::List(ids)) = groups.get_mut(policy_name(policy)) {
+            ids.push(Value::UnsignedInteger(id));
+        }
+    }
+    Value::Dict(groups)
+}
+
+/// The name the meta api gives a matching policy
+fn policy_name(policy: MatchingPolicy) -> &'static str {
+    match policy {
+        MatchingPolicy::Strict => "exact",
+        MatchingPolicy::Prefix => "prefix",
+        MatchingPolicy::Wildcard => "wildcard",
+    }
+}
+
+fn parse_policy(name: &str) -> CallResult<MatchingPolicy> {
+    match name {
+        "exact" => Ok(MatchingPolicy::Strict),
+        "prefix" => Ok(MatchingPolicy::Prefix),
+        "wildcard" => Ok(MatchingPolicy::Wildcard),
+        _ => Err(invalid_argument("Unknown matching policy")),
+    }
+}
+
+fn invalid_argument(message: &str) -> CallError {
+    CallError::new(
+        Reason::InvalidArgument,
+        Some(vec![Value::String(message.to_string())]),
+        None,
+    )
+}
+
+/// Reads a uri argument
+fn get_uri(args: &List, index: usize) -> CallResult<URI> {
+    match args.get(index) {
+        Some(Value::String(uri)) => Ok(URI::new(uri)),
+        _ => Err(invalid_argument("Expected a uri")),
+    }
+}
+
+/// Reads the `match` entry of an optional options argument
+fn get_match_option(args: &List, index: usize) -> CallResult<Option<MatchingPolicy>> {
+    match args.get(index) {
+        Some(Value::Dict(options)) => match options.get("match") {
+            Some(Value::String(name)) => parse_policy(name).map(Some),
+            Some(_) => Err(invalid_argument("Expected a matching policy")),
+            None => Ok(None),
+        },
+        Some(_) => Err(invalid_argument("Expected an options dictionary")),
+        None => Ok(None),
+    }
+}
+
+/// Reads an id argument, which may arrive as either a signed or an unsigned integer
+fn get_id(args: &List, index: usize) -> CallResult<ID> {
+    match args.get(index) {
+        Some(&Value::UnsignedInteger(id)) => Ok(id),
+        Some(&Value::Integer(id)) if id >= 0 => Ok(id as ID),
+        Some(value) => Err(CallError::new(
+            Reason::InvalidArgument,
+            Some(vec![Value::String(format!(
+                "Expected id, got {}",
+                value.summarize()
+            ))]),
+            None,
+        )),
+        None => Err(CallError::new(
+            Reason::InvalidArgument,
+            Some(vec![Value::String(format!(
+                "Expected {} arguments, got {}",
+                index + 1,
+                args.len()
+            ))]),
+            None,
+        )),
+    }
+}
@@ -168,56 +168,206 @@
 //! **What this means is: plug-and-play your app components - no matter what language.**
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     marker::Sync,
-    sync::{Arc, Mutex},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use log::{debug, info, trace};
-use rand::{thread_rng, Rng};
-use parity_ws::{listen as ws_listen, Result as WSResult, Sender};
+use log::{debug, info, trace, warn};
+use parity_ws::{
+    util::Token, Builder, CloseCode, Error as WSError, Factory, Result as WSResult, Sender,
+    Settings, WebSocket,
+};
 
-use crate::messages::{ErrorDetails, Message, Reason};
+use crate::{
+    messages::{ErrorDetails, ErrorType, Message, Reason, URI},
+    serializer::{BatchedJsonSerializer, Serializer, Serializers},
+    transport::loopback::{self, LoopbackSender},
+    CallError, CallResult, Dict, Error, ErrorKind, InvocationPolicy, List, MatchingPolicy,
+    WampResult,
+};
+#[cfg(feature = "ws-client")]
+use crate::{Client, Connection};
 
 use super::ID;
 
+mod authorization;
+pub use self::authorization::{AllowAll, Authorizer};
+
+mod errors;
+use self::errors::ErrorSink;
+pub use self::errors::{ErrorHandler, RouterError, RouterErrorKind};
+
 mod handshake;
+use self::handshake::SessionCookies;
+
+mod ids;
+pub use self::ids::{IdGenerator, RandomIdGenerator, SequentialIdGenerator};
+
+mod interception;
+pub use self::interception::{InterceptAction, MessageInterceptor, PassThrough};
+
+mod meta;
 
 mod messaging;
 use self::messaging::send_message;
 
+mod outbox;
+use self::outbox::{Outbox, Writer};
+
 mod pubsub;
-use self::pubsub::SubscriptionPatternNode;
+use self::pubsub::{AwayBuffer, RetainedEvent, SubscriptionPatternNode};
 
 mod rpc;
-use self::rpc::RegistrationPatternNode;
+use self::rpc::{FanOutCall, QueuedCall, RegistrationPatternNode};
+
+/// Announced in WELCOME unless the router is given another agent
+static DEFAULT_AGENT: &str = concat!("wampire-", env!("CARGO_PKG_VERSION"));
+
+/// Most events a subscription may ask to have kept with `keep_last`, unless the router is given
+/// another limit
+const DEFAULT_MAX_KEEP_LAST: usize = 1000;
 
 struct SubscriptionManager {
     subscriptions: SubscriptionPatternNode<Arc<Mutex<ConnectionInfo>>>,
     subscription_ids_to_uris: HashMap<u64, (String, bool)>,
+    /// The last event published with `retain` on each topic
+    retained_events: HashMap<String, RetainedEvent>,
+    /// The authid and `keep_last` of the subscriptions that asked for it, by subscription id
+    /// and session id
+    kept_subscriptions: HashMap<(ID, ID), (String, usize)>,
+    /// The events kept for authids whose sessions left while subscribed with `keep_last`, by
+    /// topic and authid
+    away_buffers: HashMap<String, HashMap<String, AwayBuffer>>,
 }
 
 struct RegistrationManager {
     registrations: RegistrationPatternNode<Arc<Mutex<ConnectionInfo>>>,
-    registration_ids_to_uris: HashMap<u64, (String, bool)>,
-    active_calls: HashMap<ID, (ID, Arc<Mutex<ConnectionInfo>>)>,
+    /// Supplies the ids of invocations
+    ids: Arc<dyn IdGenerator>,
+    /// Receives invocations that couldn't be delivered
+    errors: Arc<ErrorSink>,
+    registration_ids_to_uris: HashMap<u64, (String, bool, Dict)>,
+    /// The registration and session ids of the registrants that asked for `disclose_caller`.
+    /// The router never takes up a caller's own request to be disclosed, so
+    /// `OptionDisallowedDiscloseMe` doesn't arise and these are the only calls disclosed.
+    disclosing_registrants: HashSet<(ID, ID)>,
+    /// Maps invocation ids to the call id, the caller and the session id of the callee
+    active_calls: HashMap<ID, (ID, Arc<Mutex<ConnectionInfo>>, ID)>,
+    /// Calls held back until a registrant with a concurrency limit finishes an invocation
+    queued_calls: Vec<QueuedCall>,
+    /// Calls made to every registrant that are still waiting for some of them
+    fan_out_calls: Vec<FanOutCall>,
+    local_handlers: HashMap<ID, LocalHandler>,
 }
 
+/// Alias for a procedure handler that runs inside the router process
+pub type LocalHandler =
+    Box<dyn FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)> + Send>;
+
 struct Realm {
     subscription_manager: SubscriptionManager,
     registration_manager: RegistrationManager,
     connections: Vec<Arc<Mutex<ConnectionInfo>>>,
+    authorizer: Box<dyn Authorizer>,
+    interceptor: Box<dyn MessageInterceptor>,
+}
+
+/// A snapshot of the activity within a realm
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RealmStats {
+    /// Number of sessions attached to the realm
+    pub connections: usize,
+    /// Number of subscriptions with at least one subscriber
+    pub subscriptions: usize,
+    /// Number of procedures with at least one registrant
+    pub registrations: usize,
+    /// Number of calls forwarded to a callee that haven't been answered yet
+    pub active_calls: usize,
+}
+
+/// Tunes the websocket listener started by `Router::listen_with_settings`.
+///
+/// Each listener serves all of its connections from a single event loop thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ListenerSettings {
+    /// Connections beyond this number are refused until an open one closes.  Default: 100
+    pub max_connections: usize,
+    /// Number of events the event loop expects to queue per connection.  The queue is shared,
+    /// so it holds `queue_size * max_connections` events in total.  Default: 5
+    pub queue_size: usize,
+}
+
+impl Default for ListenerSettings {
+    fn default() -> ListenerSettings {
+        ListenerSettings {
+            max_connections: 100,
+            queue_size: 5,
+        }
+    }
+}
+
+/// The hooks of a realm added by `Router::add_realm_with_options`
+pub struct RealmOptions {
+    /// Consulted before any subscribe, publish, register or call made within the realm.
+    /// Default: `AllowAll`
+    pub authorizer: Box<dyn Authorizer>,
+    /// Sees every message the sessions of the realm send before it is acted on.
+    /// Default: `PassThrough`
+    pub interceptor: Box<dyn MessageInterceptor>,
+}
+
+impl Default for RealmOptions {
+    fn default() -> RealmOptions {
+        RealmOptions {
+            authorizer: Box::new(AllowAll),
+            interceptor: Box::new(PassThrough),
+        }
+    }
 }
 
 /// Represents WAMP Router
+///
+/// Realms are isolated from each other.  Each keeps its own subscriptions, registrations and
+/// calls in flight, so the same topic or procedure URI can be used in several realms at once,
+/// and a session only ever reaches the subscribers and callees of the realm it joined.
 pub struct Router {
     info: Arc<RouterInfo>,
 }
 
+// Locks are taken in this order: `RouterInfo::realms`, then a realm, then the `ConnectionInfo`
+// of a session.  No path locks a realm while it holds a connection, and at most one connection
+// is locked at a time, so a publish can't deadlock with a session leaving the realm.  The
+// other locks of `RouterInfo` are taken on their own, with nothing else held.
 struct RouterInfo {
     realms: Mutex<HashMap<String, Arc<Mutex<Realm>>>>,
+    listeners: Mutex<Vec<Sender>>,
+    max_event_backlog: AtomicUsize,
+    ping_interval_ms: AtomicU64,
+    realm_by_path: AtomicBool,
+    max_message_size: AtomicUsize,
+    max_subscriptions: AtomicUsize,
+    max_keep_last: AtomicUsize,
+    max_registrations: AtomicUsize,
+    max_args_len: AtomicUsize,
+    max_kwargs_len: AtomicUsize,
+    disclose_callee: AtomicBool,
+    agent: Mutex<String>,
+    /// The sub-protocols offered to new connections, most preferred first
+    serializers: Mutex<Serializers>,
+    /// Set by `Router::drain`, after which new calls and publications are refused
+    draining: AtomicBool,
+    id_generator: Arc<dyn IdGenerator>,
+    errors: Arc<ErrorSink>,
+    /// Maps the session cookies handed out by the router to the authid of the last session that
+    /// presented them
+    session_cookies: Mutex<SessionCookies>,
 }
 
 struct ConnectionHandler {
@@ -226,14 +376,70 @@ struct ConnectionHandler {
     realm: Option<Arc<Mutex<Realm>>>,
     subscribed_topics: Vec<ID>,
     registered_procedures: Vec<ID>,
+    missed_pings: u32,
+    /// The realm named by the request path, when realms are selected by path
+    path_realm: Option<String>,
+    /// The session cookie of the connection, if it was opened over HTTP
+    cookie: Option<String>,
 }
 
 /// Represents WAMP Router connection information
 pub struct ConnectionInfo {
     state: ConnectionState,
-    sender: Sender,
-    protocol: String,
+    sender: ConnectionSender,
+    /// Encodes and decodes the messages of a websocket connection, once the handshake has
+    /// settled on a sub-protocol
+    serializer: Option<Arc<dyn Serializer>>,
     id: u64,
+    authid: Option<String>,
+    authrole: Option<String>,
+    /// Set once the realm's authorizer vouched for the authid and authrole
+    vouched: bool,
+}
+
+/// The outbound side of a connection
+enum ConnectionSender {
+    /// A peer connected over a websocket
+    WebSocket(Outbox),
+    /// A synthetic connection whose procedures are handled in-process
+    Local,
+    /// A client in the same process, connected through `Router::connect_loopback`
+    #[cfg_attr(not(feature = "ws-client"), allow(dead_code))]
+    Loopback(LoopbackSender),
+}
+
+impl ConnectionInfo {
+    /// The sub-protocol the connection speaks, if it has settled on one
+    fn protocol(&self) -> &str {
+        match (&self.sender, &self.serializer) {
+            (ConnectionSender::Loopback(_), _) => loopback::PROTOCOL,
+            (_, Some(serializer)) => serializer.subprotocol_name(),
+            (_, None) => "",
+        }
+    }
+
+    /// Number of messages sent to the connection that the peer isn't known to have read
+    fn backlog(&self) -> usize {
+        match self.sender {
+            ConnectionSender::WebSocket(ref outbox) => outbox.backlog(),
+            ConnectionSender::Local | ConnectionSender::Loopback(_) => 0,
+        }
+    }
+
+    /// The authid and authrole of the session, if the realm's authorizer vouched for them
+    fn vouched_identity(&self) -> (Option<&str>, Option<&str>) {
+        if self.vouched {
+            (self.authid.as_deref(), self.authrole.as_deref())
+        } else {
+            (None, None)
+        }
+    }
+}
+
+/// The session id of `connection`.  The id never changes, so it can be read even if a thread
+/// panicked while holding the lock.
+fn connection_id(connection: &Mutex<ConnectionInfo>) -> ID {
+    connection.lock().unwrap_or_else(PoisonError::into_inner).id
 }
 
 #[derive(Clone, PartialEq)]
@@ -244,14 +450,13 @@ enum ConnectionState {
     Disconnected,
 }
 
-static WAMP_JSON: &str = "wamp.2.json";
-static WAMP_MSGPACK: &str = "wamp.2.msgpack";
+const PING_TIMEOUT: Token = Token(0);
 
-fn random_id() -> u64 {
-    let mut rng = thread_rng();
-    // TODO make this a constant
-    rng.gen_range(0..1u64.rotate_left(56) - 1)
-}
+/// Number of consecutive pings a peer may leave unanswered before its connection is closed
+const MAX_MISSED_PINGS: u32 = 3;
+
+/// How often `Router::drain` checks whether the pending calls have been answered
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 unsafe impl Sync for Router {}
 
@@ -265,62 +470,367 @@ impl Router {
     /// Create the new default router
     #[inline]
     pub fn new() -> Router {
+        Router::new_with_id_generator(Box::new(RandomIdGenerator))
+    }
+
+    /// Create a router that takes the ids it assigns from `id_generator`.  A
+    /// `SequentialIdGenerator` makes the ids the same on every run, which helps tests.
+    pub fn new_with_id_generator(id_generator: Box<dyn IdGenerator>) -> Router {
         Router {
             info: Arc::new(RouterInfo {
                 realms: Mutex::new(HashMap::new()),
+                listeners: Mutex::new(Vec::new()),
+                max_event_backlog: AtomicUsize::new(usize::MAX),
+                ping_interval_ms: AtomicU64::new(0),
+                realm_by_path: AtomicBool::new(false),
+                max_message_size: AtomicUsize::new(usize::MAX),
+                max_subscriptions: AtomicUsize::new(usize::MAX),
+                max_keep_last: AtomicUsize::new(DEFAULT_MAX_KEEP_LAST),
+                max_registrations: AtomicUsize::new(usize::MAX),
+                max_args_len: AtomicUsize::new(usize::MAX),
+                max_kwargs_len: AtomicUsize::new(usize::MAX),
+                disclose_callee: AtomicBool::new(false),
+                agent: Mutex::new(DEFAULT_AGENT.to_string()),
+                serializers: Mutex::new(Serializers::default().prefer(BatchedJsonSerializer)),
+                draining: AtomicBool::new(false),
+                id_generator: Arc::from(id_generator),
+                errors: Arc::new(ErrorSink::default()),
+                session_cookies: Mutex::new(SessionCookies::default()),
             }),
         }
     }
 
     /// Start listrning with url
+    ///
+    /// The returned thread runs until `shutdown` is called.
     pub fn listen(&self, url: &str) -> JoinHandle<()> {
-        let router_info = Arc::clone(&self.info);
+        let socket = self.create_socket(ListenerSettings::default()).unwrap();
         let url = url.to_string();
         thread::spawn(move || {
-            ws_listen(&url[..], |sender| ConnectionHandler {
-                info: Arc::new(Mutex::new(ConnectionInfo {
-                    state: ConnectionState::Initializing,
-                    sender,
-                    protocol: String::new(),
-                    id: random_id(),
-                })),
-                subscribed_topics: Vec::new(),
-                registered_procedures: Vec::new(),
-                realm: None,
-                router: Arc::clone(&router_info),
-            })
-            .unwrap();
+            socket.listen(&url[..]).unwrap();
         })
     }
 
+    /// Start listening with url, returning the address that was actually bound.
+    ///
+    /// The listener is bound on the calling thread, so binding to port `0` reports the
+    /// ephemeral port that was chosen.  Connections are then served on a background thread.
+    ///
+    /// The websocket library always binds its own listening socket, so an already bound
+    /// `std::net::TcpListener`, such as one passed in by systemd socket activation, can't be
+    /// served.  Binding to port `0` here is the way to get a free port for tests.
+    pub fn listen_addr(&self, url: &str) -> WampResult<(SocketAddr, JoinHandle<()>)> {
+        self.listen_with_settings(url, ListenerSettings::default())
+    }
+
+    /// Start listening with url like `listen_addr`, tuning the listener with `settings`
+    pub fn listen_with_settings(
+        &self,
+        url: &str,
+        settings: ListenerSettings,
+    ) -> WampResult<(SocketAddr, JoinHandle<()>)> {
+        let socket = self
+            .create_socket(settings)
+            .and_then(|socket| socket.bind(url))
+            .map_err(|e| Error::new(ErrorKind::WSError(e)))?;
+        let addr = socket
+            .local_addr()
+            .map_err(|e| Error::new(ErrorKind::WSError(WSError::from(e))))?;
+        debug!("Router bound to {}", addr);
+        let child = thread::spawn(move || {
+            socket.run().unwrap();
+        });
+        Ok((addr, child))
+    }
+
+    /// Creates a websocket whose event loop is stopped by `shutdown`
+    fn create_socket(
+        &self,
+        settings: ListenerSettings,
+    ) -> WSResult<WebSocket<impl Factory<Handler = ConnectionHandler> + Send>> {
+        let router_info = Arc::clone(&self.info);
+        let writer = Writer::start();
+        let mut ws_settings = Settings::default();
+        ws_settings.max_connections = settings.max_connections;
+        ws_settings.queue_size = settings.queue_size;
+        let socket = Builder::new()
+            .with_settings(ws_settings)
+            .build(move |sender| {
+                ConnectionHandler::new(
+                    Arc::clone(&router_info),
+                    ConnectionSender::WebSocket(Outbox::new(sender, &writer)),
+                )
+            })?;
+        self.info
+            .listeners
+            .lock()
+            .unwrap()
+            .push(socket.broadcaster());
+        Ok(socket)
+    }
+
+    /// Limit how many messages may be waiting to be sent to a subscriber.
+    ///
+    /// A message counts against the backlog from the moment it is queued until the subscriber
+    /// is known to have read it: the router follows what it sends with a ping, and the pong
+    /// acknowledges everything sent before.  A subscriber that stops reading thus keeps its
+    /// backlog, however much the socket and the event loop buffer.  Events for a subscriber
+    /// whose backlog has reached `limit` are dropped instead of queued.  By default the backlog
+    /// is unlimited.  Loopback connections have no backlog.
+    pub fn set_max_event_backlog(&mut self, limit: usize) {
+        self.info.max_event_backlog.store(limit, Ordering::Relaxed);
+    }
+
+    /// Ping every connection each `interval` to detect peers that silently went away.
+    ///
+    /// A connection that leaves several pings in a row unanswered is terminated.  A zero
+    /// interval, the default, disables pings.  The interval applies to connections opened
+    /// after it is set.
+    pub fn set_ping_interval(&mut self, interval: Duration) {
+        self.info
+            .ping_interval_ms
+            .store(interval.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Refuse messages larger than `limit` bytes.
+    ///
+    /// A peer that sends a bigger message has its connection closed before the message is
+    /// deserialized.  By default messages of any size are accepted.
+    pub fn set_max_message_size(&mut self, limit: usize) {
+        self.info.max_message_size.store(limit, Ordering::Relaxed);
+    }
+
+    /// Limit how many subscriptions a single session may hold.
+    ///
+    /// A SUBSCRIBE beyond the limit is refused with `Reason::NotAuthorized`.  By default a
+    /// session may subscribe to any number of topics.
+    pub fn set_max_subscriptions(&mut self, limit: usize) {
+        self.info.max_subscriptions.store(limit, Ordering::Relaxed);
+    }
+
+    /// Limit how many events a subscription may ask the router to keep with `keep_last`.
+    ///
+    /// A SUBSCRIBE asking for more is refused with `Reason::InvalidArgument`.  By default up to
+    /// 1000 events may be kept.
+    pub fn set_max_keep_last(&mut self, limit: usize) {
+        self.info.max_keep_last.store(limit, Ordering::Relaxed);
+    }
+
+    /// Limit how many procedures a single session may register.
+    ///
+    /// A REGISTER beyond the limit is refused with `Reason::NotAuthorized`.  By default a
+    /// session may register any number of procedures.
+    pub fn set_max_registrations(&mut self, limit: usize) {
+        self.info.max_registrations.store(limit, Ordering::Relaxed);
+    }
+
+    /// Limit how many positional arguments a single CALL or PUBLISH may carry.
+    ///
+    /// Only the top level list is counted.  A message with more is refused with
+    /// `Reason::InvalidArgument` before it reaches any callee or subscriber.  By default any
+    /// number is accepted.
+    pub fn set_max_args_len(&mut self, limit: usize) {
+        self.info.max_args_len.store(limit, Ordering::Relaxed);
+    }
+
+    /// Limit how many keyword arguments a single CALL or PUBLISH may carry, like
+    /// `set_max_args_len` does for positional ones
+    pub fn set_max_kwargs_len(&mut self, limit: usize) {
+        self.info.max_kwargs_len.store(limit, Ordering::Relaxed);
+    }
+
+    /// Tell callers which session answered their call, through the `callee` detail of each
+    /// RESULT.  Off by default, since it reveals the callee's session id.
+    pub fn set_disclose_callee(&mut self, enabled: bool) {
+        self.info.disclose_callee.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Call `handler` with each error the router runs into while serving a session, such as
+    /// a protocol violation, a message that can't be decoded, or an event or invocation that
+    /// can't be delivered.  The errors are logged either way.
+    ///
+    /// The handler runs on the thread serving the session, sometimes while a realm is locked,
+    /// so it should return quickly and must not call back into the router.  It replaces any
+    /// handler set before.
+    pub fn on_error(&mut self, handler: ErrorHandler) {
+        self.info.errors.set(handler);
+    }
+
+    /// Select each connection's realm by the path it requests, e.g. `ws://host/ca.test.realm`.
+    ///
+    /// Requests for a path that doesn't name a realm are refused, and a HELLO for any realm
+    /// other than the one in the path is aborted.  By default the realm is taken from the
+    /// HELLO alone and the path is ignored.
+    pub fn set_realm_by_path(&mut self, enabled: bool) {
+        self.info.realm_by_path.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Announce the router as `agent` in the WELCOME sent to every new session.
+    ///
+    /// The default is `wampire-<version>`.
+    pub fn set_agent(&mut self, agent: &str) {
+        *self.info.agent.lock().unwrap() = agent.to_string();
+    }
+
+    /// Speak the sub-protocols of `serializers` with new connections.  A client is served with
+    /// the first of them that it offers.
+    ///
+    /// The default is `wamp.2.json.batched`, `wamp.2.msgpack`, then `wamp.2.json`, so that
+    /// batching is only used by clients that ask for it.
+    pub fn set_serializers(&mut self, serializers: Serializers) {
+        *self.info.serializers.lock().unwrap() = serializers;
+    }
+
     /// Add realm to router
     pub fn add_realm(&mut self, realm: &str) {
+        self.add_realm_with_options(realm, RealmOptions::default());
+    }
+
+    /// Add realm to router, with the authorizer and interceptor of `options`
+    pub fn add_realm_with_options(&mut self, realm: &str, options: RealmOptions) {
+        let RealmOptions {
+            authorizer,
+            interceptor,
+        } = options;
         let mut realms = self.info.realms.lock().unwrap();
         if realms.contains_key(realm) {
             return;
         }
+        let ids = &self.info.id_generator;
         realms.insert(
             realm.to_string(),
             Arc::new(Mutex::new(Realm {
                 connections: Vec::new(),
                 subscription_manager: SubscriptionManager {
-                    subscriptions: SubscriptionPatternNode::new(),
+                    subscriptions: SubscriptionPatternNode::with_id_generator(Arc::clone(ids)),
                     subscription_ids_to_uris: HashMap::new(),
+                    retained_events: HashMap::new(),
+                    kept_subscriptions: HashMap::new(),
+                    away_buffers: HashMap::new(),
                 },
                 registration_manager: RegistrationManager {
-                    registrations: RegistrationPatternNode::new(),
+                    registrations: RegistrationPatternNode::with_id_generator(Arc::clone(ids)),
+                    ids: Arc::clone(ids),
+                    errors: Arc::clone(&self.info.errors),
                     registration_ids_to_uris: HashMap::new(),
+                    disclosing_registrants: HashSet::new(),
                     active_calls: HashMap::new(),
+                    queued_calls: Vec::new(),
+                    fan_out_calls: Vec::new(),
+                    local_handlers: HashMap::new(),
                 },
+                authorizer,
+                interceptor,
             })),
         );
         debug!("Added realm {}", realm);
     }
 
+    /// Register a procedure that is handled inside the router process.
+    ///
+    /// Calls routed to the procedure invoke `handler` directly and the result is sent
+    /// back to the caller without going over the wire.  The handler runs while the realm
+    /// is locked, so it must not call back into the router.
+    pub fn register_local(
+        &mut self,
+        realm: &str,
+        procedure: URI,
+        handler: LocalHandler,
+    ) -> CallResult<ID> {
+        let realm = match self.info.realms.lock().unwrap().get(realm) {
+            Some(realm) => Arc::clone(realm),
+            None => return Err(CallError::new(Reason::NoSuchRealm, None, None)),
+        };
+        let mut realm = realm.lock().unwrap();
+        let manager = &mut realm.registration_manager;
+        let connection_id = self.info.id_generator.next_id();
+        let connection = Arc::new(Mutex::new(ConnectionInfo {
+            state: ConnectionState::Connected,
+            sender: ConnectionSender::Local,
+            serializer: None,
+            id: connection_id,
+            authid: None,
+            authrole: None,
+            vouched: false,
+        }));
+        let procedure_id = manager
+            .registrations
+            .register_with(
+                &procedure,
+                connection,
+                MatchingPolicy::Strict,
+                InvocationPolicy::Single,
+            )
+            .map_err(|e| CallError::new(e.reason(), None, None))?;
+        manager
+            .registration_ids_to_uris
+            .insert(procedure_id, (procedure.uri, false, Dict::new()));
+        manager.local_handlers.insert(connection_id, handler);
+        debug!("Registered local procedure {}", procedure_id);
+        Ok(procedure_id)
+    }
+
+    /// Connect a client to `realm` through an in-process loopback instead of a socket.
+    ///
+    /// Messages are handed between the router and the client as they are, without being
+    /// framed or serialized, which makes for fast tests that don't need a free port.
+    #[cfg(feature = "ws-client")]
+    pub fn connect_loopback(&self, realm: &str) -> WampResult<Client> {
+        let (router_end, client_end) = loopback::pair();
+        let loopback::Endpoint { sender, events } = router_end;
+        let mut handler =
+            ConnectionHandler::new(Arc::clone(&self.info), ConnectionSender::Loopback(sender));
+        thread::spawn(move || handler.run_loopback(events));
+        Connection::new("", realm).connect_loopback(client_end)
+    }
+
+    /// Number of sessions attached to `realm`, or `None` if there is no such realm
+    pub fn connection_count(&self, realm: &str) -> Option<usize> {
+        self.stats(realm).map(|stats| stats.connections)
+    }
+
+    /// Current activity within `realm`, or `None` if there is no such realm
+    pub fn stats(&self, realm: &str) -> Option<RealmStats> {
+        let realm = Arc::clone(self.info.realms.lock().unwrap().get(realm)?);
+        let realm = realm.lock().unwrap();
+        Some(RealmStats {
+            connections: realm.connections.len(),
+            subscriptions: realm.subscription_manager.subscriptions.subscription_count(),
+            registrations: realm.registration_manager.registrations.registration_count(),
+            active_calls: realm.registration_manager.active_calls.len(),
+        })
+    }
+
+    /// Refuse new calls and publications with `Reason::SystemShutdown`, wait up to `timeout`
+    /// for the calls already forwarded to a callee to be answered, then shut down.
+    pub fn drain(&self, timeout: Duration) {
+        self.info.draining.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + timeout;
+        while self.has_pending_calls() {
+            if Instant::now() >= deadline {
+                info!("Drain timed out with calls still pending");
+                break;
+            }
+            thread::sleep(DRAIN_POLL_INTERVAL);
+        }
+        self.shutdown();
+    }
+
+    /// Whether any realm has calls that haven't been answered yet
+    fn has_pending_calls(&self) -> bool {
+        self.info.realms.lock().unwrap().values().any(|realm| {
+            let realm = realm.lock().unwrap();
+            let manager = &realm.registration_manager;
+            !manager.active_calls.is_empty() || !manager.queued_calls.is_empty()
+        })
+    }
+
     /// Shut down the router gracefully
     pub fn shutdown(&self) {
+        let mut has_connections = false;
         for realm in self.info.realms.lock().unwrap().values() {
             for connection in &realm.lock().unwrap().connections {
+                has_connections = true;
                 send_message(
                     connection,
                     &Message::Goodbye(ErrorDetails::new(), Reason::SystemShutdown),
@@ -330,25 +840,116 @@ impl Router {
                 connection.state = ConnectionState::ShuttingDown;
             }
         }
-        info!("Goodbye messages sent.  Waiting 5 seconds for response");
-        thread::sleep(Duration::from_secs(5));
+        if has_connections {
+            info!("Goodbye messages sent.  Waiting 5 seconds for response");
+            thread::sleep(Duration::from_secs(5));
+        }
         for realm in self.info.realms.lock().unwrap().values() {
             for connection in &realm.lock().unwrap().connections {
                 let connection = connection.lock().unwrap();
                 connection.sender.shutdown().ok();
             }
         }
+        for listener in self.info.listeners.lock().unwrap().drain(..) {
+            listener.shutdown().ok();
+        }
+    }
+}
+
+impl Realm {
+    /// Drops `connection` from the realm without waiting for its handler to clean up, for a
+    /// session whose state can no longer be trusted
+    fn evict(&mut self, connection: &Arc<Mutex<ConnectionInfo>>) {
+        let subscriptions = &mut self.subscription_manager;
+        for &(ref topic_uri, is_prefix) in subscriptions.subscription_ids_to_uris.values() {
+            subscriptions
+                .subscriptions
+                .unsubscribe_with(topic_uri, connection, is_prefix)
+                .ok();
+        }
+        let registrations = &mut self.registration_manager;
+        for &(ref topic_uri, is_prefix, _) in registrations.registration_ids_to_uris.values() {
+            registrations
+                .registrations
+                .vacate_with(topic_uri, connection, is_prefix)
+                .ok();
+        }
+        self.connections
+            .retain(|other| !Arc::ptr_eq(other, connection));
+    }
+}
+
+impl ConnectionSender {
+    fn close(&self, code: CloseCode) -> WSResult<()> {
+        match *self {
+            ConnectionSender::WebSocket(ref outbox) => outbox.close(code),
+            ConnectionSender::Loopback(ref sender) => {
+                sender.close();
+                Ok(())
+            }
+            ConnectionSender::Local => Ok(()),
+        }
+    }
+
+    fn shutdown(&self) -> WSResult<()> {
+        match *self {
+            ConnectionSender::WebSocket(ref outbox) => outbox.shutdown(),
+            ConnectionSender::Loopback(ref sender) => {
+                sender.close();
+                Ok(())
+            }
+            ConnectionSender::Local => Ok(()),
+        }
+    }
+
+    fn ping(&self) -> WSResult<()> {
+        match *self {
+            ConnectionSender::WebSocket(ref outbox) => outbox.ping(),
+            ConnectionSender::Local | ConnectionSender::Loopback(_) => Ok(()),
+        }
+    }
+
+    fn timeout(&self, ms: u64, token: Token) -> WSResult<()> {
+        match *self {
+            ConnectionSender::WebSocket(ref outbox) => outbox.timeout(ms, token),
+            // Loopback connections can't go quiet, so they are never pinged
+            ConnectionSender::Local | ConnectionSender::Loopback(_) => Ok(()),
+        }
     }
 }
 
 impl ConnectionHandler {
+    fn new(router: Arc<RouterInfo>, sender: ConnectionSender) -> ConnectionHandler {
+        ConnectionHandler {
+            info: Arc::new(Mutex::new(ConnectionInfo {
+                state: ConnectionState::Initializing,
+                sender,
+                serializer: None,
+                id: router.id_generator.next_id(),
+                authid: None,
+                authrole: None,
+                vouched: false,
+            })),
+            subscribed_topics: Vec::new(),
+            registered_procedures: Vec::new(),
+            missed_pings: 0,
+            path_realm: None,
+            cookie: None,
+            realm: None,
+            router,
+        }
+    }
+
+    /// Detaches the session from its realm.  Everything happens under one lock of the realm,
+    /// so no call or event can be routed to the session while it is half removed.
     fn remove(&mut self) {
         if let Some(ref realm) = self.realm {
+            let my_id = connection_id(&self.info);
             let mut realm = realm.lock().unwrap();
             {
                 trace!(
                     "Removing subscriptions for client {}",
-                    self.info.lock().unwrap().id
+                    connection_id(&self.info)
                 );
                 let manager = &mut realm.subscription_manager;
                 for subscription_id in &self.subscribed_topics {
@@ -363,25 +964,34 @@ impl ConnectionHandler {
                             .ok();
                         trace!("Subscription tree: {:?}", manager.subscriptions);
                     }
+                    manager.keep_events_while_away(*subscription_id, &self.info);
                 }
             }
             {
                 let manager = &mut realm.registration_manager;
                 for registration_id in &self.registered_procedures {
-                    if let Some(&(ref topic_uri, is_prefix)) =
+                    if let Some(&(ref topic_uri, is_prefix, _)) =
                         manager.registration_ids_to_uris.get(registration_id)
                     {
                         manager
                             .registrations
-                            .unregister_with(topic_uri, &self.info, is_prefix)
+                            .vacate_with(topic_uri, &self.info, is_prefix)
                             .ok();
                     }
                 }
+                manager
+                    .disclosing_registrants
+                    .retain(|&(_, callee_id)| callee_id != my_id);
+                manager
+                    .queued_calls
+                    .retain(|call| call.caller_id() != my_id);
+                manager.abandon_invocations(my_id);
+                // Calls queued for this session's registrations go elsewhere or fail now
+                manager.dispatch_queued_calls();
             }
-            let my_id = self.info.lock().unwrap().id;
             realm
                 .connections
-                .retain(|connection| connection.lock().unwrap().id != my_id);
+                .retain(|connection| connection_id(connection) != my_id);
         }
     }
 
@@ -389,4 +999,1226 @@ impl ConnectionHandler {
         self.remove();
         Ok(())
     }
+
+    /// Refuses the arguments of a CALL or PUBLISH that exceed the router's limits
+    fn check_argument_lengths(
+        &self,
+        error_type: ErrorType,
+        request_id: ID,
+        args: &Option<List>,
+        kwargs: &Option<Dict>,
+    ) -> WampResult<()> {
+        let args_len = args.as_ref().map_or(0, Vec::len);
+        let kwargs_len = kwargs.as_ref().map_or(0, HashMap::len);
+        if args_len > self.router.max_args_len.load(Ordering::Relaxed)
+            || kwargs_len > self.router.max_kwargs_len.load(Ordering::Relaxed)
+        {
+            warn!(
+                "Refusing {} positional and {} keyword arguments from session {}",
+                args_len,
+                kwargs_len,
+                connection_id(&self.info)
+            );
+            return Err(Error::new(ErrorKind::ErrorReason(
+                error_type,
+                request_id,
+                Reason::InvalidArgument,
+            )));
+        }
+        Ok(())
+    }
+
+    fn schedule_ping(&self) -> WSResult<()> {
+        let interval = self.router.ping_interval_ms.load(Ordering::Relaxed);
+        if interval == 0 {
+            return Ok(());
+        }
+        self.info
+            .lock()
+            .unwrap()
+            .sender
+            .timeout(interval, PING_TIMEOUT)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    #[cfg(feature = "ws-client")]
+    use futures::{executor::block_on, StreamExt};
+    use parity_ws::{Handler, Request};
+
+    use super::{
+        Authorizer, ConnectionHandler, ConnectionSender, RealmOptions, Router, RouterErrorKind,
+        SequentialIdGenerator,
+    };
+    use crate::{
+        messages::{
+            CallOptions, ClientRoles, ErrorDetails, ErrorType, HelloDetails, Message,
+            PublishOptions, RegisterOptions, RunMode, SubscribeOptions, YieldOptions,
+        },
+        transport::loopback::{self, Event},
+        InvocationPolicy, Reason, Value, ID, URI,
+    };
+
+    /// Attaches a session to `realm` that sends and receives raw messages
+    fn raw_session(router: &Router, realm: &str) -> loopback::Endpoint {
+        raw_session_with_id(router, realm).1
+    }
+
+    /// Like `raw_session`, also returning the session id
+    fn raw_session_with_id(router: &Router, realm: &str) -> (ID, loopback::Endpoint) {
+        let (router_end, session) = loopback::pair();
+        let loopback::Endpoint { sender, events } = router_end;
+        let mut handler =
+            ConnectionHandler::new(Arc::clone(&router.info), ConnectionSender::Loopback(sender));
+        thread::spawn(move || handler.run_loopback(events));
+        session
+            .sender
+            .send(Message::Hello(
+                URI::new(realm),
+                HelloDetails::new(ClientRoles::new()),
+            ))
+            .unwrap();
+        match receive(&session) {
+            Message::Welcome(session_id, _) => (session_id, session),
+            message => panic!("Expected a welcome, got {:?}", message),
+        }
+    }
+
+    fn receive(session: &loopback::Endpoint) -> Message {
+        match session.events.recv().unwrap() {
+            Event::Message(message) => message,
+            event => panic!("Expected a message, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn register_local() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+
+        router
+            .register_local(
+                "ca.test.realm",
+                URI::new("ca.test.local"),
+                Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+            )
+            .unwrap();
+
+        let error = router
+            .register_local(
+                "ca.test.realm",
+                URI::new("ca.test.local"),
+                Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+            )
+            .unwrap_err();
+        assert_eq!(*error.get_reason(), Reason::ProcedureAlreadyExists);
+
+        let error = router
+            .register_local(
+                "ca.test.missing",
+                URI::new("ca.test.local"),
+                Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+            )
+            .unwrap_err();
+        assert_eq!(*error.get_reason(), Reason::NoSuchRealm);
+    }
+
+    #[cfg(feature = "ws-client")]
+    #[test]
+    fn progressive_call_results() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+
+        // The client can't yield progressive results yet, so the callee speaks raw messages
+        let callee = raw_session(&router, "ca.test.realm");
+        callee
+            .sender
+            .send(Message::Register(
+                1,
+                RegisterOptions::new(),
+                URI::new("ca.test.countdown"),
+            ))
+            .unwrap();
+        assert!(matches!(receive(&callee), Message::Registered(1, _)));
+
+        let mut caller = router.connect_loopback("ca.test.realm").unwrap();
+        let results = caller.call_progressive(URI::new("ca.test.countdown"), None, None);
+        let invocation_id = match receive(&callee) {
+            Message::Invocation(invocation_id, _, details, ..) => {
+                assert!(details.receive_progress);
+                invocation_id
+            }
+            message => panic!("Expected an invocation, got {:?}", message),
+        };
+        for count in (1..=3).rev() {
+            let mut options = YieldOptions::new();
+            options.progress = count > 1;
+            callee
+                .sender
+                .send(Message::Yield(
+                    invocation_id,
+                    options,
+                    Some(vec![Value::Integer(count)]),
+                    None,
+                ))
+                .unwrap();
+        }
+
+        let results = block_on(results.map(|result| result.unwrap().0).collect::<Vec<_>>());
+        assert_eq!(
+            results,
+            vec![
+                vec![Value::Integer(3)],
+                vec![Value::Integer(2)],
+                vec![Value::Integer(1)],
+            ]
+        );
+    }
+
+    #[cfg(feature = "ws-client")]
+    #[test]
+    fn call_all_collects_every_result() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+        let callees = (1..=3)
+            .map(|request_id| {
+                let (session_id, callee) = raw_session_with_id(&router, "ca.test.realm");
+                let mut options = RegisterOptions::new();
+                options.invocation_policy = InvocationPolicy::RoundRobin;
+                callee
+                    .sender
+                    .send(Message::Register(
+                        request_id,
+                        options,
+                        URI::new("ca.test.shared"),
+                    ))
+                    .unwrap();
+                assert!(matches!(receive(&callee), Message::Registered(..)));
+                (session_id, callee)
+            })
+            .collect::<Vec<_>>();
+
+        let mut caller = router.connect_loopback("ca.test.realm").unwrap();
+        let result = caller.call_all(URI::new("ca.test.shared"), None, None);
+        for (session_id, callee) in &callees {
+            let invocation_id = match receive(callee) {
+                Message::Invocation(invocation_id, ..) => invocation_id,
+                message => panic!("Expected an invocation, got {:?}", message),
+            };
+            callee
+                .sender
+                .send(Message::Yield(
+                    invocation_id,
+                    YieldOptions::new(),
+                    Some(vec![Value::UnsignedInteger(*session_id * 10)]),
+                    None,
+                ))
+                .unwrap();
+        }
+
+        let (results, _) = block_on(result).unwrap();
+        assert_eq!(results.len(), 3);
+        for (session_id, _) in &callees {
+            let entry = results
+                .iter()
+                .find_map(|entry| match entry {
+                    Value::Dict(entry)
+                        if entry.get("callee") == Some(&Value::UnsignedInteger(*session_id)) =>
+                    {
+                        Some(entry)
+                    }
+                    _ => None,
+                })
+                .unwrap();
+            assert_eq!(
+                entry.get("args"),
+                Some(&Value::List(vec![Value::UnsignedInteger(*session_id * 10)]))
+            );
+        }
+    }
+
+    #[cfg(feature = "ws-client")]
+    #[test]
+    fn callee_disclosed_when_enabled() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+        let (callee_id, callee) = raw_session_with_id(&router, "ca.test.realm");
+        callee
+            .sender
+            .send(Message::Register(
+                1,
+                RegisterOptions::new(),
+                URI::new("ca.test.echo"),
+            ))
+            .unwrap();
+        assert!(matches!(receive(&callee), Message::Registered(1, _)));
+        let answer = |callee: &loopback::Endpoint| match receive(callee) {
+            Message::Invocation(invocation_id, ..) => callee
+                .sender
+                .send(Message::Yield(
+                    invocation_id,
+                    YieldOptions::new(),
+                    None,
+                    None,
+                ))
+                .unwrap(),
+            message => panic!("Expected an invocation, got {:?}", message),
+        };
+
+        let mut caller = router.connect_loopback("ca.test.realm").unwrap();
+        let result = caller.call_with_details(URI::new("ca.test.echo"), None, None);
+        answer(&callee);
+        let (_, _, details) = block_on(result).unwrap();
+        assert_eq!(details.callee, None);
+
+        router.set_disclose_callee(true);
+        let result = caller.call_with_details(URI::new("ca.test.echo"), None, None);
+        answer(&callee);
+        let (_, _, details) = block_on(result).unwrap();
+        assert_eq!(details.callee, Some(callee_id));
+    }
+
+    #[test]
+    fn caller_disclosed_to_registrants_asking_for_it() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+        let callee = raw_session(&router, "ca.test.realm");
+        let mut options = RegisterOptions::new();
+        options.disclose_caller = true;
+        callee
+            .sender
+            .send(Message::Register(1, options, URI::new("ca.test.who")))
+            .unwrap();
+        assert!(matches!(receive(&callee), Message::Registered(1, _)));
+        callee
+            .sender
+            .send(Message::Register(
+                2,
+                RegisterOptions::new(),
+                URI::new("ca.test.anonymous"),
+            ))
+            .unwrap();
+        assert!(matches!(receive(&callee), Message::Registered(2, _)));
+
+        let (caller_id, caller) = raw_session_with_id(&router, "ca.test.realm");
+        for (request_id, procedure, expected) in [
+            (3, "ca.test.who", Some(caller_id)),
+            (4, "ca.test.anonymous", None),
+        ] {
+            caller
+                .sender
+                .send(Message::Call(
+                    request_id,
+                    CallOptions::new(),
+                    URI::new(procedure),
+                    None,
+                    None,
+                ))
+                .unwrap();
+            match receive(&callee) {
+                Message::Invocation(_, _, details, ..) => assert_eq!(details.caller, expected),
+                message => panic!("Expected an invocation, got {:?}", message),
+            }
+        }
+    }
+
+    #[test]
+    fn yield_details_reach_caller() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+        let callee = raw_session(&router, "ca.test.realm");
+        callee
+            .sender
+            .send(Message::Register(
+                1,
+                RegisterOptions::new(),
+                URI::new("ca.test.traced"),
+            ))
+            .unwrap();
+        assert!(matches!(receive(&callee), Message::Registered(1, _)));
+
+        let caller = raw_session(&router, "ca.test.realm");
+        caller
+            .sender
+            .send(Message::Call(
+                2,
+                CallOptions::new(),
+                URI::new("ca.test.traced"),
+                None,
+                None,
+            ))
+            .unwrap();
+        let invocation_id = match receive(&callee) {
+            Message::Invocation(invocation_id, ..) => invocation_id,
+            message => panic!("Expected an invocation, got {:?}", message),
+        };
+        let mut options = YieldOptions::new();
+        options
+            .custom
+            .insert("_trace".to_string(), Value::String("abc".to_string()));
+        options
+            .custom
+            .insert("trace".to_string(), Value::String("abc".to_string()));
+        callee
+            .sender
+            .send(Message::Yield(invocation_id, options, None, None))
+            .unwrap();
+
+        match receive(&caller) {
+            Message::Result(2, details, ..) => {
+                assert_eq!(details.custom.len(), 1);
+                assert_eq!(details.custom["_trace"], Value::String("abc".to_string()));
+            }
+            message => panic!("Expected a result, got {:?}", message),
+        }
+    }
+
+    #[test]
+    fn concurrency_limit_queues_calls() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+        let callee = raw_session(&router, "ca.test.realm");
+        let mut options = RegisterOptions::new();
+        options.concurrency = Some(1);
+        callee
+            .sender
+            .send(Message::Register(1, options, URI::new("ca.test.limited")))
+            .unwrap();
+        assert!(matches!(receive(&callee), Message::Registered(1, _)));
+
+        let caller = raw_session(&router, "ca.test.realm");
+        for request_id in 2..=3 {
+            caller
+                .sender
+                .send(Message::Call(
+                    request_id,
+                    CallOptions::new(),
+                    URI::new("ca.test.limited"),
+                    None,
+                    None,
+                ))
+                .unwrap();
+        }
+        let first = match receive(&callee) {
+            Message::Invocation(invocation_id, ..) => invocation_id,
+            message => panic!("Expected an invocation, got {:?}", message),
+        };
+        // The second call waits until the first one is answered
+        assert!(callee
+            .events
+            .recv_timeout(Duration::from_millis(200))
+            .is_err());
+
+        callee
+            .sender
+            .send(Message::Yield(first, YieldOptions::new(), None, None))
+            .unwrap();
+        assert!(matches!(receive(&caller), Message::Result(2, ..)));
+        let second = match receive(&callee) {
+            Message::Invocation(invocation_id, ..) => invocation_id,
+            message => panic!("Expected an invocation, got {:?}", message),
+        };
+        callee
+            .sender
+            .send(Message::Yield(second, YieldOptions::new(), None, None))
+            .unwrap();
+        assert!(matches!(receive(&caller), Message::Result(3, ..)));
+    }
+
+    /// Registers and calls a procedure, returning the ids the router handed out along the way
+    fn assigned_ids(router: &Router) -> Vec<ID> {
+        let callee = raw_session(router, "ca.test.realm");
+        callee
+            .sender
+            .send(Message::Register(
+                1,
+                RegisterOptions::new(),
+                URI::new("ca.test.ids"),
+            ))
+            .unwrap();
+        let registration_id = match receive(&callee) {
+            Message::Registered(1, registration_id) => registration_id,
+            message => panic!("Expected a registration, got {:?}", message),
+        };
+        let caller = raw_session(router, "ca.test.realm");
+        caller
+            .sender
+            .send(Message::Call(
+                2,
+                CallOptions::new(),
+                URI::new("ca.test.ids"),
+                None,
+                None,
+            ))
+            .unwrap();
+        let invocation_id = match receive(&callee) {
+            Message::Invocation(invocation_id, ..) => invocation_id,
+            message => panic!("Expected an invocation, got {:?}", message),
+        };
+        vec![registration_id, invocation_id]
+    }
+
+    #[test]
+    fn sequential_ids_repeat_across_routers() {
+        let ids: Vec<Vec<ID>> = (0..2)
+            .map(|_| {
+                let mut router =
+                    Router::new_with_id_generator(Box::new(SequentialIdGenerator::new(1)));
+                router.add_realm("ca.test.realm");
+                assigned_ids(&router)
+            })
+            .collect();
+        assert_eq!(ids[0], ids[1]);
+        assert!(ids[0].iter().all(|&id| id < 100));
+    }
+
+    /// Registers two callees to a shared procedure that always picks the first of them
+    fn shared_callees(router: &Router) -> [(ID, loopback::Endpoint); 2] {
+        let register = |request_id| {
+            let (session_id, callee) = raw_session_with_id(router, "ca.test.realm");
+            let mut options = RegisterOptions::new();
+            options.invocation_policy = InvocationPolicy::First;
+            callee
+                .sender
+                .send(Message::Register(
+                    request_id,
+                    options,
+                    URI::new("ca.test.shared"),
+                ))
+                .unwrap();
+            assert!(matches!(receive(&callee), Message::Registered(..)));
+            (session_id, callee)
+        };
+        [register(1), register(2)]
+    }
+
+    #[test]
+    fn partition_call_targets_registrant() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+        let [(_, first), (second_id, second)] = shared_callees(&router);
+
+        let caller = raw_session(&router, "ca.test.realm");
+        let mut options = CallOptions::new();
+        options.runmode = Some(RunMode::Partition);
+        options.rkey = Some(second_id);
+        caller
+            .sender
+            .send(Message::Call(
+                3,
+                options,
+                URI::new("ca.test.shared"),
+                None,
+                None,
+            ))
+            .unwrap();
+        let invocation_id = match receive(&second) {
+            Message::Invocation(invocation_id, ..) => invocation_id,
+            message => panic!("Expected an invocation, got {:?}", message),
+        };
+        assert!(first
+            .events
+            .recv_timeout(Duration::from_millis(200))
+            .is_err());
+        second
+            .sender
+            .send(Message::Yield(
+                invocation_id,
+                YieldOptions::new(),
+                None,
+                None,
+            ))
+            .unwrap();
+        assert!(matches!(receive(&caller), Message::Result(3, ..)));
+
+        // A session that isn't a registrant can't be targeted
+        let mut options = CallOptions::new();
+        options.runmode = Some(RunMode::Partition);
+        options.rkey = Some(second_id + 1);
+        caller
+            .sender
+            .send(Message::Call(
+                4,
+                options,
+                URI::new("ca.test.shared"),
+                None,
+                None,
+            ))
+            .unwrap();
+        match receive(&caller) {
+            Message::Error(ErrorType::Call, 4, _, reason, ..) => {
+                assert_eq!(reason, Reason::NoEligibleCallee)
+            }
+            message => panic!("Expected an error, got {:?}", message),
+        }
+    }
+
+    #[test]
+    fn fan_out_call_aggregates_results() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+        let [(first_id, first), (second_id, second)] = shared_callees(&router);
+
+        let caller = raw_session(&router, "ca.test.realm");
+        let mut options = CallOptions::new();
+        options.runmode = Some(RunMode::All);
+        caller
+            .sender
+            .send(Message::Call(
+                3,
+                options,
+                URI::new("ca.test.shared"),
+                Some(vec![Value::Integer(1)]),
+                None,
+            ))
+            .unwrap();
+        let invocation_of = |callee: &loopback::Endpoint| match receive(callee) {
+            Message::Invocation(invocation_id, _, _, args, _) => {
+                assert_eq!(args, Some(vec![Value::Integer(1)]));
+                invocation_id
+            }
+            message => panic!("Expected an invocation, got {:?}", message),
+        };
+        let first_invocation = invocation_of(&first);
+        let second_invocation = invocation_of(&second);
+
+        first
+            .sender
+            .send(Message::Yield(
+                first_invocation,
+                YieldOptions::new(),
+                Some(vec![Value::String("first".to_string())]),
+                None,
+            ))
+            .unwrap();
+        // Nothing reaches the caller until every registrant has answered
+        assert!(caller
+            .events
+            .recv_timeout(Duration::from_millis(200))
+            .is_err());
+        second
+            .sender
+            .send(Message::Error(
+                ErrorType::Invocation,
+                second_invocation,
+                HashMap::new(),
+                Reason::InvalidArgument,
+                None,
+                None,
+            ))
+            .unwrap();
+
+        let results = match receive(&caller) {
+            Message::Result(3, _, Some(results), _) => results,
+            message => panic!("Expected a result, got {:?}", message),
+        };
+        assert_eq!(results.len(), 2);
+        let entry_for = |callee_id| {
+            results
+                .iter()
+                .find_map(|entry| match entry {
+                    Value::Dict(entry)
+                        if entry.get("callee") == Some(&Value::UnsignedInteger(callee_id)) =>
+                    {
+                        Some(entry)
+                    }
+                    _ => None,
+                })
+                .unwrap()
+        };
+        assert_eq!(
+            entry_for(first_id).get("args"),
+            Some(&Value::List(vec![Value::String("first".to_string())]))
+        );
+        assert_eq!(entry_for(first_id).get("error"), None);
+        assert_eq!(
+            entry_for(second_id).get("error"),
+            Some(&Value::String("wamp.error.invalid_argument".to_string()))
+        );
+    }
+
+    #[test]
+    fn fan_out_call_counts_unreachable_registrant_as_failed() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+        let [(first_id, first), (second_id, second)] = shared_callees(&router);
+        // The second registrant stays connected but can no longer be sent anything
+        let loopback::Endpoint {
+            sender: _second_sender,
+            events,
+        } = second;
+        drop(events);
+
+        let caller = raw_session(&router, "ca.test.realm");
+        let mut options = CallOptions::new();
+        options.runmode = Some(RunMode::All);
+        caller
+            .sender
+            .send(Message::Call(
+                3,
+                options,
+                URI::new("ca.test.shared"),
+                None,
+                None,
+            ))
+            .unwrap();
+        let invocation_id = match receive(&first) {
+            Message::Invocation(invocation_id, ..) => invocation_id,
+            message => panic!("Expected an invocation, got {:?}", message),
+        };
+        first
+            .sender
+            .send(Message::Yield(
+                invocation_id,
+                YieldOptions::new(),
+                None,
+                None,
+            ))
+            .unwrap();
+
+        let results = match receive(&caller) {
+            Message::Result(3, _, Some(results), _) => results,
+            message => panic!("Expected a result, got {:?}", message),
+        };
+        assert_eq!(results.len(), 2);
+        let error_of = |callee_id| {
+            results.iter().find_map(|entry| match entry {
+                Value::Dict(entry)
+                    if entry.get("callee") == Some(&Value::UnsignedInteger(callee_id)) =>
+                {
+                    Some(entry.get("error").cloned())
+                }
+                _ => None,
+            })
+        };
+        assert_eq!(error_of(first_id), Some(None));
+        assert_eq!(
+            error_of(second_id),
+            Some(Some(Value::String(
+                "wamp.error.no_eligible_callee".to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn yield_for_unknown_invocation_is_ignored() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+        let callee = raw_session(&router, "ca.test.realm");
+        callee
+            .sender
+            .send(Message::Yield(42, YieldOptions::new(), None, None))
+            .unwrap();
+        callee
+            .sender
+            .send(Message::Register(
+                1,
+                RegisterOptions::new(),
+                URI::new("ca.test.after_yield"),
+            ))
+            .unwrap();
+        assert!(matches!(receive(&callee), Message::Registered(1, _)));
+    }
+
+    #[test]
+    fn error_for_unknown_invocation_is_ignored() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+        let callee = raw_session(&router, "ca.test.realm");
+        callee
+            .sender
+            .send(Message::Error(
+                ErrorType::Invocation,
+                42,
+                HashMap::new(),
+                Reason::InvalidArgument,
+                None,
+                None,
+            ))
+            .unwrap();
+        callee
+            .sender
+            .send(Message::Register(
+                1,
+                RegisterOptions::new(),
+                URI::new("ca.test.after_error"),
+            ))
+            .unwrap();
+        assert!(matches!(receive(&callee), Message::Registered(1, _)));
+    }
+
+    #[test]
+    fn only_the_invoked_callee_answers() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+        let callee = raw_session(&router, "ca.test.realm");
+        callee
+            .sender
+            .send(Message::Register(
+                1,
+                RegisterOptions::new(),
+                URI::new("ca.test.procedure"),
+            ))
+            .unwrap();
+        assert!(matches!(receive(&callee), Message::Registered(1, _)));
+
+        let caller = raw_session(&router, "ca.test.realm");
+        caller
+            .sender
+            .send(Message::Call(
+                2,
+                CallOptions::new(),
+                URI::new("ca.test.procedure"),
+                None,
+                None,
+            ))
+            .unwrap();
+        let invocation_id = match receive(&callee) {
+            Message::Invocation(invocation_id, ..) => invocation_id,
+            message => panic!("Expected an invocation, got {:?}", message),
+        };
+
+        // Another session can neither answer nor fail the invocation
+        let impostor = raw_session(&router, "ca.test.realm");
+        impostor
+            .sender
+            .send(Message::Yield(
+                invocation_id,
+                YieldOptions::new(),
+                Some(vec![Value::String("impostor".to_string())]),
+                None,
+            ))
+            .unwrap();
+        impostor
+            .sender
+            .send(Message::Error(
+                ErrorType::Invocation,
+                invocation_id,
+                HashMap::new(),
+                Reason::InvalidArgument,
+                None,
+                None,
+            ))
+            .unwrap();
+        // Sessions are served in parallel, so wait until the impostor's messages were handled
+        impostor
+            .sender
+            .send(Message::Register(
+                3,
+                RegisterOptions::new(),
+                URI::new("ca.test.impostor"),
+            ))
+            .unwrap();
+        assert!(matches!(receive(&impostor), Message::Registered(3, _)));
+        callee
+            .sender
+            .send(Message::Yield(
+                invocation_id,
+                YieldOptions::new(),
+                Some(vec![Value::String("callee".to_string())]),
+                None,
+            ))
+            .unwrap();
+        match receive(&caller) {
+            Message::Result(2, _, args, _) => {
+                assert_eq!(args, Some(vec![Value::String("callee".to_string())]))
+            }
+            message => panic!("Expected a result, got {:?}", message),
+        }
+    }
+
+    #[test]
+    fn callee_outlives_caller_it_cannot_answer() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let reported = Arc::clone(&errors);
+        router.on_error(Box::new(move |error| reported.lock().unwrap().push(error)));
+        let callee = raw_session(&router, "ca.test.realm");
+        callee
+            .sender
+            .send(Message::Register(
+                1,
+                RegisterOptions::new(),
+                URI::new("ca.test.procedure"),
+            ))
+            .unwrap();
+        assert!(matches!(receive(&callee), Message::Registered(1, _)));
+
+        let caller = raw_session(&router, "ca.test.realm");
+        caller
+            .sender
+            .send(Message::Call(
+                2,
+                CallOptions::new(),
+                URI::new("ca.test.procedure"),
+                None,
+                None,
+            ))
+            .unwrap();
+        let invocation_id = match receive(&callee) {
+            Message::Invocation(invocation_id, ..) => invocation_id,
+            message => panic!("Expected an invocation, got {:?}", message),
+        };
+        // The caller can't be sent anything by the time the callee answers
+        let loopback::Endpoint {
+            sender: _caller_sender,
+            events,
+        } = caller;
+        drop(events);
+        callee
+            .sender
+            .send(Message::Yield(
+                invocation_id,
+                YieldOptions::new(),
+                None,
+                None,
+            ))
+            .unwrap();
+
+        // The callee's session carries on
+        callee
+            .sender
+            .send(Message::Register(
+                3,
+                RegisterOptions::new(),
+                URI::new("ca.test.after_yield"),
+            ))
+            .unwrap();
+        assert!(matches!(receive(&callee), Message::Registered(3, _)));
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, RouterErrorKind::SendFailed);
+    }
+
+    #[test]
+    fn disconnecting_callee_fails_its_invocations() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+        let callee = raw_session(&router, "ca.test.realm");
+        callee
+            .sender
+            .send(Message::Register(
+                1,
+                RegisterOptions::new(),
+                URI::new("ca.test.slow"),
+            ))
+            .unwrap();
+        assert!(matches!(receive(&callee), Message::Registered(1, _)));
+
+        let caller = raw_session(&router, "ca.test.realm");
+        caller
+            .sender
+            .send(Message::Call(
+                1,
+                CallOptions::new(),
+                URI::new("ca.test.slow"),
+                None,
+                None,
+            ))
+            .unwrap();
+        assert!(matches!(receive(&callee), Message::Invocation(..)));
+        callee.sender.close();
+
+        assert!(matches!(
+            receive(&caller),
+            Message::Error(ErrorType::Call, 1, _, Reason::NoEligibleCallee, None, None)
+        ));
+        assert_eq!(router.stats("ca.test.realm").unwrap().active_calls, 0);
+    }
+
+    #[test]
+    fn unreachable_callee_fails_the_call() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+        let callee = raw_session(&router, "ca.test.realm");
+        callee
+            .sender
+            .send(Message::Register(
+                1,
+                RegisterOptions::new(),
+                URI::new("ca.test.unreachable"),
+            ))
+            .unwrap();
+        assert!(matches!(receive(&callee), Message::Registered(1, _)));
+        // The callee stops reading, so invocations can't be delivered, while its session
+        // stays open
+        drop(callee.events);
+
+        let caller = raw_session(&router, "ca.test.realm");
+        caller
+            .sender
+            .send(Message::Call(
+                1,
+                CallOptions::new(),
+                URI::new("ca.test.unreachable"),
+                None,
+                None,
+            ))
+            .unwrap();
+        assert!(matches!(
+            receive(&caller),
+            Message::Error(ErrorType::Call, 1, _, Reason::NoEligibleCallee, None, None)
+        ));
+        // The caller's session is unaffected
+        caller
+            .sender
+            .send(Message::Register(
+                2,
+                RegisterOptions::new(),
+                URI::new("ca.test.after_failure"),
+            ))
+            .unwrap();
+        assert!(matches!(receive(&caller), Message::Registered(2, _)));
+    }
+
+    #[test]
+    fn session_cookie_carries_identity() {
+        fn handshake(
+            router: &Router,
+            cookie: Option<&str>,
+        ) -> (ConnectionHandler, loopback::Endpoint, String) {
+            let mut request = String::from(
+                "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\n\
+                 Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                 Sec-WebSocket-Protocol: wamp.2.json\r\n",
+            );
+            if let Some(cookie) = cookie {
+                request.push_str(&format!("Cookie: theme=dark; {}\r\n", cookie));
+            }
+            request.push_str("\r\n");
+            let request = Request::parse(request.as_bytes()).unwrap().unwrap();
+            let (router_end, session) = loopback::pair();
+            let mut handler = ConnectionHandler::new(
+                Arc::clone(&router.info),
+                ConnectionSender::Loopback(router_end.sender),
+            );
+            let response = handler.on_request(&request).unwrap();
+            let set_cookie = response
+                .headers()
+                .iter()
+                .find(|(name, _)| name == "Set-Cookie")
+                .map(|(_, value)| String::from_utf8(value.clone()).unwrap())
+                .expect("No session cookie");
+            let cookie = set_cookie.split(';').next().unwrap().to_string();
+            (handler, session, cookie)
+        }
+
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+
+        let (mut handler, _session, cookie) = handshake(&router, None);
+        assert!(cookie.starts_with("wampire_session="));
+        let mut details = HelloDetails::new(ClientRoles::new());
+        details.authid = Some("ca.test.user".to_string());
+        handler
+            .handle_hello(URI::new("ca.test.realm"), details)
+            .unwrap();
+
+        // The cookie is echoed back and the identity resumed
+        let (mut handler, _session, resumed) = handshake(&router, Some(&cookie));
+        assert_eq!(resumed, cookie);
+        handler
+            .handle_hello(
+                URI::new("ca.test.realm"),
+                HelloDetails::new(ClientRoles::new()),
+            )
+            .unwrap();
+        assert_eq!(
+            handler.info.lock().unwrap().authid.as_deref(),
+            Some("ca.test.user")
+        );
+
+        // A cookie the router never handed out is replaced
+        let (_, _, replaced) = handshake(&router, Some("wampire_session=forged"));
+        assert_ne!(replaced, "wampire_session=forged");
+
+        // Connections that never join, or join without an authid, aren't remembered
+        for _ in 0..3 {
+            handshake(&router, None);
+        }
+        let (mut handler, _session, anonymous) = handshake(&router, None);
+        handler
+            .handle_hello(
+                URI::new("ca.test.realm"),
+                HelloDetails::new(ClientRoles::new()),
+            )
+            .unwrap();
+        assert_eq!(router.info.session_cookies.lock().unwrap().entries.len(), 1);
+        let (_, _, replaced) = handshake(&router, Some(&anonymous));
+        assert_ne!(replaced, anonymous);
+    }
+
+    #[test]
+    fn poisoned_subscriber_is_skipped() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+        let (poisoned_id, poisoned) = raw_session_with_id(&router, "ca.test.realm");
+        let healthy = raw_session(&router, "ca.test.realm");
+        for subscriber in [&poisoned, &healthy] {
+            subscriber
+                .sender
+                .send(Message::Subscribe(
+                    1,
+                    SubscribeOptions::new(),
+                    URI::new("ca.test.topic"),
+                ))
+                .unwrap();
+            assert!(matches!(receive(subscriber), Message::Subscribed(1, _)));
+        }
+
+        // Panic while holding the lock of the first subscriber's connection
+        let connection = {
+            let realms = router.info.realms.lock().unwrap();
+            let realm = realms["ca.test.realm"].lock().unwrap();
+            realm
+                .connections
+                .iter()
+                .find(|connection| connection.lock().unwrap().id == poisoned_id)
+                .map(Arc::clone)
+                .unwrap()
+        };
+        thread::spawn(move || {
+            let _info = connection.lock().unwrap();
+            panic!("Poisoning the connection");
+        })
+        .join()
+        .unwrap_err();
+
+        let publisher = raw_session(&router, "ca.test.realm");
+        publisher
+            .sender
+            .send(Message::Publish(
+                2,
+                PublishOptions::new(true),
+                URI::new("ca.test.topic"),
+                Some(vec![Value::Integer(1)]),
+                None,
+            ))
+            .unwrap();
+        assert!(matches!(receive(&publisher), Message::Published(2, ..)));
+        match receive(&healthy) {
+            Message::Event(_, _, _, args, _) => assert_eq!(args, Some(vec![Value::Integer(1)])),
+            message => panic!("Expected an event, got {:?}", message),
+        }
+        // The poisoned session was dropped from the realm
+        assert_eq!(router.connection_count("ca.test.realm"), Some(2));
+    }
+
+    /// Lets every session do anything, including killing the others
+    struct AllowKill;
+
+    impl Authorizer for AllowKill {
+        fn can_subscribe(&self, _session_id: ID, _topic: &URI) -> bool {
+            true
+        }
+
+        fn can_publish(&self, _session_id: ID, _topic: &URI) -> bool {
+            true
+        }
+
+        fn can_register(&self, _session_id: ID, _procedure: &URI) -> bool {
+            true
+        }
+
+        fn can_call(&self, _session_id: ID, _procedure: &URI) -> bool {
+            true
+        }
+
+        fn can_kill(&self, _session_id: ID, _target_id: ID) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn kill_session() {
+        let mut router = Router::new();
+        router.add_realm_with_options(
+            "ca.test.realm",
+            RealmOptions {
+                authorizer: Box::new(AllowKill),
+                ..Default::default()
+            },
+        );
+        let (victim_id, victim) = raw_session_with_id(&router, "ca.test.realm");
+        let (admin_id, admin) = raw_session_with_id(&router, "ca.test.realm");
+
+        let mut kwargs = HashMap::new();
+        kwargs.insert(
+            "message".to_string(),
+            Value::String("Misbehaving".to_string()),
+        );
+        admin
+            .sender
+            .send(Message::Call(
+                1,
+                CallOptions::new(),
+                URI::new("wamp.session.kill"),
+                Some(vec![Value::UnsignedInteger(victim_id)]),
+                Some(kwargs),
+            ))
+            .unwrap();
+        assert!(matches!(receive(&admin), Message::Result(1, ..)));
+        match receive(&victim) {
+            Message::Goodbye(details, reason) => {
+                assert_eq!(details, ErrorDetails::new_with_message("Misbehaving"));
+                assert_eq!(reason.to_string(), "wamp.close.killed");
+            }
+            message => panic!("Expected a goodbye, got {:?}", message),
+        }
+        assert!(matches!(victim.events.recv().unwrap(), Event::Close));
+
+        // A session can only kill the others
+        admin
+            .sender
+            .send(Message::Call(
+                2,
+                CallOptions::new(),
+                URI::new("wamp.session.kill"),
+                Some(vec![Value::UnsignedInteger(admin_id)]),
+                None,
+            ))
+            .unwrap();
+        match receive(&admin) {
+            Message::Error(ErrorType::Call, 2, _, reason, ..) => {
+                assert_eq!(reason, Reason::NoSuchSession)
+            }
+            message => panic!("Expected an error, got {:?}", message),
+        }
+    }
+
+    #[test]
+    fn kill_session_refused_by_default() {
+        let mut router = Router::new();
+        router.add_realm("ca.test.realm");
+        let (victim_id, victim) = raw_session_with_id(&router, "ca.test.realm");
+        let caller = raw_session(&router, "ca.test.realm");
+
+        caller
+            .sender
+            .send(Message::Call(
+                1,
+                CallOptions::new(),
+                URI::new("wamp.session.kill"),
+                Some(vec![Value::UnsignedInteger(victim_id)]),
+                None,
+            ))
+            .unwrap();
+        match receive(&caller) {
+            Message::Error(ErrorType::Call, 1, _, reason, ..) => {
+                assert_eq!(reason.to_string(), "wamp.error.not_authorized")
+            }
+            message => panic!("Expected an error, got {:?}", message),
+        }
+        assert!(victim.events.try_recv().is_err());
+    }
 }
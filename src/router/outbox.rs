@@ -0,0 +1,216 @@
+//! Contains the `Outbox` struct, the outbound side of a websocket connection.
+//!
+//! Messages for a connection are encoded by whichever handler produced them, so that a
+//! message that can't be encoded fails its sender, then queued rather than written to the
+//! socket.  A single writer thread per listener drains the queues of all of its connections,
+//! handing each message to the event loop in the order it was queued.
+//!
+//! The event loop buffers what it can't write to the socket yet, so handing a message over
+//! says nothing about whether the peer is keeping up.  The writer therefore follows the
+//! messages it hands over with a ping carrying the number sent so far.  The pong tells how
+//! many the peer has read, and the rest make up the connection's backlog.
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, Sender as QueueSender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use log::{debug, warn};
+use parity_ws::{util::Token, CloseCode, Result as WSResult, Sender};
+
+use crate::{serializer::Payload, Error, ErrorKind, WampResult};
+
+/// Number of messages a connection may have queued for the writer.  Further messages are
+/// refused until the writer catches up.
+const MAX_QUEUED_MESSAGES: usize = 1024;
+
+/// Something waiting in a connection's outbound queue
+enum Outbound {
+    /// An encoded message
+    Message(Payload),
+    /// Closes the connection once everything queued before has been sent
+    Close(CloseCode),
+    /// Asks the peer to acknowledge the messages sent so far, if it has some left to
+    Acknowledge,
+}
+
+/// The state of a connection shared between its outbox and the writer
+struct Peer {
+    sender: Sender,
+    /// Number of messages queued and not yet taken up by the writer
+    queued: AtomicUsize,
+    /// Set once the event loop refused something, after which the rest is skipped
+    closed: AtomicBool,
+    /// Number of messages handed to the event loop
+    sent: AtomicU64,
+    /// Number of messages the peer is known to have read
+    acknowledged: AtomicU64,
+    /// The count carried by the ping asking for an acknowledgement, while it is unanswered
+    outstanding: Mutex<Option<u64>>,
+}
+
+impl Peer {
+    /// Pings the peer with the number of messages sent, unless a ping is already on its way
+    fn request_acknowledgement(&self) -> WSResult<()> {
+        let sent = {
+            let mut outstanding = self.outstanding.lock().unwrap();
+            if outstanding.is_some() {
+                return Ok(());
+            }
+            let sent = self.sent.load(Ordering::SeqCst);
+            *outstanding = Some(sent);
+            sent
+        };
+        self.sender.ping(sent.to_be_bytes().to_vec())
+    }
+}
+
+/// Hands the queued messages of a listener's connections to its event loop
+#[derive(Clone)]
+pub struct Writer {
+    queue: QueueSender<(Arc<Peer>, Outbound)>,
+}
+
+impl Writer {
+    /// Starts the thread that writes for the connections of one listener.  It stops once
+    /// the writer and every outbox made from it are dropped.
+    pub fn start() -> Writer {
+        let (queue, outbound) = channel();
+        thread::spawn(move || write(outbound));
+        Writer { queue }
+    }
+}
+
+/// The outbound side of a websocket connection
+pub struct Outbox {
+    peer: Arc<Peer>,
+    queue: QueueSender<(Arc<Peer>, Outbound)>,
+}
+
+impl Outbox {
+    /// Creates the outbox of the connection that `sender` belongs to, written by `writer`
+    pub fn new(sender: Sender, writer: &Writer) -> Outbox {
+        let peer = Peer {
+            sender,
+            queued: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            sent: AtomicU64::new(0),
+            acknowledged: AtomicU64::new(0),
+            outstanding: Mutex::new(None),
+        };
+        Outbox {
+            peer: Arc::new(peer),
+            queue: writer.queue.clone(),
+        }
+    }
+
+    /// Queues an encoded message.  Fails if the connection already has as many messages
+    /// queued as it may.
+    pub fn send(&self, payload: Payload) -> WampResult<()> {
+        if self.peer.queued.load(Ordering::SeqCst) >= MAX_QUEUED_MESSAGES {
+            warn!(
+                "Outbound queue is full.  Dropping a message of {} bytes",
+                payload.as_bytes().len()
+            );
+            return Err(Error::new(ErrorKind::ConnectionLost));
+        }
+        self.peer.queued.fetch_add(1, Ordering::SeqCst);
+        self.queue
+            .send((Arc::clone(&self.peer), Outbound::Message(payload)))
+            .map_err(|_| {
+                self.peer.queued.fetch_sub(1, Ordering::SeqCst);
+                Error::new(ErrorKind::ConnectionLost)
+            })
+    }
+
+    /// Closes the connection with `code` after the messages queued so far
+    pub fn close(&self, code: CloseCode) -> WSResult<()> {
+        match self
+            .queue
+            .send((Arc::clone(&self.peer), Outbound::Close(code)))
+        {
+            Ok(()) => Ok(()),
+            // The writer is gone, so nothing is left to send first
+            Err(_) => self.peer.sender.close(code),
+        }
+    }
+
+    /// Number of messages queued for the connection that the peer isn't known to have read
+    pub fn backlog(&self) -> usize {
+        let acknowledged = self.peer.acknowledged.load(Ordering::SeqCst);
+        let unread = self
+            .peer
+            .sent
+            .load(Ordering::SeqCst)
+            .saturating_sub(acknowledged);
+        self.peer.queued.load(Ordering::SeqCst) + unread as usize
+    }
+
+    /// Takes note of a pong from the peer.  Only a pong echoing the outstanding ping, which
+    /// carries the number of messages sent before it, counts as an acknowledgement.  Peers may
+    /// send pongs of their own accord, so any other is ignored.
+    pub fn acknowledge(&self, payload: &[u8]) {
+        let count = {
+            let mut outstanding = self.peer.outstanding.lock().unwrap();
+            match *outstanding {
+                Some(count) if payload == count.to_be_bytes() => {
+                    *outstanding = None;
+                    count
+                }
+                _ => return,
+            }
+        };
+        let sent = self.peer.sent.load(Ordering::SeqCst);
+        self.peer
+            .acknowledged
+            .fetch_max(count.min(sent), Ordering::SeqCst);
+        if sent > count {
+            // Whatever was sent after the ping needs another
+            self.queue
+                .send((Arc::clone(&self.peer), Outbound::Acknowledge))
+                .ok();
+        }
+    }
+
+    /// Drops the connection right away, whatever is still queued
+    pub fn shutdown(&self) -> WSResult<()> {
+        self.peer.sender.shutdown()
+    }
+
+    pub fn ping(&self) -> WSResult<()> {
+        self.peer.sender.ping(Vec::new())
+    }
+
+    pub fn timeout(&self, ms: u64, token: Token) -> WSResult<()> {
+        self.peer.sender.timeout(ms, token)
+    }
+}
+
+/// Hands the queued messages to the event loop until every outbox and the writer are gone
+fn write(outbound: Receiver<(Arc<Peer>, Outbound)>) {
+    for (peer, item) in outbound {
+        // A message leaves the queue whatever becomes of it, so that it stops counting
+        // against the backlog even if the connection is closed
+        if let Outbound::Message(..) = item {
+            peer.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+        if peer.closed.load(Ordering::SeqCst) {
+            continue;
+        }
+        let result = match item {
+            Outbound::Message(payload) => peer.sender.send(payload).and_then(|()| {
+                peer.sent.fetch_add(1, Ordering::SeqCst);
+                peer.request_acknowledgement()
+            }),
+            Outbound::Close(code) => peer.sender.close(code),
+            Outbound::Acknowledge => peer.request_acknowledgement(),
+        };
+        if let Err(e) = result {
+            debug!("Stopped writing to connection: {}", e);
+            peer.closed.store(true, Ordering::SeqCst);
+        }
+    }
+}
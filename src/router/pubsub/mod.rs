@@ -1,17 +1,55 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{atomic::Ordering, Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
 
-use log::{debug, info};
+use log::{debug, info, warn};
 
 use crate::{
-    messages::{ErrorType, EventDetails, Message, PublishOptions, Reason, SubscribeOptions, URI},
-    Dict, Error, ErrorKind, List, MatchingPolicy, WampResult,
+    messages::{
+        ErrorType, EventDetails, Message, PublishOptions, PublishedDetails, Reason,
+        SubscribeOptions, URI,
+    },
+    Dict, Error, ErrorKind, List, MatchingPolicy, WampResult, ID,
 };
 
-use super::{messaging::send_message, random_id, ConnectionHandler};
+use super::{
+    connection_id, messaging::send_message, ConnectionHandler, ConnectionInfo, RouterErrorKind,
+    SubscriptionManager,
+};
 
 mod patterns;
 pub use self::patterns::SubscriptionPatternNode;
 
+/// An event kept as the last value of its topic
+pub struct RetainedEvent {
+    publication_id: ID,
+    /// The options it was published with, whose filters also apply to later subscribers
+    options: PublishOptions,
+    args: Option<List>,
+    kwargs: Option<Dict>,
+}
+
+/// How long the events of a topic are kept for an authid after its session left
+const AWAY_BUFFER_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Number of away buffers a realm keeps.  Beyond it, the one whose session left first is
+/// dropped.
+const MAX_AWAY_BUFFERS: usize = 10_000;
+
+/// The last events of a topic, kept for an authid whose session left while subscribed with
+/// `keep_last`
+pub struct AwayBuffer {
+    capacity: usize,
+    /// The authrole of the session that left, which the publication filters are checked against
+    authrole: Option<String>,
+    /// When the session left
+    left: Instant,
+    /// Publication ids and arguments, oldest first
+    events: VecDeque<(ID, Option<List>, Option<Dict>)>,
+}
+
 impl ConnectionHandler {
     pub fn handle_subscribe(
         &mut self,
@@ -26,7 +64,47 @@ impl ConnectionHandler {
         match self.realm {
             Some(ref realm) => {
                 let mut realm = realm.lock().unwrap();
+                let session_id = connection_id(&self.info);
+                if !realm.authorizer.can_subscribe(session_id, &topic) {
+                    return Err(Error::new(ErrorKind::ErrorReason(
+                        ErrorType::Subscribe,
+                        request_id,
+                        Reason::NotAuthorized,
+                    )));
+                }
+                let max_keep_last = self.router.max_keep_last.load(Ordering::Relaxed);
+                if options
+                    .keep_last
+                    .is_some_and(|keep_last| keep_last > max_keep_last)
+                {
+                    return Err(Error::new(ErrorKind::ErrorReason(
+                        ErrorType::Subscribe,
+                        request_id,
+                        Reason::InvalidArgument,
+                    )));
+                }
                 let manager = &mut realm.subscription_manager;
+                // Subscribing again to a topic the session holds only hands back the existing
+                // subscription, so it doesn't count against the limit
+                let is_prefix = options.pattern_match == MatchingPolicy::Prefix;
+                let already_subscribed = self.subscribed_topics.iter().any(|id| {
+                    matches!(
+                        manager.subscription_ids_to_uris.get(id),
+                        Some((uri, prefix)) if *uri == topic.uri && *prefix == is_prefix
+                    )
+                });
+                let limit = self.router.max_subscriptions.load(Ordering::Relaxed);
+                if !already_subscribed && self.subscribed_topics.len() >= limit {
+                    warn!(
+                        "Session {} already holds {} subscriptions",
+                        session_id, limit
+                    );
+                    return Err(Error::new(ErrorKind::ErrorReason(
+                        ErrorType::Subscribe,
+                        request_id,
+                        Reason::NotAuthorized,
+                    )));
+                }
                 let topic_id = {
                     let topic_id = match manager.subscriptions.subscribe_with(
                         &topic,
@@ -42,14 +120,36 @@ impl ConnectionHandler {
                             )))
                         }
                     };
-                    self.subscribed_topics.push(topic_id);
+                    if !self.subscribed_topics.contains(&topic_id) {
+                        self.subscribed_topics.push(topic_id);
+                    }
                     topic_id
                 };
-                manager.subscription_ids_to_uris.insert(
-                    topic_id,
-                    (topic.uri, options.pattern_match == MatchingPolicy::Prefix),
-                );
-                send_message(&self.info, &Message::Subscribed(request_id, topic_id))
+                // Events kept while away only follow strict subscriptions of an authid the
+                // authorizer vouched for, as anyone can claim to be anyone else
+                let authid = match options.pattern_match {
+                    MatchingPolicy::Strict => {
+                        let info = self.info.lock().unwrap();
+                        info.vouched_identity().0.map(str::to_string)
+                    }
+                    _ => None,
+                };
+                if let (Some(authid), Some(keep_last)) = (&authid, options.keep_last) {
+                    if keep_last > 0 {
+                        manager
+                            .kept_subscriptions
+                            .insert((topic_id, session_id), (authid.clone(), keep_last));
+                    }
+                }
+                manager
+                    .subscription_ids_to_uris
+                    .insert(topic_id, (topic.uri.clone(), is_prefix));
+                send_message(&self.info, &Message::Subscribed(request_id, topic_id))?;
+                manager.send_retained_events(&self.info, topic_id)?;
+                match authid.and_then(|authid| manager.take_away_buffer(&topic.uri, &authid)) {
+                    Some(buffer) => buffer.replay(&self.info, topic_id),
+                    None => Ok(()),
+                }
             }
             None => Err(Error::new(ErrorKind::InvalidState(
                 "Received a message while not attached to a realm",
@@ -63,7 +163,7 @@ impl ConnectionHandler {
                 let mut realm = realm.lock().unwrap();
                 let manager = &mut realm.subscription_manager;
                 let (topic_uri, is_prefix) = match manager.subscription_ids_to_uris.get(&topic_id) {
-                    Some(&(ref uri, ref is_prefix)) => (uri.clone(), *is_prefix),
+                    Some((uri, is_prefix)) => (uri.clone(), *is_prefix),
                     None => {
                         return Err(Error::new(ErrorKind::ErrorReason(
                             ErrorType::Unsubscribe,
@@ -87,6 +187,9 @@ impl ConnectionHandler {
                     }
                 };
                 self.subscribed_topics.retain(|id| *id != topic_id);
+                manager
+                    .kept_subscriptions
+                    .remove(&(topic_id, connection_id(&self.info)));
                 send_message(&self.info, &Message::Unsubscribed(request_id))
             }
             None => Err(Error::new(ErrorKind::InvalidState(
@@ -103,21 +206,77 @@ impl ConnectionHandler {
         args: Option<List>,
         kwargs: Option<Dict>,
     ) -> WampResult<()> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!(
+            "publish",
+            session_id = connection_id(&self.info),
+            request_id,
+            topic = %topic.uri
+        )
+        .entered();
         debug!(
             "Responding to publish message (id: {}, topic: {})",
             request_id, topic.uri
         );
+        if self.router.draining.load(Ordering::SeqCst) {
+            return Err(Error::new(ErrorKind::ErrorReason(
+                ErrorType::Publish,
+                request_id,
+                Reason::SystemShutdown,
+            )));
+        }
+        self.check_argument_lengths(ErrorType::Publish, request_id, &args, &kwargs)?;
         match self.realm {
             Some(ref realm) => {
-                let realm = realm.lock().unwrap();
-                let manager = &realm.subscription_manager;
-                let publication_id = random_id();
+                let mut realm = realm.lock().unwrap();
+                let my_id = connection_id(&self.info);
+                if !realm.authorizer.can_publish(my_id, &topic) {
+                    return Err(Error::new(ErrorKind::ErrorReason(
+                        ErrorType::Publish,
+                        request_id,
+                        Reason::NotAuthorized,
+                    )));
+                }
+                let manager = &mut realm.subscription_manager;
+                let publication_id = self.router.id_generator.next_id();
                 let mut event_message =
                     Message::Event(1, publication_id, EventDetails::new(), args, kwargs);
-                let my_id = { self.info.lock().unwrap().id };
                 info!("Current topic tree: {:?}", manager.subscriptions);
+                // Events are queued on each subscriber's sender while the realm is locked, and a
+                // sender delivers messages in the order they were queued, so a subscriber always
+                // sees the events of a single publisher in the order they were published.
+                let max_backlog = self.router.max_event_backlog.load(Ordering::Relaxed);
+                let mut recipients = 0;
+                // Subscribers whose handler panicked while holding their lock are skipped, and
+                // dropped from the realm once the event has gone out to everyone else
+                let mut poisoned: Vec<Arc<Mutex<ConnectionInfo>>> = Vec::new();
                 for (subscriber, topic_id, policy) in manager.subscriptions.filter(topic.clone()) {
-                    if subscriber.lock().unwrap().id != my_id {
+                    let (subscriber_id, backlog, eligible) = match subscriber.lock() {
+                        Ok(subscriber) => {
+                            // The filters only go by identities the authorizer vouched for
+                            let (authid, authrole) = subscriber.vouched_identity();
+                            let eligible = options.is_eligible(authid, authrole);
+                            (subscriber.id, subscriber.backlog(), eligible)
+                        }
+                        Err(_) => {
+                            if !poisoned.iter().any(|other| Arc::ptr_eq(other, subscriber)) {
+                                warn!(
+                                    "Skipping subscriber {}, whose connection is poisoned",
+                                    connection_id(subscriber)
+                                );
+                                poisoned.push(Arc::clone(subscriber));
+                            }
+                            continue;
+                        }
+                    };
+                    if subscriber_id != my_id && eligible {
+                        if backlog >= max_backlog {
+                            warn!(
+                                "Dropping event for subscriber {} with {} messages waiting",
+                                subscriber_id, backlog
+                            );
+                            continue;
+                        }
                         if let Message::Event(
                             ref mut old_topic,
                             ref _publish_id,
@@ -133,11 +292,55 @@ impl ConnectionHandler {
                                 Some(topic.clone())
                             };
                         }
-                        send_message(subscriber, &event_message)?;
+                        // A subscriber that has just gone away is cleaned up by its own
+                        // handler, and shouldn't keep the event from the others
+                        if let Err(e) = send_message(subscriber, &event_message) {
+                            warn!(
+                                "Could not send event to subscriber {}: {}",
+                                subscriber_id, e
+                            );
+                            self.router.errors.report(
+                                subscriber_id,
+                                RouterErrorKind::SendFailed,
+                                format!("Could not send event on {}: {}", topic.uri, e),
+                            );
+                            continue;
+                        }
+                        recipients += 1;
+                    }
+                }
+                if let Message::Event(_, _, _, ref args, ref kwargs) = event_message {
+                    manager.keep_for_away_subscribers(
+                        &topic,
+                        publication_id,
+                        &options,
+                        args,
+                        kwargs,
+                    );
+                }
+                if options.retain {
+                    if let Message::Event(_, _, _, args, kwargs) = event_message {
+                        manager.retained_events.insert(
+                            topic.uri,
+                            RetainedEvent {
+                                publication_id,
+                                options: options.clone(),
+                                args,
+                                kwargs,
+                            },
+                        );
                     }
                 }
+                for subscriber in &poisoned {
+                    realm.evict(subscriber);
+                }
                 if options.should_acknowledge() {
-                    send_message(&self.info, &Message::Published(request_id, publication_id))?;
+                    let mut details = PublishedDetails::new();
+                    if options.report_recipients {
+                        details.recipients = Some(recipients);
+                    }
+                    let published_message = Message::Published(request_id, publication_id, details);
+                    send_message(&self.info, &published_message)?;
                 }
                 Ok(())
             }
@@ -147,3 +350,161 @@ impl ConnectionHandler {
         }
     }
 }
+
+impl SubscriptionManager {
+    /// Starts keeping the events of a subscription with `keep_last` whose session is leaving
+    pub fn keep_events_while_away(
+        &mut self,
+        subscription_id: ID,
+        subscriber: &Arc<Mutex<ConnectionInfo>>,
+    ) {
+        let (session_id, authrole) = {
+            let subscriber = subscriber.lock().unwrap_or_else(PoisonError::into_inner);
+            let authrole = subscriber.vouched_identity().1.map(str::to_string);
+            (subscriber.id, authrole)
+        };
+        let (authid, capacity) = match self
+            .kept_subscriptions
+            .remove(&(subscription_id, session_id))
+        {
+            Some(kept) => kept,
+            None => return,
+        };
+        let topic = match self.subscription_ids_to_uris.get(&subscription_id) {
+            Some((topic, _)) => topic.clone(),
+            None => return,
+        };
+        debug!(
+            "Keeping the last {} events of {} for {}",
+            capacity, topic, authid
+        );
+        self.away_buffers.retain(|_, buffers| {
+            buffers.retain(|_, buffer| buffer.left.elapsed() < AWAY_BUFFER_TTL);
+            !buffers.is_empty()
+        });
+        let kept = self.away_buffers.values().map(HashMap::len).sum::<usize>();
+        let replaces = self
+            .away_buffers
+            .get(&topic)
+            .is_some_and(|buffers| buffers.contains_key(&authid));
+        if kept >= MAX_AWAY_BUFFERS && !replaces {
+            let oldest = self
+                .away_buffers
+                .iter()
+                .flat_map(|(topic, buffers)| {
+                    buffers
+                        .iter()
+                        .map(move |(authid, buffer)| (buffer.left, topic, authid))
+                })
+                .min()
+                .map(|(_, topic, authid)| (topic.clone(), authid.clone()));
+            if let Some((topic, authid)) = oldest {
+                self.take_away_buffer(&topic, &authid);
+            }
+        }
+        self.away_buffers
+            .entry(topic)
+            .or_default()
+            .entry(authid)
+            .or_insert_with(|| AwayBuffer {
+                capacity,
+                authrole,
+                left: Instant::now(),
+                events: VecDeque::new(),
+            });
+    }
+
+    /// Removes the events kept on `topic` for `authid`, unless they have expired
+    fn take_away_buffer(&mut self, topic: &str, authid: &str) -> Option<AwayBuffer> {
+        let buffers = self.away_buffers.get_mut(topic)?;
+        let buffer = buffers.remove(authid);
+        if buffers.is_empty() {
+            self.away_buffers.remove(topic);
+        }
+        buffer.filter(|buffer| buffer.left.elapsed() < AWAY_BUFFER_TTL)
+    }
+
+    /// Adds an event to the buffers kept for the authids away from its topic
+    fn keep_for_away_subscribers(
+        &mut self,
+        topic: &URI,
+        publication_id: ID,
+        options: &PublishOptions,
+        args: &Option<List>,
+        kwargs: &Option<Dict>,
+    ) {
+        let buffers = match self.away_buffers.get_mut(&topic.uri) {
+            Some(buffers) => buffers,
+            None => return,
+        };
+        buffers.retain(|_, buffer| buffer.left.elapsed() < AWAY_BUFFER_TTL);
+        for (authid, buffer) in buffers.iter_mut() {
+            if !options.is_eligible(Some(authid), buffer.authrole.as_deref()) {
+                continue;
+            }
+            if buffer.events.len() == buffer.capacity {
+                buffer.events.pop_front();
+            }
+            buffer
+                .events
+                .push_back((publication_id, args.clone(), kwargs.clone()));
+        }
+        if buffers.is_empty() {
+            self.away_buffers.remove(&topic.uri);
+        }
+    }
+
+    /// Sends a new subscriber the retained events of the topics its subscription matches
+    fn send_retained_events(
+        &self,
+        subscriber: &Arc<Mutex<ConnectionInfo>>,
+        topic_id: ID,
+    ) -> WampResult<()> {
+        let (authid, authrole) = {
+            let subscriber = subscriber.lock().unwrap();
+            let (authid, authrole) = subscriber.vouched_identity();
+            (authid.map(str::to_string), authrole.map(str::to_string))
+        };
+        for (topic, retained) in &self.retained_events {
+            if !retained
+                .options
+                .is_eligible(authid.as_deref(), authrole.as_deref())
+            {
+                continue;
+            }
+            let policy = self
+                .subscriptions
+                .filter(URI::new(topic))
+                .find(|(other, id, _)| *id == topic_id && Arc::ptr_eq(other, subscriber))
+                .map(|(_, _, policy)| policy);
+            if let Some(policy) = policy {
+                let mut details = EventDetails::new();
+                details.retained = true;
+                if policy != MatchingPolicy::Strict {
+                    details.topic = Some(URI::new(topic));
+                }
+                let event_message = Message::Event(
+                    topic_id,
+                    retained.publication_id,
+                    details,
+                    retained.args.clone(),
+                    retained.kwargs.clone(),
+                );
+                send_message(subscriber, &event_message)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AwayBuffer {
+    /// Sends the kept events to the subscription that took over from the session that left
+    fn replay(self, subscriber: &Arc<Mutex<ConnectionInfo>>, topic_id: ID) -> WampResult<()> {
+        for (publication_id, args, kwargs) in self.events {
+            let event_message =
+                Message::Event(topic_id, publication_id, EventDetails::new(), args, kwargs);
+            send_message(subscriber, &event_message)?;
+        }
+        Ok(())
+    }
+}
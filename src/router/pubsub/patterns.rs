@@ -12,7 +12,7 @@ use itertools::Itertools;
 
 use crate::{messages::Reason, MatchingPolicy, ID, URI};
 
-use super::super::{random_id, ConnectionInfo};
+use super::super::{connection_id, ConnectionInfo, IdGenerator, RandomIdGenerator};
 
 /// Contains a trie corresponding to the subscription patterns that connections have requested.
 ///
@@ -27,6 +27,7 @@ pub struct SubscriptionPatternNode<P: PatternData> {
     prefix_connections: Vec<DataWrapper<P>>,
     id: ID,
     prefix_id: ID,
+    ids: Arc<dyn IdGenerator>,
 }
 
 /// Represents data that a pattern trie will hold
@@ -91,7 +92,7 @@ impl PatternError {
 
 impl PatternData for Arc<Mutex<ConnectionInfo>> {
     fn get_id(&self) -> ID {
-        self.lock().unwrap().id
+        connection_id(self)
     }
 }
 
@@ -119,6 +120,12 @@ impl<P: PatternData> Debug for SubscriptionPatternNode<P> {
     }
 }
 
+impl<P: PatternData> Default for SubscriptionPatternNode<P> {
+    fn default() -> SubscriptionPatternNode<P> {
+        SubscriptionPatternNode::new()
+    }
+}
+
 impl<P: PatternData> SubscriptionPatternNode<P> {
     fn fmt_with_indent(&self, f: &mut Formatter<'_>, indent: usize) -> fmt::Result {
         writeln!(
@@ -156,10 +163,11 @@ impl<P: PatternData> SubscriptionPatternNode<P> {
             Some(initial) => initial,
             None => return Err(PatternError::new(Reason::InvalidURI)),
         };
+        let ids = &self.ids;
         let edge = self
             .edges
             .entry(initial.to_string())
-            .or_insert_with(SubscriptionPatternNode::new);
+            .or_insert_with(|| SubscriptionPatternNode::with_id_generator(Arc::clone(ids)));
         edge.add_subscription(uri_bits, subscriber, matching_policy)
     }
 
@@ -174,15 +182,52 @@ impl<P: PatternData> SubscriptionPatternNode<P> {
         self.remove_subscription(uri_bits, subscriber.get_id(), is_prefix)
     }
 
+    /// Counts the subscriptions in the trie that still have at least one subscriber
+    pub fn subscription_count(&self) -> usize {
+        let own =
+            !self.connections.is_empty() as usize + !self.prefix_connections.is_empty() as usize;
+        own + self
+            .edges
+            .values()
+            .map(|edge| edge.subscription_count())
+            .sum::<usize>()
+    }
+
+    /// Lists the subscriptions in the trie that still have at least one subscriber, by id
+    /// along with how they match
+    pub fn subscription_ids(&self) -> Vec<(ID, MatchingPolicy)> {
+        let mut ids = Vec::new();
+        self.collect_subscription_ids(&mut ids);
+        ids
+    }
+
+    fn collect_subscription_ids(&self, ids: &mut Vec<(ID, MatchingPolicy)>) {
+        if let Some(entry) = self.connections.first() {
+            ids.push((self.id, entry.policy));
+        }
+        if !self.prefix_connections.is_empty() {
+            ids.push((self.prefix_id, MatchingPolicy::Prefix));
+        }
+        for edge in self.edges.values() {
+            edge.collect_subscription_ids(ids);
+        }
+    }
+
     /// Constructs a new SubscriptionPatternNode to be used as the root of the trie
     #[inline]
     pub fn new() -> SubscriptionPatternNode<P> {
+        SubscriptionPatternNode::with_id_generator(Arc::new(RandomIdGenerator))
+    }
+
+    /// Constructs a new root whose nodes take their ids from `ids`
+    pub fn with_id_generator(ids: Arc<dyn IdGenerator>) -> SubscriptionPatternNode<P> {
         SubscriptionPatternNode {
             edges: HashMap::new(),
             connections: Vec::new(),
             prefix_connections: Vec::new(),
-            id: random_id(),
-            prefix_id: random_id(),
+            id: ids.next_id(),
+            prefix_id: ids.next_id(),
+            ids,
         }
     }
 
@@ -200,26 +245,31 @@ impl<P: PatternData> SubscriptionPatternNode<P> {
                 if uri_bit.is_empty() && matching_policy != MatchingPolicy::Wildcard {
                     return Err(PatternError::new(Reason::InvalidURI));
                 }
+                let ids = &self.ids;
                 let edge = self
                     .edges
                     .entry(uri_bit.to_string())
-                    .or_insert_with(SubscriptionPatternNode::new);
+                    .or_insert_with(|| SubscriptionPatternNode::with_id_generator(Arc::clone(ids)));
                 edge.add_subscription(uri_bits, subscriber, matching_policy)
             }
             None => {
-                if matching_policy == MatchingPolicy::Prefix {
-                    self.prefix_connections.push(DataWrapper {
-                        subscriber,
-                        policy: matching_policy,
-                    });
-                    Ok(self.prefix_id)
+                let (connections, id) = if matching_policy == MatchingPolicy::Prefix {
+                    (&mut self.prefix_connections, self.prefix_id)
                 } else {
-                    self.connections.push(DataWrapper {
+                    (&mut self.connections, self.id)
+                };
+                // Subscribing again to the same topic yields the existing subscription
+                let subscriber_id = subscriber.get_id();
+                let subscribed = connections.iter().any(|sub| {
+                    sub.subscriber.get_id() == subscriber_id && sub.policy == matching_policy
+                });
+                if !subscribed {
+                    connections.push(DataWrapper {
                         subscriber,
                         policy: matching_policy,
                     });
-                    Ok(self.id)
                 }
+                Ok(id)
             }
         }
     }
@@ -443,6 +493,31 @@ mod test {
         );
     }
 
+    #[test]
+    fn duplicate_subscriptions() {
+        let mut root = SubscriptionPatternNode::new();
+        let first = root
+            .subscribe_with(
+                &URI::new("com.example.topic"),
+                MockData::new(1),
+                MatchingPolicy::Strict,
+            )
+            .unwrap();
+        let second = root
+            .subscribe_with(
+                &URI::new("com.example.topic"),
+                MockData::new(1),
+                MatchingPolicy::Strict,
+            )
+            .unwrap();
+        assert_eq!(first, second);
+        assert_eq!(root.filter(URI::new("com.example.topic")).count(), 1);
+
+        root.unsubscribe_with("com.example.topic", &MockData::new(1), false)
+            .unwrap();
+        assert_eq!(root.filter(URI::new("com.example.topic")).count(), 0);
+    }
+
     #[test]
     fn removing_patterns() {
         let connection1 = MockData::new(1);
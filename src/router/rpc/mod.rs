@@ -1,19 +1,67 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{atomic::Ordering, Arc, Mutex},
+};
 
-use log::{debug, info};
+use log::{debug, info, warn};
 
 use crate::{
     messages::{
         CallOptions, ErrorType, InvocationDetails, Message, Reason, RegisterOptions, ResultDetails,
-        YieldOptions, URI,
+        RunMode, YieldOptions, URI,
     },
-    Dict, Error, ErrorKind, List, MatchingPolicy, WampResult, ID,
+    CallError, CallResult, Dict, Error, ErrorKind, List, MatchingPolicy, Value, WampResult, ID,
 };
 
-use super::{messaging::send_message, random_id, ConnectionHandler};
+use super::{
+    connection_id, messaging::send_message, ConnectionHandler, ConnectionInfo, RegistrationManager,
+    RouterErrorKind,
+};
 
 mod patterns;
-pub use self::patterns::RegistrationPatternNode;
+use self::patterns::PatternError;
+pub use self::patterns::{PatternData, RegistrationPatternNode};
+
+/// A call waiting for a registrant to get below its concurrency limit
+pub struct QueuedCall {
+    request_id: ID,
+    caller: Arc<Mutex<ConnectionInfo>>,
+    options: CallOptions,
+    procedure: URI,
+    args: Option<List>,
+    kwargs: Option<Dict>,
+}
+
+impl QueuedCall {
+    /// The session id of the caller
+    pub fn caller_id(&self) -> ID {
+        connection_id(&self.caller)
+    }
+
+    /// Answers the caller with an error
+    fn fail(&self, reason: Reason) -> WampResult<()> {
+        let error_message = Message::Error(
+            ErrorType::Call,
+            self.request_id,
+            HashMap::new(),
+            reason,
+            None,
+            None,
+        );
+        send_message(&self.caller, &error_message)
+    }
+}
+
+/// A call made to every registrant of a procedure, waiting for all of them to answer
+pub struct FanOutCall {
+    request_id: ID,
+    caller: Arc<Mutex<ConnectionInfo>>,
+    /// Invocations that haven't been answered yet
+    pending: Vec<ID>,
+    /// One entry per registrant that has answered
+    results: List,
+}
 
 impl ConnectionHandler {
     pub fn handle_register(
@@ -29,13 +77,34 @@ impl ConnectionHandler {
         match self.realm {
             Some(ref realm) => {
                 let mut realm = realm.lock().unwrap();
+                let session_id = connection_id(&self.info);
+                if !realm.authorizer.can_register(session_id, &procedure) {
+                    return Err(Error::new(ErrorKind::ErrorReason(
+                        ErrorType::Register,
+                        request_id,
+                        Reason::NotAuthorized,
+                    )));
+                }
+                let limit = self.router.max_registrations.load(Ordering::Relaxed);
+                if self.registered_procedures.len() >= limit {
+                    warn!(
+                        "Session {} already holds {} registrations",
+                        session_id, limit
+                    );
+                    return Err(Error::new(ErrorKind::ErrorReason(
+                        ErrorType::Register,
+                        request_id,
+                        Reason::NotAuthorized,
+                    )));
+                }
                 let manager = &mut realm.registration_manager;
                 let procedure_id = {
-                    let procedure_id = match manager.registrations.register_with(
+                    let procedure_id = match manager.registrations.register_with_concurrency(
                         &procedure,
                         Arc::clone(&self.info),
                         options.pattern_match,
                         options.invocation_policy,
+                        options.concurrency,
                     ) {
                         Ok(procedure_id) => procedure_id,
                         Err(e) => {
@@ -49,11 +118,24 @@ impl ConnectionHandler {
                     self.registered_procedures.push(procedure_id);
                     procedure_id
                 };
+                if options.disclose_caller {
+                    manager
+                        .disclosing_registrants
+                        .insert((procedure_id, session_id));
+                }
+                let mut metadata = Dict::new();
+                if let Some(description) = options.description {
+                    metadata.insert("description".to_string(), Value::String(description));
+                }
+                if let Some(args_schema) = options.args_schema {
+                    metadata.insert("args_schema".to_string(), Value::Dict(args_schema));
+                }
                 manager.registration_ids_to_uris.insert(
                     procedure_id,
                     (
                         procedure.uri,
                         options.pattern_match == MatchingPolicy::Prefix,
+                        metadata,
                     ),
                 );
                 send_message(&self.info, &Message::Registered(request_id, procedure_id))
@@ -71,12 +153,12 @@ impl ConnectionHandler {
                 let manager = &mut realm.registration_manager;
                 let (procedure_uri, is_prefix) =
                     match manager.registration_ids_to_uris.get(&procedure_id) {
-                        Some(&(ref uri, is_prefix)) => (uri.clone(), is_prefix),
+                        Some(&(ref uri, is_prefix, _)) => (uri.clone(), is_prefix),
                         None => {
                             return Err(Error::new(ErrorKind::ErrorReason(
                                 ErrorType::Unregister,
                                 request_id,
-                                Reason::NoSuchProcedure,
+                                Reason::NoSuchRegistration,
                             )))
                         }
                     };
@@ -96,6 +178,9 @@ impl ConnectionHandler {
                     }
                 };
                 self.registered_procedures.retain(|id| *id != procedure_id);
+                manager
+                    .disclosing_registrants
+                    .remove(&(procedure_id, connection_id(&self.info)));
                 send_message(&self.info, &Message::Unregistered(request_id))
             }
             None => Err(Error::new(ErrorKind::InvalidState(
@@ -107,46 +192,59 @@ impl ConnectionHandler {
     pub fn handle_call(
         &mut self,
         request_id: ID,
-        _options: CallOptions,
+        options: CallOptions,
         procedure: URI,
         args: Option<List>,
         kwargs: Option<Dict>,
     ) -> WampResult<()> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!(
+            "call",
+            session_id = connection_id(&self.info),
+            request_id,
+            procedure = %procedure.uri
+        )
+        .entered();
         debug!(
             "Responding to call message (id: {}, procedure: {})",
             request_id, procedure.uri
         );
+        if self.router.draining.load(Ordering::SeqCst) {
+            return Err(Error::new(ErrorKind::ErrorReason(
+                ErrorType::Call,
+                request_id,
+                Reason::SystemShutdown,
+            )));
+        }
+        self.check_argument_lengths(ErrorType::Call, request_id, &args, &kwargs)?;
         match self.realm {
             Some(ref realm) => {
                 let mut realm = realm.lock().unwrap();
-                let manager = &mut realm.registration_manager;
-                let invocation_id = random_id();
-                info!("Current procedure tree: {:?}", manager.registrations);
-                let (registrant, procedure_id, policy) =
-                    match manager.registrations.get_registrant_for(procedure.clone()) {
-                        Ok(registrant) => registrant,
-                        Err(e) => {
-                            return Err(Error::new(ErrorKind::ErrorReason(
-                                ErrorType::Call,
-                                request_id,
-                                e.reason(),
-                            )))
-                        }
-                    };
-                manager
-                    .active_calls
-                    .insert(invocation_id, (request_id, Arc::clone(&self.info)));
-                let mut details = InvocationDetails::new();
-                details.procedure = if policy == MatchingPolicy::Strict {
-                    None
-                } else {
-                    Some(procedure)
-                };
-                let invocation_message =
-                    Message::Invocation(invocation_id, procedure_id, details, args, kwargs);
-                send_message(registrant, &invocation_message)?;
-
-                Ok(())
+                let session_id = connection_id(&self.info);
+                if !realm.authorizer.can_call(session_id, &procedure) {
+                    return Err(Error::new(ErrorKind::ErrorReason(
+                        ErrorType::Call,
+                        request_id,
+                        Reason::NotAuthorized,
+                    )));
+                }
+                if procedure.uri.starts_with("wamp.") {
+                    let result = realm.call_meta_procedure(
+                        session_id,
+                        &procedure,
+                        args.unwrap_or_default(),
+                        kwargs.unwrap_or_default(),
+                    );
+                    return send_message(&self.info, &call_result_message(request_id, result));
+                }
+                realm.registration_manager.dispatch_call(QueuedCall {
+                    request_id,
+                    caller: Arc::clone(&self.info),
+                    options,
+                    procedure,
+                    args,
+                    kwargs,
+                })
             }
             None => Err(Error::new(ErrorKind::InvalidState(
                 "Received a message while not attached to a realm",
@@ -157,23 +255,72 @@ impl ConnectionHandler {
     pub fn handle_yield(
         &mut self,
         invocation_id: ID,
-        _options: YieldOptions,
+        options: YieldOptions,
         args: Option<List>,
         kwargs: Option<Dict>,
     ) -> WampResult<()> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!(
+            "yield",
+            session_id = connection_id(&self.info),
+            invocation_id
+        )
+        .entered();
         debug!("Responding to yield message (id: {})", invocation_id);
+        let session_id = connection_id(&self.info);
         match self.realm {
             Some(ref realm) => {
                 let mut realm = realm.lock().unwrap();
                 let manager = &mut realm.registration_manager;
-                if let Some((call_id, callee)) = manager.active_calls.remove(&invocation_id) {
-                    let result_message =
-                        Message::Result(call_id, ResultDetails::new(), args, kwargs);
-                    send_message(&callee, &result_message)
+                if !manager.answerable_by(invocation_id, session_id) {
+                    warn!(
+                        "Ignoring yield from session {} for invocation {} sent to another",
+                        session_id, invocation_id
+                    );
+                    return Ok(());
+                }
+                if manager.is_fan_out_invocation(invocation_id) {
+                    // Only final results are collected for calls made to every registrant
+                    if options.progress {
+                        return Ok(());
+                    }
+                    return manager.finish_fan_out_invocation(invocation_id, Ok((args, kwargs)));
+                }
+                // The call stays active until the callee yields its final result
+                let call = if options.progress {
+                    manager
+                        .active_calls
+                        .get(&invocation_id)
+                        .map(|(call_id, caller, callee_id)| {
+                            (*call_id, Arc::clone(caller), *callee_id)
+                        })
+                } else {
+                    manager.active_calls.remove(&invocation_id)
+                };
+                if let Some((call_id, caller, callee_id)) = call {
+                    let mut details = ResultDetails::new();
+                    details.progress = options.progress;
+                    if self.router.disclose_callee.load(Ordering::Relaxed) {
+                        details.callee = Some(callee_id);
+                    }
+                    // Only implementation specific options are the callee's to pass on
+                    details.custom = options
+                        .custom
+                        .into_iter()
+                        .filter(|(key, _)| key.starts_with('_'))
+                        .collect();
+                    let result_message = Message::Result(call_id, details, args, kwargs);
+                    if let Err(e) = send_message(&caller, &result_message) {
+                        manager.report_unanswered(&caller, e);
+                    }
+                    if !options.progress {
+                        manager.dispatch_queued_calls();
+                    }
+                    Ok(())
                 } else {
-                    Err(Error::new(ErrorKind::InvalidState(
-                        "Received a yield message for a call that wasn't sent",
-                    )))
+                    // A late or repeated yield, such as one for a call that timed out
+                    warn!("Ignoring yield for unknown invocation {}", invocation_id);
+                    Ok(())
                 }
             }
             None => Err(Error::new(ErrorKind::InvalidState(
@@ -182,3 +329,332 @@ impl ConnectionHandler {
         }
     }
 }
+
+impl RegistrationManager {
+    /// Forwards a call to a registrant of the procedure, or queues it while the registrant is
+    /// at its concurrency limit.  Calls that fail are answered with an error.
+    fn dispatch_call(&mut self, mut call: QueuedCall) -> WampResult<()> {
+        let session_id = call.caller_id();
+        info!("Current procedure tree: {:?}", self.registrations);
+        if call.options.runmode == Some(RunMode::All) {
+            return self.dispatch_fan_out(call);
+        }
+        let in_flight = self.in_flight_calls();
+        let lookup = match call.options.runmode {
+            Some(RunMode::Partition) => match call.options.rkey {
+                Some(callee) => self
+                    .registrations
+                    .get_registrant_with_id(call.procedure.clone(), callee),
+                None => Err(PatternError::new(Reason::InvalidArgument)),
+            },
+            _ => self.registrations.get_registrant_for(
+                call.procedure.clone(),
+                session_id,
+                &in_flight,
+            ),
+        };
+        let (registrant, procedure_id, policy, concurrency) = match lookup {
+            Ok(registrant) => registrant,
+            Err(e) => return call.fail(e.reason()),
+        };
+        let callee_id = registrant.get_id();
+        if let Some(handler) = self.local_handlers.get_mut(&callee_id) {
+            debug!("Invoking local procedure {}", procedure_id);
+            let result = handler(
+                call.args.unwrap_or_default(),
+                call.kwargs.unwrap_or_default(),
+            );
+            return send_message(&call.caller, &call_result_message(call.request_id, result));
+        }
+        if let Some(limit) = concurrency {
+            if in_flight.get(&callee_id).copied().unwrap_or(0) >= limit as usize {
+                debug!(
+                    "Queueing call {} until callee {} finishes an invocation",
+                    call.request_id, callee_id
+                );
+                self.queued_calls.push(call);
+                return Ok(());
+            }
+        }
+        let invocation_id = self.ids.next_id();
+        self.active_calls.insert(
+            invocation_id,
+            (call.request_id, Arc::clone(&call.caller), callee_id),
+        );
+        let mut details = InvocationDetails::new();
+        details.procedure = if policy == MatchingPolicy::Strict {
+            None
+        } else {
+            Some(call.procedure.clone())
+        };
+        details.receive_progress = call.options.receive_progress;
+        if self.discloses_caller(procedure_id, callee_id) {
+            details.caller = Some(session_id);
+        }
+        let invocation_message = Message::Invocation(
+            invocation_id,
+            procedure_id,
+            details,
+            call.args.take(),
+            call.kwargs.take(),
+        );
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("invocation", callee_session_id = callee_id, invocation_id)
+            .entered();
+        if let Err(e) = send_message(registrant, &invocation_message) {
+            // The callee's connection is gone but its registrations haven't been cleaned up yet.
+            // That's no fault of the caller, whose session carries on.
+            warn!(
+                "Dropping invocation {} of call {}, callee {} is unreachable: {}",
+                invocation_id, call.request_id, callee_id, e
+            );
+            self.errors.report(
+                callee_id,
+                RouterErrorKind::SendFailed,
+                format!("Could not invoke {}: {}", call.procedure.uri, e),
+            );
+            self.active_calls.remove(&invocation_id);
+            return call.fail(Reason::NoEligibleCallee);
+        }
+        Ok(())
+    }
+
+    /// Invokes every registrant of the procedure and answers the caller with all of their
+    /// results once the last one is in.  Concurrency limits don't apply to these calls.
+    fn dispatch_fan_out(&mut self, call: QueuedCall) -> WampResult<()> {
+        let (registrants, procedure_id, policy) = match self
+            .registrations
+            .get_registrants_for(call.procedure.clone())
+        {
+            Ok(registrants) => registrants,
+            Err(e) => return call.fail(e.reason()),
+        };
+        let mut fan_out = FanOutCall {
+            request_id: call.request_id,
+            caller: Arc::clone(&call.caller),
+            pending: Vec::new(),
+            results: List::new(),
+        };
+        for registrant in registrants {
+            let callee_id = registrant.get_id();
+            if let Some(handler) = self.local_handlers.get_mut(&callee_id) {
+                debug!("Invoking local procedure {}", procedure_id);
+                let result = handler(
+                    call.args.clone().unwrap_or_default(),
+                    call.kwargs.clone().unwrap_or_default(),
+                );
+                fan_out.results.push(callee_result(callee_id, result));
+                continue;
+            }
+            let invocation_id = self.ids.next_id();
+            self.active_calls.insert(
+                invocation_id,
+                (call.request_id, Arc::clone(&call.caller), callee_id),
+            );
+            fan_out.pending.push(invocation_id);
+            let mut details = InvocationDetails::new();
+            if policy != MatchingPolicy::Strict {
+                details.procedure = Some(call.procedure.clone());
+            }
+            if self.discloses_caller(procedure_id, callee_id) {
+                details.caller = Some(call.caller_id());
+            }
+            let invocation_message = Message::Invocation(
+                invocation_id,
+                procedure_id,
+                details,
+                call.args.clone(),
+                call.kwargs.clone(),
+            );
+            if let Err(e) = send_message(registrant, &invocation_message) {
+                debug!("Could not invoke callee {}: {}", callee_id, e);
+                self.errors.report(
+                    callee_id,
+                    RouterErrorKind::SendFailed,
+                    format!("Could not invoke {}: {}", call.procedure.uri, e),
+                );
+                // A registrant that can't be reached won't answer, so it counts as failed
+                self.active_calls.remove(&invocation_id);
+                fan_out.pending.retain(|id| *id != invocation_id);
+                let error = CallError::new(Reason::NoEligibleCallee, None, None);
+                fan_out.results.push(callee_result(callee_id, Err(error)));
+            }
+        }
+        if fan_out.pending.is_empty() {
+            fan_out.finish()
+        } else {
+            self.fan_out_calls.push(fan_out);
+            Ok(())
+        }
+    }
+
+    /// Whether `session_id` may answer the invocation, which is any session if it isn't known.
+    /// Only the callee an invocation was sent to may answer it.
+    pub fn answerable_by(&self, invocation_id: ID, session_id: ID) -> bool {
+        self.active_calls
+            .get(&invocation_id)
+            .is_none_or(|(_, _, callee_id)| *callee_id == session_id)
+    }
+
+    /// Whether the invocation was made for a call to every registrant
+    pub fn is_fan_out_invocation(&self, invocation_id: ID) -> bool {
+        self.fan_out_calls
+            .iter()
+            .any(|call| call.pending.contains(&invocation_id))
+    }
+
+    /// Records the final answer to an invocation made for a call to every registrant.  The
+    /// caller gets the collected results once each registrant has answered.
+    pub fn finish_fan_out_invocation(
+        &mut self,
+        invocation_id: ID,
+        result: CallResult<(Option<List>, Option<Dict>)>,
+    ) -> WampResult<()> {
+        let index = self
+            .fan_out_calls
+            .iter()
+            .position(|call| call.pending.contains(&invocation_id));
+        let (index, (_, _, callee_id)) = match (index, self.active_calls.remove(&invocation_id)) {
+            (Some(index), Some(active_call)) => (index, active_call),
+            _ => {
+                return Err(Error::new(ErrorKind::InvalidState(
+                    "Received an answer for a call that wasn't sent",
+                )))
+            }
+        };
+        let fan_out = &mut self.fan_out_calls[index];
+        fan_out.pending.retain(|id| *id != invocation_id);
+        fan_out.results.push(callee_result(callee_id, result));
+        if !fan_out.pending.is_empty() {
+            return Ok(());
+        }
+        let fan_out = self.fan_out_calls.remove(index);
+        let caller = Arc::clone(&fan_out.caller);
+        if let Err(e) = fan_out.finish() {
+            self.report_unanswered(&caller, e);
+        }
+        self.dispatch_queued_calls();
+        Ok(())
+    }
+
+    /// Reports that a caller couldn't be sent the answer to its call, say because its queue is
+    /// full.  That is no fault of the callee that answered, whose session carries on.
+    pub fn report_unanswered(&self, caller: &Mutex<ConnectionInfo>, error: Error) {
+        let caller_id = connection_id(caller);
+        warn!("Could not answer caller {}: {}", caller_id, error);
+        self.errors.report(
+            caller_id,
+            RouterErrorKind::SendFailed,
+            format!("Could not answer a call: {}", error),
+        );
+    }
+
+    /// Fails the invocations a leaving callee hasn't answered, so that their callers aren't
+    /// left waiting for a result that won't come
+    pub fn abandon_invocations(&mut self, callee_id: ID) {
+        let abandoned: Vec<ID> = self
+            .active_calls
+            .iter()
+            .filter(|(_, (_, _, callee))| *callee == callee_id)
+            .map(|(invocation_id, _)| *invocation_id)
+            .collect();
+        for invocation_id in abandoned {
+            debug!(
+                "Callee {} left without answering invocation {}",
+                callee_id, invocation_id
+            );
+            if self.is_fan_out_invocation(invocation_id) {
+                let error = CallError::new(Reason::NoEligibleCallee, None, None);
+                if let Err(e) = self.finish_fan_out_invocation(invocation_id, Err(error)) {
+                    debug!("Could not answer a call to every registrant: {}", e);
+                }
+            } else if let Some((call_id, caller, _)) = self.active_calls.remove(&invocation_id) {
+                let error_message = Message::Error(
+                    ErrorType::Call,
+                    call_id,
+                    HashMap::new(),
+                    Reason::NoEligibleCallee,
+                    None,
+                    None,
+                );
+                if let Err(e) = send_message(&caller, &error_message) {
+                    debug!("Could not tell the caller its callee left: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Tries the queued calls again, in the order they arrived
+    pub fn dispatch_queued_calls(&mut self) {
+        for call in mem::take(&mut self.queued_calls) {
+            if let Err(e) = self.dispatch_call(call) {
+                debug!("Could not dispatch a queued call: {}", e);
+            }
+        }
+    }
+
+    /// Whether the callee registered the procedure with `disclose_caller`
+    fn discloses_caller(&self, procedure_id: ID, callee_id: ID) -> bool {
+        self.disclosing_registrants
+            .contains(&(procedure_id, callee_id))
+    }
+
+    /// Counts the invocations each callee has yet to answer
+    fn in_flight_calls(&self) -> HashMap<ID, usize> {
+        let mut counts = HashMap::new();
+        for (_, _, callee) in self.active_calls.values() {
+            *counts.entry(*callee).or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+impl FanOutCall {
+    /// Sends the caller one entry per registrant
+    fn finish(self) -> WampResult<()> {
+        let result_message = Message::Result(
+            self.request_id,
+            ResultDetails::new(),
+            Some(self.results),
+            None,
+        );
+        send_message(&self.caller, &result_message)
+    }
+}
+
+/// Describes how one registrant answered a call made to every registrant
+fn callee_result(callee_id: ID, result: CallResult<(Option<List>, Option<Dict>)>) -> Value {
+    let mut entry = Dict::new();
+    entry.insert("callee".to_string(), Value::UnsignedInteger(callee_id));
+    let (args, kwargs) = match result {
+        Ok(answer) => answer,
+        Err(error) => {
+            let (reason, args, kwargs) = error.into_tuple();
+            entry.insert("error".to_string(), Value::String(reason.to_string()));
+            (args, kwargs)
+        }
+    };
+    let args = Value::List(args.unwrap_or_default());
+    let kwargs = Value::Dict(kwargs.unwrap_or_default());
+    entry.insert("args".to_string(), args);
+    entry.insert("kwargs".to_string(), kwargs);
+    Value::Dict(entry)
+}
+
+/// Builds the message sent back to a caller once a procedure handled by the router completes
+fn call_result_message(request_id: ID, result: CallResult<(Option<List>, Option<Dict>)>) -> Message {
+    match result {
+        Ok((args, kwargs)) => Message::Result(request_id, ResultDetails::new(), args, kwargs),
+        Err(error) => {
+            let (reason, args, kwargs) = error.into_tuple();
+            Message::Error(
+                ErrorType::Call,
+                request_id,
+                HashMap::new(),
+                reason,
+                args,
+                kwargs,
+            )
+        }
+    }
+}
@@ -12,7 +12,7 @@ use rand::{seq::SliceRandom, thread_rng};
 
 use crate::{messages::Reason, InvocationPolicy, MatchingPolicy, ID, URI};
 
-use super::super::{random_id, ConnectionInfo};
+use super::super::{connection_id, ConnectionInfo, IdGenerator, RandomIdGenerator};
 
 /// Contains a trie corresponding to the registration patterns that connections have requested.
 ///
@@ -26,6 +26,7 @@ pub struct RegistrationPatternNode<P: PatternData> {
     prefix_connections: ProcdureCollection<P>,
     id: ID,
     prefix_id: ID,
+    ids: Arc<dyn IdGenerator>,
 }
 
 /// Represents data that a pattern trie will hold
@@ -36,12 +37,19 @@ pub trait PatternData {
 struct DataWrapper<P: PatternData> {
     registrant: P,
     policy: MatchingPolicy,
+    /// Most invocations the registrant accepts at a time
+    concurrency: Option<u32>,
 }
 
 struct ProcdureCollection<P: PatternData> {
     invocation_policy: InvocationPolicy,
     round_robin_counter: RefCell<usize>,
+    /// Maps each caller to the id of the registrant its calls stick to
+    sticky_callees: RefCell<HashMap<ID, ID>>,
     procedures: Vec<DataWrapper<P>>,
+    /// Set when the last registrant left without unregistering, so the procedure is still
+    /// known but nobody can currently handle it
+    vacant: bool,
 }
 
 /// Represents an error caused during adding or removing patterns
@@ -63,7 +71,7 @@ impl PatternError {
 
 impl PatternData for Arc<Mutex<ConnectionInfo>> {
     fn get_id(&self) -> ID {
-        self.lock().unwrap().id
+        connection_id(self)
     }
 }
 
@@ -85,6 +93,7 @@ impl<P: PatternData> ProcdureCollection<P> {
         registrant: P,
         matching_policy: MatchingPolicy,
         invocation_policy: InvocationPolicy,
+        concurrency: Option<u32>,
     ) -> Result<(), PatternError> {
         if self.procedures.is_empty()
             || (invocation_policy == self.invocation_policy
@@ -93,34 +102,122 @@ impl<P: PatternData> ProcdureCollection<P> {
             self.procedures.push(DataWrapper {
                 registrant,
                 policy: matching_policy,
+                concurrency,
             });
             self.invocation_policy = invocation_policy;
+            self.vacant = false;
             Ok(())
         } else {
             Err(PatternError::new(Reason::ProcedureAlreadyExists))
         }
     }
 
-    fn remove_procedure(&mut self, registrant_id: ID) {
+    fn is_empty(&self) -> bool {
+        self.procedures.is_empty()
+    }
+
+    fn remove_procedure(&mut self, registrant_id: ID, vacate: bool) {
         self.procedures
             .retain(|sub| sub.registrant.get_id() != registrant_id);
+        self.vacant = vacate && self.procedures.is_empty();
+        self.sticky_callees
+            .borrow_mut()
+            .retain(|_, callee| *callee != registrant_id);
     }
 
-    fn get_entry(&self) -> Option<&DataWrapper<P>> {
-        match self.invocation_policy {
+    fn get_entry(
+        &self,
+        caller: ID,
+        in_flight: &HashMap<ID, usize>,
+    ) -> Result<&DataWrapper<P>, Reason> {
+        self.get_entries()?;
+        let entry = match self.invocation_policy {
             InvocationPolicy::Single | InvocationPolicy::First => self.procedures.first(),
             InvocationPolicy::Last => self.procedures.last(),
             InvocationPolicy::Random => self.procedures.choose(&mut thread_rng()),
-            InvocationPolicy::RoundRobin => {
-                let mut counter = self.round_robin_counter.borrow_mut();
-                if *counter >= self.procedures.len() {
-                    *counter = 0
+            InvocationPolicy::RoundRobin => self.next_entry(),
+            InvocationPolicy::Sticky => {
+                let mut callees = self.sticky_callees.borrow_mut();
+                let stuck = callees.get(&caller).and_then(|callee| {
+                    self.procedures
+                        .iter()
+                        .find(|entry| entry.registrant.get_id() == *callee)
+                });
+                if stuck.is_some() {
+                    stuck
+                } else {
+                    let entry = self.next_entry();
+                    if let Some(entry) = entry {
+                        callees.insert(caller, entry.registrant.get_id());
+                    }
+                    entry
                 }
-                let result = self.procedures.get(*counter);
-                *counter += 1;
-                result
             }
+            InvocationPolicy::LeastBusy => self.least_busy_entry(in_flight),
+        };
+        let entry = entry.ok_or(Reason::NoEligibleCallee)?;
+        // A registrant at its concurrency limit hands the call to one that isn't, if any
+        if is_saturated(entry, in_flight) {
+            if let Some(other) = self
+                .procedures
+                .iter()
+                .find(|other| !is_saturated(other, in_flight))
+            {
+                return Ok(other);
+            }
+        }
+        Ok(entry)
+    }
+
+    /// Finds the registrant with the session id `callee`
+    fn get_entry_with_id(&self, callee: ID) -> Result<&DataWrapper<P>, Reason> {
+        self.get_entries()?
+            .iter()
+            .find(|entry| entry.registrant.get_id() == callee)
+            .ok_or(Reason::NoEligibleCallee)
+    }
+
+    /// All the registrants, as long as there is at least one
+    fn get_entries(&self) -> Result<&[DataWrapper<P>], Reason> {
+        if self.procedures.is_empty() {
+            Err(if self.vacant {
+                Reason::NoEligibleCallee
+            } else {
+                Reason::NoSuchProcedure
+            })
+        } else {
+            Ok(&self.procedures)
+        }
+    }
+
+    /// Picks the registrant with the fewest unanswered invocations.  Ties are broken by
+    /// taking the tied registrants in turn, as `RoundRobin` would.
+    fn least_busy_entry(&self, in_flight: &HashMap<ID, usize>) -> Option<&DataWrapper<P>> {
+        let load = |entry: &DataWrapper<P>| {
+            in_flight
+                .get(&entry.registrant.get_id())
+                .copied()
+                .unwrap_or(0)
+        };
+        let least = self.procedures.iter().map(load).min()?;
+        let mut counter = self.round_robin_counter.borrow_mut();
+        let len = self.procedures.len();
+        let index = (0..len)
+            .map(|offset| (*counter + offset) % len)
+            .find(|&index| load(&self.procedures[index]) == least)?;
+        *counter = index + 1;
+        self.procedures.get(index)
+    }
+
+    /// Picks registrants in turn
+    fn next_entry(&self) -> Option<&DataWrapper<P>> {
+        let mut counter = self.round_robin_counter.borrow_mut();
+        if *counter >= self.procedures.len() {
+            *counter = 0
         }
+        let result = self.procedures.get(*counter);
+        *counter += 1;
+        result
     }
 }
 
@@ -158,17 +255,37 @@ impl<P: PatternData> RegistrationPatternNode<P> {
         registrant: P,
         matching_policy: MatchingPolicy,
         invocation_policy: InvocationPolicy,
+    ) -> Result<ID, PatternError> {
+        self.register_with_concurrency(topic, registrant, matching_policy, invocation_policy, None)
+    }
+
+    /// Add a new registration whose registrant accepts at most `concurrency` invocations at
+    /// a time.  `None` sets no limit.
+    pub fn register_with_concurrency(
+        &mut self,
+        topic: &URI,
+        registrant: P,
+        matching_policy: MatchingPolicy,
+        invocation_policy: InvocationPolicy,
+        concurrency: Option<u32>,
     ) -> Result<ID, PatternError> {
         let mut uri_bits = topic.uri.split('.');
         let initial = match uri_bits.next() {
             Some(initial) => initial,
             None => return Err(PatternError::new(Reason::InvalidURI)),
         };
+        let ids = &self.ids;
         let edge = self
             .edges
             .entry(initial.to_string())
-            .or_insert_with(RegistrationPatternNode::new);
-        edge.add_registration(uri_bits, registrant, matching_policy, invocation_policy)
+            .or_insert_with(|| RegistrationPatternNode::with_id_generator(Arc::clone(ids)));
+        edge.add_registration(
+            uri_bits,
+            registrant,
+            matching_policy,
+            invocation_policy,
+            concurrency,
+        )
     }
 
     /// Removes a registration from the pattern trie.
@@ -179,38 +296,140 @@ impl<P: PatternData> RegistrationPatternNode<P> {
         is_prefix: bool,
     ) -> Result<ID, PatternError> {
         let uri_bits = topic.split('.');
-        self.remove_registration(uri_bits, registrant.get_id(), is_prefix)
+        self.remove_registration(uri_bits, registrant.get_id(), is_prefix, false)
+    }
+
+    /// Removes a registration whose registrant went away without unregistering.
+    ///
+    /// If it was the last registrant, calls to the procedure fail with `NoEligibleCallee`
+    /// rather than `NoSuchProcedure` until it is registered again.
+    pub fn vacate_with(
+        &mut self,
+        topic: &str,
+        registrant: &P,
+        is_prefix: bool,
+    ) -> Result<ID, PatternError> {
+        let uri_bits = topic.split('.');
+        self.remove_registration(uri_bits, registrant.get_id(), is_prefix, true)
     }
 
-    /// Gets a registrant that matches the given uri
+    /// Gets a registrant that matches the given uri for a call made by the session `caller`.
+    ///
+    /// `in_flight` holds the number of unanswered invocations of each registrant, by id.  A
+    /// registrant at its concurrency limit is only returned when every other registrant of
+    /// the procedure is too, along with the limit.
+    #[allow(clippy::type_complexity)]
     pub fn get_registrant_for(
         &self,
         procedure: URI,
-    ) -> Result<(&P, ID, MatchingPolicy), PatternError> {
-        let wrapper = self.find_registrant(&procedure.uri.split('.').collect::<Vec<&str>>(), 0);
+        caller: ID,
+        in_flight: &HashMap<ID, usize>,
+    ) -> Result<(&P, ID, MatchingPolicy, Option<u32>), PatternError> {
+        let wrapper = self.find_registrant(
+            &procedure.uri.split('.').collect::<Vec<&str>>(),
+            &|procedures| procedures.get_entry(caller, in_flight),
+        );
+        match wrapper {
+            Ok((data, id)) => Ok((&data.registrant, id, data.policy, data.concurrency)),
+            Err(reason) => Err(PatternError::new(reason)),
+        }
+    }
+
+    /// Gets the registrant with the session id `callee` among those matching the given uri,
+    /// whatever the invocation policy would pick.
+    #[allow(clippy::type_complexity)]
+    pub fn get_registrant_with_id(
+        &self,
+        procedure: URI,
+        callee: ID,
+    ) -> Result<(&P, ID, MatchingPolicy, Option<u32>), PatternError> {
+        let wrapper = self.find_registrant(
+            &procedure.uri.split('.').collect::<Vec<&str>>(),
+            &|procedures| procedures.get_entry_with_id(callee),
+        );
+        match wrapper {
+            Ok((data, id)) => Ok((&data.registrant, id, data.policy, data.concurrency)),
+            Err(reason) => Err(PatternError::new(reason)),
+        }
+    }
+
+    /// Gets every registrant of the registration matching the given uri
+    pub fn get_registrants_for(
+        &self,
+        procedure: URI,
+    ) -> Result<(Vec<&P>, ID, MatchingPolicy), PatternError> {
+        let wrapper = self.find_registrant(
+            &procedure.uri.split('.').collect::<Vec<&str>>(),
+            &|procedures| procedures.get_entries(),
+        );
         match wrapper {
-            Some((data, id)) => Ok((&data.registrant, id, data.policy)),
-            None => Err(PatternError::new(Reason::NoSuchProcedure)),
+            Ok((data, id)) => Ok((
+                data.iter().map(|entry| &entry.registrant).collect(),
+                id,
+                data[0].policy,
+            )),
+            Err(reason) => Err(PatternError::new(reason)),
+        }
+    }
+
+    /// Counts the registrations in the trie that still have at least one registrant
+    pub fn registration_count(&self) -> usize {
+        let own =
+            !self.connections.is_empty() as usize + !self.prefix_connections.is_empty() as usize;
+        own + self
+            .edges
+            .values()
+            .map(|edge| edge.registration_count())
+            .sum::<usize>()
+    }
+
+    /// Lists the registrations in the trie that still have at least one registrant, by id
+    /// along with how they match
+    pub fn registration_ids(&self) -> Vec<(ID, MatchingPolicy)> {
+        let mut ids = Vec::new();
+        self.collect_registration_ids(&mut ids);
+        ids
+    }
+
+    fn collect_registration_ids(&self, ids: &mut Vec<(ID, MatchingPolicy)>) {
+        if let Some(entry) = self.connections.procedures.first() {
+            ids.push((self.id, entry.policy));
+        }
+        if !self.prefix_connections.is_empty() {
+            ids.push((self.prefix_id, MatchingPolicy::Prefix));
+        }
+        for edge in self.edges.values() {
+            edge.collect_registration_ids(ids);
         }
     }
 
     /// Constructs a new RegistrationPatternNode to be used as the root of the trie
     #[inline]
     pub fn new() -> RegistrationPatternNode<P> {
+        RegistrationPatternNode::with_id_generator(Arc::new(RandomIdGenerator))
+    }
+
+    /// Constructs a new root whose nodes take their ids from `ids`
+    pub fn with_id_generator(ids: Arc<dyn IdGenerator>) -> RegistrationPatternNode<P> {
         RegistrationPatternNode {
             edges: HashMap::new(),
             connections: ProcdureCollection {
                 invocation_policy: InvocationPolicy::Single,
                 round_robin_counter: RefCell::new(0),
+                sticky_callees: RefCell::new(HashMap::new()),
                 procedures: Vec::new(),
+                vacant: false,
             },
             prefix_connections: ProcdureCollection {
                 invocation_policy: InvocationPolicy::Single,
                 round_robin_counter: RefCell::new(0),
+                sticky_callees: RefCell::new(HashMap::new()),
                 procedures: Vec::new(),
+                vacant: false,
             },
-            id: random_id(),
-            prefix_id: random_id(),
+            id: ids.next_id(),
+            prefix_id: ids.next_id(),
+            ids,
         }
     }
 
@@ -220,6 +439,7 @@ impl<P: PatternData> RegistrationPatternNode<P> {
         registrant: P,
         matching_policy: MatchingPolicy,
         invocation_policy: InvocationPolicy,
+        concurrency: Option<u32>,
     ) -> Result<ID, PatternError>
     where
         I: Iterator<Item = &'a str>,
@@ -229,11 +449,18 @@ impl<P: PatternData> RegistrationPatternNode<P> {
                 if uri_bit.is_empty() && matching_policy != MatchingPolicy::Wildcard {
                     return Err(PatternError::new(Reason::InvalidURI));
                 }
+                let ids = &self.ids;
                 let edge = self
                     .edges
                     .entry(uri_bit.to_string())
-                    .or_insert_with(RegistrationPatternNode::new);
-                edge.add_registration(uri_bits, registrant, matching_policy, invocation_policy)
+                    .or_insert_with(|| RegistrationPatternNode::with_id_generator(Arc::clone(ids)));
+                edge.add_registration(
+                    uri_bits,
+                    registrant,
+                    matching_policy,
+                    invocation_policy,
+                    concurrency,
+                )
             }
             None => {
                 if matching_policy == MatchingPolicy::Prefix {
@@ -241,6 +468,7 @@ impl<P: PatternData> RegistrationPatternNode<P> {
                         registrant,
                         matching_policy,
                         invocation_policy,
+                        concurrency,
                     )?;
                     Ok(self.prefix_id)
                 } else {
@@ -248,6 +476,7 @@ impl<P: PatternData> RegistrationPatternNode<P> {
                         registrant,
                         matching_policy,
                         invocation_policy,
+                        concurrency,
                     )?;
                     Ok(self.id)
                 }
@@ -260,6 +489,7 @@ impl<P: PatternData> RegistrationPatternNode<P> {
         mut uri_bits: I,
         registrant_id: u64,
         is_prefix: bool,
+        vacate: bool,
     ) -> Result<ID, PatternError>
     where
         I: Iterator<Item = &'a str>,
@@ -268,60 +498,114 @@ impl<P: PatternData> RegistrationPatternNode<P> {
         match uri_bits.next() {
             Some(uri_bit) => {
                 if let Some(edge) = self.edges.get_mut(uri_bit) {
-                    edge.remove_registration(uri_bits, registrant_id, is_prefix)
+                    edge.remove_registration(uri_bits, registrant_id, is_prefix, vacate)
                 } else {
                     Err(PatternError::new(Reason::InvalidURI))
                 }
             }
             None => {
                 if is_prefix {
-                    self.prefix_connections.remove_procedure(registrant_id);
+                    self.prefix_connections
+                        .remove_procedure(registrant_id, vacate);
                     Ok(self.prefix_id)
                 } else {
-                    self.connections.remove_procedure(registrant_id);
+                    self.connections.remove_procedure(registrant_id, vacate);
                     Ok(self.id)
                 }
             }
         }
     }
 
-    fn find_registrant(&self, uri_bits: &[&str], depth: usize) -> Option<(&DataWrapper<P>, ID)> {
-        if depth == uri_bits.len() {
-            if let Some(registrant) = self.connections.get_entry() {
-                Some((registrant, self.id))
-            } else if let Some(registrant) = self.prefix_connections.get_entry() {
-                Some((registrant, self.prefix_id))
-            } else {
-                None
-            }
-        } else if let Some((registrant, id)) = self.recurse(uri_bits, depth) {
-            Some((registrant, id))
-        } else if let Some(registrant) = self.prefix_connections.get_entry() {
-            Some((registrant, self.prefix_id))
-        } else {
-            None
-        }
+    /// Looks up the registrant for a call, preferring a strict registration over a wildcard
+    /// one and either of those over a prefix registration.  Among wildcard registrations the
+    /// one matching more leading components literally wins, and among prefix registrations the
+    /// longest prefix wins.
+    ///
+    /// `select` picks from the registrants of a matching registration, or fails to have the
+    /// lookup move on to the next one.
+    fn find_registrant<'a, T, F>(&'a self, uri_bits: &[&str], select: &F) -> Result<(T, ID), Reason>
+    where
+        F: Fn(&'a ProcdureCollection<P>) -> Result<T, Reason>,
+    {
+        self.find_exact(uri_bits, 0, select).or_else(|reason| {
+            self.find_prefix(uri_bits, 0, select)
+                .map_err(|prefix_reason| most_specific(reason, prefix_reason))
+        })
     }
 
-    fn recurse(&self, uri_bits: &[&str], depth: usize) -> Option<(&DataWrapper<P>, ID)> {
-        if let Some(edge) = self.edges.get(uri_bits[depth]) {
-            if let Some(registrant) = edge.find_registrant(uri_bits, depth + 1) {
-                return Some(registrant);
-            }
+    /// Looks for a strict or wildcard registration matching every component of the uri
+    fn find_exact<'a, T, F>(
+        &'a self,
+        uri_bits: &[&str],
+        depth: usize,
+        select: &F,
+    ) -> Result<(T, ID), Reason>
+    where
+        F: Fn(&'a ProcdureCollection<P>) -> Result<T, Reason>,
+    {
+        if depth == uri_bits.len() {
+            return select(&self.connections).map(|selected| (selected, self.id));
         }
-        if let Some(edge) = self.edges.get("") {
-            if let Some(registrant) = edge.find_registrant(uri_bits, depth + 1) {
-                return Some(registrant);
+        let mut reason = Reason::NoSuchProcedure;
+        for chunk in &[uri_bits[depth], ""] {
+            if let Some(edge) = self.edges.get(*chunk) {
+                match edge.find_exact(uri_bits, depth + 1, select) {
+                    Ok(registrant) => return Ok(registrant),
+                    Err(edge_reason) => reason = most_specific(reason, edge_reason),
+                }
             }
         }
-        None
+        Err(reason)
+    }
+
+    /// Looks for the longest prefix registration the uri starts with
+    fn find_prefix<'a, T, F>(
+        &'a self,
+        uri_bits: &[&str],
+        depth: usize,
+        select: &F,
+    ) -> Result<(T, ID), Reason>
+    where
+        F: Fn(&'a ProcdureCollection<P>) -> Result<T, Reason>,
+    {
+        // Prefixes never contain empty components, so only the literal edge can lead to one
+        let deeper = match uri_bits.get(depth).and_then(|chunk| self.edges.get(*chunk)) {
+            Some(edge) => edge.find_prefix(uri_bits, depth + 1, select),
+            None => Err(Reason::NoSuchProcedure),
+        };
+        deeper.or_else(|reason| {
+            select(&self.prefix_connections)
+                .map(|selected| (selected, self.prefix_id))
+                .map_err(|prefix_reason| most_specific(reason, prefix_reason))
+        })
+    }
+}
+
+/// Whether the registrant already has as many unanswered invocations as it accepts
+fn is_saturated<P: PatternData>(entry: &DataWrapper<P>, in_flight: &HashMap<ID, usize>) -> bool {
+    entry.concurrency.is_some_and(|limit| {
+        in_flight
+            .get(&entry.registrant.get_id())
+            .is_some_and(|&count| count >= limit as usize)
+    })
+}
+
+/// Chooses which of two failed lookups to report, preferring a procedure that is known but
+/// has no callee over one that was never registered
+fn most_specific(first: Reason, second: Reason) -> Reason {
+    if first == Reason::NoEligibleCallee {
+        first
+    } else {
+        second
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashMap;
+
     use super::{PatternData, RegistrationPatternNode};
-    use crate::{InvocationPolicy, MatchingPolicy, ID, URI};
+    use crate::{messages::Reason, InvocationPolicy, MatchingPolicy, ID, URI};
 
     #[derive(Clone)]
     struct MockData {
@@ -380,25 +664,35 @@ mod test {
         println!("ids: {:?}", ids);
 
         assert_eq!(
-            root.get_registrant_for(URI::new("com.example.test.specific.topic"))
-                .unwrap()
-                .1,
+            root.get_registrant_for(
+                URI::new("com.example.test.specific.topic"),
+                0,
+                &HashMap::new()
+            )
+            .unwrap()
+            .1,
             ids[1]
         );
         assert_eq!(
-            root.get_registrant_for(URI::new("com.example.test.another.topic"))
-                .unwrap()
-                .1,
+            root.get_registrant_for(
+                URI::new("com.example.test.another.topic"),
+                0,
+                &HashMap::new()
+            )
+            .unwrap()
+            .1,
             ids[0]
         );
         assert_eq!(
-            root.get_registrant_for(URI::new("com.example.test.another"))
+            root.get_registrant_for(URI::new("com.example.test.another"), 0, &HashMap::new())
                 .unwrap()
                 .1,
             ids[3]
         );
         assert_eq!(
-            root.get_registrant_for(URI::new("com.example")).unwrap().1,
+            root.get_registrant_for(URI::new("com.example"), 0, &HashMap::new())
+                .unwrap()
+                .1,
             ids[2]
         );
     }
@@ -449,10 +743,204 @@ mod test {
 
         println!("ids: {:?}", ids);
         assert_eq!(
-            root.get_registrant_for(URI::new("com.example.test.specific.topic"))
-                .unwrap()
-                .1,
+            root.get_registrant_for(
+                URI::new("com.example.test.specific.topic"),
+                0,
+                &HashMap::new()
+            )
+            .unwrap()
+            .1,
             ids[1]
         );
     }
+
+    #[test]
+    fn sticky_policy() {
+        let mut root = RegistrationPatternNode::new();
+        for id in 1..=2 {
+            root.register_with(
+                &URI::new("com.example.shared"),
+                MockData::new(id),
+                MatchingPolicy::Strict,
+                InvocationPolicy::Sticky,
+            )
+            .unwrap();
+        }
+
+        fn callee(root: &RegistrationPatternNode<MockData>, caller: ID) -> ID {
+            root.get_registrant_for(URI::new("com.example.shared"), caller, &HashMap::new())
+                .unwrap()
+                .0
+                .get_id()
+        }
+        let first = callee(&root, 10);
+        let second = callee(&root, 20);
+        assert_ne!(first, second);
+        for _ in 0..3 {
+            assert_eq!(callee(&root, 10), first);
+            assert_eq!(callee(&root, 20), second);
+        }
+
+        // A caller whose callee went away sticks to another one
+        root.unregister_with("com.example.shared", &MockData::new(first), false)
+            .unwrap();
+        assert_eq!(callee(&root, 10), second);
+        assert_eq!(callee(&root, 10), second);
+    }
+
+    #[test]
+    fn least_busy_policy() {
+        let mut root = RegistrationPatternNode::new();
+        for id in 1..=3 {
+            root.register_with(
+                &URI::new("com.example.shared"),
+                MockData::new(id),
+                MatchingPolicy::Strict,
+                InvocationPolicy::LeastBusy,
+            )
+            .unwrap();
+        }
+
+        fn callee(root: &RegistrationPatternNode<MockData>, in_flight: &HashMap<ID, usize>) -> ID {
+            root.get_registrant_for(URI::new("com.example.shared"), 0, in_flight)
+                .unwrap()
+                .0
+                .get_id()
+        }
+        let mut in_flight = HashMap::new();
+        in_flight.insert(1, 5);
+        in_flight.insert(2, 5);
+        assert_eq!(callee(&root, &in_flight), 3);
+        assert_eq!(callee(&root, &in_flight), 3);
+
+        // Equally busy callees are taken in turn
+        in_flight.insert(3, 5);
+        let picked = (0..3)
+            .map(|_| callee(&root, &in_flight))
+            .collect::<Vec<_>>();
+        assert_eq!(picked, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn concurrency_limit_reroutes() {
+        let mut root = RegistrationPatternNode::new();
+        for id in 1..=2 {
+            root.register_with_concurrency(
+                &URI::new("com.example.limited"),
+                MockData::new(id),
+                MatchingPolicy::Strict,
+                InvocationPolicy::First,
+                Some(1),
+            )
+            .unwrap();
+        }
+
+        fn callee(root: &RegistrationPatternNode<MockData>, in_flight: &HashMap<ID, usize>) -> ID {
+            root.get_registrant_for(URI::new("com.example.limited"), 0, in_flight)
+                .unwrap()
+                .0
+                .get_id()
+        }
+        let mut in_flight = HashMap::new();
+        assert_eq!(callee(&root, &in_flight), 1);
+        in_flight.insert(1, 1);
+        assert_eq!(callee(&root, &in_flight), 2);
+
+        // With every registrant busy the policy's choice comes back, along with its limit
+        in_flight.insert(2, 1);
+        let (registrant, _, _, concurrency) = root
+            .get_registrant_for(URI::new("com.example.limited"), 0, &in_flight)
+            .unwrap();
+        assert_eq!(registrant.get_id(), 1);
+        assert_eq!(concurrency, Some(1));
+    }
+
+    #[test]
+    fn vacant_registration() {
+        let mut root = RegistrationPatternNode::new();
+        root.register_with(
+            &URI::new("com.example.gone"),
+            MockData::new(1),
+            MatchingPolicy::Strict,
+            InvocationPolicy::Single,
+        )
+        .unwrap();
+        root.register_with(
+            &URI::new("com.example.unregistered"),
+            MockData::new(1),
+            MatchingPolicy::Strict,
+            InvocationPolicy::Single,
+        )
+        .unwrap();
+        root.vacate_with("com.example.gone", &MockData::new(1), false)
+            .unwrap();
+        root.unregister_with("com.example.unregistered", &MockData::new(1), false)
+            .unwrap();
+
+        let reason = |uri| {
+            root.get_registrant_for(URI::new(uri), 0, &HashMap::new())
+                .err()
+                .unwrap()
+                .reason()
+        };
+        assert_eq!(reason("com.example.gone"), Reason::NoEligibleCallee);
+        assert_eq!(reason("com.example.unregistered"), Reason::NoSuchProcedure);
+        assert_eq!(reason("com.example.missing"), Reason::NoSuchProcedure);
+
+        // Registering again fills the vacancy
+        root.register_with(
+            &URI::new("com.example.gone"),
+            MockData::new(2),
+            MatchingPolicy::Strict,
+            InvocationPolicy::Single,
+        )
+        .unwrap();
+        assert!(root
+            .get_registrant_for(URI::new("com.example.gone"), 0, &HashMap::new())
+            .is_ok());
+    }
+
+    #[test]
+    fn registration_precedence() {
+        let mut root = RegistrationPatternNode::new();
+        let mut register = |uri, id, policy| {
+            root.register_with(
+                &URI::new(uri),
+                MockData::new(id),
+                policy,
+                InvocationPolicy::Single,
+            )
+            .unwrap();
+        };
+        register("com", 1, MatchingPolicy::Prefix);
+        register("com.foo", 2, MatchingPolicy::Prefix);
+        register("com..bar", 3, MatchingPolicy::Wildcard);
+        register("com.foo.", 4, MatchingPolicy::Wildcard);
+        register("com.foo.bar", 5, MatchingPolicy::Strict);
+
+        let callee = |root: &RegistrationPatternNode<MockData>, uri| {
+            root.get_registrant_for(URI::new(uri), 0, &HashMap::new())
+                .unwrap()
+                .0
+                .get_id()
+        };
+        // Strict beats wildcard, which beats prefix
+        assert_eq!(callee(&root, "com.foo.bar"), 5);
+        // The wildcard matching more leading components literally wins
+        assert_eq!(callee(&root, "com.foo.baz"), 4);
+        assert_eq!(callee(&root, "com.qux.bar"), 3);
+        // The longest prefix wins
+        assert_eq!(callee(&root, "com.foo.bar.baz"), 2);
+        assert_eq!(callee(&root, "com.qux.baz"), 1);
+
+        root.unregister_with("com.foo.bar", &MockData::new(5), false)
+            .unwrap();
+        root.unregister_with("com.foo.", &MockData::new(4), false)
+            .unwrap();
+        // A wildcard registration deeper in the trie still beats a shorter prefix
+        assert_eq!(callee(&root, "com.foo.bar"), 3);
+        root.unregister_with("com..bar", &MockData::new(3), false)
+            .unwrap();
+        assert_eq!(callee(&root, "com.foo.bar"), 2);
+    }
 }
@@ -0,0 +1,245 @@
+//! Contains the `Serializer` trait, which turns messages into websocket frames for one WAMP
+//! sub-protocol, and `Serializers`, the sub-protocols a router or client is willing to speak.
+//!
+//! Both ends offer their serializers during the websocket handshake, and the one agreed on
+//! encodes and decodes every message of the connection.  `wamp.2.json`, `wamp.2.json.batched`
+//! and `wamp.2.msgpack` are built in; another sub-protocol, such as CBOR, only needs a
+//! `Serializer` implementation registered on the router and the clients.
+use std::{fmt, str, sync::Arc};
+
+use parity_ws::Message as WSMessage;
+
+use crate::{
+    messages::{encode_json, encode_msgpack, Message, MessageRef},
+    Error, ErrorKind, WampResult,
+};
+
+pub(crate) static WAMP_JSON: &str = "wamp.2.json";
+pub(crate) static WAMP_JSON_BATCHED: &str = "wamp.2.json.batched";
+pub(crate) static WAMP_MSGPACK: &str = "wamp.2.msgpack";
+
+/// Terminates each message in a batched JSON frame
+const BATCH_SEPARATOR: char = '\u{1e}';
+
+/// The contents of a websocket frame
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payload {
+    /// A text frame
+    Text(String),
+    /// A binary frame
+    Binary(Vec<u8>),
+}
+
+impl Payload {
+    /// The contents, whatever the kind of frame
+    pub fn as_bytes(&self) -> &[u8] {
+        match *self {
+            Payload::Text(ref text) => text.as_bytes(),
+            Payload::Binary(ref bytes) => bytes,
+        }
+    }
+
+    /// The contents as text, if they are valid UTF-8
+    fn as_text(&self) -> WampResult<&str> {
+        match *self {
+            Payload::Text(ref text) => Ok(text),
+            Payload::Binary(ref bytes) => {
+                str::from_utf8(bytes).map_err(|_| Error::new(ErrorKind::MalformedData))
+            }
+        }
+    }
+}
+
+impl From<Payload> for WSMessage {
+    fn from(payload: Payload) -> WSMessage {
+        match payload {
+            Payload::Text(text) => WSMessage::Text(text),
+            Payload::Binary(bytes) => WSMessage::Binary(bytes),
+        }
+    }
+}
+
+impl From<WSMessage> for Payload {
+    fn from(message: WSMessage) -> Payload {
+        match message {
+            WSMessage::Text(text) => Payload::Text(text),
+            WSMessage::Binary(bytes) => Payload::Binary(bytes),
+        }
+    }
+}
+
+/// Encodes and decodes the messages of one WAMP websocket sub-protocol
+pub trait Serializer: Send + Sync {
+    /// The sub-protocol negotiated during the websocket handshake, such as `wamp.2.json`
+    fn subprotocol_name(&self) -> &str;
+    /// Encodes a message into the frame that carries it
+    fn encode(&self, message: &Message) -> WampResult<Payload>;
+    /// Decodes the messages a frame carries, in the order they were sent
+    fn decode(&self, payload: &Payload) -> WampResult<Vec<Message>>;
+    /// Encodes a message whose arguments are borrowed.  By default they are copied into an
+    /// owned message for `encode`; a serializer that can write them where they are, as the
+    /// built in ones do, overrides this.
+    fn encode_ref(&self, message: &MessageRef<'_>) -> WampResult<Payload> {
+        self.encode(&message.to_message())
+    }
+}
+
+/// `wamp.2.json`, with one message per text frame
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonSerializer;
+
+impl Serializer for JsonSerializer {
+    fn subprotocol_name(&self) -> &str {
+        WAMP_JSON
+    }
+
+    fn encode(&self, message: &Message) -> WampResult<Payload> {
+        encode_json(message).map(Payload::Text)
+    }
+
+    fn decode(&self, payload: &Payload) -> WampResult<Vec<Message>> {
+        Message::from_json_str(payload.as_text()?).map(|message| vec![message])
+    }
+
+    fn encode_ref(&self, message: &MessageRef<'_>) -> WampResult<Payload> {
+        encode_json(message).map(Payload::Text)
+    }
+}
+
+/// `wamp.2.json.batched`, where a text frame may carry several messages, each followed by a
+/// record separator
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BatchedJsonSerializer;
+
+impl Serializer for BatchedJsonSerializer {
+    fn subprotocol_name(&self) -> &str {
+        WAMP_JSON_BATCHED
+    }
+
+    fn encode(&self, message: &Message) -> WampResult<Payload> {
+        encode_batch_of_one(message)
+    }
+
+    fn decode(&self, payload: &Payload) -> WampResult<Vec<Message>> {
+        payload
+            .as_text()?
+            .split_terminator(BATCH_SEPARATOR)
+            .map(Message::from_json_str)
+            .collect()
+    }
+
+    fn encode_ref(&self, message: &MessageRef<'_>) -> WampResult<Payload> {
+        encode_batch_of_one(message)
+    }
+}
+
+/// Encodes a message as a batch of one
+fn encode_batch_of_one<M: serde::Serialize>(message: &M) -> WampResult<Payload> {
+    let mut payload = encode_json(message)?;
+    payload.push(BATCH_SEPARATOR);
+    Ok(Payload::Text(payload))
+}
+
+/// `wamp.2.msgpack`, with one message per binary frame
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MsgPackSerializer;
+
+impl Serializer for MsgPackSerializer {
+    fn subprotocol_name(&self) -> &str {
+        WAMP_MSGPACK
+    }
+
+    fn encode(&self, message: &Message) -> WampResult<Payload> {
+        encode_msgpack(message).map(Payload::Binary)
+    }
+
+    fn decode(&self, payload: &Payload) -> WampResult<Vec<Message>> {
+        Message::from_msgpack(payload.as_bytes()).map(|message| vec![message])
+    }
+
+    fn encode_ref(&self, message: &MessageRef<'_>) -> WampResult<Payload> {
+        encode_msgpack(message).map(Payload::Binary)
+    }
+}
+
+/// The serializers a router or client offers, most preferred first.
+///
+/// During the handshake the router picks the first of its own serializers that the client
+/// offered, so a client only gets a sub-protocol both ends know.
+#[derive(Clone)]
+pub struct Serializers {
+    serializers: Vec<Arc<dyn Serializer>>,
+}
+
+impl Serializers {
+    /// A registry without any serializer
+    pub fn empty() -> Serializers {
+        Serializers {
+            serializers: Vec::new(),
+        }
+    }
+
+    /// Adds `serializer`, preferred less than the ones added before.  A serializer for the
+    /// same sub-protocol is replaced.
+    pub fn with<S: Serializer + 'static>(mut self, serializer: S) -> Serializers {
+        self.remove(serializer.subprotocol_name());
+        self.serializers.push(Arc::new(serializer));
+        self
+    }
+
+    /// Adds `serializer`, preferred over all the others.  A serializer for the same
+    /// sub-protocol is replaced.
+    pub fn prefer<S: Serializer + 'static>(mut self, serializer: S) -> Serializers {
+        self.remove(serializer.subprotocol_name());
+        self.serializers.insert(0, Arc::new(serializer));
+        self
+    }
+
+    /// The serializer for `subprotocol`, if there is one
+    pub fn get(&self, subprotocol: &str) -> Option<Arc<dyn Serializer>> {
+        self.serializers
+            .iter()
+            .find(|serializer| serializer.subprotocol_name() == subprotocol)
+            .cloned()
+    }
+
+    /// The most preferred serializer among the sub-protocols the peer `offered`
+    pub fn negotiate<'a, I>(&self, offered: I) -> Option<Arc<dyn Serializer>>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let offered = offered.into_iter().collect::<Vec<_>>();
+        self.serializers
+            .iter()
+            .find(|serializer| offered.contains(&serializer.subprotocol_name()))
+            .cloned()
+    }
+
+    /// The names of the sub-protocols, most preferred first
+    pub fn subprotocols(&self) -> Vec<&str> {
+        self.serializers
+            .iter()
+            .map(|serializer| serializer.subprotocol_name())
+            .collect()
+    }
+
+    fn remove(&mut self, subprotocol: &str) {
+        self.serializers
+            .retain(|serializer| serializer.subprotocol_name() != subprotocol);
+    }
+}
+
+/// `wamp.2.msgpack`, then `wamp.2.json`
+impl Default for Serializers {
+    fn default() -> Serializers {
+        Serializers::empty()
+            .with(MsgPackSerializer)
+            .with(JsonSerializer)
+    }
+}
+
+impl fmt::Debug for Serializers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.subprotocols()).finish()
+    }
+}
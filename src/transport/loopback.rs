@@ -0,0 +1,88 @@
+//! An in-process transport that hands `Message`s straight from one end of a connection to the
+//! other, without websocket framing or serialization.
+//!
+//! Each end reads `Event`s from its own queue.  Besides the messages sent by the peer, that
+//! queue receives the timeouts the end scheduled for itself and a `Close` once either end
+//! closes the connection.
+use std::{
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+    time::Duration,
+};
+
+use parity_ws::util::Token;
+
+use crate::{messages::Message, Error, ErrorKind, WampResult};
+
+/// Reported as the negotiated protocol of loopback connections
+pub static PROTOCOL: &str = "wampire.loopback";
+
+/// Something that happened to one end of a loopback connection
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Event {
+    /// A message sent by the peer
+    Message(Message),
+    /// A timeout scheduled with `LoopbackSender::timeout` has elapsed
+    Timeout(Token),
+    /// Either end closed the connection
+    Close,
+}
+
+/// The outbound side of one end of a loopback connection
+#[derive(Clone)]
+pub struct LoopbackSender {
+    peer: Sender<Event>,
+    own: Sender<Event>,
+}
+
+/// One end of a loopback connection
+pub struct Endpoint {
+    pub sender: LoopbackSender,
+    pub events: Receiver<Event>,
+}
+
+/// Creates the two ends of a new loopback connection
+pub fn pair() -> (Endpoint, Endpoint) {
+    let (first_tx, first_rx) = channel();
+    let (second_tx, second_rx) = channel();
+    let first = Endpoint {
+        sender: LoopbackSender {
+            peer: second_tx.clone(),
+            own: first_tx.clone(),
+        },
+        events: first_rx,
+    };
+    let second = Endpoint {
+        sender: LoopbackSender {
+            peer: first_tx,
+            own: second_tx,
+        },
+        events: second_rx,
+    };
+    (first, second)
+}
+
+impl LoopbackSender {
+    /// Hands `message` to the peer
+    pub fn send(&self, message: Message) -> WampResult<()> {
+        self.peer
+            .send(Event::Message(message))
+            .map_err(|_| Error::new(ErrorKind::ConnectionLost))
+    }
+
+    /// Closes the connection on both ends
+    pub fn close(&self) {
+        self.peer.send(Event::Close).ok();
+        self.own.send(Event::Close).ok();
+    }
+
+    /// Delivers `token` back to this end once `ms` milliseconds have passed
+    pub fn timeout(&self, ms: u64, token: Token) {
+        let own = self.own.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(ms));
+            own.send(Event::Timeout(token)).ok();
+        });
+    }
+}
@@ -0,0 +1,3 @@
+//! Transports that carry WAMP messages between a router and its clients besides websockets
+
+pub mod loopback;
@@ -0,0 +1,285 @@
+#![cfg(feature = "async-client")]
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::StreamExt;
+use wampire::{client::async_client::Connection, Reason, Router, Value, URI};
+
+#[tokio::test]
+async fn call_and_publish() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let mut callee = Connection::new(&url, "ca.test.realm").connect().await.unwrap();
+    assert_eq!(callee.protocol(), "wamp.2.msgpack");
+    callee
+        .register(
+            URI::new("ca.test.echo"),
+            Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+        )
+        .await
+        .unwrap();
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let events = Arc::clone(&received);
+    callee
+        .subscribe(
+            URI::new("ca.test.topic"),
+            Box::new(move |args, _kwargs| events.lock().unwrap().extend(args)),
+        )
+        .await
+        .unwrap();
+
+    let mut caller = Connection::new(&url, "ca.test.realm").connect().await.unwrap();
+    let (args, _kwargs) = caller
+        .call(
+            URI::new("ca.test.echo"),
+            Some(vec![Value::String("ping".to_string())]),
+            None,
+        )
+        .await
+        .unwrap();
+    assert_eq!(args, vec![Value::String("ping".to_string())]);
+
+    caller
+        .publish_and_acknowledge(
+            URI::new("ca.test.topic"),
+            Some(vec![Value::String("event".to_string())]),
+            None,
+        )
+        .await
+        .unwrap();
+    for _ in 0..50 {
+        if !received.lock().unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![Value::String("event".to_string())]
+    );
+
+    let error = caller
+        .call_with_timeout(
+            URI::new("ca.test.missing"),
+            None,
+            None,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(*error.get_reason(), Reason::NoSuchProcedure);
+
+    caller.shutdown().await.unwrap();
+    callee.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn batched_json() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let mut client = Connection::new(&url, "ca.test.realm")
+        .with_batched_json()
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(client.protocol(), "wamp.2.json.batched");
+    client
+        .register(
+            URI::new("ca.test.echo"),
+            Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+        )
+        .await
+        .unwrap();
+    let (args, _kwargs) = client
+        .call(
+            URI::new("ca.test.echo"),
+            Some(vec![Value::String("batched".to_string())]),
+            None,
+        )
+        .await
+        .unwrap();
+    assert_eq!(args, vec![Value::String("batched".to_string())]);
+    client.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn subscribe_stream() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let mut subscriber = Connection::new(&url, "ca.test.realm").connect().await.unwrap();
+    let (subscription, mut events) = subscriber
+        .subscribe_stream(URI::new("ca.test.topic"))
+        .await
+        .unwrap();
+    assert_eq!(subscription.topic.uri, "ca.test.topic");
+
+    let mut publisher = Connection::new(&url, "ca.test.realm").connect().await.unwrap();
+    for i in 0..2 {
+        publisher
+            .publish_and_acknowledge(
+                URI::new("ca.test.topic"),
+                Some(vec![Value::UnsignedInteger(i)]),
+                None,
+            )
+            .await
+            .unwrap();
+    }
+    for i in 0..2 {
+        let (args, _kwargs) = tokio::time::timeout(Duration::from_secs(5), events.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(args, vec![Value::UnsignedInteger(i)]);
+    }
+}
+
+#[tokio::test]
+async fn streaming_callee_yields_progressive_results() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let mut callee = Connection::new(&url, "ca.test.realm").connect().await.unwrap();
+    callee
+        .register_stream(
+            URI::new("ca.test.countdown"),
+            Box::new(|_args, _kwargs, yielder| {
+                for i in 1..=3 {
+                    yielder
+                        .progress(Some(vec![Value::UnsignedInteger(i)]), None)
+                        .unwrap();
+                }
+                yielder
+                    .finish(Some(vec![Value::String("done".to_string())]), None)
+                    .unwrap();
+            }),
+        )
+        .await
+        .unwrap();
+
+    let mut caller = Connection::new(&url, "ca.test.realm").connect().await.unwrap();
+    let results = caller
+        .call_progressive(URI::new("ca.test.countdown"), None, None)
+        .map(|result| result.unwrap().0)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(
+        results,
+        vec![
+            vec![Value::UnsignedInteger(1)],
+            vec![Value::UnsignedInteger(2)],
+            vec![Value::UnsignedInteger(3)],
+            vec![Value::String("done".to_string())],
+        ]
+    );
+
+    // A caller that didn't ask for progressive results only gets the final one
+    let (args, _kwargs, _details) = caller
+        .call_with_details(URI::new("ca.test.countdown"), None, None)
+        .await
+        .unwrap();
+    assert_eq!(args, vec![Value::String("done".to_string())]);
+}
+
+#[tokio::test]
+async fn next_event_resolves_once() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let mut subscriber = Connection::new(&url, "ca.test.realm").connect().await.unwrap();
+    let subscription = subscriber
+        .subscribe(URI::new("ca.test.topic"), Box::new(|_, _| {}))
+        .await
+        .unwrap();
+    let event = subscriber.next_event(&subscription);
+
+    let mut publisher = Connection::new(&url, "ca.test.realm").connect().await.unwrap();
+    publisher
+        .publish_and_acknowledge(
+            URI::new("ca.test.topic"),
+            Some(vec![Value::UnsignedInteger(1)]),
+            None,
+        )
+        .await
+        .unwrap();
+    let (args, kwargs) = tokio::time::timeout(Duration::from_secs(5), event)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(args, vec![Value::UnsignedInteger(1)]);
+    assert!(kwargs.is_empty());
+
+    // Unsubscribing fails the events still awaited
+    let event = subscriber.next_event(&subscription);
+    subscriber.unsubscribe(subscription).await.unwrap();
+    assert_eq!(
+        *event.await.unwrap_err().get_reason(),
+        Reason::NoSuchSubscription
+    );
+}
+
+#[tokio::test]
+async fn local_delivery_of_borrowed_arguments() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let mut client = Connection::new(&url, "ca.test.realm")
+        .with_local_delivery()
+        .with_header("X-Test", "value")
+        .connect()
+        .await
+        .unwrap();
+    client
+        .register(
+            URI::new("ca.test.echo"),
+            Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+        )
+        .await
+        .unwrap();
+    let own = Arc::new(Mutex::new(Vec::new()));
+    let events = Arc::clone(&own);
+    client
+        .subscribe(
+            URI::new("ca.test.topic"),
+            Box::new(move |args, _kwargs| events.lock().unwrap().push(args)),
+        )
+        .await
+        .unwrap();
+
+    let args = (0..100).map(Value::Integer).collect::<Vec<_>>();
+    client
+        .publish_borrowed(URI::new("ca.test.topic"), Some(&args), None)
+        .unwrap();
+    // The local callback has run by the time the publish returns
+    assert_eq!(*own.lock().unwrap(), vec![args.clone()]);
+
+    let output = client
+        .call_split(URI::new("ca.test.echo"), Some(args.clone()), None)
+        .await
+        .unwrap();
+    assert_eq!(output.args, args);
+    let (echoed, _kwargs) = client
+        .call_borrowed(URI::new("ca.test.echo"), Some(&args[..10]), None)
+        .await
+        .unwrap();
+    assert_eq!(echoed, args[..10]);
+    client.shutdown().await.unwrap();
+}
@@ -0,0 +1,136 @@
+#![cfg(feature = "ws-client")]
+
+use std::thread;
+
+use futures::executor::block_on;
+use parity_ws::{Message as WSMessage, Request, Response, Sender, WebSocket};
+use serde_json::{json, Value as JSONValue};
+
+use wampire::{Connection, URI};
+
+/// A router that answers the handshake and subscriptions, and holds back unsubscribe
+/// confirmations until two are pending so that both are in flight at once.
+///
+/// Subscription ids are chosen to collide with the request ids the client uses next.
+struct ScriptedRouter {
+    out: Sender,
+    pending_unsubscribes: Vec<u64>,
+    /// Number of events sent ahead of the welcome
+    early_events: usize,
+}
+
+impl parity_ws::Handler for ScriptedRouter {
+    fn on_request(&mut self, request: &Request) -> parity_ws::Result<Response> {
+        let mut response = Response::from_request(request)?;
+        response.set_protocol("wamp.2.json");
+        Ok(response)
+    }
+
+    fn on_message(&mut self, message: WSMessage) -> parity_ws::Result<()> {
+        let message: Vec<JSONValue> = serde_json::from_str(message.as_text()?).unwrap();
+        match message[0].as_u64().unwrap() {
+            // HELLO
+            1 => {
+                for _ in 0..self.early_events {
+                    self.out.send(json!([36, 99, 1, {}]).to_string())?;
+                }
+                self.out
+                    .send(json!([2, 1, {"roles": {"dealer": {}, "broker": {}}}]).to_string())
+            }
+            // SUBSCRIBE
+            32 => {
+                let request_id = message[1].as_u64().unwrap();
+                self.out
+                    .send(json!([33, request_id, request_id + 2]).to_string())
+            }
+            // UNSUBSCRIBE
+            34 => {
+                self.pending_unsubscribes
+                    .push(message[1].as_u64().unwrap());
+                if self.pending_unsubscribes.len() == 2 {
+                    for request_id in self.pending_unsubscribes.drain(..) {
+                        self.out.send(json!([35, request_id]).to_string())?;
+                    }
+                }
+                Ok(())
+            }
+            // GOODBYE
+            6 => self
+                .out
+                .send(json!([6, {}, "wamp.close.goodbye_and_out"]).to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Accepts the websocket handshake without choosing one of the offered protocols, then ignores
+/// everything
+struct SilentRouter;
+
+impl parity_ws::Handler for SilentRouter {}
+
+fn start_scripted_router(early_events: usize) -> String {
+    let socket = WebSocket::new(move |out| ScriptedRouter {
+        out,
+        pending_unsubscribes: Vec::new(),
+        early_events,
+    })
+    .unwrap()
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || socket.run().unwrap());
+    format!("ws://{}/ws", addr)
+}
+
+#[test]
+fn concurrent_unsubscribes_resolve_independently() {
+    let url = start_scripted_router(0);
+    let mut client = Connection::new(&url, "ca.test.realm").connect().unwrap();
+
+    // Subscription ids 3 and 4
+    let first = block_on(client.subscribe(URI::new("ca.test.first"), Box::new(|_, _| {}))).unwrap();
+    let second =
+        block_on(client.subscribe(URI::new("ca.test.second"), Box::new(|_, _| {}))).unwrap();
+
+    // Request ids 3 and 4, confirmed together
+    let second_done = client.unsubscribe(second);
+    let first_done = client.unsubscribe(first);
+    block_on(second_done).unwrap();
+    block_on(first_done).unwrap();
+}
+
+#[test]
+fn messages_before_welcome_are_held_back() {
+    let url = start_scripted_router(2);
+    let mut client = Connection::new(&url, "ca.test.realm").connect().unwrap();
+
+    let subscription =
+        block_on(client.subscribe(URI::new("ca.test.topic"), Box::new(|_, _| {}))).unwrap();
+    assert_eq!(subscription.topic.uri, "ca.test.topic");
+}
+
+#[test]
+fn too_many_messages_before_welcome() {
+    let url = start_scripted_router(100);
+    assert!(Connection::new(&url, "ca.test.realm").connect().is_err());
+}
+
+#[test]
+fn router_without_protocol_fails_handshake() {
+    let socket = WebSocket::new(|_out| SilentRouter)
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}/ws", socket.local_addr().unwrap());
+    thread::spawn(move || socket.run().unwrap());
+
+    let error = Connection::new(&url, "ca.test.realm")
+        .connect()
+        .err()
+        .expect("Connected without a protocol");
+    assert_eq!(
+        error.to_string(),
+        "WAMP Error: wamp.error.protocol_violation"
+    );
+}
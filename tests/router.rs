@@ -0,0 +1,2328 @@
+#![cfg(feature = "ws-client")]
+
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::TcpStream,
+    sync::{mpsc::channel, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use futures::{executor::block_on, StreamExt};
+use parity_ws::{CloseCode, Handshake, Message as WSMessage, Request, Sender};
+
+use wampire::{
+    router::{Authorizer, InterceptAction, MessageInterceptor, RouterErrorKind},
+    serializer::{JsonSerializer, Payload},
+    wamp_list, ClientPool, Connection, Error, ErrorKind, ListenerSettings, MatchingPolicy, Message,
+    PublishOptions, RealmOptions, RealmStats, Reason, Router, Serializer, Serializers, Value,
+    WampResult, ID, URI,
+};
+
+/// An authorizer that allows everything and vouches for the given authids
+struct VouchFor(&'static [&'static str]);
+
+impl Authorizer for VouchFor {
+    fn can_subscribe(&self, _session_id: ID, _topic: &URI) -> bool {
+        true
+    }
+
+    fn can_publish(&self, _session_id: ID, _topic: &URI) -> bool {
+        true
+    }
+
+    fn can_register(&self, _session_id: ID, _procedure: &URI) -> bool {
+        true
+    }
+
+    fn can_call(&self, _session_id: ID, _procedure: &URI) -> bool {
+        true
+    }
+
+    fn vouches_for(&self, _session_id: ID, authid: Option<&str>, _authrole: Option<&str>) -> bool {
+        authid.is_some_and(|authid| self.0.contains(&authid))
+    }
+}
+
+/// A bare websocket peer that sends raw WAMP messages as soon as it connects and records
+/// everything the router sends back until the connection is closed
+struct RawPeer {
+    out: Sender,
+    protocol: &'static str,
+    outgoing: Vec<String>,
+    received: Arc<Mutex<Vec<String>>>,
+    close_code: Arc<Mutex<Option<CloseCode>>>,
+}
+
+impl parity_ws::Handler for RawPeer {
+    fn build_request(&mut self, url: &url::Url) -> parity_ws::Result<Request> {
+        let mut request = Request::from_url(url)?;
+        request.add_protocol(self.protocol);
+        Ok(request)
+    }
+
+    fn on_open(&mut self, _: Handshake) -> parity_ws::Result<()> {
+        for message in self.outgoing.drain(..) {
+            self.out.send(message)?;
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, message: WSMessage) -> parity_ws::Result<()> {
+        self.received
+            .lock()
+            .unwrap()
+            .push(message.as_text()?.to_string());
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, _reason: &str) {
+        *self.close_code.lock().unwrap() = Some(code);
+    }
+}
+
+/// Sends `outgoing` over a fresh connection and returns the router's replies once it closes
+fn raw_exchange(
+    addr: &std::net::SocketAddr,
+    protocol: &'static str,
+    outgoing: &[&str],
+) -> Vec<String> {
+    raw_exchange_with_close(addr, protocol, outgoing).0
+}
+
+/// Like `raw_exchange`, also returning the code the router closed the connection with
+fn raw_exchange_with_close(
+    addr: &std::net::SocketAddr,
+    protocol: &'static str,
+    outgoing: &[&str],
+) -> (Vec<String>, Option<CloseCode>) {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let close_code = Arc::new(Mutex::new(None));
+    let outgoing: Vec<String> = outgoing.iter().map(|message| message.to_string()).collect();
+    let (done_tx, done_rx) = channel();
+    {
+        let received = Arc::clone(&received);
+        let close_code = Arc::clone(&close_code);
+        let url = format!("ws://{}/ws", addr);
+        thread::spawn(move || {
+            parity_ws::connect(url, move |out| RawPeer {
+                out,
+                protocol,
+                outgoing: outgoing.clone(),
+                received: Arc::clone(&received),
+                close_code: Arc::clone(&close_code),
+            })
+            .unwrap();
+            done_tx.send(()).unwrap();
+        });
+    }
+    done_rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("router did not close the connection");
+    let received = received.lock().unwrap().clone();
+    let close_code = *close_code.lock().unwrap();
+    (received, close_code)
+}
+
+#[test]
+fn listen_on_ephemeral_port() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    assert_ne!(addr.port(), 0);
+
+    let connection = Connection::new(&format!("ws://{}/ws", addr), "ca.test.realm");
+    let mut client = connection.connect().unwrap();
+    block_on(client.register(
+        URI::new("ca.test.echo"),
+        Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+    ))
+    .unwrap();
+
+    let (args, _kwargs) = block_on(client.call(
+        URI::new("ca.test.echo"),
+        Some(vec![Value::String("ping".to_string())]),
+        None,
+    ))
+    .unwrap();
+    assert_eq!(args, vec![Value::String("ping".to_string())]);
+}
+
+#[test]
+fn max_connections_refuses_extra_connection() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let settings = ListenerSettings {
+        max_connections: 2,
+        ..ListenerSettings::default()
+    };
+    let (addr, _child) = router
+        .listen_with_settings("127.0.0.1:0", settings)
+        .unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let _clients = (0..2)
+        .map(|_| Connection::new(&url, "ca.test.realm").connect().unwrap())
+        .collect::<Vec<_>>();
+    assert!(Connection::new(&url, "ca.test.realm")
+        .with_timeout(Duration::from_secs(1))
+        .connect()
+        .is_err());
+    assert_eq!(router.connection_count("ca.test.realm"), Some(2));
+}
+
+#[test]
+fn shutdown_stops_listener() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let child = router.listen("127.0.0.1:0");
+    let (_addr, bound_child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    router.shutdown();
+
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        child.join().unwrap();
+        bound_child.join().unwrap();
+        tx.send(()).unwrap();
+    });
+    rx.recv_timeout(Duration::from_secs(5)).unwrap();
+}
+
+#[test]
+fn events_from_one_publisher_arrive_in_order() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let mut subscriber = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    let events = Arc::clone(&received);
+    block_on(subscriber.subscribe(
+        URI::new("ca.test.topic"),
+        Box::new(move |args, _kwargs| events.lock().unwrap().extend(args)),
+    ))
+    .unwrap();
+
+    let mut publisher = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    let published = (0..100)
+        .map(|i| Value::String(i.to_string()))
+        .collect::<Vec<_>>();
+    for value in &published {
+        publisher
+            .publish(URI::new("ca.test.topic"), Some(vec![value.clone()]), None)
+            .unwrap();
+    }
+
+    for _ in 0..50 {
+        if received.lock().unwrap().len() == published.len() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(*received.lock().unwrap(), published);
+}
+
+#[test]
+fn publish_to_many_topics() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+
+    let received = Arc::new(Mutex::new(0));
+    let mut subscriber = router.connect_loopback("ca.test.realm").unwrap();
+    let events = Arc::clone(&received);
+    block_on(subscriber.subscribe_with_pattern(
+        URI::new("ca.test.topic"),
+        Box::new(move |_args, _kwargs| *events.lock().unwrap() += 1),
+        MatchingPolicy::Prefix,
+    ))
+    .unwrap();
+
+    let mut publisher = router.connect_loopback("ca.test.realm").unwrap();
+    let publications = (0..100)
+        .map(|i| {
+            (
+                URI::new(&format!("ca.test.topic.{}", i)),
+                Some(wamp_list![i]),
+                None,
+            )
+        })
+        .collect::<Vec<_>>();
+    let results = publisher.publish_many(&publications);
+    assert_eq!(results.len(), publications.len());
+    assert!(results.iter().all(Result::is_ok));
+
+    for _ in 0..50 {
+        if *received.lock().unwrap() == publications.len() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(*received.lock().unwrap(), publications.len());
+}
+
+#[test]
+fn call_split_names_the_results() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+
+    let mut callee = router.connect_loopback("ca.test.realm").unwrap();
+    block_on(callee.register(
+        URI::new("ca.test.echo"),
+        Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+    ))
+    .unwrap();
+
+    let mut caller = router.connect_loopback("ca.test.realm").unwrap();
+    let mut kwargs = HashMap::new();
+    kwargs.insert("name".to_string(), Value::String("echo".to_string()));
+    let result = block_on(caller.call_split(
+        URI::new("ca.test.echo"),
+        Some(wamp_list![1, 2]),
+        Some(kwargs.clone()),
+    ))
+    .unwrap();
+    assert_eq!(result.first_arg(), Some(&Value::UnsignedInteger(1)));
+    assert_eq!(
+        result.kwarg("name"),
+        Some(&Value::String("echo".to_string()))
+    );
+    assert_eq!(result.kwarg("missing"), None);
+    assert_eq!(result.args.len(), 2);
+    assert_eq!(result.kwargs, kwargs);
+}
+
+#[test]
+fn swap_subscription_callback() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+
+    let (old_tx, old_rx) = channel();
+    let mut subscriber = router.connect_loopback("ca.test.realm").unwrap();
+    let subscription = block_on(subscriber.subscribe(
+        URI::new("ca.test.topic"),
+        Box::new(move |args, _kwargs| old_tx.send(args).unwrap()),
+    ))
+    .unwrap();
+
+    let mut publisher = router.connect_loopback("ca.test.realm").unwrap();
+    publisher
+        .publish_args(URI::new("ca.test.topic"), wamp_list![1])
+        .unwrap();
+    assert_eq!(
+        old_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+        wamp_list![1]
+    );
+
+    let (new_tx, new_rx) = channel();
+    assert!(subscriber.set_subscription_callback(
+        subscription.id(),
+        Box::new(move |args, _kwargs| new_tx.send(args).unwrap()),
+    ));
+    publisher
+        .publish_args(URI::new("ca.test.topic"), wamp_list![2])
+        .unwrap();
+    assert_eq!(
+        new_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+        wamp_list![2]
+    );
+    // The old callback was dropped along with its sender
+    assert!(old_rx.recv().is_err());
+
+    assert!(!subscriber.set_subscription_callback(subscription.id() + 1, Box::new(|_, _| {})));
+}
+
+#[test]
+fn local_delivery_to_own_subscription() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let own = Arc::new(Mutex::new(Vec::new()));
+    let mut client = Connection::new(&url, "ca.test.realm")
+        .with_local_delivery()
+        .connect()
+        .unwrap();
+    let events = Arc::clone(&own);
+    block_on(client.subscribe(
+        URI::new("ca.test.topic"),
+        Box::new(move |args, _kwargs| events.lock().unwrap().push(args)),
+    ))
+    .unwrap();
+
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    let mut other = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    block_on(other.subscribe(
+        URI::new("ca.test.topic"),
+        Box::new(move |args, _kwargs| tx.lock().unwrap().send(args).unwrap()),
+    ))
+    .unwrap();
+
+    client
+        .publish_args(URI::new("ca.test.topic"), wamp_list![1])
+        .unwrap();
+    client
+        .publish_args(URI::new("ca.test.other"), wamp_list![2])
+        .unwrap();
+    // The local callback has run by the time the publish returns
+    assert_eq!(*own.lock().unwrap(), vec![wamp_list![1]]);
+    assert_eq!(
+        rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+        wamp_list![1]
+    );
+
+    // The router doesn't echo the event back, so it is only delivered once
+    block_on(client.publish_and_acknowledge(URI::new("ca.test.topic"), None, None)).unwrap();
+    assert_eq!(own.lock().unwrap().len(), 2);
+
+    // Without local delivery the client never sees its own events
+    let received = Arc::new(Mutex::new(0));
+    let count = Arc::clone(&received);
+    block_on(other.subscribe(
+        URI::new("ca.test.mine"),
+        Box::new(move |_args, _kwargs| *count.lock().unwrap() += 1),
+    ))
+    .unwrap();
+    block_on(other.publish_and_acknowledge(URI::new("ca.test.mine"), None, None)).unwrap();
+    assert_eq!(*received.lock().unwrap(), 0);
+}
+
+#[test]
+fn concurrent_publishers_to_one_subscriber() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let mut subscriber = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    let events = Arc::clone(&received);
+    block_on(subscriber.subscribe(
+        URI::new("ca.test.topic"),
+        Box::new(move |args, _kwargs| events.lock().unwrap().push(args)),
+    ))
+    .unwrap();
+
+    const PUBLISHERS: i64 = 4;
+    const EVENTS: i64 = 250;
+    let publishers = (0..PUBLISHERS)
+        .map(|publisher_id| {
+            let mut publisher = Connection::new(&url, "ca.test.realm").connect().unwrap();
+            thread::spawn(move || {
+                for i in 0..EVENTS {
+                    publisher
+                        .publish_args(URI::new("ca.test.topic"), wamp_list![publisher_id, i])
+                        .unwrap();
+                }
+                publisher
+            })
+        })
+        .collect::<Vec<_>>();
+    // Keep the publishers connected until every event has arrived
+    let _publishers = publishers
+        .into_iter()
+        .map(|publisher| publisher.join().unwrap())
+        .collect::<Vec<_>>();
+
+    let expected = (PUBLISHERS * EVENTS) as usize;
+    for _ in 0..100 {
+        if received.lock().unwrap().len() == expected {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), expected);
+    // Every event arrives intact, and each publisher's events arrive in order
+    for publisher_id in 0..PUBLISHERS {
+        let events = received
+            .iter()
+            .filter(|args| args[0] == Value::Integer(publisher_id))
+            .map(|args| args[1].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(events, (0..EVENTS).map(Value::Integer).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn call_times_out() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let mut callee = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    block_on(callee.register(
+        URI::new("ca.test.slow"),
+        Box::new(|args, kwargs| {
+            thread::sleep(Duration::from_millis(500));
+            Ok((Some(args), Some(kwargs)))
+        }),
+    ))
+    .unwrap();
+
+    let mut caller = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    let error = block_on(caller.call_with_timeout(
+        URI::new("ca.test.slow"),
+        None,
+        None,
+        Duration::from_millis(50),
+    ))
+    .unwrap_err();
+    assert_eq!(*error.get_reason(), Reason::Cancelled);
+
+    // The late result is dropped and the client keeps working
+    let (args, _kwargs) = block_on(caller.call_with_timeout(
+        URI::new("ca.test.slow"),
+        Some(vec![Value::String("ping".to_string())]),
+        None,
+        Duration::from_secs(5),
+    ))
+    .unwrap();
+    assert_eq!(args, vec![Value::String("ping".to_string())]);
+}
+
+#[test]
+fn call_over_loopback() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+
+    let mut callee = router.connect_loopback("ca.test.realm").unwrap();
+    assert_eq!(callee.protocol(), "wampire.loopback");
+    block_on(callee.register(
+        URI::new("ca.test.echo"),
+        Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+    ))
+    .unwrap();
+
+    let mut caller = router.connect_loopback("ca.test.realm").unwrap();
+    let (args, _kwargs) = block_on(caller.call(
+        URI::new("ca.test.echo"),
+        Some(vec![Value::String("ping".to_string())]),
+        None,
+    ))
+    .unwrap();
+    assert_eq!(args, vec![Value::String("ping".to_string())]);
+    assert_eq!(router.connection_count("ca.test.realm"), Some(2));
+
+    block_on(caller.shutdown()).unwrap();
+    block_on(callee.shutdown()).unwrap();
+    assert_eq!(router.connection_count("ca.test.realm"), Some(0));
+    assert!(router.connect_loopback("ca.test.missing").is_err());
+}
+
+#[test]
+fn same_procedure_in_two_realms() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.first");
+    router.add_realm("ca.test.second");
+    router.add_realm("ca.test.third");
+
+    let mut callees = Vec::new();
+    for realm in &["ca.test.first", "ca.test.second"] {
+        let mut callee = router.connect_loopback(realm).unwrap();
+        let name = realm.to_string();
+        block_on(callee.register(
+            URI::new("com.test.proc"),
+            Box::new(move |_args, _kwargs| Ok((Some(wamp_list![name.clone()]), None))),
+        ))
+        .unwrap();
+        callees.push(callee);
+    }
+
+    for realm in &["ca.test.first", "ca.test.second"] {
+        let mut caller = router.connect_loopback(realm).unwrap();
+        for _ in 0..3 {
+            let (args, _kwargs) =
+                block_on(caller.call(URI::new("com.test.proc"), None, None)).unwrap();
+            assert_eq!(args, wamp_list![realm.to_string()]);
+        }
+    }
+
+    let mut caller = router.connect_loopback("ca.test.third").unwrap();
+    let error = block_on(caller.call(URI::new("com.test.proc"), None, None)).unwrap_err();
+    assert_eq!(*error.get_reason(), Reason::NoSuchProcedure);
+
+    // Leaving one realm doesn't touch the registration in the other
+    block_on(callees.remove(0).shutdown()).unwrap();
+    let mut caller = router.connect_loopback("ca.test.second").unwrap();
+    let (args, _kwargs) = block_on(caller.call(URI::new("com.test.proc"), None, None)).unwrap();
+    assert_eq!(args, wamp_list!["ca.test.second"]);
+}
+
+#[test]
+fn concurrent_calls_through_pool() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let connection = Connection::new(&format!("ws://{}/ws", addr), "ca.test.realm");
+
+    let mut callee = connection.connect().unwrap();
+    block_on(callee.register(
+        URI::new("ca.test.echo"),
+        Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+    ))
+    .unwrap();
+
+    let pool = Arc::new(ClientPool::new(connection, 2).unwrap());
+    assert_eq!(router.connection_count("ca.test.realm"), Some(3));
+
+    let callers = (0..4u64)
+        .map(|caller| {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || {
+                for call in 0..25u64 {
+                    let value = caller * 100 + call;
+                    let (args, _kwargs) = block_on(pool.call(
+                        URI::new("ca.test.echo"),
+                        Some(wamp_list![value]),
+                        None,
+                    ))
+                    .unwrap();
+                    assert_eq!(args, wamp_list![value]);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for caller in callers {
+        caller.join().unwrap();
+    }
+    // Every call went through one of the pooled sessions
+    assert_eq!(router.connection_count("ca.test.realm"), Some(3));
+}
+
+#[test]
+fn borrowed_arguments() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let mut callee = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    block_on(callee.register(
+        URI::new("ca.test.echo"),
+        Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+    ))
+    .unwrap();
+    let (tx, rx) = channel();
+    block_on(callee.subscribe(
+        URI::new("ca.test.topic"),
+        Box::new(move |args, kwargs| tx.send((args, kwargs)).unwrap()),
+    ))
+    .unwrap();
+
+    let args = (0..1000).map(Value::Integer).collect::<Vec<_>>();
+    let mut kwargs = HashMap::new();
+    kwargs.insert("key".to_string(), Value::String("value".to_string()));
+    // Both encodings write the borrowed arguments
+    for connection in [
+        Connection::new(&url, "ca.test.realm"),
+        Connection::new(&url, "ca.test.realm").with_batched_json(),
+    ] {
+        let mut client = connection.connect().unwrap();
+        let result =
+            block_on(client.call_borrowed(URI::new("ca.test.echo"), Some(&args), Some(&kwargs)))
+                .unwrap();
+        assert_eq!(result, (args.clone(), kwargs.clone()));
+
+        client
+            .publish_borrowed(URI::new("ca.test.topic"), Some(&args[..10]), None)
+            .unwrap();
+        let (event_args, event_kwargs) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(event_args, args[..10]);
+        assert!(event_kwargs.is_empty());
+    }
+}
+
+#[test]
+fn duplicate_subscription_delivers_once() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    router
+        .register_local(
+            "ca.test.realm",
+            URI::new("ca.test.ping"),
+            Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+        )
+        .unwrap();
+
+    let received = Arc::new(Mutex::new(0));
+    let mut subscriber = router.connect_loopback("ca.test.realm").unwrap();
+    for _ in 0..2 {
+        let received = Arc::clone(&received);
+        block_on(subscriber.subscribe(
+            URI::new("ca.test.topic"),
+            Box::new(move |_, _| *received.lock().unwrap() += 1),
+        ))
+        .unwrap();
+    }
+    assert_eq!(subscriber.subscriptions().len(), 1);
+
+    let mut publisher = router.connect_loopback("ca.test.realm").unwrap();
+    for _ in 0..2 {
+        block_on(publisher.publish_and_acknowledge(URI::new("ca.test.topic"), None, None)).unwrap();
+    }
+    // Events are handled before the result of a call made after they were published
+    block_on(subscriber.call(URI::new("ca.test.ping"), None, None)).unwrap();
+    assert_eq!(*received.lock().unwrap(), 2);
+}
+
+#[test]
+fn subscribe_stream() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+
+    let mut subscriber = router.connect_loopback("ca.test.realm").unwrap();
+    let (subscription, mut events) =
+        block_on(subscriber.subscribe_stream(URI::new("ca.test.topic"))).unwrap();
+    assert_eq!(subscription.topic.uri, "ca.test.topic");
+
+    let mut publisher = router.connect_loopback("ca.test.realm").unwrap();
+    for i in 0..2 {
+        block_on(publisher.publish_and_acknowledge(
+            URI::new("ca.test.topic"),
+            Some(vec![Value::UnsignedInteger(i)]),
+            None,
+        ))
+        .unwrap();
+    }
+    for i in 0..2 {
+        let (args, _kwargs) = block_on(events.next()).unwrap();
+        assert_eq!(args, vec![Value::UnsignedInteger(i)]);
+    }
+}
+
+#[test]
+fn next_event_resolves_once() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+
+    let mut subscriber = router.connect_loopback("ca.test.realm").unwrap();
+    let subscription =
+        block_on(subscriber.subscribe(URI::new("ca.test.topic"), Box::new(|_, _| {}))).unwrap();
+    let event = subscriber.next_event(&subscription);
+
+    let mut publisher = router.connect_loopback("ca.test.realm").unwrap();
+    block_on(publisher.publish_and_acknowledge(
+        URI::new("ca.test.topic"),
+        Some(wamp_list![1]),
+        None,
+    ))
+    .unwrap();
+    let (args, kwargs) = block_on(event).unwrap();
+    assert_eq!(args, wamp_list![1]);
+    assert!(kwargs.is_empty());
+
+    // Unsubscribing fails the events still awaited
+    let event = subscriber.next_event(&subscription);
+    block_on(subscriber.unsubscribe(subscription)).unwrap();
+    assert_eq!(
+        *block_on(event).unwrap_err().get_reason(),
+        Reason::NoSuchSubscription
+    );
+}
+
+#[test]
+fn kept_events_replayed_on_resubscribe() {
+    let mut router = Router::new();
+    router.add_realm_with_options(
+        "ca.test.realm",
+        RealmOptions {
+            authorizer: Box::new(VouchFor(&["ca.test.user"])),
+            ..Default::default()
+        },
+    );
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let mut subscriber = Connection::new(&url, "ca.test.realm")
+        .with_authid("ca.test.user")
+        .connect()
+        .unwrap();
+    block_on(subscriber.subscribe_keeping_last(URI::new("ca.test.topic"), Box::new(|_, _| {}), 2))
+        .unwrap();
+    block_on(subscriber.shutdown()).unwrap();
+    while router.stats("ca.test.realm").unwrap().connections > 0 {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut publisher = router.connect_loopback("ca.test.realm").unwrap();
+    for i in 1..=3 {
+        block_on(publisher.publish_and_acknowledge(
+            URI::new("ca.test.topic"),
+            Some(wamp_list![i]),
+            None,
+        ))
+        .unwrap();
+    }
+
+    // Another authid doesn't get the kept events
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    let mut stranger = Connection::new(&url, "ca.test.realm")
+        .with_authid("ca.test.stranger")
+        .connect()
+        .unwrap();
+    let events = tx.lock().unwrap().clone();
+    block_on(stranger.subscribe(
+        URI::new("ca.test.topic"),
+        Box::new(move |args, _kwargs| events.send(("stranger", args)).unwrap()),
+    ))
+    .unwrap();
+
+    // The same authid gets the last two events when it subscribes again
+    let mut subscriber = Connection::new(&url, "ca.test.realm")
+        .with_authid("ca.test.user")
+        .connect()
+        .unwrap();
+    block_on(subscriber.subscribe(
+        URI::new("ca.test.topic"),
+        Box::new(move |args, _kwargs| tx.lock().unwrap().send(("user", args)).unwrap()),
+    ))
+    .unwrap();
+    for i in 2..=3 {
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            ("user", wamp_list![i])
+        );
+    }
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+}
+
+#[test]
+fn kept_events_need_a_vouched_authid() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let mut subscriber = Connection::new(&url, "ca.test.realm")
+        .with_authid("ca.test.user")
+        .connect()
+        .unwrap();
+    block_on(subscriber.subscribe_keeping_last(URI::new("ca.test.topic"), Box::new(|_, _| {}), 2))
+        .unwrap();
+    block_on(subscriber.shutdown()).unwrap();
+    while router.stats("ca.test.realm").unwrap().connections > 0 {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut publisher = router.connect_loopback("ca.test.realm").unwrap();
+    block_on(publisher.publish_and_acknowledge(
+        URI::new("ca.test.topic"),
+        Some(wamp_list![1]),
+        None,
+    ))
+    .unwrap();
+
+    // Nobody vouched for the authid, so claiming it again gets nothing
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    let mut claimant = Connection::new(&url, "ca.test.realm")
+        .with_authid("ca.test.user")
+        .connect()
+        .unwrap();
+    block_on(claimant.subscribe(
+        URI::new("ca.test.topic"),
+        Box::new(move |args, _kwargs| tx.lock().unwrap().send(args).unwrap()),
+    ))
+    .unwrap();
+    assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+}
+
+#[test]
+fn keep_last_beyond_the_limit_is_refused() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    router.set_max_keep_last(2);
+
+    let mut client = router.connect_loopback("ca.test.realm").unwrap();
+    let error =
+        block_on(client.subscribe_keeping_last(URI::new("ca.test.topic"), Box::new(|_, _| {}), 3))
+            .unwrap_err();
+    assert_eq!(error.get_reason(), &Reason::InvalidArgument);
+    block_on(client.subscribe_keeping_last(URI::new("ca.test.topic"), Box::new(|_, _| {}), 2))
+        .unwrap();
+}
+
+#[test]
+fn args_and_kwargs_shorthands() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+
+    let mut client = router.connect_loopback("ca.test.realm").unwrap();
+    block_on(client.register(
+        URI::new("ca.test.echo"),
+        Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+    ))
+    .unwrap();
+    let (_subscription, mut events) =
+        block_on(client.subscribe_stream(URI::new("ca.test.topic"))).unwrap();
+
+    let args = vec![Value::String("ping".to_string())];
+    let mut kwargs = HashMap::new();
+    kwargs.insert("message".to_string(), Value::String("ping".to_string()));
+    assert_eq!(
+        block_on(client.call_args(URI::new("ca.test.echo"), args.clone())).unwrap(),
+        (args.clone(), HashMap::new())
+    );
+    assert_eq!(
+        block_on(client.call_kwargs(URI::new("ca.test.echo"), kwargs.clone())).unwrap(),
+        (Vec::new(), kwargs.clone())
+    );
+
+    let mut publisher = router.connect_loopback("ca.test.realm").unwrap();
+    publisher
+        .publish_args(URI::new("ca.test.topic"), args.clone())
+        .unwrap();
+    publisher
+        .publish_kwargs(URI::new("ca.test.topic"), kwargs.clone())
+        .unwrap();
+    assert_eq!(block_on(events.next()).unwrap(), (args, HashMap::new()));
+    assert_eq!(block_on(events.next()).unwrap(), (Vec::new(), kwargs));
+}
+
+#[test]
+fn get_unknown_registration() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    let mut client = Connection::new(&format!("ws://{}/ws", addr), "ca.test.realm")
+        .connect()
+        .unwrap();
+    let error = block_on(client.call(
+        URI::new("wamp.registration.get"),
+        Some(vec![Value::UnsignedInteger(1)]),
+        None,
+    ))
+    .unwrap_err();
+    assert_eq!(*error.get_reason(), Reason::NoSuchRegistration);
+}
+
+#[test]
+fn lookup_and_list_registrations() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+
+    let mut callee = router.connect_loopback("ca.test.realm").unwrap();
+    let registration = block_on(callee.register(
+        URI::new("ca.test.lookup"),
+        Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+    ))
+    .unwrap();
+    let registration_id = Value::UnsignedInteger(registration.id());
+
+    let mut client = router.connect_loopback("ca.test.realm").unwrap();
+    let lookup = |client: &mut wampire::Client, args| {
+        block_on(client.call(URI::new("wamp.registration.lookup"), Some(args), None))
+            .unwrap()
+            .0
+    };
+    let procedure = Value::String("ca.test.lookup".to_string());
+    assert_eq!(
+        lookup(&mut client, vec![procedure.clone()]),
+        vec![registration_id.clone()]
+    );
+    let mut options = HashMap::new();
+    options.insert("match".to_string(), Value::String("prefix".to_string()));
+    assert_eq!(
+        lookup(&mut client, vec![procedure, Value::Dict(options)]),
+        Vec::new()
+    );
+    assert_eq!(
+        lookup(
+            &mut client,
+            vec![Value::String("ca.test.missing".to_string())]
+        ),
+        Vec::new()
+    );
+
+    let (list, _) = block_on(client.call(URI::new("wamp.registration.list"), None, None)).unwrap();
+    let groups = match &list[0] {
+        Value::Dict(groups) => groups,
+        value => panic!("Expected a dictionary, got {:?}", value),
+    };
+    assert_eq!(
+        groups.get("exact"),
+        Some(&Value::List(vec![registration_id]))
+    );
+    assert_eq!(groups.get("prefix"), Some(&Value::List(Vec::new())));
+}
+
+#[test]
+fn lookup_and_list_subscriptions() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+
+    let mut subscriber = router.connect_loopback("ca.test.realm").unwrap();
+    let exact =
+        block_on(subscriber.subscribe(URI::new("ca.test.topic"), Box::new(|_, _| {}))).unwrap();
+    let prefix = block_on(subscriber.subscribe_with_pattern(
+        URI::new("ca.test"),
+        Box::new(|_, _| {}),
+        MatchingPolicy::Prefix,
+    ))
+    .unwrap();
+    let exact_id = Value::UnsignedInteger(exact.id());
+    let prefix_id = Value::UnsignedInteger(prefix.id());
+
+    let mut client = router.connect_loopback("ca.test.realm").unwrap();
+    let (list, _) = block_on(client.call(URI::new("wamp.subscription.list"), None, None)).unwrap();
+    let groups = match &list[0] {
+        Value::Dict(groups) => groups,
+        value => panic!("Expected a dictionary, got {:?}", value),
+    };
+    assert_eq!(
+        groups.get("exact"),
+        Some(&Value::List(vec![exact_id.clone()]))
+    );
+    assert_eq!(
+        groups.get("prefix"),
+        Some(&Value::List(vec![prefix_id.clone()]))
+    );
+    assert_eq!(groups.get("wildcard"), Some(&Value::List(Vec::new())));
+
+    let topic = Value::String("ca.test.topic".to_string());
+    let (mut ids, _) = block_on(client.call(
+        URI::new("wamp.subscription.lookup"),
+        Some(vec![topic.clone()]),
+        None,
+    ))
+    .unwrap();
+    ids.sort_by_key(|id| match id {
+        Value::UnsignedInteger(id) => *id,
+        value => panic!("Expected an id, got {:?}", value),
+    });
+    let mut expected = vec![exact.id(), prefix.id()];
+    expected.sort_unstable();
+    assert_eq!(
+        ids,
+        expected
+            .into_iter()
+            .map(Value::UnsignedInteger)
+            .collect::<Vec<_>>()
+    );
+
+    let mut options = HashMap::new();
+    options.insert("match".to_string(), Value::String("prefix".to_string()));
+    let (ids, _) = block_on(client.call(
+        URI::new("wamp.subscription.lookup"),
+        Some(vec![topic, Value::Dict(options)]),
+        None,
+    ))
+    .unwrap();
+    assert_eq!(ids, vec![prefix_id]);
+}
+
+#[test]
+fn count_subscribers() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+
+    let mut subscribers = Vec::new();
+    let mut subscription_id = 0;
+    for _ in 0..3 {
+        let mut subscriber = router.connect_loopback("ca.test.realm").unwrap();
+        let subscription =
+            block_on(subscriber.subscribe(URI::new("ca.test.topic"), Box::new(|_, _| {}))).unwrap();
+        subscription_id = subscription.id();
+        subscribers.push(subscriber);
+    }
+
+    let mut client = router.connect_loopback("ca.test.realm").unwrap();
+    for argument in [
+        Value::String("ca.test.topic".to_string()),
+        Value::UnsignedInteger(subscription_id),
+    ] {
+        let (count, _) = block_on(client.call(
+            URI::new("wamp.subscription.count_subscribers"),
+            Some(vec![argument]),
+            None,
+        ))
+        .unwrap();
+        assert_eq!(count, vec![Value::UnsignedInteger(3)]);
+    }
+}
+
+/// Rejects every call to one procedure
+struct BlockProcedure(&'static str);
+
+impl MessageInterceptor for BlockProcedure {
+    fn on_inbound(&self, _session_id: u64, message: &Message) -> InterceptAction {
+        match message {
+            Message::Call(_, _, procedure, ..) if procedure.uri == self.0 => {
+                InterceptAction::Reject(Reason::NotAuthorized)
+            }
+            _ => InterceptAction::Allow,
+        }
+    }
+}
+
+#[test]
+fn interceptor_rejects_calls() {
+    let mut router = Router::new();
+    router.add_realm_with_options(
+        "ca.test.realm",
+        RealmOptions {
+            interceptor: Box::new(BlockProcedure("ca.test.blocked")),
+            ..Default::default()
+        },
+    );
+
+    let mut callee = router.connect_loopback("ca.test.realm").unwrap();
+    for procedure in &["ca.test.blocked", "ca.test.echo"] {
+        block_on(callee.register(
+            URI::new(procedure),
+            Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+        ))
+        .unwrap();
+    }
+
+    let mut caller = router.connect_loopback("ca.test.realm").unwrap();
+    let error = block_on(caller.call(URI::new("ca.test.blocked"), None, None)).unwrap_err();
+    assert_eq!(*error.get_reason(), Reason::NotAuthorized);
+    let (args, _kwargs) =
+        block_on(caller.call(URI::new("ca.test.echo"), Some(wamp_list!["ping"]), None)).unwrap();
+    assert_eq!(args, wamp_list!["ping"]);
+}
+
+#[test]
+fn call_without_remaining_callee() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let mut callee = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    block_on(callee.register(
+        URI::new("ca.test.echo"),
+        Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+    ))
+    .unwrap();
+    block_on(callee.shutdown()).unwrap();
+
+    let mut caller = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    let error = block_on(caller.call(URI::new("ca.test.echo"), None, None)).unwrap_err();
+    assert_eq!(*error.get_reason(), Reason::NoEligibleCallee);
+
+    let error = block_on(caller.call(URI::new("ca.test.missing"), None, None)).unwrap_err();
+    assert_eq!(*error.get_reason(), Reason::NoSuchProcedure);
+}
+
+#[test]
+fn oversized_argument_lists_are_refused() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    router.set_max_args_len(3);
+    router.set_max_kwargs_len(1);
+
+    let received = Arc::new(Mutex::new(0));
+    let mut subscriber = router.connect_loopback("ca.test.realm").unwrap();
+    let count = Arc::clone(&received);
+    block_on(subscriber.subscribe(
+        URI::new("ca.test.topic"),
+        Box::new(move |_args, _kwargs| *count.lock().unwrap() += 1),
+    ))
+    .unwrap();
+    block_on(subscriber.register(
+        URI::new("ca.test.echo"),
+        Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+    ))
+    .unwrap();
+
+    let mut client = router.connect_loopback("ca.test.realm").unwrap();
+    let error = block_on(client.publish_and_acknowledge(
+        URI::new("ca.test.topic"),
+        Some(wamp_list![1, 2, 3, 4]),
+        None,
+    ))
+    .unwrap_err();
+    assert_eq!(*error.get_reason(), Reason::InvalidArgument);
+    block_on(client.publish_and_acknowledge(
+        URI::new("ca.test.topic"),
+        Some(wamp_list![1, 2, 3]),
+        None,
+    ))
+    .unwrap();
+
+    let mut kwargs = HashMap::new();
+    kwargs.insert("a".to_string(), Value::Integer(1));
+    kwargs.insert("b".to_string(), Value::Integer(2));
+    let error = block_on(client.call(URI::new("ca.test.echo"), None, Some(kwargs))).unwrap_err();
+    assert_eq!(*error.get_reason(), Reason::InvalidArgument);
+    let error =
+        block_on(client.call_args(URI::new("ca.test.echo"), wamp_list![1, 2, 3, 4])).unwrap_err();
+    assert_eq!(*error.get_reason(), Reason::InvalidArgument);
+    let (args, _kwargs) =
+        block_on(client.call_args(URI::new("ca.test.echo"), wamp_list![1, 2, 3])).unwrap();
+    assert_eq!(args, wamp_list![1, 2, 3]);
+
+    // Only the accepted publication reached the subscriber
+    assert_eq!(*received.lock().unwrap(), 1);
+}
+
+/// A websocket peer that subscribes, then stops reading once the subscription is confirmed
+/// until `release` is dropped
+struct StalledSubscriber {
+    out: Sender,
+    subscribed: std::sync::mpsc::Sender<()>,
+    release: Arc<Mutex<std::sync::mpsc::Receiver<()>>>,
+}
+
+impl parity_ws::Handler for StalledSubscriber {
+    fn build_request(&mut self, url: &url::Url) -> parity_ws::Result<Request> {
+        let mut request = Request::from_url(url)?;
+        request.add_protocol("wamp.2.json");
+        Ok(request)
+    }
+
+    fn on_open(&mut self, _: Handshake) -> parity_ws::Result<()> {
+        self.out.send(
+            r#"[1,"ca.test.realm",{"roles":{"publisher":{"features":{}},"subscriber":{"features":{}},"caller":{"features":{}},"callee":{"features":{}}}}]"#,
+        )?;
+        self.out.send(r#"[32,1,{},"ca.test.topic"]"#)
+    }
+
+    fn on_message(&mut self, message: WSMessage) -> parity_ws::Result<()> {
+        if message.as_text()?.starts_with("[33,") {
+            self.subscribed.send(()).unwrap();
+            // Blocks the peer's event loop, so nothing more is read from the socket
+            self.release.lock().unwrap().recv().ok();
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn events_beyond_backlog_are_dropped() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    router.set_max_event_backlog(4);
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let (subscribed_tx, subscribed_rx) = channel();
+    let (release_tx, release_rx) = channel();
+    let release_rx = Arc::new(Mutex::new(release_rx));
+    thread::spawn(move || {
+        parity_ws::connect(url, move |out| StalledSubscriber {
+            out,
+            subscribed: subscribed_tx.clone(),
+            release: Arc::clone(&release_rx),
+        })
+        .unwrap();
+    });
+    subscribed_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    // Loopback connections have no backlog, so this one gets every event
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let mut subscriber = router.connect_loopback("ca.test.realm").unwrap();
+    let events = Arc::clone(&received);
+    block_on(subscriber.subscribe(
+        URI::new("ca.test.topic"),
+        Box::new(move |args, _kwargs| events.lock().unwrap().extend(args)),
+    ))
+    .unwrap();
+
+    let mut publisher = router.connect_loopback("ca.test.realm").unwrap();
+    let recipients: Vec<usize> = (0..20)
+        .map(|i| {
+            block_on(publisher.publish_and_acknowledge(
+                URI::new("ca.test.topic"),
+                Some(wamp_list![i]),
+                None,
+            ))
+            .unwrap()
+            .1
+        })
+        .collect();
+    // The stalled subscriber got events until its backlog reached the limit, and no more
+    let stalled: usize = recipients.iter().map(|count| count - 1).sum();
+    assert!(stalled <= 4, "{:?}", recipients);
+    assert_eq!(recipients[19], 1);
+    for _ in 0..50 {
+        if received.lock().unwrap().len() == 20 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(received.lock().unwrap().len(), 20);
+    drop(release_tx);
+}
+
+struct ForgingSubscriber {
+    out: Sender,
+    messages: std::sync::mpsc::Sender<String>,
+}
+
+impl parity_ws::Handler for ForgingSubscriber {
+    fn build_request(&mut self, url: &url::Url) -> parity_ws::Result<Request> {
+        let mut request = Request::from_url(url)?;
+        request.add_protocol("wamp.2.json");
+        Ok(request)
+    }
+
+    fn on_open(&mut self, _: Handshake) -> parity_ws::Result<()> {
+        self.out.send(
+            r#"[1,"ca.test.realm",{"roles":{"publisher":{"features":{}},"subscriber":{"features":{}},"caller":{"features":{}},"callee":{"features":{}}}}]"#,
+        )?;
+        self.out.send(r#"[32,1,{},"ca.test.topic"]"#)
+    }
+
+    fn on_message(&mut self, message: WSMessage) -> parity_ws::Result<()> {
+        let message = message.as_text()?.to_string();
+        if message.starts_with("[33,") {
+            // Claims to have read far more than the router ever sent
+            self.out.pong(vec![0xFF; 8])?;
+        }
+        self.messages.send(message).ok();
+        Ok(())
+    }
+}
+
+#[test]
+fn unsolicited_pongs_acknowledge_nothing() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    router.set_max_event_backlog(4);
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let (messages_tx, messages_rx) = channel();
+    thread::spawn(move || {
+        parity_ws::connect(url, move |out| ForgingSubscriber {
+            out,
+            messages: messages_tx.clone(),
+        })
+        .unwrap();
+    });
+    loop {
+        let message = messages_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        if message.starts_with("[33,") {
+            break;
+        }
+    }
+
+    let mut publisher = router.connect_loopback("ca.test.realm").unwrap();
+    for i in 0..10 {
+        let (_id, recipients) = block_on(publisher.publish_and_acknowledge(
+            URI::new("ca.test.topic"),
+            Some(wamp_list![i]),
+            None,
+        ))
+        .unwrap();
+        assert_eq!(recipients, 1);
+        let event = messages_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(event.starts_with("[36,"), "{}", event);
+    }
+}
+
+#[test]
+fn router_features_after_connect() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    let client = Connection::new(&format!("ws://{}/ws", addr), "ca.test.realm")
+        .connect()
+        .unwrap();
+    let features = client.router_features();
+    assert!(features.pattern_based_subscription);
+    assert!(features.pattern_based_registration);
+}
+
+#[test]
+fn msgpack_is_negotiated() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    let client = Connection::new(&format!("ws://{}/ws", addr), "ca.test.realm")
+        .connect()
+        .unwrap();
+    assert_eq!(client.protocol(), "wamp.2.msgpack");
+}
+
+#[test]
+fn list_subscriptions_and_registrations() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    let mut client = Connection::new(&format!("ws://{}/ws", addr), "ca.test.realm")
+        .connect()
+        .unwrap();
+    let first = block_on(client.subscribe(URI::new("ca.test.first"), Box::new(|_, _| {}))).unwrap();
+    block_on(client.subscribe(URI::new("ca.test.second"), Box::new(|_, _| {}))).unwrap();
+    let registration = block_on(client.register(
+        URI::new("ca.test.echo"),
+        Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+    ))
+    .unwrap();
+
+    let mut topics = client
+        .subscriptions()
+        .into_iter()
+        .map(|(_, topic)| topic.uri)
+        .collect::<Vec<_>>();
+    topics.sort();
+    assert_eq!(topics, vec!["ca.test.first", "ca.test.second"]);
+    let procedures = client
+        .registrations()
+        .into_iter()
+        .map(|(_, procedure)| procedure.uri)
+        .collect::<Vec<_>>();
+    assert_eq!(procedures, vec!["ca.test.echo"]);
+    assert!(client
+        .subscriptions()
+        .contains(&(first.id(), URI::new("ca.test.first"))));
+    assert_eq!(client.registrations()[0].0, registration.id());
+
+    block_on(client.unsubscribe(first.clone())).unwrap();
+    assert_eq!(first.topic.uri, "ca.test.first");
+    let topics = client
+        .subscriptions()
+        .into_iter()
+        .map(|(_, topic)| topic.uri)
+        .collect::<Vec<_>>();
+    assert_eq!(topics, vec!["ca.test.second"]);
+}
+
+#[test]
+fn unregister_and_unsubscribe_all() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+
+    let mut client = router.connect_loopback("ca.test.realm").unwrap();
+    for procedure in &["ca.test.first", "ca.test.second", "ca.test.third"] {
+        block_on(client.register(
+            URI::new(procedure),
+            Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+        ))
+        .unwrap();
+    }
+    block_on(client.subscribe(URI::new("ca.test.topic"), Box::new(|_, _| {}))).unwrap();
+    assert_eq!(router.stats("ca.test.realm").unwrap().registrations, 3);
+
+    block_on(client.unregister_all()).unwrap();
+    assert!(client.registrations().is_empty());
+    assert_eq!(client.subscriptions().len(), 1);
+    assert_eq!(router.stats("ca.test.realm").unwrap().registrations, 0);
+
+    block_on(client.unsubscribe_all()).unwrap();
+    assert!(client.subscriptions().is_empty());
+    assert_eq!(router.stats("ca.test.realm").unwrap().subscriptions, 0);
+}
+
+#[test]
+fn streaming_callee_yields_progressive_results() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+
+    let mut callee = router.connect_loopback("ca.test.realm").unwrap();
+    block_on(callee.register_stream(
+        URI::new("ca.test.countdown"),
+        Box::new(|_args, _kwargs, yielder| {
+            for i in 1..=3 {
+                yielder.progress(Some(wamp_list![i]), None).unwrap();
+            }
+            yielder.finish(Some(wamp_list!["done"]), None).unwrap();
+        }),
+    ))
+    .unwrap();
+
+    let mut caller = router.connect_loopback("ca.test.realm").unwrap();
+    let results: Vec<Vec<Value>> = block_on(
+        caller
+            .call_progressive(URI::new("ca.test.countdown"), None, None)
+            .map(|result| result.unwrap().0)
+            .collect(),
+    );
+    assert_eq!(
+        results,
+        vec![
+            wamp_list![1],
+            wamp_list![2],
+            wamp_list![3],
+            wamp_list!["done"]
+        ]
+    );
+
+    // A caller that didn't ask for progressive results only gets the final one
+    let (args, _) = block_on(caller.call(URI::new("ca.test.countdown"), None, None)).unwrap();
+    assert_eq!(args, wamp_list!["done"]);
+}
+
+#[test]
+fn callee_leaving_mid_call_fails_the_call() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    let mut callee = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    block_on(callee.register_stream(
+        URI::new("ca.test.forever"),
+        // Keep the yielder without answering, as a procedure still running would
+        Box::new(move |_args, _kwargs, yielder| tx.lock().unwrap().send(yielder).unwrap()),
+    ))
+    .unwrap();
+
+    let (result_tx, result_rx) = channel();
+    thread::spawn(move || {
+        let mut caller = Connection::new(&url, "ca.test.realm").connect().unwrap();
+        let result = block_on(caller.call(URI::new("ca.test.forever"), None, None));
+        result_tx.send(result).unwrap();
+    });
+    let _yielder = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    block_on(callee.shutdown()).unwrap();
+
+    let error = result_rx
+        .recv_timeout(Duration::from_secs(5))
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(*error.get_reason(), Reason::NoEligibleCallee);
+}
+
+#[test]
+fn concurrent_publish_and_disconnect() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let router = Arc::new(router);
+
+    let mut workers = Vec::new();
+    for _ in 0..4 {
+        let router = Arc::clone(&router);
+        workers.push(thread::spawn(move || {
+            let mut publisher = router.connect_loopback("ca.test.realm").unwrap();
+            for i in 0..200 {
+                publisher
+                    .publish(URI::new("ca.test.topic"), Some(wamp_list![i]), None)
+                    .unwrap();
+            }
+            block_on(publisher.shutdown()).unwrap();
+        }));
+    }
+    for _ in 0..4 {
+        let router = Arc::clone(&router);
+        workers.push(thread::spawn(move || {
+            for _ in 0..25 {
+                let mut subscriber = router.connect_loopback("ca.test.realm").unwrap();
+                block_on(subscriber.subscribe(URI::new("ca.test.topic"), Box::new(|_, _| {})))
+                    .unwrap();
+                block_on(subscriber.shutdown()).unwrap();
+            }
+        }));
+    }
+
+    let (done, finished) = channel();
+    thread::spawn(move || {
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        done.send(()).unwrap();
+    });
+    finished
+        .recv_timeout(Duration::from_secs(30))
+        .expect("Publishing while sessions come and go deadlocked");
+    assert_eq!(router.connection_count("ca.test.realm"), Some(0));
+}
+
+#[test]
+fn publish_filters_by_authid_and_authrole() {
+    let mut router = Router::new();
+    router.add_realm_with_options(
+        "ca.test.realm",
+        RealmOptions {
+            authorizer: Box::new(VouchFor(&["alice", "bob"])),
+            ..Default::default()
+        },
+    );
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let mut subscribers = Vec::new();
+    let mut received = Vec::new();
+    // Nobody vouches for carol, so the filters don't take her word that she's an admin
+    for (authid, authrole) in &[("alice", "admin"), ("bob", "user"), ("carol", "admin")] {
+        let mut subscriber = Connection::new(&url, "ca.test.realm")
+            .with_authid(authid)
+            .with_authrole(authrole)
+            .connect()
+            .unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        block_on(subscriber.subscribe(
+            URI::new("ca.test.topic"),
+            Box::new(move |args, _kwargs| sink.lock().unwrap().extend(args)),
+        ))
+        .unwrap();
+        subscribers.push(subscriber);
+        received.push(events);
+    }
+
+    let mut publisher = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    type SetFilter = fn(&mut PublishOptions);
+    let filters: Vec<(&str, SetFilter)> = vec![
+        ("eligible_authid", |options| {
+            options.eligible_authid = Some(vec!["alice".to_string()])
+        }),
+        ("exclude_authid", |options| {
+            options.exclude_authid = Some(vec!["alice".to_string()])
+        }),
+        ("eligible_authrole", |options| {
+            options.eligible_authrole = Some(vec!["admin".to_string()])
+        }),
+        ("exclude_authrole", |options| {
+            options.exclude_authrole = Some(vec!["admin".to_string()])
+        }),
+        ("unfiltered", |_| {}),
+    ];
+    for (name, set_filter) in filters {
+        let mut options = PublishOptions::new(false);
+        set_filter(&mut options);
+        block_on(publisher.publish_with_options(
+            URI::new("ca.test.topic"),
+            Some(vec![Value::String(name.to_string())]),
+            None,
+            options,
+        ))
+        .unwrap();
+    }
+
+    let last = Value::String("unfiltered".to_string());
+    for _ in 0..50 {
+        if received
+            .iter()
+            .all(|events| events.lock().unwrap().last() == Some(&last))
+        {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let names = |events: &Arc<Mutex<Vec<Value>>>| {
+        events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|value| match value {
+                Value::String(name) => name.clone(),
+                _ => panic!("Unexpected event {:?}", value),
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        names(&received[0]),
+        vec!["eligible_authid", "eligible_authrole", "unfiltered"]
+    );
+    assert_eq!(
+        names(&received[1]),
+        vec!["exclude_authid", "exclude_authrole", "unfiltered"]
+    );
+    assert_eq!(
+        names(&received[2]),
+        vec!["exclude_authid", "exclude_authrole", "unfiltered"]
+    );
+}
+
+#[test]
+fn acknowledged_publish_counts_recipients() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+
+    let mut subscribers = Vec::new();
+    for _ in 0..2 {
+        let mut subscriber = router.connect_loopback("ca.test.realm").unwrap();
+        block_on(subscriber.subscribe(URI::new("ca.test.topic"), Box::new(|_, _| {}))).unwrap();
+        subscribers.push(subscriber);
+    }
+
+    let mut publisher = router.connect_loopback("ca.test.realm").unwrap();
+    let (_, recipients) =
+        block_on(publisher.publish_and_acknowledge(URI::new("ca.test.topic"), None, None)).unwrap();
+    assert_eq!(recipients, 2);
+}
+
+#[test]
+fn retained_event_reaches_new_subscriber() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let mut publisher = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    let mut options = PublishOptions::new(false);
+    options.retain = true;
+    block_on(publisher.publish_with_options(
+        URI::new("ca.test.sensor"),
+        Some(vec![Value::Integer(21)]),
+        None,
+        options,
+    ))
+    .unwrap();
+
+    let mut subscriber = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    let (event_tx, event_rx) = channel();
+    let event_tx = Mutex::new(event_tx);
+    block_on(subscriber.subscribe(
+        URI::new("ca.test.sensor"),
+        Box::new(move |args, _kwargs| event_tx.lock().unwrap().send(args).unwrap()),
+    ))
+    .unwrap();
+    assert_eq!(
+        event_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+        vec![Value::Integer(21)]
+    );
+}
+
+#[test]
+fn unanswered_pings_close_connection() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    router.set_ping_interval(Duration::from_millis(50));
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    // A peer that completes the websocket handshake and then never reads or answers pings
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    write!(
+        stream,
+        "GET /ws HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\
+         Sec-WebSocket-Protocol: wamp.2.json\r\n\r\n",
+        addr
+    )
+    .unwrap();
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte).unwrap();
+        response.push(byte[0]);
+    }
+
+    let mut pings = 0;
+    loop {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).unwrap();
+        let mut payload = vec![0; (header[1] & 0x7f) as usize];
+        stream.read_exact(&mut payload).unwrap();
+        match header[0] & 0x0f {
+            0x9 => pings += 1,
+            0x8 => break,
+            opcode => panic!("Unexpected frame with opcode {}", opcode),
+        }
+    }
+    assert!(pings >= 3);
+}
+
+#[test]
+fn answered_pings_keep_connection() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    router.set_ping_interval(Duration::from_millis(20));
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    let mut client = Connection::new(&format!("ws://{}/ws", addr), "ca.test.realm")
+        .with_ping_interval(Duration::from_millis(20))
+        .connect()
+        .unwrap();
+    block_on(client.register(
+        URI::new("ca.test.echo"),
+        Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+    ))
+    .unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    let (args, _kwargs) = block_on(client.call(
+        URI::new("ca.test.echo"),
+        Some(vec![Value::String("ping".to_string())]),
+        None,
+    ))
+    .unwrap();
+    assert_eq!(args, vec![Value::String("ping".to_string())]);
+}
+
+#[test]
+fn goodbye_before_hello_aborts() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    let received = raw_exchange(
+        &addr,
+        "wamp.2.json",
+        &[r#"[6,{},"wamp.close.close_realm"]"#],
+    );
+    assert_eq!(received, vec![r#"[3,{},"wamp.error.protocol_violation"]"#]);
+}
+
+#[test]
+fn message_before_hello_aborts() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    let received = raw_exchange(&addr, "wamp.2.json", &[r#"[32,1,{},"ca.test.topic"]"#]);
+    assert_eq!(
+        received,
+        vec![
+            r#"[3,{"message":"Received a message before HELLO"},"wamp.error.protocol_violation"]"#
+        ]
+    );
+}
+
+#[test]
+fn protocol_violation_reaches_error_handler() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let reported = Arc::clone(&errors);
+    router.on_error(Box::new(move |error| reported.lock().unwrap().push(error)));
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    raw_exchange(&addr, "wamp.2.json", &[r#"[32,1,{},"ca.test.topic"]"#]);
+    raw_exchange(&addr, "wamp.2.json", &["[1,"]);
+
+    let errors = errors.lock().unwrap();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].kind, RouterErrorKind::ProtocolViolation);
+    assert!(errors[0].context.contains("Received a message before HELLO"));
+    assert_eq!(errors[1].kind, RouterErrorKind::Serialization);
+    assert_ne!(errors[0].session_id, errors[1].session_id);
+}
+
+#[test]
+fn close_code_tells_errors_from_normal_close() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let roles = r#"{"roles":{"publisher":{"features":{}},"subscriber":{"features":{}},"caller":{"features":{}},"callee":{"features":{}}}}"#;
+    let hello = format!(r#"[1,"ca.test.realm",{}]"#, roles);
+
+    let (_, code) =
+        raw_exchange_with_close(&addr, "wamp.2.json", &[r#"[32,1,{},"ca.test.topic"]"#]);
+    assert_eq!(code, Some(CloseCode::Protocol));
+
+    let (received, code) = raw_exchange_with_close(&addr, "wamp.2.json", &[&hello, "[48,1,"]);
+    assert_eq!(
+        received.last().unwrap(),
+        r#"[3,{"message":"Received a message that could not be parsed"},"wamp.error.protocol_violation"]"#
+    );
+    assert_eq!(code, Some(CloseCode::Protocol));
+
+    let (_, code) = raw_exchange_with_close(
+        &addr,
+        "wamp.2.json",
+        &[&format!(r#"[1,"ca.test.missing",{}]"#, roles)],
+    );
+    assert_eq!(code, Some(CloseCode::Policy));
+
+    let (_, code) = raw_exchange_with_close(
+        &addr,
+        "wamp.2.json",
+        &[&hello, r#"[6,{},"wamp.close.system_shutdown"]"#],
+    );
+    assert_eq!(code, Some(CloseCode::Normal));
+}
+
+#[test]
+fn unexpected_message_aborts() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    // Only callers receive RESULT
+    let received = raw_exchange(
+        &addr,
+        "wamp.2.json",
+        &[
+            r#"[1,"ca.test.realm",{"roles":{"publisher":{"features":{}},"subscriber":{"features":{}},"caller":{"features":{}},"callee":{"features":{}}}}]"#,
+            r#"[50,1,{}]"#,
+        ],
+    );
+    assert_eq!(received.len(), 2);
+    assert!(received[0].starts_with("[2,"));
+    assert_eq!(received[1], r#"[3,{},"wamp.error.protocol_violation"]"#);
+}
+
+#[test]
+fn second_hello_aborts() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    let hello = r#"[1,"ca.test.realm",{"roles":{"publisher":{"features":{}},"subscriber":{"features":{}},"caller":{"features":{}},"callee":{"features":{}}}}]"#;
+    let received = raw_exchange(&addr, "wamp.2.json", &[hello, hello]);
+    assert_eq!(received.len(), 2);
+    assert!(received[0].starts_with("[2,"));
+    assert_eq!(
+        received[1],
+        r#"[3,{"message":"Received a HELLO after WELCOME"},"wamp.error.protocol_violation"]"#
+    );
+    for _ in 0..50 {
+        if router.connection_count("ca.test.realm") == Some(0) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(router.connection_count("ca.test.realm"), Some(0));
+}
+
+#[test]
+fn hello_with_invalid_realm_aborts() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    let roles = r#"{"roles":{"publisher":{"features":{}},"subscriber":{"features":{}},"caller":{"features":{}},"callee":{"features":{}}}}"#;
+    let received = raw_exchange(&addr, "wamp.2.json", &[&format!(r#"[1,"",{}]"#, roles)]);
+    assert_eq!(received, vec![r#"[3,{},"wamp.error.no_such_realm"]"#]);
+    let received = raw_exchange(
+        &addr,
+        "wamp.2.json",
+        &[&format!(r#"[1,"ca..realm",{}]"#, roles)],
+    );
+    assert_eq!(received, vec![r#"[3,{},"wamp.error.protocol_violation"]"#]);
+
+    assert!(Connection::new(&format!("ws://{}/ws", addr), "")
+        .connect()
+        .is_err());
+}
+
+#[test]
+fn welcome_announces_agent() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    router.set_agent("ca.test.router");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    let received = raw_exchange(
+        &addr,
+        "wamp.2.json",
+        &[
+            r#"[1,"ca.test.realm",{"roles":{"publisher":{"features":{}},"subscriber":{"features":{}},"caller":{"features":{}},"callee":{"features":{}}}}]"#,
+            r#"[6,{},"wamp.close.close_realm"]"#,
+        ],
+    );
+    assert!(received[0].starts_with("[2,"));
+    assert!(received[0].contains(r#""agent":"ca.test.router""#));
+}
+
+#[test]
+fn batched_json_frames() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    let batch = concat!(
+        r#"[1,"ca.test.realm",{"roles":{"publisher":{"features":{}},"subscriber":{"features":{}},"caller":{"features":{}},"callee":{"features":{}}}}]"#,
+        "\u{1e}",
+        r#"[32,1,{},"ca.test.topic"]"#,
+        "\u{1e}",
+        r#"[6,{},"wamp.close.close_realm"]"#,
+        "\u{1e}",
+    );
+    let received = raw_exchange(&addr, "wamp.2.json.batched", &[batch]);
+    assert_eq!(received.len(), 3);
+    assert!(received[0].starts_with("[2,"));
+    assert!(received[1].starts_with("[33,1,"));
+    assert_eq!(received[2], "[6,{},\"wamp.error.goodbye_and_out\"]\u{1e}");
+    assert!(received.iter().all(|message| message.ends_with('\u{1e}')));
+}
+
+#[test]
+fn client_with_batched_json() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    let mut client = Connection::new(&format!("ws://{}/ws", addr), "ca.test.realm")
+        .with_batched_json()
+        .connect()
+        .unwrap();
+    assert_eq!(client.protocol(), "wamp.2.json.batched");
+    block_on(client.register(
+        URI::new("ca.test.echo"),
+        Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+    ))
+    .unwrap();
+    let (args, _kwargs) = block_on(client.call(
+        URI::new("ca.test.echo"),
+        Some(vec![Value::String("batched".to_string())]),
+        None,
+    ))
+    .unwrap();
+    assert_eq!(args, vec![Value::String("batched".to_string())]);
+}
+
+/// JSON carried in binary frames, under a sub-protocol only the tests know
+struct BinaryJsonSerializer;
+
+impl Serializer for BinaryJsonSerializer {
+    fn subprotocol_name(&self) -> &str {
+        "ca.test.json.binary"
+    }
+
+    fn encode(&self, message: &Message) -> WampResult<Payload> {
+        message
+            .to_json_string()
+            .map(|json| Payload::Binary(json.into_bytes()))
+    }
+
+    fn decode(&self, payload: &Payload) -> WampResult<Vec<Message>> {
+        let json = String::from_utf8_lossy(payload.as_bytes());
+        Message::from_json_str(&json).map(|message| vec![message])
+    }
+}
+
+#[test]
+fn custom_serializer() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    router.set_serializers(Serializers::default().prefer(BinaryJsonSerializer));
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    // The router's favourite is only picked by clients that offer it
+    let client = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    assert_eq!(client.protocol(), "wamp.2.msgpack");
+
+    let mut client = Connection::new(&url, "ca.test.realm")
+        .with_serializers(Serializers::empty().with(BinaryJsonSerializer))
+        .connect()
+        .unwrap();
+    assert_eq!(client.protocol(), "ca.test.json.binary");
+    block_on(client.register(
+        URI::new("ca.test.echo"),
+        Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+    ))
+    .unwrap();
+    let (args, _kwargs) = block_on(client.call(
+        URI::new("ca.test.echo"),
+        Some(vec![Value::String("binary".to_string())]),
+        None,
+    ))
+    .unwrap();
+    assert_eq!(args, vec![Value::String("binary".to_string())]);
+
+    // A router without the serializer refuses the handshake
+    let mut plain = Router::new();
+    plain.add_realm("ca.test.realm");
+    let (addr, _child) = plain.listen_addr("127.0.0.1:0").unwrap();
+    assert!(
+        Connection::new(&format!("ws://{}/ws", addr), "ca.test.realm")
+            .with_serializers(Serializers::empty().with(BinaryJsonSerializer))
+            .connect()
+            .is_err()
+    );
+}
+
+/// `wamp.2.json` that refuses to encode a PUBLISH, standing in for a replaced built in
+struct NoPublishJsonSerializer;
+
+impl Serializer for NoPublishJsonSerializer {
+    fn subprotocol_name(&self) -> &str {
+        "wamp.2.json"
+    }
+
+    fn encode(&self, message: &Message) -> WampResult<Payload> {
+        match message {
+            Message::Publish(..) => Err(Error::new(ErrorKind::InvalidState("No publishing"))),
+            _ => JsonSerializer.encode(message),
+        }
+    }
+
+    fn decode(&self, payload: &Payload) -> WampResult<Vec<Message>> {
+        JsonSerializer.decode(payload)
+    }
+}
+
+#[test]
+fn replaced_serializer_encodes_borrowed_messages() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let mut client = Connection::new(&url, "ca.test.realm")
+        .with_serializers(Serializers::empty().with(NoPublishJsonSerializer))
+        .connect()
+        .unwrap();
+    assert_eq!(client.protocol(), "wamp.2.json");
+    let args = [Value::String("borrowed".to_string())];
+    let error = client
+        .publish_borrowed(URI::new("ca.test.topic"), Some(&args), None)
+        .unwrap_err();
+    assert!(matches!(
+        error.kind,
+        ErrorKind::InvalidState("No publishing")
+    ));
+}
+
+/// JSON under a sub-protocol only the tests know, which refuses to encode an EVENT
+struct NoEventJsonSerializer;
+
+impl Serializer for NoEventJsonSerializer {
+    fn subprotocol_name(&self) -> &str {
+        "ca.test.json.no_event"
+    }
+
+    fn encode(&self, message: &Message) -> WampResult<Payload> {
+        match message {
+            Message::Event(..) => Err(Error::new(ErrorKind::InvalidState("No events"))),
+            _ => JsonSerializer.encode(message),
+        }
+    }
+
+    fn decode(&self, payload: &Payload) -> WampResult<Vec<Message>> {
+        JsonSerializer.decode(payload)
+    }
+}
+
+#[test]
+fn event_that_cannot_be_encoded_fails_its_sender() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    router.set_serializers(Serializers::default().with(NoEventJsonSerializer));
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let reported = Arc::clone(&errors);
+    router.on_error(Box::new(move |error| reported.lock().unwrap().push(error)));
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    let mut subscriber = Connection::new(&format!("ws://{}/ws", addr), "ca.test.realm")
+        .with_serializers(Serializers::empty().with(NoEventJsonSerializer))
+        .connect()
+        .unwrap();
+    block_on(subscriber.subscribe(URI::new("ca.test.topic"), Box::new(|_, _| {}))).unwrap();
+
+    let mut publisher = router.connect_loopback("ca.test.realm").unwrap();
+    let (_, recipients) =
+        block_on(publisher.publish_and_acknowledge(URI::new("ca.test.topic"), None, None)).unwrap();
+    assert_eq!(recipients, 0);
+    let errors = errors.lock().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind, RouterErrorKind::SendFailed);
+    assert!(errors[0].context.contains("No events"));
+}
+
+#[test]
+fn drain_finishes_in_flight_calls() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let mut callee = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    block_on(callee.register(
+        URI::new("ca.test.slow"),
+        Box::new(|args, kwargs| {
+            thread::sleep(Duration::from_millis(500));
+            Ok((Some(args), Some(kwargs)))
+        }),
+    ))
+    .unwrap();
+    let mut caller = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    let mut late_caller = Connection::new(&url, "ca.test.realm").connect().unwrap();
+
+    let result = caller.call(
+        URI::new("ca.test.slow"),
+        Some(wamp_list!["in flight"]),
+        None,
+    );
+    thread::sleep(Duration::from_millis(100));
+    let late = thread::spawn(move || {
+        // Called once the router has started draining
+        thread::sleep(Duration::from_millis(100));
+        let error = block_on(late_caller.call(URI::new("ca.test.slow"), None, None)).unwrap_err();
+        error.get_reason().clone()
+    });
+    router.drain(Duration::from_secs(5));
+
+    let (args, _kwargs) = block_on(result).unwrap();
+    assert_eq!(args, wamp_list!["in flight"]);
+    assert_eq!(late.join().unwrap(), Reason::SystemShutdown);
+}
+
+#[test]
+fn session_subscription_and_registration_limits() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    router.set_max_subscriptions(2);
+    router.set_max_registrations(1);
+
+    let mut client = router.connect_loopback("ca.test.realm").unwrap();
+    let mut subscriptions = Vec::new();
+    for topic in &["ca.test.first", "ca.test.second"] {
+        subscriptions
+            .push(block_on(client.subscribe(URI::new(topic), Box::new(|_, _| {}))).unwrap());
+    }
+    let error =
+        block_on(client.subscribe(URI::new("ca.test.third"), Box::new(|_, _| {}))).unwrap_err();
+    assert_eq!(error.get_reason(), &Reason::NotAuthorized);
+    // Subscribing again to a held topic returns the same subscription, even at the limit
+    let again = block_on(client.subscribe(URI::new("ca.test.first"), Box::new(|_, _| {}))).unwrap();
+    assert_eq!(again.id(), subscriptions[0].id());
+
+    let first = block_on(client.register(
+        URI::new("ca.test.first"),
+        Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+    ))
+    .unwrap();
+    let error = block_on(client.register(
+        URI::new("ca.test.second"),
+        Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+    ))
+    .unwrap_err();
+    assert_eq!(error.get_reason(), &Reason::NotAuthorized);
+
+    // Giving one up makes room for another
+    block_on(client.unregister(first)).unwrap();
+    block_on(client.register(
+        URI::new("ca.test.second"),
+        Box::new(|args, kwargs| Ok((Some(args), Some(kwargs)))),
+    ))
+    .unwrap();
+}
+
+#[test]
+fn non_finite_float_over_json_keeps_sessions_open() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+
+    let mut subscriber = Connection::new(&url, "ca.test.realm")
+        .with_batched_json()
+        .connect()
+        .unwrap();
+    let (sink, events) = channel();
+    block_on(subscriber.subscribe(
+        URI::new("ca.test.topic"),
+        Box::new(move |args, _kwargs| sink.send(args).unwrap()),
+    ))
+    .unwrap();
+
+    let mut publisher = Connection::new(&url, "ca.test.realm")
+        .with_batched_json()
+        .connect()
+        .unwrap();
+    publisher
+        .publish(
+            URI::new("ca.test.topic"),
+            Some(vec![Value::Float(f64::NAN)]),
+            None,
+        )
+        .unwrap();
+    publisher
+        .publish(URI::new("ca.test.topic"), Some(wamp_list![1]), None)
+        .unwrap();
+
+    // JSON has no NaN, so it arrives spelled out
+    let timeout = Duration::from_secs(5);
+    assert_eq!(events.recv_timeout(timeout).unwrap(), wamp_list!["NaN"]);
+    assert_eq!(events.recv_timeout(timeout).unwrap(), wamp_list![1]);
+}
+
+#[test]
+fn realm_stats() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", addr);
+    assert_eq!(router.stats("ca.test.realm"), Some(RealmStats::default()));
+    assert_eq!(router.stats("ca.test.missing"), None);
+
+    let mut callee = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    block_on(callee.register(
+        URI::new("ca.test.slow"),
+        Box::new(|args, kwargs| {
+            thread::sleep(Duration::from_millis(500));
+            Ok((Some(args), Some(kwargs)))
+        }),
+    ))
+    .unwrap();
+    let mut caller = Connection::new(&url, "ca.test.realm").connect().unwrap();
+    let subscription =
+        block_on(caller.subscribe(URI::new("ca.test.topic"), Box::new(|_, _| {}))).unwrap();
+    block_on(callee.subscribe(URI::new("ca.test.topic"), Box::new(|_, _| {}))).unwrap();
+
+    let result = caller.call(URI::new("ca.test.slow"), None, None);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(router.connection_count("ca.test.realm"), Some(2));
+    assert_eq!(
+        router.stats("ca.test.realm"),
+        Some(RealmStats {
+            connections: 2,
+            subscriptions: 1,
+            registrations: 1,
+            active_calls: 1,
+        })
+    );
+
+    block_on(result).unwrap();
+    block_on(caller.unsubscribe(subscription)).unwrap();
+    let stats = router.stats("ca.test.realm").unwrap();
+    assert_eq!(stats.subscriptions, 1);
+    assert_eq!(stats.active_calls, 0);
+}
+
+#[test]
+fn realm_selected_by_path() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.one");
+    router.add_realm("ca.test.two");
+    router.set_realm_by_path(true);
+    let (addr, _child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    Connection::new(&format!("ws://{}/ca.test.one", addr), "ca.test.one")
+        .connect()
+        .unwrap();
+    assert!(
+        Connection::new(&format!("ws://{}/ca.test.one", addr), "ca.test.two")
+            .connect()
+            .is_err()
+    );
+    assert!(
+        Connection::new(&format!("ws://{}/ca.test.missing", addr), "ca.test.one")
+            .connect()
+            .is_err()
+    );
+    assert_eq!(router.connection_count("ca.test.one"), Some(1));
+    assert_eq!(router.connection_count("ca.test.two"), Some(0));
+}
+
+#[test]
+fn oversized_message_closes_connection() {
+    let mut router = Router::new();
+    router.add_realm("ca.test.realm");
+    router.set_max_message_size(200);
+    let (addr, child) = router.listen_addr("127.0.0.1:0").unwrap();
+
+    let publish = format!(r#"[16,1,{{}},"ca.test.topic",["{}"]]"#, "x".repeat(200));
+    let received = raw_exchange(
+        &addr,
+        "wamp.2.json",
+        &[
+            r#"[1,"ca.test.realm",{"roles":{"publisher":{"features":{}},"subscriber":{"features":{}},"caller":{"features":{}},"callee":{"features":{}}}}]"#,
+            &publish,
+        ],
+    );
+    assert_eq!(received.len(), 1);
+    assert!(received[0].starts_with("[2,"));
+    assert_eq!(router.connection_count("ca.test.realm"), Some(0));
+
+    // The router carries on serving other connections
+    Connection::new(&format!("ws://{}/ws", addr), "ca.test.realm")
+        .connect()
+        .unwrap();
+    assert!(!child.is_finished());
+}